@@ -41,6 +41,10 @@ impl Aabb {
         longest
     }
 
+    pub fn diagonal(&self) -> Float {
+        self.min.distance(self.max)
+    }
+
     pub fn longest_edge_i(&self) -> usize {
         let mut longest = 0.0;
         let mut index = 0;
@@ -86,6 +90,143 @@ impl Intersect<'_, Float> for Aabb {
     }
 }
 
+/// Intersect a ray with two boxes at once.
+/// Return the entry distances of the hits like Aabb::intersect.
+pub fn intersect_pair(a: &Aabb, b: &Aabb, ray: &Ray) -> (Option<Float>, Option<Float>) {
+    // Axis aligned rays need the separate slab test of the scalar version
+    if ray.dir.x == 0.0 || ray.dir.y == 0.0 || ray.dir.z == 0.0 {
+        return (a.intersect(ray), b.intersect(ray));
+    }
+    let (start, end) = slabs_pair(a, b, ray);
+    let hit = |i: usize| {
+        if start[i] <= end[i] && end[i] > 0.0 && start[i] < ray.length {
+            Some(start[i])
+        } else {
+            None
+        }
+    };
+    (hit(0), hit(1))
+}
+
+/// Entry and exit distances of the ray for boxes a and b computed with SSE2.
+/// The operand order of min and max matches the scalar version so that
+/// NaNs from 0 * inf are ignored in the same way.
+#[cfg(target_arch = "x86_64")]
+fn slabs_pair(a: &Aabb, b: &Aabb, ray: &Ray) -> ([Float; 2], [Float; 2]) {
+    use self::lanes::*;
+    // Safety: SSE2 is always available on x86_64
+    unsafe {
+        let mut start = splat(consts::MIN);
+        let mut end = splat(consts::MAX);
+        for i in 0..3 {
+            let orig = splat(ray.orig[i]);
+            let reciprocal_dir = splat(ray.reciprocal_dir[i]);
+            let t1 = mul(sub(pair(a.min[i], b.min[i]), orig), reciprocal_dir);
+            let t2 = mul(sub(pair(a.max[i], b.max[i]), orig), reciprocal_dir);
+            let (near, far) = if ray.neg_dir[i] { (t2, t1) } else { (t1, t2) };
+            start = max(near, start);
+            end = min(far, end);
+        }
+        (to_array(start), to_array(end))
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn slabs_pair(a: &Aabb, b: &Aabb, ray: &Ray) -> ([Float; 2], [Float; 2]) {
+    let mut start = [consts::MIN; 2];
+    let mut end = [consts::MAX; 2];
+    for (j, aabb) in [a, b].iter().enumerate() {
+        for i in 0..3 {
+            let t1 = (aabb.min[i] - ray.orig[i]) * ray.reciprocal_dir[i];
+            let t2 = (aabb.max[i] - ray.orig[i]) * ray.reciprocal_dir[i];
+            let (near, far) = if ray.neg_dir[i] { (t2, t1) } else { (t1, t2) };
+            start[j] = start[j].max(near);
+            end[j] = end[j].min(far);
+        }
+    }
+    (start, end)
+}
+
+/// Two lane vectors of Float
+#[cfg(all(target_arch = "x86_64", not(feature = "single_precision")))]
+mod lanes {
+    use std::arch::x86_64::*;
+
+    pub type Lanes = __m128d;
+
+    pub unsafe fn pair(a: f64, b: f64) -> Lanes {
+        _mm_set_pd(b, a)
+    }
+
+    pub unsafe fn splat(a: f64) -> Lanes {
+        _mm_set1_pd(a)
+    }
+
+    pub unsafe fn sub(a: Lanes, b: Lanes) -> Lanes {
+        _mm_sub_pd(a, b)
+    }
+
+    pub unsafe fn mul(a: Lanes, b: Lanes) -> Lanes {
+        _mm_mul_pd(a, b)
+    }
+
+    /// Return b if a is NaN
+    pub unsafe fn min(a: Lanes, b: Lanes) -> Lanes {
+        _mm_min_pd(a, b)
+    }
+
+    /// Return b if a is NaN
+    pub unsafe fn max(a: Lanes, b: Lanes) -> Lanes {
+        _mm_max_pd(a, b)
+    }
+
+    pub unsafe fn to_array(a: Lanes) -> [f64; 2] {
+        let mut array = [0.0; 2];
+        _mm_storeu_pd(array.as_mut_ptr(), a);
+        array
+    }
+}
+
+/// Two lane vectors of Float. Only the two lowest of the four lanes are used.
+#[cfg(all(target_arch = "x86_64", feature = "single_precision"))]
+mod lanes {
+    use std::arch::x86_64::*;
+
+    pub type Lanes = __m128;
+
+    pub unsafe fn pair(a: f32, b: f32) -> Lanes {
+        _mm_set_ps(0.0, 0.0, b, a)
+    }
+
+    pub unsafe fn splat(a: f32) -> Lanes {
+        _mm_set1_ps(a)
+    }
+
+    pub unsafe fn sub(a: Lanes, b: Lanes) -> Lanes {
+        _mm_sub_ps(a, b)
+    }
+
+    pub unsafe fn mul(a: Lanes, b: Lanes) -> Lanes {
+        _mm_mul_ps(a, b)
+    }
+
+    /// Return b if a is NaN
+    pub unsafe fn min(a: Lanes, b: Lanes) -> Lanes {
+        _mm_min_ps(a, b)
+    }
+
+    /// Return b if a is NaN
+    pub unsafe fn max(a: Lanes, b: Lanes) -> Lanes {
+        _mm_max_ps(a, b)
+    }
+
+    pub unsafe fn to_array(a: Lanes) -> [f32; 2] {
+        let mut array = [0.0; 4];
+        _mm_storeu_ps(array.as_mut_ptr(), a);
+        [array[0], array[1]]
+    }
+}
+
 pub fn min_point(p1: &Point3<Float>, p2: &Point3<Float>) -> Point3<Float> {
     let mut p_min = Point3::max_value();
     for i in 0..3 {
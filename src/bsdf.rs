@@ -12,6 +12,9 @@ mod microfacet;
 mod specular;
 mod util;
 
+use self::fresnel::Fresnel;
+
+pub use self::fresnel::Conductor;
 use self::lambertian::*;
 use self::microfacet::*;
 use self::specular::*;
@@ -22,6 +25,8 @@ use self::specular::*;
 /// where (0, 0, 1) is the normal pointing outwards
 pub trait BsdfT {
     fn is_specular(&self) -> bool;
+    /// Base color of the surface
+    fn albedo(&self) -> Color;
     /// Evaluate reflected radiance
     fn brdf(&self, wo: Vector3<Float>, wi: Vector3<Float>) -> Color;
     /// Evaluate transmitted radiance
@@ -34,6 +39,16 @@ pub trait BsdfT {
         wo: Vector3<Float>,
         path_type: PathType,
     ) -> Option<(Color, Vector3<Float>, Float)>;
+    /// Evaluate the value divided by the pdf of a specular sample from wo to wi.
+    /// Wi is assumed to be the direction that sample returns for the hemisphere.
+    fn specular_weight(
+        &self,
+        _wo: Vector3<Float>,
+        _wi: Vector3<Float>,
+        _path_type: PathType,
+    ) -> Color {
+        Color::black()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -55,16 +70,31 @@ impl Bsdf {
         Bsdf::Lr(LambertianBrdf::new(color))
     }
 
-    pub fn microfacet_brdf(color: Color, shininess: Float) -> Self {
-        Bsdf::Mr(MicrofacetBrdf::with_schlick(color, shininess))
+    /// Fresnel uses eta if it is given and Schlick otherwise
+    pub fn microfacet_brdf(color: Color, shininess: Float, eta: Option<Float>) -> Self {
+        Bsdf::Mr(MicrofacetBrdf::new(
+            color,
+            shininess,
+            reflection_fresnel(eta),
+        ))
+    }
+
+    /// Glossy conductor if shininess is given and specular otherwise
+    pub fn conductor_brdf(conductor: Conductor, shininess: Option<Float>) -> Self {
+        let fresnel = Fresnel::Conductor(conductor);
+        match shininess {
+            Some(shininess) => Bsdf::Mr(MicrofacetBrdf::new(Color::white(), shininess, fresnel)),
+            None => Bsdf::Sr(SpecularBrdf::new(Color::white(), fresnel)),
+        }
     }
 
     pub fn microfacet_bsdf(reflect: Color, transmit: Color, shininess: Float, eta: Float) -> Self {
         Bsdf::Ms(MicrofacetBsdf::new(reflect, transmit, shininess, eta))
     }
 
-    pub fn specular_brdf(color: Color) -> Self {
-        Bsdf::Sr(SpecularBrdf::with_schlick(color))
+    /// Fresnel uses eta if it is given and Schlick otherwise
+    pub fn specular_brdf(color: Color, eta: Option<Float>) -> Self {
+        Bsdf::Sr(SpecularBrdf::new(color, reflection_fresnel(eta)))
     }
 
     pub fn specular_bsdf(reflect: Color, transmit: Color, eta: Float) -> Self {
@@ -72,6 +102,23 @@ impl Bsdf {
     }
 }
 
+impl Bsdf {
+    /// Compensate the energy lost by single scattering microfacet reflection.
+    /// Other distributions, including microfacet transmission, are left as is.
+    pub fn compensate_energy(&mut self) {
+        if let Bsdf::Mr(brdf) = self {
+            brdf.compensate_energy();
+        }
+    }
+}
+
+fn reflection_fresnel(eta: Option<Float>) -> Fresnel {
+    match eta {
+        Some(eta) => Fresnel::Dielectric(eta),
+        None => Fresnel::Schlick,
+    }
+}
+
 impl Deref for Bsdf {
     type Target = dyn BsdfT;
 
@@ -3,6 +3,7 @@ use cgmath::Vector3;
 use crate::color::Color;
 use crate::float::*;
 use crate::pt_renderer::PathType;
+use crate::sampler;
 
 use super::util;
 use super::BsdfT;
@@ -28,11 +29,96 @@ fn dielectric(w: Vector3<Float>, eta_mat: Float) -> Float {
     (paral.powi(2) + perp.powi(2)) / 2.0
 }
 
+/// Fresnel reflection for w of a single channel of a conductor
+fn conductor_channel(w: Vector3<Float>, eta: Float, k: Float) -> Float {
+    let cos2_t = util::cos_t(w).abs().min(1.0).powi(2);
+    let sin2_t = 1.0 - cos2_t;
+    let eta2 = eta.powi(2);
+    let k2 = k.powi(2);
+    let t0 = eta2 - k2 - sin2_t;
+    let a2_plus_b2 = (t0.powi(2) + 4.0 * eta2 * k2).sqrt();
+    let t1 = a2_plus_b2 + cos2_t;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos2_t.sqrt() * a;
+    let perp = (t1 - t2) / (t1 + t2);
+    let t3 = cos2_t * a2_plus_b2 + sin2_t.powi(2);
+    let t4 = t2 * sin2_t;
+    let paral = perp * (t3 - t4) / (t3 + t4);
+    (paral + perp) / 2.0
+}
+
+/// Complex index of refraction eta + ik of a conductor for each channel
+#[derive(Clone, Copy, Debug)]
+pub struct Conductor {
+    pub eta: Color,
+    pub k: Color,
+}
+
+impl Conductor {
+    pub fn new(eta: Color, k: Color) -> Self {
+        Self { eta, k }
+    }
+
+    /// Get the conductor by name. Values are RGB fits of measured data.
+    pub fn from_preset(name: &str) -> Option<Self> {
+        let (eta, k) = match name.to_lowercase().as_str() {
+            "gold" | "au" => (
+                [0.143_119, 0.374_957, 1.442_48],
+                [3.983_16, 2.385_72, 1.603_22],
+            ),
+            "copper" | "cu" => (
+                [0.200_438, 0.924_033, 1.102_21],
+                [3.912_95, 2.452_85, 2.142_19],
+            ),
+            "aluminum" | "aluminium" | "al" => (
+                [1.657_46, 0.880_369, 0.521_229],
+                [9.223_87, 6.269_52, 4.837],
+            ),
+            _ => return None,
+        };
+        Some(Self::new(Color::from(eta), Color::from(k)))
+    }
+
+    /// Fresnel reflection for w
+    pub fn reflectance(&self, w: Vector3<Float>) -> Color {
+        Color::new(
+            conductor_channel(w, self.eta.r(), self.k.r()),
+            conductor_channel(w, self.eta.g(), self.k.g()),
+            conductor_channel(w, self.eta.b(), self.k.b()),
+        )
+    }
+}
+
 pub fn schlick(w: Vector3<Float>, specular: Color) -> Color {
     let cos_t = util::cos_t(w).abs();
     specular + (1.0 - cos_t).powi(5) * (Color::white() - specular)
 }
 
+/// Fresnel term of a reflection
+#[derive(Clone, Copy, Debug)]
+pub enum Fresnel {
+    /// Color is used as is
+    Off,
+    /// Schlick's approximation with color as the normal incidence reflectance
+    Schlick,
+    /// Dielectric reflectance with the given index of refraction tinted by color
+    Dielectric(Float),
+    /// Conductor reflectance tinted by color
+    Conductor(Conductor),
+}
+
+impl Fresnel {
+    /// Apply the fresnel term to the color of a reflection of w
+    pub fn apply(self, w: Vector3<Float>, color: Color) -> Color {
+        match self {
+            Fresnel::Off => color,
+            Fresnel::Schlick => schlick(w, color),
+            Fresnel::Dielectric(eta) => dielectric(w, eta) * color,
+            Fresnel::Conductor(conductor) => conductor.reflectance(w) * color,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct FresnelBsdf<R: BsdfT, T: BsdfT> {
     pub brdf: R,
@@ -45,6 +131,11 @@ impl<R: BsdfT, T: BsdfT> BsdfT for FresnelBsdf<R, T> {
         self.brdf.is_specular() || self.btdf.is_specular()
     }
 
+    fn albedo(&self) -> Color {
+        // Most of the light is transmitted through dielectrics
+        self.btdf.albedo()
+    }
+
     fn brdf(&self, wo: Vector3<Float>, wi: Vector3<Float>) -> Color {
         let fr = dielectric(wo, self.eta);
         fr * self.brdf.brdf(wo, wi)
@@ -71,7 +162,7 @@ impl<R: BsdfT, T: BsdfT> BsdfT for FresnelBsdf<R, T> {
         path_type: PathType,
     ) -> Option<(Color, Vector3<Float>, Float)> {
         let fr = dielectric(wo, self.eta);
-        if rand::random::<Float>() < fr {
+        if sampler::random() < fr {
             let (color, wi, pdf) = self.brdf.sample(wo, path_type)?;
             Some((fr * color, wi, fr * pdf))
         } else {
@@ -80,4 +171,77 @@ impl<R: BsdfT, T: BsdfT> BsdfT for FresnelBsdf<R, T> {
             Some((ft * color, wi, ft * pdf))
         }
     }
+
+    fn specular_weight(
+        &self,
+        wo: Vector3<Float>,
+        wi: Vector3<Float>,
+        path_type: PathType,
+    ) -> Color {
+        // Fresnel term cancels out of the value and pdf of the chosen lobe
+        if util::same_hemisphere(wo, wi) {
+            self.brdf.specular_weight(wo, wi, path_type)
+        } else {
+            self.btdf.specular_weight(wo, wi, path_type)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bsdf::Bsdf;
+
+    #[test]
+    fn conductor_at_normal_incidence() {
+        let gold = Conductor::from_preset("Gold").unwrap();
+        let r = gold.reflectance(Vector3::unit_z());
+        for &(r, eta, k) in &[
+            (r.r(), gold.eta.r(), gold.k.r()),
+            (r.g(), gold.eta.g(), gold.k.g()),
+            (r.b(), gold.eta.b(), gold.k.b()),
+        ] {
+            let expected = ((eta - 1.0).powi(2) + k.powi(2)) / ((eta + 1.0).powi(2) + k.powi(2));
+            assert!((r - expected).abs() < 1e-4, "{} {}", r, expected);
+        }
+        // Gold reflects more red than blue
+        assert!(r.r() > r.b());
+    }
+
+    #[test]
+    fn conductor_without_absorption_is_dielectric() {
+        for &cos_t in &[1.0 as Float, 0.8, 0.5, 0.1] {
+            let w = Vector3::new((1.0 - cos_t.powi(2)).sqrt(), 0.0, cos_t);
+            let (r_c, r_d) = (conductor_channel(w, 1.5, 0.0), dielectric(w, 1.5));
+            assert!((r_c - r_d).abs() < 1e-4, "{} {}", r_c, r_d);
+        }
+    }
+
+    #[test]
+    fn conductor_presets() {
+        for name in &["gold", "au", "copper", "Cu", "aluminum", "aluminium", "AL"] {
+            assert!(Conductor::from_preset(name).is_some(), "{}", name);
+        }
+        assert!(Conductor::from_preset("silver").is_none());
+    }
+
+    #[test]
+    fn higher_index_reflects_more() {
+        let normal = Vector3::unit_z();
+        let mut previous = (0.0, 0.0);
+        for &eta in &[1.2, 1.5, 2.0, 2.5] {
+            let r = Fresnel::Dielectric(eta)
+                .apply(normal, Color::white())
+                .luma();
+            let expected = ((eta - 1.0) / (eta + 1.0)).powi(2);
+            assert!((r - expected).abs() < 1e-6, "{} {}", r, expected);
+            // Glossy reflection of a material with the index of refraction
+            let glossy = Bsdf::microfacet_brdf(Color::white(), 100.0, Some(eta))
+                .brdf(normal, normal)
+                .luma();
+            assert!(r > previous.0 && glossy > previous.1, "{}", eta);
+            previous = (r, glossy);
+        }
+    }
 }
@@ -25,6 +25,10 @@ impl BsdfT for LambertianBrdf {
         false
     }
 
+    fn albedo(&self) -> Color {
+        self.color
+    }
+
     fn brdf(&self, _wo: Vector3<Float>, _wi: Vector3<Float>) -> Color {
         self.color / consts::PI
     }
@@ -6,11 +6,85 @@ use crate::consts;
 use crate::float::*;
 use crate::pt_renderer::PathType;
 use crate::sample;
+use crate::sampler;
 
-use super::fresnel::{self, FresnelBsdf};
+use super::fresnel::{self, Fresnel, FresnelBsdf};
 use super::util;
 use super::BsdfT;
 
+/// Resolution of the directional albedo table along both axes
+const ALBEDO_TABLE_SIZE: usize = 32;
+/// Number of samples per direction used to integrate a single albedo table entry
+const ALBEDO_SAMPLES: usize = 32;
+
+lazy_static::lazy_static! {
+    static ref GGX_ALBEDO: AlbedoTable = AlbedoTable::compute();
+}
+
+/// Directional albedo of single scattering Ggx reflection without fresnel
+/// tabulated over alpha and the cosine of the outgoing direction
+struct AlbedoTable {
+    /// Albedo for each (alpha, cos_t) pair
+    albedo: Vec<Float>,
+    /// Cosine weighted average albedo for each alpha
+    average: Vec<Float>,
+}
+
+impl AlbedoTable {
+    fn compute() -> Self {
+        let n = ALBEDO_TABLE_SIZE;
+        let mut albedo = Vec::with_capacity(n * n);
+        let mut average = Vec::with_capacity(n);
+        for alpha_i in 0..n {
+            let ggx = Ggx {
+                alpha: Self::grid_value(alpha_i),
+            };
+            let mut avg = 0.0;
+            for cos_i in 0..n {
+                let e = ggx.albedo(Self::grid_value(cos_i));
+                albedo.push(e);
+                // Midpoint rule for 2 * int_0^1 E(mu) mu dmu
+                let mu = (cos_i.to_float() + 0.5) / n.to_float();
+                avg += 2.0 * ggx.albedo(mu) * mu / n.to_float();
+            }
+            average.push(avg);
+        }
+        Self { albedo, average }
+    }
+
+    /// Table values are sampled at [0.01, 1] to avoid degenerate distributions
+    fn grid_value(i: usize) -> Float {
+        0.01 + 0.99 * i.to_float() / (ALBEDO_TABLE_SIZE - 1).to_float()
+    }
+
+    /// Continuous table coordinate of value
+    fn grid_pos(value: Float) -> Float {
+        let pos = (value - 0.01) / 0.99 * (ALBEDO_TABLE_SIZE - 1).to_float();
+        pos.clamp(0.0, (ALBEDO_TABLE_SIZE - 1).to_float())
+    }
+
+    fn lerp(values: &[Float], pos: Float) -> Float {
+        let i = (pos.floor() as usize).min(values.len() - 2);
+        let t = pos - i.to_float();
+        (1.0 - t) * values[i] + t * values[i + 1]
+    }
+
+    fn albedo(&self, alpha: Float, cos_t: Float) -> Float {
+        let n = ALBEDO_TABLE_SIZE;
+        let alpha_pos = Self::grid_pos(alpha);
+        let cos_pos = Self::grid_pos(cos_t);
+        let alpha_i = (alpha_pos.floor() as usize).min(n - 2);
+        let t = alpha_pos - alpha_i.to_float();
+        let e1 = Self::lerp(&self.albedo[alpha_i * n..(alpha_i + 1) * n], cos_pos);
+        let e2 = Self::lerp(&self.albedo[(alpha_i + 1) * n..(alpha_i + 2) * n], cos_pos);
+        (1.0 - t) * e1 + t * e2
+    }
+
+    fn average(&self, alpha: Float) -> Float {
+        Self::lerp(&self.average, Self::grid_pos(alpha))
+    }
+}
+
 /// Ggx (Trowbridge-Reitz) microfacet distribution
 #[derive(Clone, Debug)]
 struct Ggx {
@@ -49,8 +123,12 @@ impl Ggx {
     // https://agraphicsguy.wordpress.com/2015/11/01/sampling-microfacet-brdf/
     // TODO: Take shadowing into account
     fn sample_wh(&self, wo: Vector3<Float>) -> Vector3<Float> {
-        let phi = 2.0 * consts::PI * rand::random::<Float>();
-        let r1 = rand::random::<Float>();
+        self.warp_wh(wo, sampler::random(), sampler::random())
+    }
+
+    /// Map uniform random numbers r1 and r2 to a half vector distributed by pdf_wh
+    fn warp_wh(&self, wo: Vector3<Float>, r1: Float, r2: Float) -> Vector3<Float> {
+        let phi = 2.0 * consts::PI * r2;
         let a2 = self.alpha.powi(2);
         let cos2_t = (1.0 - r1) / (r1 * (a2 - 1.0) + 1.0);
         let sin_t = (1.0 - cos2_t).sqrt();
@@ -68,29 +146,75 @@ impl Ggx {
     fn pdf_wh(&self, _wo: Vector3<Float>, wh: Vector3<Float>) -> Float {
         self.d_wh(wh) * util::cos_t(wh).abs()
     }
+
+    /// Integrate the directional albedo of reflection without fresnel
+    /// for an outgoing direction with cosine cos_t
+    fn albedo(&self, cos_t: Float) -> Float {
+        let wo = Vector3::new((1.0 - cos_t.powi(2)).max(0.0).sqrt(), 0.0, cos_t);
+        let n = ALBEDO_SAMPLES;
+        let mut sum = 0.0;
+        for j in 0..n {
+            for i in 0..n {
+                let r1 = (j.to_float() + 0.5) / n.to_float();
+                let r2 = (i.to_float() + 0.5) / n.to_float();
+                let wh = self.warp_wh(wo, r1, r2);
+                let wi = util::reflect(wo, wh);
+                if !util::same_hemisphere(wo, wi) {
+                    continue;
+                }
+                // brdf * cos_i / pdf simplifies to this
+                sum += self.g(wo, wi) * wo.dot(wh).abs() / (cos_t * util::cos_t(wh).abs());
+            }
+        }
+        sum / (n * n).to_float()
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct MicrofacetBrdf {
     color: Color,
     microfacets: Ggx,
-    use_schlick: bool,
+    fresnel: Fresnel,
+    /// Scale of the multiple scattering lobe if energy compensation is on
+    multiple_scattering: Option<Color>,
 }
 
 impl MicrofacetBrdf {
-    pub fn with_schlick(color: Color, exponent: Float) -> Self {
+    pub fn new(color: Color, exponent: Float, fresnel: Fresnel) -> Self {
         Self {
             color,
             microfacets: Ggx::from_exponent(exponent),
-            use_schlick: true,
+            fresnel,
+            multiple_scattering: None,
         }
     }
 
-    pub fn without_schlick(color: Color, exponent: Float) -> Self {
-        Self {
-            color,
-            microfacets: Ggx::from_exponent(exponent),
-            use_schlick: false,
+    /// Add the energy lost to multiple scattering back as a diffuse like lobe.
+    /// Kulla and Conty 2017: Revisiting Physically Based Shading at Imageworks
+    pub fn compensate_energy(&mut self) {
+        let e_avg = GGX_ALBEDO.average(self.microfacets.alpha);
+        // Cosine weighted average of the fresnel term
+        let n = 16usize;
+        let mut f_avg = Color::black();
+        for i in 0..n {
+            let mu = (i.to_float() + 0.5) / n.to_float();
+            let w = Vector3::new((1.0 - mu.powi(2)).sqrt(), 0.0, mu);
+            f_avg += 2.0 * mu / n.to_float() * self.fresnel.apply(w, self.color);
+        }
+        let f_ms = |f: Float| f.powi(2) * e_avg / (1.0 - f * (1.0 - e_avg));
+        let f_ms = Color::new(f_ms(f_avg.r()), f_ms(f_avg.g()), f_ms(f_avg.b()));
+        self.multiple_scattering = Some(f_ms / (consts::PI * (1.0 - e_avg)));
+    }
+
+    fn multiple_scattering(&self, wo: Vector3<Float>, wi: Vector3<Float>) -> Color {
+        match self.multiple_scattering {
+            Some(scale) => {
+                let alpha = self.microfacets.alpha;
+                let e_o = GGX_ALBEDO.albedo(alpha, util::cos_t(wo).abs());
+                let e_i = GGX_ALBEDO.albedo(alpha, util::cos_t(wi).abs());
+                (1.0 - e_o) * (1.0 - e_i) * scale
+            }
+            None => Color::black(),
         }
     }
 }
@@ -100,17 +224,16 @@ impl BsdfT for MicrofacetBrdf {
         false
     }
 
+    fn albedo(&self) -> Color {
+        self.color
+    }
+
     fn brdf(&self, wo: Vector3<Float>, wi: Vector3<Float>) -> Color {
         let g = self.microfacets.g(wo, wi);
         let wh = (wo + wi).normalize();
         let d = self.microfacets.d_wh(wh);
         let denom = 4.0 * wo.z * wi.z;
-        let color = if self.use_schlick {
-            fresnel::schlick(wo, self.color)
-        } else {
-            self.color
-        };
-        color * d * g / denom
+        self.fresnel.apply(wo, self.color) * d * g / denom + self.multiple_scattering(wo, wi)
     }
 
     fn btdf(&self, _wo: Vector3<Float>, _wi: Vector3<Float>, _path_type: PathType) -> Color {
@@ -164,6 +287,10 @@ impl BsdfT for FresnelBlendBrdf {
         false
     }
 
+    fn albedo(&self) -> Color {
+        self.diffuse
+    }
+
     fn brdf(&self, wo: Vector3<Float>, wi: Vector3<Float>) -> Color {
         let wh = (wo + wi).normalize();
         let d = self.microfacets.d_wh(wh);
@@ -196,7 +323,7 @@ impl BsdfT for FresnelBlendBrdf {
         wo: Vector3<Float>,
         _path_type: PathType,
     ) -> Option<(Color, Vector3<Float>, Float)> {
-        let wi = if rand::random::<Float>() < 0.5 {
+        let wi = if sampler::random() < 0.5 {
             let wh = self.microfacets.sample_wh(wo);
             let wi = util::reflect(wo, wh);
             if !util::same_hemisphere(wo, wi) {
@@ -245,6 +372,10 @@ impl BsdfT for MicrofacetBtdf {
         false
     }
 
+    fn albedo(&self) -> Color {
+        self.color
+    }
+
     fn brdf(&self, _wo: Vector3<Float>, _wi: Vector3<Float>) -> Color {
         Color::black()
     }
@@ -302,7 +433,7 @@ pub type MicrofacetBsdf = FresnelBsdf<MicrofacetBrdf, MicrofacetBtdf>;
 
 impl MicrofacetBsdf {
     pub fn new(reflect: Color, transmit: Color, exponent: Float, eta: Float) -> Self {
-        let brdf = MicrofacetBrdf::without_schlick(reflect, exponent);
+        let brdf = MicrofacetBrdf::new(reflect, exponent, Fresnel::Off);
         let btdf = MicrofacetBtdf::new(transmit, exponent, eta);
         Self { brdf, btdf, eta }
     }
@@ -4,29 +4,19 @@ use crate::color::Color;
 use crate::float::*;
 use crate::pt_renderer::PathType;
 
-use super::fresnel::{self, FresnelBsdf};
+use super::fresnel::{Fresnel, FresnelBsdf};
 use super::util;
 use super::BsdfT;
 
 #[derive(Clone, Debug)]
 pub struct SpecularBrdf {
     color: Color,
-    use_schlick: bool,
+    fresnel: Fresnel,
 }
 
 impl SpecularBrdf {
-    pub fn with_schlick(color: Color) -> Self {
-        Self {
-            color,
-            use_schlick: true,
-        }
-    }
-
-    pub fn without_schlick(color: Color) -> Self {
-        Self {
-            color,
-            use_schlick: false,
-        }
+    pub fn new(color: Color, fresnel: Fresnel) -> Self {
+        Self { color, fresnel }
     }
 }
 
@@ -35,6 +25,10 @@ impl BsdfT for SpecularBrdf {
         true
     }
 
+    fn albedo(&self) -> Color {
+        self.color
+    }
+
     fn brdf(&self, _wo: Vector3<Float>, _wi: Vector3<Float>) -> Color {
         Color::black()
     }
@@ -53,12 +47,20 @@ impl BsdfT for SpecularBrdf {
         _path_type: PathType,
     ) -> Option<(Color, Vector3<Float>, Float)> {
         let wi = util::reflect_n(wo);
-        let color = if self.use_schlick {
-            fresnel::schlick(wo, self.color)
+        Some((self.fresnel.apply(wo, self.color), wi, 1.0))
+    }
+
+    fn specular_weight(
+        &self,
+        wo: Vector3<Float>,
+        wi: Vector3<Float>,
+        _path_type: PathType,
+    ) -> Color {
+        if util::same_hemisphere(wo, wi) {
+            self.fresnel.apply(wo, self.color)
         } else {
-            self.color
-        };
-        Some((color, wi, 1.0))
+            Color::black()
+        }
     }
 }
 
@@ -79,6 +81,10 @@ impl BsdfT for SpecularBtdf {
         true
     }
 
+    fn albedo(&self) -> Color {
+        self.color
+    }
+
     fn brdf(&self, _wo: Vector3<Float>, _wi: Vector3<Float>) -> Color {
         Color::black()
     }
@@ -97,13 +103,25 @@ impl BsdfT for SpecularBtdf {
         path_type: PathType,
     ) -> Option<(Color, Vector3<Float>, Float)> {
         let wi = util::refract_n(wo, self.eta)?;
+        Some((self.specular_weight(wo, wi, path_type), wi, 1.0))
+    }
+
+    fn specular_weight(
+        &self,
+        wo: Vector3<Float>,
+        wi: Vector3<Float>,
+        path_type: PathType,
+    ) -> Color {
+        if util::same_hemisphere(wo, wi) {
+            return Color::black();
+        }
         let mut color = self.color / util::cos_t(wi).abs();
         // Account for non-symmetry
         if path_type.is_camera() {
             let eta = util::eta(wo, self.eta);
             color *= eta.powi(2);
         }
-        Some((color, wi, 1.0))
+        color
     }
 }
 
@@ -111,7 +129,7 @@ pub type SpecularBsdf = FresnelBsdf<SpecularBrdf, SpecularBtdf>;
 
 impl SpecularBsdf {
     pub fn new(reflect: Color, transmit: Color, eta: Float) -> Self {
-        let brdf = SpecularBrdf::without_schlick(reflect);
+        let brdf = SpecularBrdf::new(reflect, Fresnel::Off);
         let btdf = SpecularBtdf::new(transmit, eta);
         Self { brdf, btdf, eta }
     }
@@ -1,22 +1,46 @@
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::ops::{Index, Range};
+use std::path::Path;
 
+use cgmath::prelude::*;
 use cgmath::Point3;
 
-use crate::aabb::Aabb;
+use crate::aabb::{self, Aabb};
 use crate::consts;
 use crate::float::*;
 use crate::intersect::{Intersect, Ray};
-use crate::stats;
 use crate::triangle::Triangle;
 
-const MAX_LEAF_SIZE: usize = 8;
+use self::sbvh::SbvhBuilder;
+
+mod sbvh;
+
+/// Subtrees smaller than this are not worth building on a separate thread
+const MIN_PARALLEL_TRIANGLES: usize = 4096;
+/// Number of bins per axis for binned sah
+const SAH_BINS: usize = 16;
+/// Identifies bvh cache files
+const CACHE_MAGIC: &[u8; 4] = b"RBVH";
+/// Increment to invalidate the cached bvhs when the build or the file format changes
+const CACHE_VERSION: u32 = 1;
+/// Traversal stack can't exceed this multiple of the bvh depth unless the bvh is corrupted
+const MAX_STACK_DEPTH_FACTOR: usize = 2;
 
 #[allow(dead_code)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq)]
 pub enum SplitMode {
     Object,
     Spatial,
     Sah,
+    /// Approximate sah that only evaluates splits between centroid bins
+    SahBinned,
+    /// Sah that may also split triangles that straddle the split plane.
+    /// Triangles can then be referenced by multiple leaves.
+    Sbvh,
 }
 
 enum Indices {
@@ -31,7 +55,7 @@ pub struct BvhNode {
 }
 
 impl BvhNode {
-    fn new(triangles: &Triangles) -> BvhNode {
+    fn new<T: Bounded>(triangles: &Triangles<T>) -> BvhNode {
         let start_i = triangles.start_i as u32;
         let end_i = start_i + triangles.len() as u32;
         BvhNode {
@@ -44,6 +68,21 @@ impl BvhNode {
         self.indices = Indices::Inner(left_child as u32, right_child as u32);
     }
 
+    /// Shift the child indices or the triangle range
+    /// when the node is moved into larger arrays
+    fn offset_indices(&mut self, node_offset: usize, range_offset: usize) {
+        match &mut self.indices {
+            Indices::Inner(left_i, right_i) => {
+                *left_i += node_offset as u32;
+                *right_i += node_offset as u32;
+            }
+            Indices::Leaf(start_i, end_i) => {
+                *start_i += range_offset as u32;
+                *end_i += range_offset as u32;
+            }
+        }
+    }
+
     pub fn range(&self) -> Option<Range<usize>> {
         match self.indices {
             Indices::Leaf(start_i, end_i) => Some(start_i as usize..end_i as usize),
@@ -58,8 +97,33 @@ impl Intersect<'_, Float> for BvhNode {
     }
 }
 
-struct Triangles<'a> {
-    triangles: &'a [Triangle],
+/// Intersect the ray with both children of a node at once
+pub fn intersect_children(
+    left: &BvhNode,
+    right: &BvhNode,
+    ray: &Ray,
+) -> (Option<Float>, Option<Float>) {
+    aabb::intersect_pair(&left.aabb, &right.aabb, ray)
+}
+
+/// Primitive that a bvh can be built over
+pub trait Bounded {
+    fn aabb(&self) -> Aabb;
+    fn center(&self) -> Point3<Float>;
+}
+
+impl Bounded for Triangle {
+    fn aabb(&self) -> Aabb {
+        Triangle::aabb(self)
+    }
+
+    fn center(&self) -> Point3<Float> {
+        Triangle::center(self)
+    }
+}
+
+struct Triangles<'a, T = Triangle> {
+    triangles: &'a [T],
     centers: &'a [Point3<Float>],
     indices: &'a mut [usize],
     aabb: Aabb,
@@ -69,13 +133,13 @@ struct Triangles<'a> {
     sorted_axis: usize,
 }
 
-impl<'a> Triangles<'a> {
+impl<'a, T: Bounded> Triangles<'a, T> {
     fn new(
-        triangles: &'a [Triangle],
+        triangles: &'a [T],
         centers: &'a [Point3<Float>],
         indices: &'a mut [usize],
         start_i: usize,
-    ) -> Triangles<'a> {
+    ) -> Triangles<'a, T> {
         let mut aabb = Aabb::empty();
         for &i in indices.iter() {
             let tri = &triangles[i];
@@ -111,7 +175,7 @@ impl<'a> Triangles<'a> {
         self.sorted_axis = axis_i;
     }
 
-    fn split(self, i: usize) -> (Triangles<'a>, Triangles<'a>) {
+    fn split(self, i: usize) -> (Triangles<'a, T>, Triangles<'a, T>) {
         let (i1, i2) = self.indices.split_at_mut(i);
         let mut node1 = Triangles::new(self.triangles, self.centers, i1, self.start_i);
         let mut node2 = Triangles::new(self.triangles, self.centers, i2, self.start_i + i);
@@ -124,16 +188,31 @@ impl<'a> Triangles<'a> {
         self.indices.len()
     }
 
-    fn last(&self) -> &Triangle {
+    /// Move the triangles for which pred is true to the start.
+    /// Return the number of such triangles.
+    fn partition<P: Fn(usize) -> bool>(&mut self, pred: P) -> usize {
+        let mut n_true = 0;
+        for i in 0..self.indices.len() {
+            if pred(self.indices[i]) {
+                self.indices.swap(i, n_true);
+                n_true += 1;
+            }
+        }
+        // Partitioning breaks the ordering
+        self.sorted_axis = 42;
+        n_true
+    }
+
+    fn last(&self) -> &T {
         let &i = self.indices.last().unwrap();
         &self.triangles[i]
     }
 }
 
-impl Index<usize> for Triangles<'_> {
-    type Output = Triangle;
+impl<T> Index<usize> for Triangles<'_, T> {
+    type Output = T;
 
-    fn index(&self, i: usize) -> &Triangle {
+    fn index(&self, i: usize) -> &T {
         let i = self.indices[i];
         &self.triangles[i]
     }
@@ -141,58 +220,331 @@ impl Index<usize> for Triangles<'_> {
 
 pub struct Bvh {
     nodes: Vec<BvhNode>,
+    /// Length of the longest path from the root to a leaf
+    depth: usize,
 }
 
 impl Bvh {
-    pub fn build(triangles: &[Triangle], split_mode: SplitMode) -> (Bvh, Vec<usize>) {
-        assert!(
-            !triangles.is_empty(),
-            "Scene doesn't contain any triangles!"
-        );
-        assert!(
-            triangles.len() <= 2usize.pow(32),
-            "Scene can contain maximum of 2^32 triangles! This scene has {} triangles.",
-            triangles.len()
-        );
-        stats::start_bvh();
-        let centers: Vec<Point3<Float>> = triangles.iter().map(|tri| tri.center()).collect();
-        let mut permutation: Vec<usize> = (0..triangles.len()).collect();
-        let tris = Triangles::new(triangles, &centers, &mut permutation, 0);
-        let mut nodes = Vec::with_capacity(Float::log2(triangles.len().to_float()) as usize);
-        nodes.push(BvhNode::new(&tris));
-        let mut split_stack = vec![(0usize, tris)];
-
-        while let Some((node_i, mut tris)) = split_stack.pop() {
-            let mid_offset = match split_mode {
-                SplitMode::Object => object_split(&mut tris),
-                SplitMode::Spatial => spatial_split(&mut tris),
-                SplitMode::Sah => sah_split(&mut tris),
+    /// Build a bvh whose leaves contain at most max_leaf_size triangles
+    /// unless the triangles can't be split. Return the bvh and the indices of
+    /// the triangles referenced by the leaf ranges. Each triangle is referenced
+    /// at least once and only Sbvh references triangles multiple times.
+    pub fn build(
+        triangles: &[Triangle],
+        split_mode: SplitMode,
+        max_leaf_size: usize,
+    ) -> (Bvh, Vec<usize>) {
+        if split_mode != SplitMode::Sbvh {
+            return Bvh::build_bounded(triangles, split_mode, max_leaf_size);
+        }
+        check_build_input(triangles.len(), max_leaf_size);
+        let (nodes, depth, references) =
+            SbvhBuilder::build(triangles, max_leaf_size, num_cpus::get());
+        Bvh::finish(nodes, depth, references)
+    }
+
+    /// Build a bvh over the bounding boxes of any primitives.
+    /// Sbvh falls back to Sah since the primitives can't be clipped.
+    pub fn build_bounded<T: Bounded + Sync>(
+        primitives: &[T],
+        split_mode: SplitMode,
+        max_leaf_size: usize,
+    ) -> (Bvh, Vec<usize>) {
+        check_build_input(primitives.len(), max_leaf_size);
+        let split_mode = match split_mode {
+            SplitMode::Sbvh => SplitMode::Sah,
+            split_mode => split_mode,
+        };
+        let centers: Vec<Point3<Float>> = primitives.iter().map(Bounded::center).collect();
+        let mut permutation: Vec<usize> = (0..primitives.len()).collect();
+        let tris = Triangles::new(primitives, &centers, &mut permutation, 0);
+        let (nodes, depth) = build_subtree(tris, split_mode, max_leaf_size, num_cpus::get());
+        Bvh::finish(nodes, depth, permutation)
+    }
+
+    fn finish(mut nodes: Vec<BvhNode>, depth: usize, references: Vec<usize>) -> (Bvh, Vec<usize>) {
+        nodes.shrink_to_fit();
+        let bvh = Bvh { nodes, depth };
+        bvh.validate(references.len());
+        (bvh, references)
+    }
+
+    /// Check that children are always stored after their parent.
+    /// This guarantees that traversal can't get stuck in a cycle.
+    fn validate(&self, n_references: usize) {
+        if let Err(err) = self.check(n_references) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Check that the bvh is valid and its leaves index at most n_references triangles
+    fn check(&self, n_references: usize) -> Result<(), String> {
+        for (i, node) in self.nodes.iter().enumerate() {
+            match node.indices {
+                Indices::Inner(left_i, right_i) => {
+                    for &child_i in &[left_i as usize, right_i as usize] {
+                        if i >= child_i || child_i >= self.nodes.len() {
+                            return Err(format!(
+                                "Bvh node {} has an invalid child {}!",
+                                i, child_i
+                            ));
+                        }
+                    }
+                }
+                Indices::Leaf(start_i, end_i) => {
+                    if start_i > end_i || end_i as usize > n_references {
+                        return Err(format!(
+                            "Bvh node {} has an invalid range {}..{}!",
+                            i, start_i, end_i
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Hash of everything the build depends on.
+    /// Cached bvhs with a different key are stale.
+    pub fn cache_key(triangles: &[Triangle], split_mode: SplitMode, max_leaf_size: usize) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        CACHE_VERSION.hash(&mut hasher);
+        split_mode.hash(&mut hasher);
+        max_leaf_size.hash(&mut hasher);
+        std::mem::size_of::<Float>().hash(&mut hasher);
+        triangles.len().hash(&mut hasher);
+        for tri in triangles {
+            let aabb = tri.aabb();
+            let center = tri.center();
+            for p in &[aabb.min, aabb.max, center] {
+                for c in 0..3 {
+                    p[c].to_bits().hash(&mut hasher);
+                }
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Save the bvh and the triangle references of its build
+    pub fn save(&self, path: &Path, references: &[usize], key: u64) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(CACHE_MAGIC)?;
+        writer.write_all(&key.to_le_bytes())?;
+        writer.write_all(&(self.nodes.len() as u64).to_le_bytes())?;
+        writer.write_all(&(references.len() as u64).to_le_bytes())?;
+        for node in &self.nodes {
+            for p in &[node.aabb.min, node.aabb.max] {
+                for c in 0..3 {
+                    writer.write_all(&p[c].to_le_bytes())?;
+                }
+            }
+            let (tag, i1, i2) = match node.indices {
+                Indices::Inner(left_i, right_i) => (0u8, left_i, right_i),
+                Indices::Leaf(start_i, end_i) => (1u8, start_i, end_i),
             };
-            let (t1, t2) = if let Some(offset) = mid_offset {
-                tris.split(offset)
-            } else {
-                continue;
+            writer.write_all(&[tag])?;
+            writer.write_all(&i1.to_le_bytes())?;
+            writer.write_all(&i2.to_le_bytes())?;
+        }
+        for &i in references {
+            writer.write_all(&(i as u32).to_le_bytes())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Load a bvh saved for n_tris triangles with the given cache key.
+    /// Fails if the file is stale or corrupted.
+    pub fn load(path: &Path, n_tris: usize, key: u64) -> Result<(Bvh, Vec<usize>), Box<dyn Error>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != CACHE_MAGIC {
+            return Err("Not a bvh cache file".into());
+        }
+        if read_u64(&mut reader)? != key {
+            return Err("Bvh cache is stale".into());
+        }
+        let n_nodes = read_u64(&mut reader)? as usize;
+        let n_references = read_u64(&mut reader)? as usize;
+        if n_references < n_tris {
+            return Err("Bvh cache has too few triangle references".into());
+        }
+        let mut nodes = Vec::with_capacity(n_nodes);
+        for _ in 0..n_nodes {
+            let min = read_point(&mut reader)?;
+            let max = read_point(&mut reader)?;
+            let mut tag = [0u8; 1];
+            reader.read_exact(&mut tag)?;
+            let i1 = read_u32(&mut reader)?;
+            let i2 = read_u32(&mut reader)?;
+            let indices = match tag[0] {
+                0 => Indices::Inner(i1, i2),
+                1 => Indices::Leaf(i1, i2),
+                _ => return Err("Invalid bvh node".into()),
             };
+            nodes.push(BvhNode {
+                aabb: Aabb { min, max },
+                indices,
+            });
+        }
+        let mut references = Vec::with_capacity(n_references);
+        let mut seen = vec![false; n_tris];
+        for _ in 0..n_references {
+            let i = read_u32(&mut reader)? as usize;
+            if i >= n_tris {
+                return Err("Invalid triangle reference".into());
+            }
+            seen[i] = true;
+            references.push(i);
+        }
+        if !seen.into_iter().all(|seen| seen) {
+            return Err("Bvh cache doesn't reference all the triangles".into());
+        }
+        if nodes.is_empty() {
+            return Err("Bvh cache has no nodes".into());
+        }
+        let mut bvh = Bvh { nodes, depth: 0 };
+        bvh.check(n_references)?;
+        // Children are stored after their parents so the depths can be propagated in order
+        let mut depths = vec![0; bvh.nodes.len()];
+        for (i, node) in bvh.nodes.iter().enumerate() {
+            if let Indices::Inner(left_i, right_i) = node.indices {
+                depths[left_i as usize] = depths[i] + 1;
+                depths[right_i as usize] = depths[i] + 1;
+            }
+        }
+        bvh.depth = depths.into_iter().max().unwrap();
+        Ok((bvh, references))
+    }
+
+    /// Export the bounding boxes of the nodes up to max_depth as wireframe boxes.
+    /// The root is at depth 0. Leaves and inner nodes use the materials leaf and
+    /// inner of a material library written next to the obj.
+    pub fn export_obj(&self, path: &Path, max_depth: usize) -> Result<(), Box<dyn Error>> {
+        let mtl_path = path.with_extension("mtl");
+        let mtl_name = mtl_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or("Invalid obj path")?;
+        let mut mtl = BufWriter::new(File::create(&mtl_path)?);
+        writeln!(mtl, "newmtl inner\nKd 0.2 0.4 1.0")?;
+        writeln!(mtl, "newmtl leaf\nKd 1.0 0.5 0.1")?;
+        mtl.flush()?;
 
-            let left_child = BvhNode::new(&t1);
-            let left_child_i = nodes.len();
-            if t1.len() > MAX_LEAF_SIZE {
-                split_stack.push((nodes.len(), t1));
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "mtllib {}", mtl_name)?;
+        let mut n_boxes = 0;
+        let mut stack = vec![(self.root(), 0)];
+        while let Some((node, depth)) = stack.pop() {
+            let (min, max) = (node.aabb.min, node.aabb.max);
+            // Bit c of the corner index selects the max of coordinate c
+            for corner in 0..8 {
+                let x = if corner & 1 == 0 { min.x } else { max.x };
+                let y = if corner & 2 == 0 { min.y } else { max.y };
+                let z = if corner & 4 == 0 { min.z } else { max.z };
+                writeln!(writer, "v {} {} {}", x, y, z)?;
             }
-            nodes.push(left_child);
+            let material = if node.range().is_some() {
+                "leaf"
+            } else {
+                "inner"
+            };
+            writeln!(writer, "usemtl {}", material)?;
+            // Edges connect corners that differ in a single coordinate
+            let base = 8 * n_boxes + 1;
+            for corner in 0..8 {
+                for bit in &[1, 2, 4] {
+                    if corner & bit == 0 {
+                        writeln!(writer, "l {} {}", base + corner, base + (corner | bit))?;
+                    }
+                }
+            }
+            n_boxes += 1;
+            if depth < max_depth {
+                if let Some((left, right)) = self.get_children(node) {
+                    stack.push((right, depth + 1));
+                    stack.push((left, depth + 1));
+                }
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Maximum size of the node stack during a valid traversal
+    pub fn max_stack_size(&self) -> usize {
+        // Traversal pushes at most one extra node per level
+        MAX_STACK_DEPTH_FACTOR * (self.depth + 1)
+    }
 
-            let right_child = BvhNode::new(&t2);
-            let right_child_i = nodes.len();
-            if t2.len() > MAX_LEAF_SIZE {
-                split_stack.push((nodes.len(), t2));
+    /// Visit the leaves hit by the ray closest first and call intersect_leaf for
+    /// each primitive in them until it returns true. The traversal uses the top of
+    /// node_stack so intersect_leaf may traverse other bvhs with the same stack.
+    /// Return true if the traversal was stopped by intersect_leaf.
+    pub fn traverse<'a, F>(
+        &'a self,
+        ray: &mut Ray,
+        node_stack: &mut Vec<(&'a BvhNode, Float)>,
+        mut intersect_leaf: F,
+    ) -> bool
+    where
+        F: FnMut(usize, &mut Ray, &mut Vec<(&'a BvhNode, Float)>) -> bool,
+    {
+        let base = node_stack.len();
+        node_stack.push((self.root(), 0.0));
+        let mut n_nodes = 0;
+        let mut n_tris = 0;
+        let mut stopped = false;
+        while node_stack.len() > base {
+            let (node, t) = node_stack.pop().unwrap();
+            debug_assert!(
+                node_stack.len() - base < self.max_stack_size(),
+                "Node stack exceeded {} nodes. Bvh is likely corrupted!",
+                self.max_stack_size(),
+            );
+            // We've already found a closer hit
+            if ray.length <= t {
+                continue;
+            }
+            n_nodes += 1;
+            if let Some(range) = node.range() {
+                for i in range {
+                    n_tris += 1;
+                    if intersect_leaf(i, ray, node_stack) {
+                        stopped = true;
+                        break;
+                    }
+                }
+                if stopped {
+                    break;
+                }
+            } else {
+                let (left, right) = self.get_children(node).unwrap();
+                // TODO: Could this work without pushing the next node to the stack
+                let (left_intersect, right_intersect) = intersect_children(left, right, ray);
+                if let Some(t_left) = left_intersect {
+                    if let Some(t_right) = right_intersect {
+                        // Put the closer hit on top
+                        if t_left >= t_right {
+                            node_stack.push((left, t_left));
+                            node_stack.push((right, t_right));
+                        } else {
+                            node_stack.push((right, t_right));
+                            node_stack.push((left, t_left));
+                        }
+                    } else {
+                        node_stack.push((left, t_left));
+                    }
+                } else if let Some(t_right) = right_intersect {
+                    node_stack.push((right, t_right));
+                }
             }
-            nodes.push(right_child);
-            nodes[node_i].convert_to_inner(left_child_i, right_child_i);
         }
-        nodes.shrink_to_fit();
-        let bvh = Bvh { nodes };
-        stats::stop_bvh(&bvh, triangles.len());
-        (bvh, permutation)
+        // Early exits leave nodes to the stack
+        node_stack.truncate(base);
+        Ray::count_traversal(n_nodes, n_tris);
+        stopped
     }
 
     pub fn get_children(&self, node: &BvhNode) -> Option<(&BvhNode, &BvhNode)> {
@@ -211,14 +563,169 @@ impl Bvh {
     pub fn size(&self) -> usize {
         self.nodes.len()
     }
+
+    pub fn size_bytes(&self) -> usize {
+        self.nodes.len() * std::mem::size_of::<BvhNode>()
+    }
+
+    /// Number of triangle references in the leaves
+    pub fn n_references(&self) -> usize {
+        self.nodes
+            .iter()
+            .filter_map(BvhNode::range)
+            .map(|range| range.len())
+            .sum()
+    }
+
+    /// Expected cost of tracing a random ray relative to intersecting a single triangle
+    /// assuming that node and triangle intersections cost the same
+    pub fn sah_cost(&self) -> Float {
+        let root_area = self.root().aabb.area();
+        if root_area <= 0.0 {
+            return 0.0;
+        }
+        let cost: Float = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let n_intersects = node.range().map_or(1, |range| range.len());
+                n_intersects.to_float() * node.aabb.area()
+            })
+            .sum();
+        cost / root_area
+    }
+}
+
+fn check_build_input(n_primitives: usize, max_leaf_size: usize) {
+    assert!(n_primitives > 0, "Scene doesn't contain any triangles!");
+    assert!(
+        n_primitives <= 2usize.pow(32),
+        "Scene can contain maximum of 2^32 triangles! This scene has {} triangles.",
+        n_primitives
+    );
+    assert!(
+        max_leaf_size > 0,
+        "Bvh leaves need to fit at least 1 triangle!"
+    );
 }
 
-fn object_split(triangles: &mut Triangles) -> Option<usize> {
+fn read_u32<R: Read>(reader: &mut R) -> std::io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_point<R: Read>(reader: &mut R) -> std::io::Result<Point3<Float>> {
+    let mut p = Point3::origin();
+    for c in 0..3 {
+        let mut bytes = [0u8; std::mem::size_of::<Float>()];
+        reader.read_exact(&mut bytes)?;
+        p[c] = Float::from_le_bytes(bytes);
+    }
+    Ok(p)
+}
+
+/// Build the subtree of tris with the given number of threads.
+/// Child indices of the returned nodes are relative to the subtree root at index 0.
+/// Return the nodes and the depth of the subtree.
+fn build_subtree<T: Bounded + Sync>(
+    mut tris: Triangles<T>,
+    split_mode: SplitMode,
+    max_leaf_size: usize,
+    threads: usize,
+) -> (Vec<BvhNode>, usize) {
+    if threads <= 1 || tris.len() < MIN_PARALLEL_TRIANGLES {
+        return build_serial(tris, split_mode, max_leaf_size);
+    }
+    let mut root = BvhNode::new(&tris);
+    let (t1, t2) = match split(&mut tris, split_mode) {
+        Some(offset) => tris.split(offset),
+        None => return (vec![root], 0),
+    };
+    // The subtrees own disjoint parts of the permutation so they can be built independently
+    let right_threads = threads / 2;
+    let ((left_nodes, left_depth), (right_nodes, right_depth)) = std::thread::scope(|s| {
+        let right = s.spawn(move || build_subtree(t2, split_mode, max_leaf_size, right_threads));
+        let left = build_subtree(t1, split_mode, max_leaf_size, threads - right_threads);
+        (left, right.join().unwrap())
+    });
+    // Store the root followed by the left and right subtrees
+    let right_offset = 1 + left_nodes.len();
+    root.convert_to_inner(1, right_offset);
+    let mut nodes = Vec::with_capacity(right_offset + right_nodes.len());
+    nodes.push(root);
+    for (offset, subtree) in [(1, left_nodes), (right_offset, right_nodes)] {
+        nodes.extend(subtree.into_iter().map(|mut node| {
+            node.offset_indices(offset, 0);
+            node
+        }));
+    }
+    (nodes, 1 + left_depth.max(right_depth))
+}
+
+/// Build the subtree of tris on the current thread
+fn build_serial<T: Bounded>(
+    tris: Triangles<T>,
+    split_mode: SplitMode,
+    max_leaf_size: usize,
+) -> (Vec<BvhNode>, usize) {
+    let mut nodes = Vec::with_capacity(Float::log2(tris.len().to_float()) as usize);
+    nodes.push(BvhNode::new(&tris));
+    let mut split_stack = Vec::new();
+    if tris.len() > max_leaf_size {
+        split_stack.push((0usize, 0usize, tris));
+    }
+    let mut depth = 0;
+
+    while let Some((node_i, node_depth, mut tris)) = split_stack.pop() {
+        let (t1, t2) = if let Some(offset) = split(&mut tris, split_mode) {
+            tris.split(offset)
+        } else {
+            continue;
+        };
+        let child_depth = node_depth + 1;
+        depth = depth.max(child_depth);
+
+        let left_child = BvhNode::new(&t1);
+        let left_child_i = nodes.len();
+        if t1.len() > max_leaf_size {
+            split_stack.push((nodes.len(), child_depth, t1));
+        }
+        nodes.push(left_child);
+
+        let right_child = BvhNode::new(&t2);
+        let right_child_i = nodes.len();
+        if t2.len() > max_leaf_size {
+            split_stack.push((nodes.len(), child_depth, t2));
+        }
+        nodes.push(right_child);
+        nodes[node_i].convert_to_inner(left_child_i, right_child_i);
+    }
+    (nodes, depth)
+}
+
+fn split<T: Bounded>(triangles: &mut Triangles<T>, split_mode: SplitMode) -> Option<usize> {
+    match split_mode {
+        SplitMode::Object => object_split(triangles),
+        SplitMode::Spatial => spatial_split(triangles),
+        SplitMode::Sah => sah_split(triangles),
+        SplitMode::SahBinned => binned_sah_split(triangles),
+        SplitMode::Sbvh => unreachable!("Sbvh is built by SbvhBuilder"),
+    }
+}
+
+fn object_split<T: Bounded>(triangles: &mut Triangles<T>) -> Option<usize> {
     triangles.sort_longest_axis();
     Some(triangles.len() / 2)
 }
 
-fn spatial_split(triangles: &mut Triangles) -> Option<usize> {
+fn spatial_split<T: Bounded>(triangles: &mut Triangles<T>) -> Option<usize> {
     let aabb = &triangles.aabb;
     let axis_i = aabb.longest_edge_i();
     let mid_val = aabb.center()[axis_i];
@@ -239,7 +746,7 @@ fn spatial_split(triangles: &mut Triangles) -> Option<usize> {
     }
 }
 
-fn sah_split(triangles: &mut Triangles) -> Option<usize> {
+fn sah_split<T: Bounded>(triangles: &mut Triangles<T>) -> Option<usize> {
     let mut min_score = consts::MAX;
     let mut min_axis = 0;
     let mut min_i = 0;
@@ -278,3 +785,313 @@ fn sah_split(triangles: &mut Triangles) -> Option<usize> {
         Some(min_i)
     }
 }
+
+fn binned_sah_split<T: Bounded>(triangles: &mut Triangles<T>) -> Option<usize> {
+    let centers = triangles.centers;
+    let mut center_min = Point3::from_value(consts::MAX);
+    let mut center_max = Point3::from_value(consts::MIN);
+    for &i in triangles.indices.iter() {
+        center_min = aabb::min_point(&center_min, &centers[i]);
+        center_max = aabb::max_point(&center_max, &centers[i]);
+    }
+    let bin_index = |i: usize, axis: usize| {
+        let extent = center_max[axis] - center_min[axis];
+        let rel = (centers[i][axis] - center_min[axis]) / extent;
+        ((rel * SAH_BINS.to_float()) as usize).min(SAH_BINS - 1)
+    };
+    let mut min_score = consts::MAX;
+    let mut min_axis = 0;
+    let mut min_bin = 0;
+    for axis in 0..3 {
+        // All centers are on the same plane
+        if center_max[axis] <= center_min[axis] {
+            continue;
+        }
+        let mut bin_bbs = vec![Aabb::empty(); SAH_BINS];
+        let mut bin_counts = [0usize; SAH_BINS];
+        for i in 0..triangles.len() {
+            let tri_i = triangles.indices[i];
+            let bin = bin_index(tri_i, axis);
+            bin_bbs[bin].add_aabb(&triangles.triangles[tri_i].aabb());
+            bin_counts[bin] += 1;
+        }
+        // Accumulate the right side bbs of each boundary
+        let mut right_bbs = vec![Aabb::empty(); SAH_BINS];
+        let mut right_counts = [0usize; SAH_BINS];
+        let mut right_bb = Aabb::empty();
+        let mut right_count = 0;
+        for bin in (1..SAH_BINS).rev() {
+            right_bb.add_aabb(&bin_bbs[bin]);
+            right_count += bin_counts[bin];
+            right_bbs[bin] = right_bb.clone();
+            right_counts[bin] = right_count;
+        }
+        // Boundary b splits the bins into [0, b) and [b, SAH_BINS)
+        let mut left_bb = Aabb::empty();
+        let mut left_count = 0;
+        for b in 1..SAH_BINS {
+            left_bb.add_aabb(&bin_bbs[b - 1]);
+            left_count += bin_counts[b - 1];
+            if left_count == 0 || right_counts[b] == 0 {
+                continue;
+            }
+            let score = left_count.to_float() * left_bb.area()
+                + right_counts[b].to_float() * right_bbs[b].area();
+            if score < min_score {
+                min_score = score;
+                min_axis = axis;
+                min_bin = b;
+            }
+        }
+    }
+    if min_bin == 0 {
+        // Centers can't be separated by bins so fall back to the object median
+        object_split(triangles)
+    } else {
+        Some(triangles.partition(|i| bin_index(i, min_axis) < min_bin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cgmath::Vector3;
+
+    impl Bounded for Aabb {
+        fn aabb(&self) -> Aabb {
+            self.clone()
+        }
+
+        fn center(&self) -> Point3<Float> {
+            Aabb::center(self)
+        }
+    }
+
+    /// Count the lines of the exported obj starting with each of the keys
+    fn export_counts(bvh: &Bvh, name: &str, max_depth: usize, keys: &[&str]) -> Vec<usize> {
+        let path = std::env::temp_dir().join(format!("rusty_test_{}.obj", name));
+        bvh.export_obj(&path, max_depth).unwrap();
+        let obj = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("mtl")).unwrap();
+        keys.iter()
+            .map(|key| obj.lines().filter(|line| line.starts_with(key)).count())
+            .collect()
+    }
+
+    #[test]
+    fn export_obj_boxes() {
+        let boxes: Vec<Aabb> = (0..4usize)
+            .map(|i| {
+                let x = 2.0 * i.to_float();
+                Aabb {
+                    min: Point3::new(x, 0.0, 0.0),
+                    max: Point3::new(x + 1.0, 1.0, 1.0),
+                }
+            })
+            .collect();
+        let (bvh, _) = Bvh::build_bounded(&boxes, SplitMode::Object, 1);
+        assert_eq!(bvh.size(), 7);
+        let keys = ["v ", "l ", "usemtl leaf", "usemtl inner"];
+        // The root box only
+        assert_eq!(export_counts(&bvh, "bvh_root", 0, &keys), [8, 12, 0, 1]);
+        // Every node has a box of 8 corners and 12 edges
+        assert_eq!(export_counts(&bvh, "bvh_all", 8, &keys), [56, 84, 4, 3]);
+    }
+
+    /// Deterministic scattered unit boxes
+    fn scattered_boxes(n: usize) -> Vec<Aabb> {
+        (0..n)
+            .map(|i| {
+                let f = i.to_float();
+                let min = Point3::new((f * 7.3) % 20.0, (f * 3.1) % 11.0, (f * 5.7) % 13.0);
+                Aabb {
+                    min,
+                    max: min + Vector3::new(1.0, 0.5, 0.25),
+                }
+            })
+            .collect()
+    }
+
+    /// Check that the leaves reference every box once
+    /// within their ancestors' bounds and the leaf size limit
+    fn check_leaves(bvh: &Bvh, boxes: &[Aabb], references: &[usize], max_leaf_size: usize) {
+        let contains = |outer: &Aabb, inner: &Aabb| {
+            (0..3).all(|c| outer.min[c] <= inner.min[c] && inner.max[c] <= outer.max[c])
+        };
+        let mut counts = vec![0; boxes.len()];
+        let mut stack = vec![bvh.root()];
+        while let Some(node) = stack.pop() {
+            match bvh.get_children(node) {
+                Some((left, right)) => {
+                    assert!(contains(&node.aabb, &left.aabb) && contains(&node.aabb, &right.aabb));
+                    stack.push(left);
+                    stack.push(right);
+                }
+                None => {
+                    let range = node.range().unwrap();
+                    assert!(range.len() <= max_leaf_size);
+                    for &box_i in &references[range] {
+                        assert!(contains(&node.aabb, &boxes[box_i]));
+                        counts[box_i] += 1;
+                    }
+                }
+            }
+        }
+        assert!(counts.iter().all(|&count| count == 1), "{:?}", counts);
+    }
+
+    #[test]
+    fn split_modes_reference_every_primitive() {
+        let boxes = scattered_boxes(300);
+        for &split_mode in &[
+            SplitMode::Object,
+            SplitMode::Spatial,
+            SplitMode::Sah,
+            SplitMode::SahBinned,
+        ] {
+            for &max_leaf_size in &[1, 4] {
+                let (bvh, references) = Bvh::build_bounded(&boxes, split_mode, max_leaf_size);
+                check_leaves(&bvh, &boxes, &references, max_leaf_size);
+            }
+        }
+    }
+
+    /// Bounds, leaf flag and indices of a node
+    type NodeData = (Point3<Float>, Point3<Float>, bool, u32, u32);
+
+    /// Data of the nodes in storage order
+    fn node_data(bvh: &Bvh) -> Vec<NodeData> {
+        bvh.nodes
+            .iter()
+            .map(|node| {
+                let (leaf, i1, i2) = match node.indices {
+                    Indices::Inner(left_i, right_i) => (false, left_i, right_i),
+                    Indices::Leaf(start_i, end_i) => (true, start_i, end_i),
+                };
+                (node.aabb.min, node.aabb.max, leaf, i1, i2)
+            })
+            .collect()
+    }
+
+    /// Boxes hit by fixed rays in the order that the traversal visits them
+    fn traversal_hits(bvh: &Bvh, boxes: &[Aabb], references: &[usize]) -> Vec<Vec<usize>> {
+        let mut node_stack = Vec::new();
+        (0..50usize)
+            .map(|i| {
+                let f = i.to_float();
+                let orig = Point3::new(-5.0, (f * 1.3) % 12.0, (f * 2.9) % 14.0);
+                let mut ray = Ray::from_point(orig, boxes[i].center());
+                let mut hits = Vec::new();
+                bvh.traverse(&mut ray, &mut node_stack, |i, ray, _| {
+                    if boxes[references[i]].intersect(ray).is_some() {
+                        hits.push(references[i]);
+                    }
+                    false
+                });
+                hits
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let boxes = scattered_boxes(300);
+        let (bvh, references) = Bvh::build_bounded(&boxes, SplitMode::Sah, 4);
+        let path = std::env::temp_dir().join("rusty_test_round_trip.bvh");
+        bvh.save(&path, &references, 42).unwrap();
+        let (loaded, loaded_references) = Bvh::load(&path, boxes.len(), 42).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(node_data(&loaded), node_data(&bvh));
+        assert_eq!(loaded_references, references);
+        assert_eq!(loaded.depth, bvh.depth);
+        assert_eq!(
+            traversal_hits(&loaded, &boxes, &loaded_references),
+            traversal_hits(&bvh, &boxes, &references)
+        );
+    }
+
+    #[test]
+    fn cache_rejects_mismatched_scene() {
+        let boxes = scattered_boxes(100);
+        let (bvh, references) = Bvh::build_bounded(&boxes, SplitMode::Sah, 4);
+        let path = std::env::temp_dir().join("rusty_test_mismatch.bvh");
+        bvh.save(&path, &references, 42).unwrap();
+        assert!(Bvh::load(&path, boxes.len(), 43).is_err());
+        // More triangles than the references cover
+        assert!(Bvh::load(&path, boxes.len() + 1, 42).is_err());
+        // Fewer triangles than the references index
+        assert!(Bvh::load(&path, boxes.len() - 1, 42).is_err());
+        assert!(Bvh::load(&path, boxes.len(), 42).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn check_rejects_child_before_parent() {
+        let boxes = scattered_boxes(100);
+        let (mut bvh, references) = Bvh::build_bounded(&boxes, SplitMode::Sah, 4);
+        assert!(bvh.check(references.len()).is_ok());
+        // Point a child of the last inner node back to the root
+        let inner_i = bvh
+            .nodes
+            .iter()
+            .rposition(|node| node.range().is_none())
+            .unwrap();
+        if let Indices::Inner(left_i, _) = bvh.nodes[inner_i].indices {
+            bvh.nodes[inner_i].indices = Indices::Inner(left_i, 0);
+        }
+        assert!(bvh.check(references.len()).is_err());
+        // A node can't be its own child either
+        bvh.nodes[inner_i].indices = Indices::Inner(inner_i as u32, inner_i as u32 + 1);
+        assert!(bvh.check(references.len()).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid child")]
+    fn validate_panics_on_cycle() {
+        let boxes = scattered_boxes(10);
+        let (mut bvh, references) = Bvh::build_bounded(&boxes, SplitMode::Sah, 4);
+        bvh.nodes[0].indices = Indices::Inner(0, 1);
+        bvh.validate(references.len());
+    }
+
+    /// Bounds and leaf range of a node
+    type NodeBounds = (Point3<Float>, Point3<Float>, Option<Range<usize>>);
+
+    /// Bounds of the nodes in depth first order.
+    /// Independent of the order that the nodes are stored in.
+    fn preorder(nodes: &[BvhNode]) -> Vec<NodeBounds> {
+        let mut result = Vec::new();
+        let mut stack = vec![0];
+        while let Some(i) = stack.pop() {
+            let node = &nodes[i];
+            result.push((node.aabb.min, node.aabb.max, node.range()));
+            if let Indices::Inner(left_i, right_i) = node.indices {
+                stack.push(right_i as usize);
+                stack.push(left_i as usize);
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn parallel_build_matches_serial() {
+        let boxes = scattered_boxes(4 * MIN_PARALLEL_TRIANGLES);
+        let centers: Vec<Point3<Float>> = boxes.iter().map(Bounded::center).collect();
+        for &split_mode in &[SplitMode::Object, SplitMode::Sah, SplitMode::SahBinned] {
+            let build = |threads: usize| {
+                let mut permutation: Vec<usize> = (0..boxes.len()).collect();
+                let tris = Triangles::new(&boxes, &centers, &mut permutation, 0);
+                let (nodes, depth) = build_subtree(tris, split_mode, 4, threads);
+                (preorder(&nodes), depth, permutation)
+            };
+            let (serial_nodes, serial_depth, serial_permutation) = build(1);
+            let (parallel_nodes, parallel_depth, parallel_permutation) = build(4);
+            assert!(serial_nodes.len() > 1);
+            assert_eq!(parallel_nodes, serial_nodes, "{:?}", split_mode);
+            assert_eq!(parallel_depth, serial_depth, "{:?}", split_mode);
+            assert_eq!(parallel_permutation, serial_permutation, "{:?}", split_mode);
+        }
+    }
+}
@@ -0,0 +1,446 @@
+use cgmath::Point3;
+
+use crate::aabb::Aabb;
+use crate::float::*;
+use crate::triangle::Triangle;
+
+use super::{BvhNode, Indices, MIN_PARALLEL_TRIANGLES};
+
+/// Spatial splits are only considered when the children of the best object split
+/// overlap by more than this fraction of the root surface area
+const MIN_OVERLAP: Float = 1e-5;
+/// Maximum number of extra triangle references relative to the number of triangles
+const DUPLICATION_BUDGET: Float = 0.3;
+/// Number of bins per axis for spatial splits
+const SPATIAL_BINS: usize = 32;
+
+/// Reference to a triangle whose bounds may have been clipped by spatial splits
+#[derive(Clone)]
+struct Reference {
+    tri_i: usize,
+    aabb: Aabb,
+}
+
+impl Reference {
+    fn center(&self, axis: usize) -> Float {
+        0.5 * (self.aabb.min[axis] + self.aabb.max[axis])
+    }
+}
+
+/// Best object split of a node
+struct ObjectSplit {
+    axis: usize,
+    i: usize,
+    cost: Float,
+    overlap: Float,
+}
+
+/// Best spatial split of a node
+struct SpatialSplit {
+    axis: usize,
+    plane: Float,
+    cost: Float,
+}
+
+/// Builder of a spatial split bvh, which splits the references of triangles
+/// that straddle a split plane when it's cheaper than separating them by their centers.
+/// Triangles may then be referenced by multiple leaves.
+pub(super) struct SbvhBuilder<'a> {
+    triangles: &'a [Triangle],
+    max_leaf_size: usize,
+    /// Overlap area above which spatial splits are considered
+    min_overlap: Float,
+}
+
+impl<'a> SbvhBuilder<'a> {
+    /// Build the bvh with the given number of threads.
+    /// Return the nodes, the depth and the triangle indices referenced by the leaves.
+    pub fn build(
+        triangles: &'a [Triangle],
+        max_leaf_size: usize,
+        threads: usize,
+    ) -> (Vec<BvhNode>, usize, Vec<usize>) {
+        let refs: Vec<Reference> = triangles
+            .iter()
+            .enumerate()
+            .map(|(tri_i, tri)| Reference {
+                tri_i,
+                aabb: tri.aabb(),
+            })
+            .collect();
+        let root_area = bounds(&refs).area();
+        let builder = Self {
+            triangles,
+            max_leaf_size,
+            min_overlap: MIN_OVERLAP * root_area,
+        };
+        let budget = (DUPLICATION_BUDGET * triangles.len().to_float()) as usize;
+        builder.build_subtree(refs, budget, threads)
+    }
+
+    /// Build the subtree of refs that can create at most budget extra references.
+    /// Child indices and leaf ranges are relative to the subtree.
+    fn build_subtree(
+        &self,
+        mut refs: Vec<Reference>,
+        budget: usize,
+        threads: usize,
+    ) -> (Vec<BvhNode>, usize, Vec<usize>) {
+        let aabb = bounds(&refs);
+        let n = refs.len();
+        if n <= self.max_leaf_size {
+            return leaf(aabb, refs);
+        }
+        let object = object_split(&mut refs);
+        let spatial = match &object {
+            Some(object) if object.overlap <= self.min_overlap => None,
+            _ if budget == 0 => None,
+            _ => self.spatial_split(&refs, &aabb),
+        };
+        // Cost of the object split or of not splitting at all
+        let object_cost = object
+            .as_ref()
+            .map_or(n.to_float() * aabb.area(), |object| object.cost);
+        let spatial_children = spatial
+            .filter(|spatial| spatial.cost < object_cost)
+            .and_then(|spatial| self.split_references(&refs, &spatial))
+            .filter(|(left, right)| left.len() + right.len() - n <= budget);
+        let (left, right) = match (spatial_children, object) {
+            (Some(children), _) => children,
+            (None, Some(object)) => {
+                // Object split leaves the references sorted along the last axis
+                if object.axis != 2 {
+                    sort(&mut refs, object.axis);
+                }
+                let right = refs.split_off(object.i);
+                (refs, right)
+            }
+            (None, None) => return leaf(aabb, refs),
+        };
+        // Divide the remaining budget in proportion to the sizes of the children
+        let budget = budget - (left.len() + right.len() - n);
+        let left_budget = budget * left.len() / (left.len() + right.len());
+        let right_budget = budget - left_budget;
+
+        let ((left_nodes, left_depth, left_refs), (right_nodes, right_depth, right_refs)) =
+            if threads > 1 && n >= MIN_PARALLEL_TRIANGLES {
+                let right_threads = threads / 2;
+                std::thread::scope(|s| {
+                    let right_handle =
+                        s.spawn(move || self.build_subtree(right, right_budget, right_threads));
+                    let left = self.build_subtree(left, left_budget, threads - right_threads);
+                    (left, right_handle.join().unwrap())
+                })
+            } else {
+                (
+                    self.build_subtree(left, left_budget, 1),
+                    self.build_subtree(right, right_budget, 1),
+                )
+            };
+        // Store the root followed by the left and right subtrees
+        let right_offset = 1 + left_nodes.len();
+        let mut nodes = Vec::with_capacity(right_offset + right_nodes.len());
+        nodes.push(BvhNode {
+            aabb,
+            indices: Indices::Inner(1, right_offset as u32),
+        });
+        for (node_offset, range_offset, subtree) in [
+            (1, 0, left_nodes),
+            (right_offset, left_refs.len(), right_nodes),
+        ] {
+            nodes.extend(subtree.into_iter().map(|mut node| {
+                node.offset_indices(node_offset, range_offset);
+                node
+            }));
+        }
+        let mut tri_indices = left_refs;
+        tri_indices.extend(right_refs);
+        (nodes, 1 + left_depth.max(right_depth), tri_indices)
+    }
+
+    /// Find the cheapest plane between spatial bins that splits
+    /// the references straddling it
+    fn spatial_split(&self, refs: &[Reference], aabb: &Aabb) -> Option<SpatialSplit> {
+        let mut best: Option<SpatialSplit> = None;
+        let mut min_cost = Float::INFINITY;
+        for axis in 0..3 {
+            let start = aabb.min[axis];
+            let extent = aabb.max[axis] - start;
+            if extent <= 0.0 {
+                continue;
+            }
+            let bin_width = extent / SPATIAL_BINS.to_float();
+            let bin_index =
+                |x: Float| (((x - start) / bin_width).max(0.0) as usize).min(SPATIAL_BINS - 1);
+            let plane = |b: usize| start + b.to_float() * bin_width;
+            let mut bin_bbs = vec![Aabb::empty(); SPATIAL_BINS];
+            // Number of references that start and end in each bin
+            let mut entries = [0usize; SPATIAL_BINS];
+            let mut exits = [0usize; SPATIAL_BINS];
+            for r in refs {
+                let first = bin_index(r.aabb.min[axis]);
+                let last = bin_index(r.aabb.max[axis]);
+                entries[first] += 1;
+                exits[last] += 1;
+                let tri = &self.triangles[r.tri_i];
+                for (b, bin_bb) in bin_bbs.iter_mut().enumerate().take(last + 1).skip(first) {
+                    if let Some(clipped) = clip(tri, &r.aabb, axis, plane(b), plane(b + 1)) {
+                        bin_bb.add_aabb(&clipped);
+                    }
+                }
+            }
+            // Accumulate the right side bbs of each boundary
+            let mut right_bbs = vec![Aabb::empty(); SPATIAL_BINS];
+            let mut right_counts = [0usize; SPATIAL_BINS];
+            let mut right_bb = Aabb::empty();
+            let mut right_count = 0;
+            for b in (1..SPATIAL_BINS).rev() {
+                right_bb.add_aabb(&bin_bbs[b]);
+                right_count += exits[b];
+                right_bbs[b] = right_bb.clone();
+                right_counts[b] = right_count;
+            }
+            // Boundary b splits the bins into [0, b) and [b, SPATIAL_BINS)
+            let mut left_bb = Aabb::empty();
+            let mut left_count = 0;
+            for b in 1..SPATIAL_BINS {
+                left_bb.add_aabb(&bin_bbs[b - 1]);
+                left_count += entries[b - 1];
+                if left_count == 0 || right_counts[b] == 0 {
+                    continue;
+                }
+                let cost = left_count.to_float() * left_bb.area()
+                    + right_counts[b].to_float() * right_bbs[b].area();
+                if cost < min_cost {
+                    min_cost = cost;
+                    best = Some(SpatialSplit {
+                        axis,
+                        plane: plane(b),
+                        cost,
+                    });
+                }
+            }
+        }
+        best
+    }
+
+    /// Split the references by the plane of the spatial split.
+    /// Return None if the split doesn't reduce the size of both children.
+    fn split_references(
+        &self,
+        refs: &[Reference],
+        split: &SpatialSplit,
+    ) -> Option<(Vec<Reference>, Vec<Reference>)> {
+        let axis = split.axis;
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        for r in refs {
+            if r.aabb.max[axis] <= split.plane {
+                left.push(r.clone());
+            } else if r.aabb.min[axis] >= split.plane {
+                right.push(r.clone());
+            } else {
+                let tri = &self.triangles[r.tri_i];
+                let halves = [
+                    (&mut left, r.aabb.min[axis], split.plane),
+                    (&mut right, split.plane, r.aabb.max[axis]),
+                ];
+                for (side, start, end) in halves {
+                    if let Some(aabb) = clip(tri, &r.aabb, axis, start, end) {
+                        side.push(Reference {
+                            tri_i: r.tri_i,
+                            aabb,
+                        });
+                    }
+                }
+            }
+        }
+        if left.is_empty()
+            || right.is_empty()
+            || left.len() >= refs.len()
+            || right.len() >= refs.len()
+        {
+            None
+        } else {
+            Some((left, right))
+        }
+    }
+}
+
+/// Find the sah optimal split of the references sorted by their centers.
+/// Return None if not splitting is cheaper. Leaves refs in arbitrary order.
+fn object_split(refs: &mut [Reference]) -> Option<ObjectSplit> {
+    let mut best: Option<ObjectSplit> = None;
+    // Cost of not splitting
+    let mut min_cost = refs.len().to_float() * bounds(refs).area();
+    for axis in 0..3 {
+        sort(refs, axis);
+        // Precompute all right side bbs
+        let mut right_bbs = vec![Aabb::empty(); refs.len() + 1];
+        for i in (0..refs.len()).rev() {
+            let mut bb = right_bbs[i + 1].clone();
+            bb.add_aabb(&refs[i].aabb);
+            right_bbs[i] = bb;
+        }
+        let mut left_bb = Aabb::empty();
+        for i in 1..refs.len() {
+            left_bb.add_aabb(&refs[i - 1].aabb);
+            let right_bb = &right_bbs[i];
+            let cost =
+                i.to_float() * left_bb.area() + (refs.len() - i).to_float() * right_bb.area();
+            if cost < min_cost {
+                min_cost = cost;
+                best = Some(ObjectSplit {
+                    axis,
+                    i,
+                    cost,
+                    overlap: overlap_area(&left_bb, right_bb),
+                });
+            }
+        }
+    }
+    best
+}
+
+fn sort(refs: &mut [Reference], axis: usize) {
+    refs.sort_unstable_by(|r1, r2| {
+        let c1 = r1.center(axis);
+        let c2 = r2.center(axis);
+        c1.partial_cmp(&c2).unwrap().then(r1.tri_i.cmp(&r2.tri_i))
+    });
+}
+
+fn leaf(aabb: Aabb, refs: Vec<Reference>) -> (Vec<BvhNode>, usize, Vec<usize>) {
+    let node = BvhNode {
+        aabb,
+        indices: Indices::Leaf(0, refs.len() as u32),
+    };
+    let tri_indices = refs.into_iter().map(|r| r.tri_i).collect();
+    (vec![node], 0, tri_indices)
+}
+
+fn bounds(refs: &[Reference]) -> Aabb {
+    let mut aabb = Aabb::empty();
+    for r in refs {
+        aabb.add_aabb(&r.aabb);
+    }
+    aabb
+}
+
+fn overlap_area(aabb1: &Aabb, aabb2: &Aabb) -> Float {
+    let mut overlap = Aabb::empty();
+    for axis in 0..3 {
+        overlap.min[axis] = aabb1.min[axis].max(aabb2.min[axis]);
+        overlap.max[axis] = aabb1.max[axis].min(aabb2.max[axis]);
+        if overlap.min[axis] > overlap.max[axis] {
+            return 0.0;
+        }
+    }
+    overlap.area()
+}
+
+/// Bounds of the part of tri that lies between start and end along axis
+/// intersected with the previous bounds of the reference
+fn clip(tri: &Triangle, bounds: &Aabb, axis: usize, start: Float, end: Float) -> Option<Aabb> {
+    let mut clipped = Aabb::empty();
+    let mut add_point = |p: Point3<Float>| {
+        clipped.add_aabb(&Aabb { min: p, max: p });
+    };
+    let positions = tri.positions();
+    for i in 0..3 {
+        let p1 = positions[i];
+        let p2 = positions[(i + 1) % 3];
+        let (a, b) = (p1[axis], p2[axis]);
+        if start <= a && a <= end {
+            add_point(p1);
+        }
+        // Points where the edge crosses the planes
+        for &plane in &[start, end] {
+            if (a < plane && plane < b) || (b < plane && plane < a) {
+                let t = (plane - a) / (b - a);
+                let mut p = p1 + t * (p2 - p1);
+                p[axis] = plane;
+                add_point(p);
+            }
+        }
+    }
+    for c in 0..3 {
+        clipped.min[c] = clipped.min[c].max(bounds.min[c]);
+        clipped.max[c] = clipped.max[c].min(bounds.max[c]);
+        if clipped.min[c] > clipped.max[c] {
+            return None;
+        }
+    }
+    clipped.min[axis] = clipped.min[axis].max(start);
+    clipped.max[axis] = clipped.max[axis].min(end);
+    if clipped.min[axis] > clipped.max[axis] {
+        return None;
+    }
+    Some(clipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::bvh::SplitMode;
+    use crate::config::RenderConfig;
+    use crate::scene::SceneBuilder;
+    use crate::stats;
+
+    /// Grid of small triangles crossed by a few long diagonal ones
+    /// whose bounds cover the whole grid
+    fn diagonal_scene_obj() -> String {
+        let mut obj = "mtllib scene.mtl\nusemtl white\n".to_string();
+        let mut add_triangle = |x: Float, y: Float, dx: Float, dy: Float| {
+            obj += &format!(
+                "v {} {} 0\nv {} {} 0\nv {} {} 0\nf -3 -2 -1\n",
+                x,
+                y,
+                x + dx,
+                y + dy,
+                x + dx - 0.1,
+                y + dy
+            );
+        };
+        for i in 0..10usize {
+            for j in 0..10usize {
+                add_triangle(i.to_float(), j.to_float(), 0.3, 0.3);
+            }
+        }
+        for i in 0..4usize {
+            add_triangle(0.2 * i.to_float(), 0.0, 9.0, 10.0);
+        }
+        obj
+    }
+
+    /// Spatial splits clip the diagonal triangles so that they don't
+    /// inflate the bounds of every node that contains them
+    #[test]
+    fn spatial_splits_lower_sah_cost() {
+        let dir = std::env::temp_dir().join("rusty_test_sbvh");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("scene.mtl"), "newmtl white\nKd 0.8 0.8 0.8\n").unwrap();
+        let path = dir.join("scene.obj");
+        fs::write(&path, diagonal_scene_obj()).unwrap();
+        let _stats = stats::test_scene("sbvh");
+        let sah_cost = |bvh_split| {
+            let config = RenderConfig {
+                bvh_split,
+                ..RenderConfig::bdpt()
+            };
+            let scene = SceneBuilder::new(&config).build(&path).unwrap();
+            scene.bvh().unwrap().sah_cost()
+        };
+        let object = sah_cost(SplitMode::Object);
+        let spatial = sah_cost(SplitMode::Sbvh);
+        assert!(
+            spatial < 0.5 * object,
+            "Sbvh cost {} is not lower than object split cost {}",
+            spatial,
+            object
+        );
+    }
+}
@@ -14,13 +14,13 @@ use crate::input::InputState;
 use crate::light::{Light, PointLight};
 
 /// Representation of a camera
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Camera {
     /// Position of the camera in world coordinates
     pub pos: Point3<Float>,
     /// Rotation of the camera
     rot: Quaternion<Float>,
-    /// Aspect ratio of the viewport
+    /// Aspect ratio of the image plane (width / height)
     ratio: Float,
     /// Vertical field-of-view of the camera
     fov: Rad<Float>,
@@ -51,6 +51,17 @@ impl PtCamera {
         &self.flash
     }
 
+    /// Approximate angle that a single pixel of an image with the given height covers
+    pub fn pixel_spread(&self, height: u32) -> Float {
+        2.0 * (0.5 * self.camera.fov.0).tan() / height.to_float()
+    }
+
+    /// Area of the image plane at unit distance from the camera
+    fn image_plane_area(&self) -> Float {
+        let height = 2.0 * (0.5 * self.camera.fov.0).tan();
+        self.camera.ratio * height.powi(2)
+    }
+
     /// Evaluate the geometric cosine with dir
     pub fn cos_g(&self, dir: Vector3<Float>) -> Float {
         dir.dot(self.rot * -Vector3::unit_z())
@@ -67,7 +78,7 @@ impl PtCamera {
             if clip_p.x < -1.0 || clip_p.x > 1.0 || clip_p.y < -1.0 || clip_p.y > 1.0 {
                 Color::black()
             } else {
-                let area = 2.0;
+                let area = self.image_plane_area();
                 Color::white() / (area * cos_t.powi(4))
             }
         }
@@ -85,7 +96,7 @@ impl PtCamera {
             if clip_p.x < -1.0 || clip_p.x > 1.0 || clip_p.y < -1.0 || clip_p.y > 1.0 {
                 0.0
             } else {
-                let area = 2.0;
+                let area = self.image_plane_area();
                 // Directional pdf
                 1.0 / (area * cos_t.powi(3))
             }
@@ -140,8 +151,11 @@ impl Camera {
         }
     }
 
-    pub fn update_viewport(&mut self, size: LogicalSize<Float>) {
-        self.ratio = size.width / size.height;
+    /// Match the image plane to a viewport of size pixels,
+    /// where each pixel is pixel_aspect_ratio times as wide as it is tall.
+    /// Empty viewports are treated as a single pixel to keep the ratio finite.
+    pub fn update_viewport(&mut self, size: LogicalSize<Float>, pixel_aspect_ratio: Float) {
+        self.ratio = pixel_aspect_ratio * size.width.max(1.0) / size.height.max(1.0);
     }
 
     pub fn set_scale(&mut self, scale: Float) {
@@ -243,3 +257,58 @@ impl Camera {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cgmath::Deg;
+
+    #[test]
+    fn image_plane_area_matches_fov() {
+        for &(fov, width, height) in &[(60.0, 3.0, 2.0), (90.0, 1.0, 1.0), (40.0, 16.0, 9.0)] {
+            let mut camera = Camera::new(Point3::origin(), Quaternion::one());
+            camera.fov = Deg(fov).into();
+            camera.update_viewport(LogicalSize::new(width, height), 1.0);
+            let camera = PtCamera::new(camera);
+            let area = camera.image_plane_area();
+            // Corners of an image plane with the computed area at unit distance
+            let half_height = 0.5 * (area / camera.ratio).sqrt();
+            let corner = |scale: Float| {
+                Vector3::new(
+                    scale * camera.ratio * half_height,
+                    scale * half_height,
+                    -1.0,
+                )
+            };
+            let clip_p = camera.clip_pos(corner(0.99)).unwrap();
+            assert!((clip_p.x - 0.99).abs() < 5e-3, "{:?}", clip_p);
+            assert!((clip_p.y - 0.99).abs() < 5e-3, "{:?}", clip_p);
+            assert!(camera.clip_pos(corner(1.01)).is_none());
+        }
+        // The old hard-coded area is only right for 60 degrees at 3:2
+        let mut camera = Camera::new(Point3::origin(), Quaternion::one());
+        camera.update_viewport(LogicalSize::new(3.0, 2.0), 1.0);
+        assert!((PtCamera::new(camera).image_plane_area() - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn viewport_ratio() {
+        let mut camera = Camera::new(Point3::origin(), Quaternion::one());
+        camera.update_viewport(LogicalSize::new(200.0, 100.0), 1.0);
+        assert_eq!(camera.ratio, 2.0);
+        camera.update_viewport(LogicalSize::new(200.0, 100.0), 0.5);
+        assert_eq!(camera.ratio, 1.0);
+    }
+
+    #[test]
+    fn empty_viewport_ratio_is_finite() {
+        let mut camera = Camera::new(Point3::origin(), Quaternion::one());
+        camera.update_viewport(LogicalSize::new(200.0, 0.0), 1.0);
+        assert_eq!(camera.ratio, 200.0);
+        camera.update_viewport(LogicalSize::new(0.0, 0.0), 1.0);
+        assert_eq!(camera.ratio, 1.0);
+        let m: [[Float; 4]; 4] = camera.world_to_clip().into();
+        assert!(m.iter().flatten().all(|v| v.is_finite()));
+    }
+}
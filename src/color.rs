@@ -73,6 +73,10 @@ impl SrgbColor {
 pub struct Color(BaseColor);
 
 impl Color {
+    pub fn new(r: Float, g: Float, b: Float) -> Self {
+        Self(BaseColor::new(r, g, b))
+    }
+
     pub fn black() -> Self {
         Self(BaseColor::black())
     }
@@ -94,10 +98,25 @@ impl Color {
         self.0.luma()
     }
 
+    /// Scale the color such that its luma is at most max_luma
+    pub fn clamp_luma(self, max_luma: Float) -> Self {
+        let luma = self.luma();
+        if luma > max_luma {
+            self * (max_luma / luma)
+        } else {
+            self
+        }
+    }
+
     pub fn is_black(&self) -> bool {
         self.0.is_black()
     }
 
+    /// Exponential of each component
+    pub fn exp(self) -> Self {
+        Self::new(self.r().exp(), self.g().exp(), self.b().exp())
+    }
+
     pub fn r(&self) -> Float {
         self.0.r()
     }
@@ -0,0 +1,200 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use image::codecs::hdr::HdrDecoder;
+use image::{GenericImage, ImageBuffer, Rgb, RgbImage, RgbaImage};
+
+use crate::float::*;
+use crate::util;
+
+/// Percentile of the pixel differences that maps to the top of the heatmap.
+/// Using the maximum would let a few fireflies wash out the rest of the map.
+const HEATMAP_PERCENTILE: f32 = 0.99;
+
+/// Offset that keeps the relative MSE of black reference pixels finite
+const REL_MSE_EPSILON: Float = 1e-2;
+
+/// Linear .hdr image
+pub type HdrImage = ImageBuffer<Rgb<f32>, Vec<f32>>;
+
+/// Error of an image against a reference
+#[derive(Clone, Copy, Debug)]
+pub struct ImageError {
+    /// Root mean square error
+    pub rmse: Float,
+    /// Mean of the squared errors relative to the squared reference values
+    pub rel_mse: Float,
+}
+
+pub fn load_hdr(path: &Path) -> Result<HdrImage, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let decoder = HdrDecoder::new(reader)?;
+    let metadata = decoder.metadata();
+    let pixels = decoder.read_image_hdr()?;
+    let data = pixels.iter().flat_map(|pixel| pixel.0).collect();
+    ImageBuffer::from_raw(metadata.width, metadata.height, data)
+        .ok_or_else(|| "Invalid hdr image size".into())
+}
+
+/// Load a reference image as linear radiance.
+/// Png images are only decoded from sRGB so they should be rendered without tone mapping.
+pub fn load_reference(path: &Path) -> Result<HdrImage, Box<dyn Error>> {
+    if util::lowercase_extension(path).as_deref() == Some("hdr") {
+        return load_hdr(path);
+    }
+    let image = image::open(path)?.to_rgb8();
+    let data = image
+        .as_raw()
+        .iter()
+        .map(|&c| srgb_to_linear(f32::from(c) / 255.0))
+        .collect();
+    ImageBuffer::from_raw(image.width(), image.height(), data)
+        .ok_or_else(|| "Invalid reference image size".into())
+}
+
+/// Exact sRGB decoding to match the encoding of the saved images
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Error of image against the reference over all the channels
+pub fn image_error(image: &HdrImage, reference: &HdrImage) -> Result<ImageError, Box<dyn Error>> {
+    if image.dimensions() != reference.dimensions() {
+        return Err("Images have different dimensions".into());
+    }
+    let mut squared_error = 0.0;
+    let mut rel_squared_error = 0.0;
+    for (&c, &r) in image.iter().zip(reference.iter()) {
+        let error = (c.to_float() - r.to_float()).powi(2);
+        squared_error += error;
+        rel_squared_error += error / (r.to_float().powi(2) + REL_MSE_EPSILON);
+    }
+    let n = image.len().max(1).to_float();
+    Ok(ImageError {
+        rmse: (squared_error / n).sqrt(),
+        rel_mse: rel_squared_error / n,
+    })
+}
+
+/// Place two images next to each other
+pub fn side_by_side(left: &RgbaImage, right: &RgbaImage) -> Result<RgbaImage, Box<dyn Error>> {
+    let width = left.width() + right.width();
+    let height = left.height().max(right.height());
+    let mut image = RgbaImage::new(width, height);
+    image.copy_from(left, 0, 0)?;
+    image.copy_from(right, left.width(), 0)?;
+    Ok(image)
+}
+
+/// Heatmap of the absolute per-pixel differences of two images
+/// that goes from black through red and yellow to white
+pub fn difference_heatmap(image: &HdrImage, other: &HdrImage) -> Result<RgbImage, Box<dyn Error>> {
+    if image.dimensions() != other.dimensions() {
+        return Err("Images have different dimensions".into());
+    }
+    let differences: Vec<f32> = image
+        .pixels()
+        .zip(other.pixels())
+        .map(|(p1, p2)| (0..3).map(|c| (p1[c] - p2[c]).abs()).sum::<f32>() / 3.0)
+        .collect();
+    // Hdr images may contain NaNs or infinities which are left out of the scale
+    let mut sorted: Vec<f32> = differences
+        .iter()
+        .copied()
+        .filter(|d| d.is_finite())
+        .collect();
+    sorted.sort_unstable_by(f32::total_cmp);
+    let i = ((sorted.len().max(1) - 1) as f32 * HEATMAP_PERCENTILE).round() as usize;
+    let scale = match sorted.get(i) {
+        Some(&max) if max > 0.0 => 1.0 / max,
+        _ => 0.0,
+    };
+    Ok(RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let difference = differences[(y * image.width() + x) as usize];
+        // Non-finite differences are shown as the maximum difference
+        let t = if difference.is_finite() {
+            3.0 * (scale * difference).min(1.0)
+        } else {
+            3.0
+        };
+        let channel = |offset: f32| (255.0 * (t - offset).clamp(0.0, 1.0)) as u8;
+        Rgb([channel(0.0), channel(1.0), channel(2.0)])
+    }))
+}
+
+/// Root mean square error between two images relative to the mean of the reference
+pub fn relative_rmse(image: &HdrImage, reference: &HdrImage) -> Result<Float, Box<dyn Error>> {
+    if image.dimensions() != reference.dimensions() {
+        return Err("Images have different dimensions".into());
+    }
+    let mut squared_error = 0.0;
+    let mut sum = 0.0;
+    for (pixel, reference) in image.pixels().zip(reference.pixels()) {
+        for c in 0..3 {
+            squared_error += (pixel[c].to_float() - reference[c].to_float()).powi(2);
+            sum += reference[c].to_float();
+        }
+    }
+    let n = image.len().max(1).to_float();
+    let mean = sum / n;
+    if mean <= 0.0 {
+        return Err("Reference image is black".into());
+    }
+    Ok((squared_error / n).sqrt() / mean)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heatmap_of_non_finite_pixels() {
+        let mut image = HdrImage::from_pixel(4, 2, Rgb([0.5, 0.5, 0.5]));
+        let reference = HdrImage::from_pixel(4, 2, Rgb([0.25, 0.25, 0.25]));
+        image.put_pixel(0, 0, Rgb([f32::NAN, 0.5, 0.5]));
+        image.put_pixel(1, 0, Rgb([f32::INFINITY, 0.5, 0.5]));
+        let heatmap = difference_heatmap(&image, &reference).unwrap();
+        assert_eq!(heatmap.get_pixel(0, 0), &Rgb([255, 255, 255]));
+        assert_eq!(heatmap.get_pixel(1, 0), &Rgb([255, 255, 255]));
+        // Finite differences are scaled to the top of the heatmap
+        assert_eq!(heatmap.get_pixel(2, 0), &Rgb([255, 255, 255]));
+    }
+
+    #[test]
+    fn equal_images_have_black_heatmap() {
+        let image = HdrImage::from_pixel(3, 3, Rgb([1.0, 2.0, 3.0]));
+        let heatmap = difference_heatmap(&image, &image).unwrap();
+        assert!(heatmap.pixels().all(|pixel| pixel == &Rgb([0, 0, 0])));
+    }
+
+    #[test]
+    fn error_against_itself() {
+        let image = HdrImage::from_fn(4, 3, |x, y| Rgb([x as f32, y as f32, 0.5]));
+        let error = image_error(&image, &image).unwrap();
+        assert_eq!(error.rmse, 0.0);
+        assert_eq!(error.rel_mse, 0.0);
+    }
+
+    #[test]
+    fn error_against_scaled_copy() {
+        let reference = HdrImage::from_pixel(4, 3, Rgb([0.5, 0.5, 0.5]));
+        let scaled = HdrImage::from_pixel(4, 3, Rgb([1.0, 1.0, 1.0]));
+        let error = image_error(&scaled, &reference).unwrap();
+        assert!((error.rmse - 0.5).abs() < 1e-6);
+        let expected_rel_mse = 0.25 / (0.25 + REL_MSE_EPSILON);
+        assert!((error.rel_mse - expected_rel_mse).abs() < 1e-6);
+    }
+
+    #[test]
+    fn error_of_different_dimensions() {
+        let image = HdrImage::new(2, 2);
+        let reference = HdrImage::new(2, 3);
+        assert!(image_error(&image, &reference).is_err());
+    }
+}
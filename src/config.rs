@@ -1,27 +1,49 @@
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
 use glium::glutin::{dpi::LogicalSize, event::VirtualKeyCode};
+use glium::Rect;
 
 use crate::bvh::SplitMode;
 use crate::float::*;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum RenderMode {
     /// Standard path tracing
     PathTracing,
     /// Bidirectional path tracing
     Bdpt,
+    /// Progressive photon mapping
+    Ppm,
     /// Debug
     Debug(DebugMode),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum DebugMode {
     /// Normals
     Normals,
     /// Normals that point away from the camera
     ForwardNormals,
+    /// Distance to the first hit relative to the diagonal of the scene bounds.
+    /// Misses are white.
+    Depth,
+    /// Texture coordinates wrapped to [0, 1) as red and green.
+    /// Triangles whose texture coordinates have zero area are magenta.
+    TexCoords,
+    /// Texels of a 1024 texel wide texture per pixel width on a log scale.
+    /// Green is one texel per pixel, red is minified and blue is magnified.
+    /// Triangles whose texture coordinates have zero area are magenta.
+    TexelDensity,
+    /// Pseudo random color for each material
+    MaterialId,
+    /// Pseudo random color for each mesh
+    MeshId,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum LightMode {
     /// Use scene lights only (will still fall back to camera if there are none)
     Scene,
@@ -29,7 +51,57 @@ pub enum LightMode {
     Camera,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightSampling {
+    /// Sample lights proportionally to their emitted power
+    Power,
+    /// Sample all lights with equal probability so that dim lights
+    /// that are important to parts of the scene are not starved
+    Uniform,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpAxis {
+    /// Scene is authored with y pointing up, which is what the camera expects
+    Y,
+    /// Scene is authored with z pointing up and is rotated to y up when loaded
+    Z,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerType {
+    /// Jittered pixel strata and independent uniform numbers for everything else
+    Independent,
+    /// Multi-jittered pixel samples and latin hypercube samples for the other
+    /// dimensions, which stratifies each dimension over the samples of a pixel
+    Stratified,
+    /// Owen scrambled Sobol points. Pixel samples use the first two Sobol dimensions
+    /// and the other dimensions use the first one with shuffled sample indices.
+    Sobol,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileOrder {
+    /// Rows of tiles from the bottom to the top
+    Raster,
+    /// Rings of tiles spiraling out from the image center
+    Spiral,
+    /// Hilbert curve over the tiles starting from the tile nearest the image center
+    Hilbert,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneMap {
+    /// Tone map each channel separately, which desaturates bright colors towards white
+    PerChannel,
+    /// Tone map the luminance and scale the color by the same factor
+    Luminance,
+    /// Tone map the luminance and desaturate colors that fall out of gamut
+    /// towards their luminance instead of clipping them, which preserves the hue
+    HuePreserving,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum RussianRoulette {
     /// Select survival probability based on path throughput
     Dynamic,
@@ -39,26 +111,78 @@ pub enum RussianRoulette {
     Off,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct RenderConfig {
     /// Width of the render target in pixels
     pub width: u32,
     /// Height of the render target in pixels
     pub height: u32,
+    /// Width of a pixel relative to its height. Wider pixels widen the horizontal
+    /// field of view without changing the resolution, e.g. for anamorphic formats.
+    pub pixel_aspect_ratio: Float,
     /// Maximum number of threads to use for rendering
     pub max_threads: usize,
+    /// Fraction of the time the render threads are allowed to work.
+    /// Threads sleep between blocks to leave cpu time for other programs.
+    pub cpu_budget: Float,
     /// Should normal mapping be used
     pub normal_mapping: bool,
+    /// Filter textures of surfaces seen by the camera according to their pixel footprint
+    pub mipmapping: bool,
     /// Source of the image color
     pub render_mode: RenderMode,
     /// Which lights should be used
     pub light_mode: LightMode,
+    /// Probabilities of choosing each light for a light sample
+    pub light_sampling: LightSampling,
+    /// Flip triangles whose winding disagrees with their vertex normals
+    /// or points into a closed mesh. Breaks intentionally inward facing geometry.
+    pub orient_normals: bool,
+    /// Point the geometric normals of triangles with vertex normals to the side
+    /// of the vertex normals instead of deriving them from the winding.
+    /// Fixes the emission direction of inconsistently wound lights.
+    pub ng_from_vertex_normals: bool,
+    /// Ignore ray hits on the back faces of triangles
+    pub backface_cull: bool,
+    /// Ignore back face hits of shadow rays. Separate from backface_cull
+    /// since culling shadow rays lets light through closed meshes.
+    pub backface_cull_shadows: bool,
+    /// Bvh depth at which path traced shadow rays stop descending and treat
+    /// the hit nodes as occluders. Gives cheap but blobby shadows for previews.
+    /// None uses exact shadows.
+    pub approx_shadows: Option<usize>,
+    /// Up axis of the loaded scenes
+    pub up_axis: UpAxis,
+    /// Equirectangular .hdr image that lights the scene from infinitely far away
+    pub environment_map: Option<PathBuf>,
+    /// Sample the environment explicitly at path vertices. Bdpt weights the
+    /// explicit samples against escaped camera paths with MIS.
+    pub environment_nee: bool,
+    /// Extinction coefficient per unit length of the homogeneous fog that fills
+    /// the whole scene. 0 disables the fog. Only the path tracer renders fog and
+    /// the environment never reaches the scene through it.
+    pub fog_density: Float,
+    /// Fraction of the fog extinction that scatters light instead of absorbing it
+    pub fog_albedo: Float,
+    /// Henyey-Greenstein asymmetry of the fog scattering.
+    /// Positive values scatter forward and negative values backward.
+    pub fog_g: Float,
+    /// File that replaces parameters of the scene materials by name
+    pub material_overrides: Option<PathBuf>,
+    /// File that places transformed copies of the scene groups by name
+    pub placements: Option<PathBuf>,
     /// Maximum number of iterations. None corresponds to manual stop.
     pub max_iterations: Option<usize>,
     /// Type of russian roulette
     pub russian_roulette: RussianRoulette,
     /// Multiple importance sampling on or off
     pub mis: bool,
+    /// Scale the bdpt MIS weights of the strategies by the inverse of their
+    /// variance, which is learned during rendering. Has no effect without mis.
+    pub variance_aware_mis: bool,
+    /// Lower the russian roulette survival probabilities of path tracing
+    /// in blocks whose estimated variance is already low
+    pub variance_rr: bool,
     /// Number of bounces before starting russian roulette.
     /// Won't have effect is russian roulette is off.
     pub pre_rr_bounces: usize,
@@ -67,10 +191,83 @@ pub struct RenderConfig {
     pub max_bounces: usize,
     /// Samples per pixel per direction. Squared to get the total samples per pixel.
     pub samples_per_dir: usize,
+    /// How the random numbers of the samples are generated
+    pub sampler: SamplerType,
+    /// Base seed of the random numbers. Each block is seeded from it and its
+    /// position so a fixed seed makes renders reproducible. Adaptive sampling and
+    /// the variance driven features share state between threads so they are only
+    /// reproducible with a single thread. None seeds from system entropy.
+    pub seed: Option<u64>,
+    /// Compensate the energy lost by single scattering in glossy reflections
+    pub energy_compensation: bool,
+    /// Number of light paths traced for each camera path in bdpt.
+    /// Their contributions are averaged.
+    pub light_paths_per_pixel: usize,
+    /// Number of cached light path vertices that each camera path vertex in bdpt
+    /// is connected to. The cache is traced once per block and it has a light path
+    /// for each camera sample of the block. 0 traces light_paths_per_pixel
+    /// fresh light paths for each camera path instead.
+    pub light_path_reuse: usize,
+    /// Number of photon paths traced for each block in photon mapping
+    pub ppm_photons: usize,
+    /// Photon gathering radius of the first iteration relative to the size of the scene
+    pub ppm_radius: Float,
+    /// Fraction of the photons kept when the gathering radius shrinks after
+    /// each iteration. Smaller values shrink faster with more noise.
+    pub ppm_alpha: Float,
+    /// Maximum luminance of the indirect radiance of a single sample.
+    /// Biased but removes fireflies. Directly visible and directly lit
+    /// radiance is never clamped. None disables clamping.
+    pub clamp_indirect: Option<Float>,
     /// Should tone mapping be used
     pub tone_map: bool,
+    /// Tone mapping operator used when tone mapping is on
+    pub tone_map_operator: ToneMap,
+    /// Exposure compensation in stops applied before tone mapping
+    pub exposure: Float,
+    /// Save the image every convergence_interval iterations. Offline renders save
+    /// the frames to a convergence directory next to the final image and
+    /// online renders to a convergence directory in results/online.
+    pub convergence_frames: bool,
+    /// Number of iterations between convergence frames
+    pub convergence_interval: usize,
+    /// Show the progress of offline renders in a window.
+    /// Closing the window stops the render early.
+    pub offline_preview: bool,
+    /// Also save the linear radiance of offline renders as an .hdr image
+    pub save_hdr: bool,
+    /// Write the exposure and tone mapping of the preview next to the .hdr image
+    /// so that viewers can reproduce the look of the tone mapped image
+    pub hdr_metadata: bool,
+    /// Accumulate the albedo and shading normal of the first hits as inputs
+    /// for denoisers. Offline renders save them as .pfm images.
+    pub aovs: bool,
+    /// Number of preview frames between uploads of the traced image to the GPU
+    pub upload_interval: usize,
+    /// Request an sRGB framebuffer for the preview window.
+    /// If the framebuffer is linear, the shaders apply the sRGB encoding instead.
+    pub srgb_framebuffer: bool,
     /// Splitting method for bvh
     pub bvh_split: SplitMode,
+    /// Bvh nodes with more triangles than this are split
+    pub bvh_max_leaf_size: usize,
+    /// Save built bvhs next to the scene files and load them on later runs
+    pub bvh_cache: bool,
+    /// Relative variance below which a block is considered converged.
+    /// Adaptivity is per 50x50 pixel block rather than per pixel: converged
+    /// blocks are no longer rendered and the render terminates once all
+    /// blocks have converged. None disables adaptive sampling.
+    /// Variance is estimated within each pass so samples_per_dir needs to be at least 2.
+    /// The estimate only covers the camera samples of a block. Bdpt light paths
+    /// splat anywhere in the image, so their contribution is not included and
+    /// blocks lit mostly by light paths may be marked converged early.
+    pub adaptive_threshold: Option<Float>,
+    /// Order in which the tiles of an iteration are rendered
+    pub tile_order: TileOrder,
+    /// Render only the pixels inside the rect. Its position is measured in pixels
+    /// from the bottom left corner of the image. The camera still covers the
+    /// whole image so the crop matches the same part of a full render.
+    pub crop: Option<Rect>,
 }
 
 impl RenderConfig {
@@ -78,18 +275,61 @@ impl RenderConfig {
         Self {
             width: 1000,
             height: 800,
+            pixel_aspect_ratio: 1.0,
             max_threads: num_cpus::get_physical(),
+            cpu_budget: 1.0,
             normal_mapping: true,
+            mipmapping: false,
             render_mode: RenderMode::PathTracing,
             light_mode: LightMode::Scene,
+            light_sampling: LightSampling::Power,
+            orient_normals: false,
+            ng_from_vertex_normals: false,
+            backface_cull: false,
+            backface_cull_shadows: false,
+            approx_shadows: None,
+            up_axis: UpAxis::Y,
+            environment_map: None,
+            environment_nee: true,
+            fog_density: 0.0,
+            fog_albedo: 1.0,
+            fog_g: 0.0,
+            material_overrides: None,
+            placements: None,
             max_iterations: None,
             russian_roulette: RussianRoulette::Dynamic,
             mis: true,
+            variance_aware_mis: false,
+            variance_rr: false,
             pre_rr_bounces: 5,
             max_bounces: usize::MAX,
             samples_per_dir: 2,
+            sampler: SamplerType::Independent,
+            seed: None,
+            energy_compensation: false,
+            light_paths_per_pixel: 1,
+            light_path_reuse: 0,
+            ppm_photons: 10_000,
+            ppm_radius: 0.03,
+            ppm_alpha: 0.7,
+            clamp_indirect: None,
             tone_map: true,
+            tone_map_operator: ToneMap::Luminance,
+            exposure: 0.0,
+            convergence_frames: false,
+            convergence_interval: 1,
+            offline_preview: false,
+            save_hdr: false,
+            hdr_metadata: true,
+            aovs: false,
+            upload_interval: 1,
+            srgb_framebuffer: true,
             bvh_split: SplitMode::Sah,
+            bvh_max_leaf_size: 8,
+            bvh_cache: false,
+            adaptive_threshold: None,
+            tile_order: TileOrder::Spiral,
+            crop: None,
         }
     }
 
@@ -103,22 +343,73 @@ impl RenderConfig {
         }
     }
 
+    pub fn ppm() -> Self {
+        Self {
+            render_mode: RenderMode::Ppm,
+            ..Self::path_trace()
+        }
+    }
+
     pub fn benchmark() -> Self {
         Self {
             width: 600,
             height: 400,
+            pixel_aspect_ratio: 1.0,
             max_threads: 8,
+            cpu_budget: 1.0,
             normal_mapping: true,
+            mipmapping: false,
             render_mode: RenderMode::PathTracing,
             light_mode: LightMode::Scene,
+            light_sampling: LightSampling::Power,
+            orient_normals: false,
+            ng_from_vertex_normals: false,
+            backface_cull: false,
+            backface_cull_shadows: false,
+            approx_shadows: None,
+            up_axis: UpAxis::Y,
+            environment_map: None,
+            environment_nee: true,
+            fog_density: 0.0,
+            fog_albedo: 1.0,
+            fog_g: 0.0,
+            material_overrides: None,
+            placements: None,
             max_iterations: Some(1),
             russian_roulette: RussianRoulette::Off,
             mis: true,
+            variance_aware_mis: false,
+            variance_rr: false,
             pre_rr_bounces: 5,
             max_bounces: 5,
             samples_per_dir: 3,
+            sampler: SamplerType::Independent,
+            seed: None,
+            energy_compensation: false,
+            light_paths_per_pixel: 1,
+            light_path_reuse: 0,
+            ppm_photons: 10_000,
+            ppm_radius: 0.03,
+            ppm_alpha: 0.7,
+            clamp_indirect: None,
             tone_map: true,
+            tone_map_operator: ToneMap::Luminance,
+            exposure: 0.0,
+            convergence_frames: false,
+            convergence_interval: 1,
+            offline_preview: false,
+            save_hdr: false,
+            hdr_metadata: true,
+            aovs: false,
+            upload_interval: 1,
+            srgb_framebuffer: true,
             bvh_split: SplitMode::Sah,
+            bvh_max_leaf_size: 8,
+            // Benchmarks should measure the build
+            bvh_cache: false,
+            adaptive_threshold: None,
+            tile_order: TileOrder::Spiral,
+            crop: None,
         }
     }
 
@@ -170,6 +461,41 @@ impl RenderConfig {
         }
     }
 
+    pub fn debug_depth() -> Self {
+        Self {
+            render_mode: RenderMode::Debug(DebugMode::Depth),
+            ..Self::debug_normals()
+        }
+    }
+
+    pub fn debug_tex_coords() -> Self {
+        Self {
+            render_mode: RenderMode::Debug(DebugMode::TexCoords),
+            ..Self::debug_normals()
+        }
+    }
+
+    pub fn debug_texel_density() -> Self {
+        Self {
+            render_mode: RenderMode::Debug(DebugMode::TexelDensity),
+            ..Self::debug_normals()
+        }
+    }
+
+    pub fn debug_material_id() -> Self {
+        Self {
+            render_mode: RenderMode::Debug(DebugMode::MaterialId),
+            ..Self::debug_normals()
+        }
+    }
+
+    pub fn debug_mesh_id() -> Self {
+        Self {
+            render_mode: RenderMode::Debug(DebugMode::MeshId),
+            ..Self::debug_normals()
+        }
+    }
+
     #[allow(dead_code)]
     pub fn single_threaded(self) -> Self {
         println!("Running single threaded!");
@@ -179,6 +505,442 @@ impl RenderConfig {
         }
     }
 
+    /// Check for settings that can't be rendered
+    pub fn validate(&self) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 {
+            return Err(format!(
+                "Image dimensions {}x{} contain no pixels",
+                self.width, self.height
+            ));
+        }
+        if self.pixel_aspect_ratio <= 0.0 {
+            return Err(format!(
+                "Pixel aspect ratio {} needs to be positive",
+                self.pixel_aspect_ratio
+            ));
+        }
+        if self.cpu_budget <= 0.0 || self.cpu_budget > 1.0 {
+            return Err(format!(
+                "Cpu budget {} needs to be in (0, 1]",
+                self.cpu_budget
+            ));
+        }
+        if let Some(crop) = self.crop {
+            if crop.width == 0
+                || crop.height == 0
+                || crop.left >= self.width
+                || crop.bottom >= self.height
+            {
+                return Err(format!(
+                    "Crop {:?} contains no pixels of the {}x{} image",
+                    crop, self.width, self.height
+                ));
+            }
+        }
+        if self.samples_per_dir == 0 {
+            return Err("samples_per_dir needs to be at least 1".to_string());
+        }
+        if self.bvh_max_leaf_size == 0 {
+            return Err("bvh_max_leaf_size needs to be at least 1".to_string());
+        }
+        if self.adaptive_threshold.is_some() && self.samples_per_dir < 2 {
+            return Err("Adaptive sampling needs samples_per_dir of at least 2".to_string());
+        }
+        if self.render_mode == RenderMode::Bdpt {
+            if self.max_bounces == 0 {
+                return Err("Bdpt needs max_bounces of at least 1".to_string());
+            }
+            if self.light_paths_per_pixel == 0 {
+                return Err("Bdpt needs light_paths_per_pixel of at least 1".to_string());
+            }
+        }
+        if self.render_mode == RenderMode::Ppm {
+            if self.ppm_radius <= 0.0 {
+                return Err(format!(
+                    "Photon gathering radius {} needs to be positive",
+                    self.ppm_radius
+                ));
+            }
+            if self.ppm_alpha <= 0.0 || self.ppm_alpha >= 1.0 {
+                return Err(format!(
+                    "Photon mapping alpha {} needs to be in (0, 1)",
+                    self.ppm_alpha
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get a preset config by the name of its constructor
+    pub fn preset(name: &str) -> Result<Self, String> {
+        match name {
+            "path_trace" => Ok(Self::path_trace()),
+            "bdpt" => Ok(Self::bdpt()),
+            "ppm" => Ok(Self::ppm()),
+            "benchmark" => Ok(Self::benchmark()),
+            "bdpt_benchmark" => Ok(Self::bdpt_benchmark()),
+            "high_quality" => Ok(Self::high_quality()),
+            "high_quality_pt" => Ok(Self::high_quality_pt()),
+            "debug_normals" => Ok(Self::debug_normals()),
+            "forward_normals" => Ok(Self::forward_normals()),
+            "debug_depth" => Ok(Self::debug_depth()),
+            "debug_tex_coords" => Ok(Self::debug_tex_coords()),
+            "debug_texel_density" => Ok(Self::debug_texel_density()),
+            "debug_material_id" => Ok(Self::debug_material_id()),
+            "debug_mesh_id" => Ok(Self::debug_mesh_id()),
+            _ => Err(format!("Unknown preset {}", name)),
+        }
+    }
+
+    /// Load a .cfg file of key = value lines. Lines starting with # are comments.
+    /// Keys are the field names of the config and values are written like Rust
+    /// literals: numbers and bools are plain, strings are quoted and optional
+    /// values are disabled with "none". Rects are quoted "left bottom width height".
+    /// A preset key replaces the whole config with the named preset so it
+    /// should come first.
+    /// Fields that are not given keep the values of the path tracing preset.
+    /// Configs that don't pass validate are rejected.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut config = Self::path_trace();
+        let file = std::fs::read_to_string(path)?;
+        for (i, line) in file.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Line {}: expected key = value", i + 1))?;
+            config
+                .set(key.trim(), value.trim())
+                .map_err(|err| format!("Line {}: {}", i + 1, err))?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Set the field key from its value in a config file
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "preset" => *self = Self::preset(&parse_string(value)?)?,
+            "width" => self.width = parse(value)?,
+            "height" => self.height = parse(value)?,
+            "pixel_aspect_ratio" => self.pixel_aspect_ratio = parse(value)?,
+            "max_threads" => self.max_threads = parse(value)?,
+            "cpu_budget" => self.cpu_budget = parse(value)?,
+            "normal_mapping" => self.normal_mapping = parse(value)?,
+            "mipmapping" => self.mipmapping = parse(value)?,
+            "render_mode" => {
+                self.render_mode = match parse_string(value)?.as_str() {
+                    "pt" => RenderMode::PathTracing,
+                    "bdpt" => RenderMode::Bdpt,
+                    "ppm" => RenderMode::Ppm,
+                    "normals" => RenderMode::Debug(DebugMode::Normals),
+                    "forward_normals" => RenderMode::Debug(DebugMode::ForwardNormals),
+                    "depth" => RenderMode::Debug(DebugMode::Depth),
+                    "tex_coords" => RenderMode::Debug(DebugMode::TexCoords),
+                    "texel_density" => RenderMode::Debug(DebugMode::TexelDensity),
+                    "material_id" => RenderMode::Debug(DebugMode::MaterialId),
+                    "mesh_id" => RenderMode::Debug(DebugMode::MeshId),
+                    mode => return Err(format!("Unknown render mode {}", mode)),
+                }
+            }
+            "light_mode" => {
+                self.light_mode = match parse_string(value)?.as_str() {
+                    "scene" => LightMode::Scene,
+                    "camera" => LightMode::Camera,
+                    mode => return Err(format!("Unknown light mode {}", mode)),
+                }
+            }
+            "light_sampling" => {
+                self.light_sampling = match parse_string(value)?.as_str() {
+                    "power" => LightSampling::Power,
+                    "uniform" => LightSampling::Uniform,
+                    sampling => return Err(format!("Unknown light sampling {}", sampling)),
+                }
+            }
+            "orient_normals" => self.orient_normals = parse(value)?,
+            "ng_from_vertex_normals" => self.ng_from_vertex_normals = parse(value)?,
+            "backface_cull" => self.backface_cull = parse(value)?,
+            "backface_cull_shadows" => self.backface_cull_shadows = parse(value)?,
+            "approx_shadows" => self.approx_shadows = parse_option(value)?,
+            "up_axis" => {
+                self.up_axis = match parse_string(value)?.as_str() {
+                    "y" => UpAxis::Y,
+                    "z" => UpAxis::Z,
+                    axis => return Err(format!("Unknown up axis {}", axis)),
+                }
+            }
+            "environment_map" => self.environment_map = parse_path(value)?,
+            "environment_nee" => self.environment_nee = parse(value)?,
+            "fog_density" => self.fog_density = parse(value)?,
+            "fog_albedo" => self.fog_albedo = parse(value)?,
+            "fog_g" => self.fog_g = parse(value)?,
+            "material_overrides" => self.material_overrides = parse_path(value)?,
+            "placements" => self.placements = parse_path(value)?,
+            "max_iterations" => self.max_iterations = parse_option(value)?,
+            "russian_roulette" => {
+                self.russian_roulette = match parse_string(value).as_deref() {
+                    Ok("dynamic") => RussianRoulette::Dynamic,
+                    Ok("off") => RussianRoulette::Off,
+                    Ok(rr) => return Err(format!("Unknown russian roulette {}", rr)),
+                    // Survival probability of static russian roulette
+                    Err(_) => RussianRoulette::Static(parse(value)?),
+                }
+            }
+            "mis" => self.mis = parse(value)?,
+            "variance_aware_mis" => self.variance_aware_mis = parse(value)?,
+            "variance_rr" => self.variance_rr = parse(value)?,
+            "pre_rr_bounces" => self.pre_rr_bounces = parse(value)?,
+            "max_bounces" => {
+                self.max_bounces = match parse_string(value).as_deref() {
+                    Ok("unlimited") => usize::MAX,
+                    _ => parse(value)?,
+                }
+            }
+            "samples_per_dir" => self.samples_per_dir = parse(value)?,
+            "sampler" => {
+                self.sampler = match parse_string(value)?.as_str() {
+                    "independent" => SamplerType::Independent,
+                    "stratified" => SamplerType::Stratified,
+                    "sobol" => SamplerType::Sobol,
+                    sampler => return Err(format!("Unknown sampler {}", sampler)),
+                }
+            }
+            "seed" => self.seed = parse_option(value)?,
+            "energy_compensation" => self.energy_compensation = parse(value)?,
+            "light_paths_per_pixel" => self.light_paths_per_pixel = parse(value)?,
+            "light_path_reuse" => self.light_path_reuse = parse(value)?,
+            "ppm_photons" => self.ppm_photons = parse(value)?,
+            "ppm_radius" => self.ppm_radius = parse(value)?,
+            "ppm_alpha" => self.ppm_alpha = parse(value)?,
+            "clamp_indirect" => self.clamp_indirect = parse_option(value)?,
+            "tone_map" => self.tone_map = parse(value)?,
+            "tone_map_operator" => {
+                self.tone_map_operator = match parse_string(value)?.as_str() {
+                    "per_channel" => ToneMap::PerChannel,
+                    "luminance" => ToneMap::Luminance,
+                    "hue_preserving" => ToneMap::HuePreserving,
+                    operator => return Err(format!("Unknown tone map operator {}", operator)),
+                }
+            }
+            "exposure" => self.exposure = parse(value)?,
+            "convergence_frames" => self.convergence_frames = parse(value)?,
+            "convergence_interval" => self.convergence_interval = parse(value)?,
+            "offline_preview" => self.offline_preview = parse(value)?,
+            "save_hdr" => self.save_hdr = parse(value)?,
+            "hdr_metadata" => self.hdr_metadata = parse(value)?,
+            "aovs" => self.aovs = parse(value)?,
+            "upload_interval" => self.upload_interval = parse(value)?,
+            "srgb_framebuffer" => self.srgb_framebuffer = parse(value)?,
+            "bvh_split" => {
+                self.bvh_split = match parse_string(value)?.as_str() {
+                    "object" => SplitMode::Object,
+                    "spatial" => SplitMode::Spatial,
+                    "sah" => SplitMode::Sah,
+                    "sah_binned" => SplitMode::SahBinned,
+                    "sbvh" => SplitMode::Sbvh,
+                    mode => return Err(format!("Unknown bvh split mode {}", mode)),
+                }
+            }
+            "bvh_max_leaf_size" => self.bvh_max_leaf_size = parse(value)?,
+            "bvh_cache" => self.bvh_cache = parse(value)?,
+            "adaptive_threshold" => self.adaptive_threshold = parse_option(value)?,
+            "tile_order" => {
+                self.tile_order = match parse_string(value)?.as_str() {
+                    "raster" => TileOrder::Raster,
+                    "spiral" => TileOrder::Spiral,
+                    "hilbert" => TileOrder::Hilbert,
+                    order => return Err(format!("Unknown tile order {}", order)),
+                }
+            }
+            "crop" => self.crop = parse_rect(value)?,
+            _ => return Err(format!("Unknown key {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Copy of the config with the (key, value) pairs of a config file applied.
+    /// Panics on invalid pairs since patches are written in code.
+    pub fn patched(&self, patch: &[(&str, &str)]) -> Self {
+        let mut config = self.clone();
+        for (key, value) in patch {
+            config
+                .set(key, value)
+                .unwrap_or_else(|err| panic!("Invalid config patch: {}", err));
+        }
+        config
+    }
+
+    /// Write all the fields in the format of from_file
+    pub fn to_file_string(&self) -> String {
+        let string = |s: &str| format!("\"{}\"", s);
+        let option = |value: Option<String>| value.unwrap_or_else(|| string("none"));
+        let path = |path: &Option<PathBuf>| {
+            option(
+                path.as_ref()
+                    .map(|path| string(&path.display().to_string())),
+            )
+        };
+        let render_mode = match self.render_mode {
+            RenderMode::PathTracing => "pt",
+            RenderMode::Bdpt => "bdpt",
+            RenderMode::Ppm => "ppm",
+            RenderMode::Debug(DebugMode::Normals) => "normals",
+            RenderMode::Debug(DebugMode::ForwardNormals) => "forward_normals",
+            RenderMode::Debug(DebugMode::Depth) => "depth",
+            RenderMode::Debug(DebugMode::TexCoords) => "tex_coords",
+            RenderMode::Debug(DebugMode::TexelDensity) => "texel_density",
+            RenderMode::Debug(DebugMode::MaterialId) => "material_id",
+            RenderMode::Debug(DebugMode::MeshId) => "mesh_id",
+        };
+        let light_mode = match self.light_mode {
+            LightMode::Scene => "scene",
+            LightMode::Camera => "camera",
+        };
+        let light_sampling = match self.light_sampling {
+            LightSampling::Power => "power",
+            LightSampling::Uniform => "uniform",
+        };
+        let up_axis = match self.up_axis {
+            UpAxis::Y => "y",
+            UpAxis::Z => "z",
+        };
+        let tone_map_operator = match self.tone_map_operator {
+            ToneMap::PerChannel => "per_channel",
+            ToneMap::Luminance => "luminance",
+            ToneMap::HuePreserving => "hue_preserving",
+        };
+        let russian_roulette = match self.russian_roulette {
+            RussianRoulette::Dynamic => string("dynamic"),
+            RussianRoulette::Static(prob) => format!("{:?}", prob),
+            RussianRoulette::Off => string("off"),
+        };
+        let max_bounces = if self.max_bounces == usize::MAX {
+            string("unlimited")
+        } else {
+            self.max_bounces.to_string()
+        };
+        let bvh_split = match self.bvh_split {
+            SplitMode::Object => "object",
+            SplitMode::Spatial => "spatial",
+            SplitMode::Sah => "sah",
+            SplitMode::SahBinned => "sah_binned",
+            SplitMode::Sbvh => "sbvh",
+        };
+        let sampler = match self.sampler {
+            SamplerType::Independent => "independent",
+            SamplerType::Stratified => "stratified",
+            SamplerType::Sobol => "sobol",
+        };
+        let tile_order = match self.tile_order {
+            TileOrder::Raster => "raster",
+            TileOrder::Spiral => "spiral",
+            TileOrder::Hilbert => "hilbert",
+        };
+        let fields = [
+            ("width", self.width.to_string()),
+            ("height", self.height.to_string()),
+            (
+                "pixel_aspect_ratio",
+                format!("{:?}", self.pixel_aspect_ratio),
+            ),
+            ("max_threads", self.max_threads.to_string()),
+            ("cpu_budget", format!("{:?}", self.cpu_budget)),
+            ("normal_mapping", self.normal_mapping.to_string()),
+            ("mipmapping", self.mipmapping.to_string()),
+            ("render_mode", string(render_mode)),
+            ("light_mode", string(light_mode)),
+            ("light_sampling", string(light_sampling)),
+            ("orient_normals", self.orient_normals.to_string()),
+            (
+                "ng_from_vertex_normals",
+                self.ng_from_vertex_normals.to_string(),
+            ),
+            ("backface_cull", self.backface_cull.to_string()),
+            (
+                "backface_cull_shadows",
+                self.backface_cull_shadows.to_string(),
+            ),
+            (
+                "approx_shadows",
+                option(self.approx_shadows.map(|depth| depth.to_string())),
+            ),
+            ("up_axis", string(up_axis)),
+            ("environment_map", path(&self.environment_map)),
+            ("environment_nee", self.environment_nee.to_string()),
+            ("fog_density", format!("{:?}", self.fog_density)),
+            ("fog_albedo", format!("{:?}", self.fog_albedo)),
+            ("fog_g", format!("{:?}", self.fog_g)),
+            ("material_overrides", path(&self.material_overrides)),
+            ("placements", path(&self.placements)),
+            (
+                "max_iterations",
+                option(self.max_iterations.map(|n| n.to_string())),
+            ),
+            ("russian_roulette", russian_roulette),
+            ("mis", self.mis.to_string()),
+            ("variance_aware_mis", self.variance_aware_mis.to_string()),
+            ("variance_rr", self.variance_rr.to_string()),
+            ("pre_rr_bounces", self.pre_rr_bounces.to_string()),
+            ("max_bounces", max_bounces),
+            ("samples_per_dir", self.samples_per_dir.to_string()),
+            ("sampler", string(sampler)),
+            ("seed", option(self.seed.map(|seed| seed.to_string()))),
+            ("energy_compensation", self.energy_compensation.to_string()),
+            (
+                "light_paths_per_pixel",
+                self.light_paths_per_pixel.to_string(),
+            ),
+            ("light_path_reuse", self.light_path_reuse.to_string()),
+            ("ppm_photons", self.ppm_photons.to_string()),
+            ("ppm_radius", format!("{:?}", self.ppm_radius)),
+            ("ppm_alpha", format!("{:?}", self.ppm_alpha)),
+            (
+                "clamp_indirect",
+                option(self.clamp_indirect.map(|luma| format!("{:?}", luma))),
+            ),
+            ("tone_map", self.tone_map.to_string()),
+            ("tone_map_operator", string(tone_map_operator)),
+            ("exposure", format!("{:?}", self.exposure)),
+            ("convergence_frames", self.convergence_frames.to_string()),
+            (
+                "convergence_interval",
+                self.convergence_interval.to_string(),
+            ),
+            ("offline_preview", self.offline_preview.to_string()),
+            ("save_hdr", self.save_hdr.to_string()),
+            ("hdr_metadata", self.hdr_metadata.to_string()),
+            ("aovs", self.aovs.to_string()),
+            ("upload_interval", self.upload_interval.to_string()),
+            ("srgb_framebuffer", self.srgb_framebuffer.to_string()),
+            ("bvh_split", string(bvh_split)),
+            ("bvh_max_leaf_size", self.bvh_max_leaf_size.to_string()),
+            ("bvh_cache", self.bvh_cache.to_string()),
+            (
+                "adaptive_threshold",
+                option(
+                    self.adaptive_threshold
+                        .map(|threshold| format!("{:?}", threshold)),
+                ),
+            ),
+            ("tile_order", string(tile_order)),
+            (
+                "crop",
+                option(self.crop.map(|rect| {
+                    string(&format!(
+                        "{} {} {} {}",
+                        rect.left, rect.bottom, rect.width, rect.height
+                    ))
+                })),
+            ),
+        ];
+        fields
+            .iter()
+            .map(|(key, value)| format!("{} = {}\n", key, value))
+            .collect()
+    }
+
     pub fn dimensions(&self) -> LogicalSize<Float> {
         LogicalSize::from((self.width, self.height))
     }
@@ -205,6 +967,10 @@ impl RenderConfig {
                     }
                 }
             }
+            VirtualKeyCode::G => {
+                self.energy_compensation = !self.energy_compensation;
+                println!("Energy compensation: {}", self.energy_compensation);
+            }
             VirtualKeyCode::F1 => {
                 println!("Config: Path trace");
                 *self = Self::path_trace();
@@ -221,7 +987,290 @@ impl RenderConfig {
                 println!("Config: Forward normals");
                 *self = Self::forward_normals();
             }
+            VirtualKeyCode::F5 => {
+                println!("Config: Debug depth");
+                *self = Self::debug_depth();
+            }
+            VirtualKeyCode::F6 => {
+                println!("Config: Debug texture coordinates");
+                *self = Self::debug_tex_coords();
+            }
+            VirtualKeyCode::F7 => {
+                println!("Config: Debug texel density");
+                *self = Self::debug_texel_density();
+            }
+            VirtualKeyCode::F8 => {
+                println!("Config: Debug material ids");
+                *self = Self::debug_material_id();
+            }
+            VirtualKeyCode::F9 => {
+                println!("Config: Debug mesh ids");
+                *self = Self::debug_mesh_id();
+            }
             _ => (),
         }
     }
 }
+
+/// Parse a plain config file value
+fn parse<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value {}", value))
+}
+
+/// Parse a quoted config file string
+fn parse_string(value: &str) -> Result<String, String> {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .map(str::to_string)
+        .ok_or_else(|| format!("Expected a quoted string instead of {}", value))
+}
+
+/// Parse a plain value or "none"
+fn parse_option<T: FromStr>(value: &str) -> Result<Option<T>, String> {
+    match parse_string(value).as_deref() {
+        Ok("none") => Ok(None),
+        _ => parse(value).map(Some),
+    }
+}
+
+/// Parse a quoted "left bottom width height" rect or "none"
+fn parse_rect(value: &str) -> Result<Option<Rect>, String> {
+    let value = parse_string(value)?;
+    if value == "none" {
+        return Ok(None);
+    }
+    let parts = value
+        .split_whitespace()
+        .map(parse)
+        .collect::<Result<Vec<u32>, _>>()?;
+    match parts[..] {
+        [left, bottom, width, height] => Ok(Some(Rect {
+            left,
+            bottom,
+            width,
+            height,
+        })),
+        _ => Err(format!(
+            "Expected left bottom width height instead of {}",
+            value
+        )),
+    }
+}
+
+/// Parse a quoted path or "none"
+fn parse_path(value: &str) -> Result<Option<PathBuf>, String> {
+    match parse_string(value)?.as_str() {
+        "none" => Ok(None),
+        path => Ok(Some(PathBuf::from(path))),
+    }
+}
+
+/// Readable summary of the settings that affect the rendered image and its cost
+impl fmt::Display for RenderConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        writeln!(f, "Resolution: {}x{}", self.width, self.height)?;
+        if let Some(crop) = self.crop {
+            writeln!(
+                f,
+                "Crop: {}x{} at ({}, {})",
+                crop.width, crop.height, crop.left, crop.bottom
+            )?;
+        }
+        writeln!(
+            f,
+            "Samples per pixel: {} ({}x{})",
+            self.samples_per_dir.pow(2),
+            self.samples_per_dir,
+            self.samples_per_dir
+        )?;
+        match self.max_iterations {
+            Some(n) => writeln!(f, "Iterations: {}", n)?,
+            None => writeln!(f, "Iterations: unlimited")?,
+        }
+        writeln!(f, "Render mode: {:?}", self.render_mode)?;
+        writeln!(f, "Light mode: {:?}", self.light_mode)?;
+        writeln!(f, "Light sampling: {:?}", self.light_sampling)?;
+        if self.max_bounces == usize::MAX {
+            writeln!(f, "Max bounces: unlimited")?;
+        } else {
+            writeln!(f, "Max bounces: {}", self.max_bounces)?;
+        }
+        match self.russian_roulette {
+            RussianRoulette::Off => writeln!(f, "Russian roulette: off")?,
+            RussianRoulette::Dynamic => writeln!(
+                f,
+                "Russian roulette: dynamic after {} bounces",
+                self.pre_rr_bounces
+            )?,
+            RussianRoulette::Static(prob) => writeln!(
+                f,
+                "Russian roulette: static {} after {} bounces",
+                prob, self.pre_rr_bounces
+            )?,
+        }
+        if self.fog_density > 0.0 {
+            writeln!(
+                f,
+                "Fog: density {} albedo {} g {}",
+                self.fog_density, self.fog_albedo, self.fog_g
+            )?;
+        }
+        if let Some(max_luma) = self.clamp_indirect {
+            writeln!(f, "Indirect clamped to luminance: {}", max_luma)?;
+        }
+        if let Some(threshold) = self.adaptive_threshold {
+            writeln!(f, "Adaptive sampling threshold: {}", threshold)?;
+        }
+        writeln!(f, "MIS: {}", on_off(self.mis))?;
+        if self.render_mode == RenderMode::Bdpt {
+            if self.light_path_reuse > 0 {
+                writeln!(
+                    f,
+                    "Cached light vertices per vertex: {}",
+                    self.light_path_reuse
+                )?;
+            } else {
+                writeln!(f, "Light paths per pixel: {}", self.light_paths_per_pixel)?;
+            }
+        }
+        if self.render_mode == RenderMode::Ppm {
+            writeln!(
+                f,
+                "Photons per block: {}, radius {}, alpha {}",
+                self.ppm_photons, self.ppm_radius, self.ppm_alpha
+            )?;
+        }
+        writeln!(
+            f,
+            "Bvh: {:?} split, max leaf size {}",
+            self.bvh_split, self.bvh_max_leaf_size
+        )?;
+        if let Some(seed) = self.seed {
+            writeln!(f, "Seed: {}", seed)?;
+        }
+        writeln!(f, "Threads: {}", self.max_threads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_are_valid() {
+        for name in &["path_trace", "bdpt", "ppm", "benchmark", "debug_normals"] {
+            let config = RenderConfig::preset(name).unwrap();
+            assert_eq!(config.validate(), Ok(()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn validate_zero_samples() {
+        let config = RenderConfig {
+            samples_per_dir: 0,
+            ..RenderConfig::path_trace()
+        };
+        assert_eq!(
+            config.validate(),
+            Err("samples_per_dir needs to be at least 1".to_string())
+        );
+    }
+
+    #[test]
+    fn validate_bdpt_without_bounces() {
+        let config = RenderConfig {
+            max_bounces: 0,
+            ..RenderConfig::bdpt()
+        };
+        assert_eq!(
+            config.validate(),
+            Err("Bdpt needs max_bounces of at least 1".to_string())
+        );
+        // Path tracing still renders the emitters
+        let config = RenderConfig {
+            max_bounces: 0,
+            ..RenderConfig::path_trace()
+        };
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_bdpt_without_light_paths() {
+        let config = RenderConfig {
+            light_paths_per_pixel: 0,
+            ..RenderConfig::bdpt()
+        };
+        assert_eq!(
+            config.validate(),
+            Err("Bdpt needs light_paths_per_pixel of at least 1".to_string())
+        );
+    }
+
+    #[test]
+    fn validate_empty_image() {
+        let config = RenderConfig {
+            height: 0,
+            ..RenderConfig::path_trace()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validate_adaptive_single_sample() {
+        let config = RenderConfig {
+            samples_per_dir: 1,
+            adaptive_threshold: Some(0.01),
+            ..RenderConfig::path_trace()
+        };
+        assert_eq!(
+            config.validate(),
+            Err("Adaptive sampling needs samples_per_dir of at least 2".to_string())
+        );
+    }
+
+    #[test]
+    fn validate_empty_bvh_leaves() {
+        let config = RenderConfig {
+            bvh_max_leaf_size: 0,
+            ..RenderConfig::path_trace()
+        };
+        assert_eq!(
+            config.validate(),
+            Err("bvh_max_leaf_size needs to be at least 1".to_string())
+        );
+    }
+
+    #[test]
+    fn from_file_rejects_invalid_config() {
+        let path = std::env::temp_dir().join("rusty_test_invalid_leaf_size.cfg");
+        std::fs::write(&path, "bvh_max_leaf_size = 0\n").unwrap();
+        let res = RenderConfig::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        let err = res.unwrap_err();
+        assert_eq!(err.to_string(), "bvh_max_leaf_size needs to be at least 1");
+    }
+
+    #[test]
+    fn bdpt_round_trip() {
+        let path = std::env::temp_dir().join("rusty_test_bdpt_round_trip.cfg");
+        std::fs::write(&path, RenderConfig::bdpt().to_file_string()).unwrap();
+        let res = RenderConfig::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(res.unwrap(), RenderConfig::bdpt());
+    }
+
+    #[test]
+    fn from_file_skips_comments() {
+        let path = std::env::temp_dir().join("rusty_test_comments.cfg");
+        std::fs::write(&path, "# Small bdpt\npreset = \"bdpt\"\n\nwidth = 64\n").unwrap();
+        let res = RenderConfig::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        let config = res.unwrap();
+        assert_eq!(config.width, 64);
+        assert_eq!(config.render_mode, RenderMode::Bdpt);
+    }
+}
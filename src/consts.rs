@@ -12,6 +12,8 @@ mod double {
     pub const EPSILON: Float = 1e-10;
     #[allow(dead_code)]
     pub const MACHINE_EPSILON: Float = f64::EPSILON / 2.0;
+    /// Largest value below 1
+    pub const ONE_MINUS_EPSILON: Float = 1.0 - f64::EPSILON / 2.0;
     pub const INFINITY: Float = f64::INFINITY;
     pub const MAX: Float = f64::MAX;
     pub const MIN: Float = f64::MIN;
@@ -25,6 +27,8 @@ mod single {
     pub const EPSILON: Float = 1e-5;
     #[allow(dead_code)]
     pub const MACHINE_EPSILON: Float = f32::EPSILON / 2.0;
+    /// Largest value below 1
+    pub const ONE_MINUS_EPSILON: Float = 1.0 - f32::EPSILON / 2.0;
     pub const INFINITY: Float = f32::INFINITY;
     pub const MAX: Float = f32::MAX;
     pub const MIN: Float = f32::MIN;
@@ -1,10 +1,17 @@
 use glium::backend::Facade;
-use glium::{uniform, DrawParameters, Surface};
+use glium::{uniform, Display, DrawParameters, Surface};
 
 use crate::camera::Camera;
 use crate::float::IntoArray;
 use crate::scene::GpuScene;
 
+/// Check if the shaders need to apply the sRGB encoding themselves.
+/// This is the case when the default framebuffer of the display is linear,
+/// since then OpenGL won't do the conversion automatically.
+pub fn needs_srgb_encoding(display: &Display) -> bool {
+    !display.gl_window().get_pixel_format().srgb
+}
+
 pub struct GlRenderer {
     shader: glium::Program,
 }
@@ -19,7 +26,13 @@ impl GlRenderer {
         GlRenderer { shader }
     }
 
-    pub fn render<S: Surface>(&self, target: &mut S, scene: &GpuScene, camera: &Camera) {
+    pub fn render<S: Surface>(
+        &self,
+        target: &mut S,
+        scene: &GpuScene,
+        camera: &Camera,
+        encode_srgb: bool,
+    ) {
         let draw_parameters = DrawParameters {
             depth: glium::Depth {
                 test: glium::draw_parameters::DepthTest::IfLess,
@@ -35,6 +48,7 @@ impl GlRenderer {
                 world_to_clip: camera.world_to_clip().into_array(),
                 u_light: [-1.0, 0.4, 0.9f32],
                 u_is_emissive: material.is_emissive,
+                u_encode_srgb: encode_srgb,
                 tex: &material.texture
             };
             target
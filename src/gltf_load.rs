@@ -0,0 +1,897 @@
+/// Module for loading glTF 2.0 scenes (.gltf and .glb) into the object representation
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use cgmath::prelude::*;
+use cgmath::{Matrix3, Matrix4, Point3, Quaternion, Vector3};
+
+use crate::obj_load::{self, IndexVertex, Material, Object, TextureMap, TextureOptions, Triangle};
+use crate::stats;
+
+mod json;
+
+use self::json::Json;
+
+const GLB_MAGIC: &[u8] = b"glTF";
+const GLB_CHUNK_JSON: u32 = 0x4E4F_534A;
+const GLB_CHUNK_BIN: u32 = 0x004E_4942;
+
+/// Extensions that are understood well enough to load files that require them
+const SUPPORTED_EXTENSIONS: &[&str] = &["KHR_materials_emissive_strength"];
+
+/// Parsed glTF document with the contents of its buffers
+struct Document {
+    json: Json,
+    buffers: Vec<Vec<u8>>,
+    /// Directory that relative uris are resolved against
+    dir: PathBuf,
+}
+
+impl Document {
+    /// Get element i of a top level array such as "nodes" or "accessors"
+    fn element(&self, array: &str, i: usize) -> Result<&Json, String> {
+        list(&self.json, array)
+            .get(i)
+            .ok_or_else(|| format!("{} {} doesn't exist", array, i))
+    }
+}
+
+/// Array member of an object. Missing members are treated as empty arrays.
+fn list<'a>(json: &'a Json, key: &str) -> &'a [Json] {
+    json.get(key).and_then(Json::as_array).unwrap_or(&[])
+}
+
+fn number(json: &Json, key: &str) -> Option<f64> {
+    json.get(key).and_then(Json::as_f64)
+}
+
+fn index(json: &Json, key: &str) -> Option<usize> {
+    json.get(key).and_then(Json::as_usize)
+}
+
+/// Array member of exactly n numbers
+fn numbers(json: &Json, key: &str, n: usize) -> Option<Vec<f64>> {
+    let values: Option<Vec<f64>> = list(json, key).iter().map(Json::as_f64).collect();
+    values.filter(|values| values.len() == n)
+}
+
+/// Load a glTF scene found at the given path.
+/// Each node with a mesh becomes a group named after the node.
+pub fn load_gltf(path: &Path) -> Result<Object, Box<dyn Error>> {
+    let _t = stats::time("Load gltf");
+    let dir = path.parent().ok_or("Couldn't get scene directory")?;
+    let data = std::fs::read(path)?;
+    let (text, bin) = if data.starts_with(GLB_MAGIC) {
+        parse_glb(&data)?
+    } else {
+        (std::str::from_utf8(&data)?, None)
+    };
+    let json = Json::parse(text)?;
+    let version = json
+        .get("asset")
+        .and_then(|asset| asset.get("version"))
+        .and_then(Json::as_str)
+        .ok_or("No asset version")?;
+    if !version.starts_with("2.") {
+        return Err(format!("Unsupported glTF version {}", version).into());
+    }
+    for extension in list(&json, "extensionsRequired") {
+        let name = extension.as_str().unwrap_or_default();
+        if !SUPPORTED_EXTENSIONS.contains(&name) {
+            return Err(format!("Required extension {} is not supported", name).into());
+        }
+    }
+    let buffers = load_buffers(&json, dir, bin)?;
+    let doc = Document {
+        json,
+        buffers,
+        dir: dir.to_path_buf(),
+    };
+
+    let mut obj = Object::default();
+    let material_names = load_materials(&doc, &mut obj);
+    let roots: Vec<usize> = match index(&doc.json, "scene")
+        .or(Some(0))
+        .and_then(|i| list(&doc.json, "scenes").get(i))
+    {
+        Some(scene) => list(scene, "nodes")
+            .iter()
+            .filter_map(Json::as_usize)
+            .collect(),
+        // Without scenes every node that isn't a child is a root
+        None => {
+            let children: HashSet<usize> = list(&doc.json, "nodes")
+                .iter()
+                .flat_map(|node| list(node, "children"))
+                .filter_map(Json::as_usize)
+                .collect();
+            (0..list(&doc.json, "nodes").len())
+                .filter(|i| !children.contains(i))
+                .collect()
+        }
+    };
+    let mut state = TraversalState {
+        obj: &mut obj,
+        material_names: &material_names,
+        visited: HashSet::new(),
+        group_breaks: Vec::new(),
+    };
+    for node_i in roots {
+        add_node(&doc, node_i, &Matrix4::identity(), &mut state)?;
+    }
+    let group_breaks = state.group_breaks;
+    // Primitives without a material use the default material
+    if obj.triangles.iter().any(|tri| tri.material.is_none()) {
+        obj.materials.insert(
+            obj_load::DEFAULT_MATERIAL.to_string(),
+            Material::default_material(),
+        );
+    }
+    obj.update_ranges(&group_breaks);
+    // Vertices without a color are left white
+    if !obj.colors.is_empty() {
+        obj.colors.resize(obj.positions.len(), [1.0; 3]);
+    }
+    Ok(obj)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| "Truncated binary glTF".to_string())
+}
+
+/// JSON text and optional binary chunk of a binary glTF
+type GlbChunks<'a> = (&'a str, Option<&'a [u8]>);
+
+/// Split a binary glTF into its chunks
+fn parse_glb(data: &[u8]) -> Result<GlbChunks<'_>, Box<dyn Error>> {
+    let version = read_u32(data, 4)?;
+    if version != 2 {
+        return Err(format!("Unsupported binary glTF version {}", version).into());
+    }
+    let length = (read_u32(data, 8)? as usize).min(data.len());
+    let mut text = None;
+    let mut bin = None;
+    let mut offset = 12;
+    while offset + 8 <= length {
+        let chunk_length = read_u32(data, offset)? as usize;
+        let chunk_type = read_u32(data, offset + 4)?;
+        let chunk = data
+            .get(offset + 8..offset + 8 + chunk_length)
+            .ok_or("Truncated binary glTF chunk")?;
+        match chunk_type {
+            GLB_CHUNK_JSON if text.is_none() => text = Some(std::str::from_utf8(chunk)?),
+            GLB_CHUNK_BIN if bin.is_none() => bin = Some(chunk),
+            // Unknown chunks must be ignored
+            _ => {}
+        }
+        offset += 8 + chunk_length;
+    }
+    Ok((text.ok_or("Binary glTF has no JSON chunk")?, bin))
+}
+
+/// Read the contents of all buffers. In binary files the buffer without an uri
+/// refers to the binary chunk.
+fn load_buffers(
+    json: &Json,
+    dir: &Path,
+    bin: Option<&[u8]>,
+) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let mut buffers = Vec::new();
+    for (i, buffer) in list(json, "buffers").iter().enumerate() {
+        let byte_length = index(buffer, "byteLength").ok_or("Buffer has no byte length")?;
+        let data = match buffer.get("uri").and_then(Json::as_str) {
+            Some(uri) if uri.starts_with("data:") => decode_data_uri(uri)?,
+            Some(uri) => {
+                let path = dir.join(percent_decode(uri));
+                std::fs::read(&path)
+                    .map_err(|err| format!("Couldn't read buffer {:?}: {}", path, err))?
+            }
+            None => bin
+                .ok_or_else(|| format!("Buffer {} has no data", i))?
+                .to_vec(),
+        };
+        if data.len() < byte_length {
+            return Err(format!(
+                "Buffer {} has {} bytes instead of {}",
+                i,
+                data.len(),
+                byte_length
+            )
+            .into());
+        }
+        buffers.push(data);
+    }
+    Ok(buffers)
+}
+
+fn decode_data_uri(uri: &str) -> Result<Vec<u8>, String> {
+    const MARKER: &str = ";base64,";
+    match uri.find(MARKER) {
+        Some(i) => decode_base64(&uri[i + MARKER.len()..]),
+        None => Err("Only base64 data uris are supported".to_string()),
+    }
+}
+
+/// Decode standard or url safe base64. Padding is optional.
+fn decode_base64(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut n_bits = 0;
+    for c in text.bytes().take_while(|&c| c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return Err(format!("Invalid base64 character {:?}", c as char)),
+        };
+        bits = (bits << 6) | u32::from(value);
+        n_bits += 6;
+        if n_bits >= 8 {
+            n_bits -= 8;
+            bytes.push((bits >> n_bits) as u8);
+            bits &= (1 << n_bits) - 1;
+        }
+    }
+    Ok(bytes)
+}
+
+/// Decode the %XX escapes of a relative uri
+fn percent_decode(uri: &str) -> PathBuf {
+    let bytes = uri.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Size in bytes of an accessor component type
+fn component_size(component_type: usize) -> Option<usize> {
+    match component_type {
+        5120 | 5121 => Some(1),
+        5122 | 5123 => Some(2),
+        5125 | 5126 => Some(4),
+        _ => None,
+    }
+}
+
+/// Read a little endian component. Normalized integers are mapped to [0, 1] or [-1, 1].
+fn read_component(data: &[u8], component_type: usize, normalized: bool) -> f64 {
+    let (value, max) = match component_type {
+        5120 => (f64::from(data[0] as i8), 127.0),
+        5121 => (f64::from(data[0]), 255.0),
+        5122 => (f64::from(i16::from_le_bytes([data[0], data[1]])), 32767.0),
+        5123 => (f64::from(u16::from_le_bytes([data[0], data[1]])), 65535.0),
+        5125 => (
+            f64::from(u32::from_le_bytes(data[..4].try_into().unwrap())),
+            1.0,
+        ),
+        _ => (
+            f64::from(f32::from_le_bytes(data[..4].try_into().unwrap())),
+            1.0,
+        ),
+    };
+    if normalized {
+        (value / max).max(-1.0)
+    } else {
+        value
+    }
+}
+
+/// Read the elements of an accessor as floats.
+/// Returns the values of all elements and the number of components per element.
+fn read_accessor(doc: &Document, accessor_i: usize) -> Result<(Vec<f64>, usize), Box<dyn Error>> {
+    let accessor = doc.element("accessors", accessor_i)?;
+    if accessor.get("sparse").is_some() {
+        return Err(format!("Accessor {} is sparse, which is not supported", accessor_i).into());
+    }
+    let count = index(accessor, "count").ok_or("Accessor has no count")?;
+    let n_components = match accessor.get("type").and_then(Json::as_str) {
+        Some("SCALAR") => 1,
+        Some("VEC2") => 2,
+        Some("VEC3") => 3,
+        Some("VEC4") | Some("MAT2") => 4,
+        Some("MAT3") => 9,
+        Some("MAT4") => 16,
+        other => return Err(format!("Unknown accessor type {:?}", other).into()),
+    };
+    let component_type = index(accessor, "componentType").unwrap_or_default();
+    let size = component_size(component_type)
+        .ok_or_else(|| format!("Unknown component type {}", component_type))?;
+    let normalized = accessor
+        .get("normalized")
+        .and_then(Json::as_bool)
+        .unwrap_or(false);
+    let mut values = vec![0.0; count * n_components];
+    // Accessors without a buffer view are all zeros
+    let view_i = match index(accessor, "bufferView") {
+        Some(view_i) => view_i,
+        None => return Ok((values, n_components)),
+    };
+    let view = doc.element("bufferViews", view_i)?;
+    let buffer_i = index(view, "buffer").ok_or("Buffer view has no buffer")?;
+    let buffer = doc
+        .buffers
+        .get(buffer_i)
+        .ok_or_else(|| format!("buffers {} doesn't exist", buffer_i))?;
+    let view_offset = index(view, "byteOffset").unwrap_or(0);
+    let view_length = index(view, "byteLength").ok_or("Buffer view has no byte length")?;
+    let view_data = buffer
+        .get(view_offset..view_offset + view_length)
+        .ok_or_else(|| format!("Buffer view {} is out of bounds", view_i))?;
+    let element_size = size * n_components;
+    let stride = index(view, "byteStride").unwrap_or(element_size);
+    let offset = index(accessor, "byteOffset").unwrap_or(0);
+    if count > 0 && offset + stride * (count - 1) + element_size > view_data.len() {
+        return Err(format!("Accessor {} is out of bounds", accessor_i).into());
+    }
+    for (i, element) in values.chunks_mut(n_components).enumerate() {
+        for (c, value) in element.iter_mut().enumerate() {
+            let start = offset + i * stride + c * size;
+            *value = read_component(&view_data[start..], component_type, normalized);
+        }
+    }
+    Ok((values, n_components))
+}
+
+/// Read an accessor that should have the given number of components
+fn read_attribute(doc: &Document, accessor_i: usize, n: usize) -> Result<Vec<f64>, Box<dyn Error>> {
+    let (values, n_components) = read_accessor(doc, accessor_i)?;
+    if n_components != n {
+        return Err(format!(
+            "Accessor {} has {} components instead of {}",
+            accessor_i, n_components, n
+        )
+        .into());
+    }
+    Ok(values)
+}
+
+/// Convert the materials and return their names in the order of the material array.
+/// Names are made unique since the object identifies materials by name.
+fn load_materials(doc: &Document, obj: &mut Object) -> Vec<String> {
+    let mut names = Vec::new();
+    for (i, gltf_mat) in list(&doc.json, "materials").iter().enumerate() {
+        let mut name = gltf_mat
+            .get("name")
+            .and_then(Json::as_str)
+            .map_or_else(|| format!("material{}", i), str::to_string);
+        if name == obj_load::DEFAULT_MATERIAL || obj.materials.contains_key(&name) {
+            name = format!("{}_{}", name, i);
+        }
+        let material = convert_material(doc, gltf_mat, &name, &mut obj.warnings);
+        obj.materials.insert(name.clone(), material);
+        names.push(name);
+    }
+    names
+}
+
+/// Approximate a metallic-roughness material with the materials of the mtl format
+fn convert_material(
+    doc: &Document,
+    gltf_mat: &Json,
+    name: &str,
+    warnings: &mut Vec<String>,
+) -> Material {
+    let mut material = Material::new(name);
+    let null = Json::Null;
+    let pbr = gltf_mat.get("pbrMetallicRoughness").unwrap_or(&null);
+    let base_color = numbers(pbr, "baseColorFactor", 4).unwrap_or_else(|| vec![1.0; 4]);
+    let base_color = [
+        base_color[0] as f32,
+        base_color[1] as f32,
+        base_color[2] as f32,
+    ];
+    let metallic = number(pbr, "metallicFactor").unwrap_or(1.0);
+    let roughness = number(pbr, "roughnessFactor").unwrap_or(1.0) as f32;
+    // Perceptual roughness is the square root of the microfacet alpha
+    let exponent = obj_load::alpha_to_exponent(roughness * roughness);
+    let base_texture = pbr
+        .get("baseColorTexture")
+        .and_then(|info| texture_map(doc, info, name, warnings));
+    if metallic >= 0.5 {
+        // Conductor with eta = 1 has the base color as its normal incidence reflectance
+        material.index_of_refraction = Some(1.0);
+        material.extinction_coefficient = Some(base_color.map(|r| {
+            let r = r.clamp(0.0, 0.999);
+            2.0 * (r / (1.0 - r)).sqrt()
+        }));
+        material.specular_exponent = if roughness > 0.0 {
+            Some(exponent)
+        } else {
+            None
+        };
+        if base_texture.is_some() {
+            warnings.push(format!(
+                "Material {}: Ignored the base color texture of a metal",
+                name
+            ));
+        }
+    } else {
+        material.diffuse_color = Some(base_color);
+        material.diffuse_texture = base_texture;
+        // Fully rough dielectrics are left diffuse
+        if roughness < 1.0 {
+            material.illumination_model = Some(2);
+            material.specular_color = Some([0.04; 3]);
+            material.specular_exponent = Some(exponent);
+        }
+    }
+    let strength = gltf_mat
+        .get("extensions")
+        .and_then(|extensions| extensions.get("KHR_materials_emissive_strength"))
+        .and_then(|extension| number(extension, "emissiveStrength"))
+        .unwrap_or(1.0);
+    let emissive = numbers(gltf_mat, "emissiveFactor", 3).unwrap_or_else(|| vec![0.0; 3]);
+    if emissive.iter().any(|&e| e > 0.0) {
+        material.emissive_color = Some([
+            (emissive[0] * strength) as f32,
+            (emissive[1] * strength) as f32,
+            (emissive[2] * strength) as f32,
+        ]);
+        material.emissive_texture = gltf_mat
+            .get("emissiveTexture")
+            .and_then(|info| texture_map(doc, info, name, warnings));
+    }
+    material.two_sided = gltf_mat
+        .get("doubleSided")
+        .and_then(Json::as_bool)
+        .unwrap_or(false);
+    for key in &["normalTexture", "occlusionTexture"] {
+        if gltf_mat.get(key).is_some() {
+            warnings.push(format!("Material {}: Ignored {}", name, key));
+        }
+    }
+    if pbr.get("metallicRoughnessTexture").is_some() {
+        warnings.push(format!(
+            "Material {}: Ignored metallicRoughnessTexture",
+            name
+        ));
+    }
+    if let Some(mode) = gltf_mat.get("alphaMode").and_then(Json::as_str) {
+        if mode != "OPAQUE" {
+            warnings.push(format!(
+                "Material {}: Alpha mode {} is rendered opaque",
+                name, mode
+            ));
+        }
+    }
+    material
+}
+
+/// Get the image file of a texture info. Only images with an external uri are supported.
+fn texture_map(
+    doc: &Document,
+    info: &Json,
+    material: &str,
+    warnings: &mut Vec<String>,
+) -> Option<TextureMap> {
+    if index(info, "texCoord").unwrap_or(0) != 0 {
+        warnings.push(format!(
+            "Material {}: Texture uses a second set of texture coordinates",
+            material
+        ));
+    }
+    let texture = doc.element("textures", index(info, "index")?).ok()?;
+    let image = doc.element("images", index(texture, "source")?).ok()?;
+    match image.get("uri").and_then(Json::as_str) {
+        Some(uri) if !uri.starts_with("data:") => Some(TextureMap {
+            path: doc.dir.join(percent_decode(uri)),
+            options: TextureOptions::default(),
+        }),
+        _ => {
+            warnings.push(format!(
+                "Material {}: Skipped a texture embedded in the file",
+                material
+            ));
+            None
+        }
+    }
+}
+
+/// State that is threaded through the node hierarchy
+struct TraversalState<'a> {
+    obj: &'a mut Object,
+    material_names: &'a [String],
+    visited: HashSet<usize>,
+    group_breaks: Vec<usize>,
+}
+
+/// Local transform of a node from its matrix or its translation, rotation and scale
+fn local_transform(node: &Json) -> Matrix4<f64> {
+    if let Some(m) = numbers(node, "matrix", 16) {
+        // The matrix is column major like cgmath
+        return Matrix4::new(
+            m[0], m[1], m[2], m[3], m[4], m[5], m[6], m[7], m[8], m[9], m[10], m[11], m[12], m[13],
+            m[14], m[15],
+        );
+    }
+    let t = numbers(node, "translation", 3).unwrap_or_else(|| vec![0.0; 3]);
+    let r = numbers(node, "rotation", 4).unwrap_or_else(|| vec![0.0, 0.0, 0.0, 1.0]);
+    let s = numbers(node, "scale", 3).unwrap_or_else(|| vec![1.0; 3]);
+    // glTF stores the quaternion as x, y, z, w
+    let rotation = Quaternion::new(r[3], r[0], r[1], r[2]);
+    Matrix4::from_translation(Vector3::new(t[0], t[1], t[2]))
+        * Matrix4::from(rotation)
+        * Matrix4::from_nonuniform_scale(s[0], s[1], s[2])
+}
+
+/// Add the mesh of the node and its children with their transforms applied
+fn add_node(
+    doc: &Document,
+    node_i: usize,
+    parent: &Matrix4<f64>,
+    state: &mut TraversalState,
+) -> Result<(), Box<dyn Error>> {
+    if !state.visited.insert(node_i) {
+        return Err(format!("Node {} appears more than once in the hierarchy", node_i).into());
+    }
+    let node = doc.element("nodes", node_i)?;
+    let transform = parent * local_transform(node);
+    if let Some(mesh_i) = index(node, "mesh") {
+        let mesh = doc.element("meshes", mesh_i)?;
+        let group = node
+            .get("name")
+            .or_else(|| mesh.get("name"))
+            .and_then(Json::as_str)
+            .map_or_else(|| format!("node{}", node_i), str::to_string);
+        // Nodes are separate groups even if they share a name
+        state.group_breaks.push(state.obj.triangles.len());
+        for primitive in list(mesh, "primitives") {
+            add_primitive(doc, primitive, &transform, &group, state)?;
+        }
+    }
+    for child_i in list(node, "children").iter().filter_map(Json::as_usize) {
+        add_node(doc, child_i, &transform, state)?;
+    }
+    Ok(())
+}
+
+fn add_primitive(
+    doc: &Document,
+    primitive: &Json,
+    transform: &Matrix4<f64>,
+    group: &str,
+    state: &mut TraversalState,
+) -> Result<(), Box<dyn Error>> {
+    let mode = index(primitive, "mode").unwrap_or(4);
+    if mode != 4 {
+        state.obj.warnings.push(format!(
+            "Group {}: Skipped a primitive with mode {} that isn't triangles",
+            group, mode
+        ));
+        return Ok(());
+    }
+    let null = Json::Null;
+    let attributes = primitive.get("attributes").unwrap_or(&null);
+    let position_i = index(attributes, "POSITION").ok_or("Primitive has no positions")?;
+    let positions = read_attribute(doc, position_i, 3)?;
+    let n_vertices = positions.len() / 3;
+    let normals = match index(attributes, "NORMAL") {
+        Some(i) => Some(read_attribute(doc, i, 3)?),
+        None => None,
+    };
+    let tex_coords = match index(attributes, "TEXCOORD_0") {
+        Some(i) => Some(read_attribute(doc, i, 2)?),
+        None => None,
+    };
+    let colors = match index(attributes, "COLOR_0") {
+        Some(i) => Some(read_accessor(doc, i)?),
+        None => None,
+    };
+    let lengths = [
+        normals.as_ref().map(|normals| normals.len() / 3),
+        tex_coords.as_ref().map(|tex_coords| tex_coords.len() / 2),
+        colors.as_ref().map(|(colors, n)| colors.len() / n),
+    ];
+    if lengths.iter().flatten().any(|&len| len != n_vertices) {
+        return Err("Primitive attributes have different lengths".into());
+    }
+    let indices: Vec<usize> = match index(primitive, "indices") {
+        Some(i) => read_attribute(doc, i, 1)?
+            .into_iter()
+            .map(|i| i as usize)
+            .collect(),
+        None => (0..n_vertices).collect(),
+    };
+    if let Some(&i) = indices.iter().find(|&&i| i >= n_vertices) {
+        return Err(format!(
+            "Primitive refers to vertex {} outside of the {} defined ones",
+            i, n_vertices
+        )
+        .into());
+    }
+    let material = index(primitive, "material")
+        .map(|i| {
+            state
+                .material_names
+                .get(i)
+                .cloned()
+                .ok_or_else(|| format!("materials {} doesn't exist", i))
+        })
+        .transpose()?;
+
+    let obj = &mut *state.obj;
+    let pos_base = obj.positions.len();
+    let normal_base = obj.normals.len();
+    let tex_base = obj.tex_coords.len();
+    for p in positions.chunks(3) {
+        let p = transform.transform_point(Point3::new(p[0], p[1], p[2]));
+        obj.positions.push([p.x as f32, p.y as f32, p.z as f32]);
+    }
+    if let Some(normals) = &normals {
+        let linear = Matrix3::from_cols(
+            transform.x.truncate(),
+            transform.y.truncate(),
+            transform.z.truncate(),
+        );
+        let normal_transform = linear
+            .invert()
+            .unwrap_or_else(Matrix3::identity)
+            .transpose();
+        for n in normals.chunks(3) {
+            let n = (normal_transform * Vector3::new(n[0], n[1], n[2])).normalize();
+            obj.normals.push([n.x as f32, n.y as f32, n.z as f32]);
+        }
+    }
+    if let Some(tex_coords) = &tex_coords {
+        // glTF has the origin of the texture coordinates at the top left
+        for uv in tex_coords.chunks(2) {
+            obj.tex_coords.push([uv[0] as f32, 1.0 - uv[1] as f32]);
+        }
+    }
+    if let Some((colors, n)) = &colors {
+        obj.colors.resize(pos_base, [1.0; 3]);
+        for color in colors.chunks(*n) {
+            obj.colors
+                .push([color[0] as f32, color[1] as f32, color[2] as f32]);
+        }
+    }
+
+    // Mirroring transforms flip the winding
+    let mirrored = transform.determinant() < 0.0;
+    for tri in indices.chunks_exact(3) {
+        let mut vertices = [tri[0], tri[1], tri[2]];
+        if mirrored {
+            vertices.swap(1, 2);
+        }
+        let index_vertices = vertices.map(|i| IndexVertex {
+            pos_i: pos_base + i,
+            tex_i: tex_coords.as_ref().map(|_| tex_base + i),
+            normal_i: normals.as_ref().map(|_| normal_base + i),
+        });
+        obj.triangles.push(Triangle {
+            index_vertices,
+            group: Some(group.to_string()),
+            smoothing_group: None,
+            material: material.clone(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::load;
+
+    fn cornell_path(extension: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("scenes")
+            .join("cornell-box")
+            .join(format!("CornellBox-Original.{}", extension))
+    }
+
+    /// Single triangle with normals and texture coordinates in a binary buffer
+    fn triangle_buffer() -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for v in &[0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0] {
+            buffer.extend_from_slice(&v.to_le_bytes());
+        }
+        for v in &[0.0f32, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0] {
+            buffer.extend_from_slice(&v.to_le_bytes());
+        }
+        for v in &[0.0f32, 0.0, 1.0, 0.0, 0.0, 0.25] {
+            buffer.extend_from_slice(&v.to_le_bytes());
+        }
+        buffer
+    }
+
+    const TRIANGLE_JSON: &str = r#"{
+        "asset": {"version": "2.0"},
+        "scene": 0,
+        "scenes": [{"nodes": [0]}],
+        "nodes": [{"name": "parent", "translation": [0, 0, 1], "children": [1]},
+                  {"mesh": 0, "scale": [-2, 1, 1]}],
+        "meshes": [{"name": "tri", "primitives": [{"attributes":
+            {"POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2}}]}],
+        "accessors": [
+            {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3"},
+            {"bufferView": 0, "byteOffset": 36, "componentType": 5126, "count": 3, "type": "VEC3"},
+            {"bufferView": 0, "byteOffset": 72, "componentType": 5126, "count": 3, "type": "VEC2"}],
+        "bufferViews": [{"buffer": 0, "byteLength": 96}],
+        "buffers": [{"byteLength": 96}]
+    }"#;
+
+    fn glb(json: &str, bin: &[u8]) -> Vec<u8> {
+        let mut json = json.as_bytes().to_vec();
+        json.resize(json.len().div_ceil(4) * 4, b' ');
+        let length = 12 + 8 + json.len() + 8 + bin.len();
+        let mut data = GLB_MAGIC.to_vec();
+        for value in &[2, length as u32, json.len() as u32, GLB_CHUNK_JSON] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&json);
+        data.extend_from_slice(&(bin.len() as u32).to_le_bytes());
+        data.extend_from_slice(&GLB_CHUNK_BIN.to_le_bytes());
+        data.extend_from_slice(bin);
+        data
+    }
+
+    fn load_temp(name: &str, contents: &[u8]) -> Result<Object, Box<dyn Error>> {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, contents).unwrap();
+        let res = load_gltf(&path);
+        std::fs::remove_file(&path).unwrap();
+        res
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert_eq!(decode_base64("TWFu").unwrap(), b"Man");
+        assert_eq!(decode_base64("TWE=").unwrap(), b"Ma");
+        assert_eq!(decode_base64("TQ==").unwrap(), b"M");
+        assert_eq!(decode_base64("TQ").unwrap(), b"M");
+        assert_eq!(
+            decode_base64("-_8=").unwrap(),
+            decode_base64("+/8=").unwrap()
+        );
+        assert!(decode_base64("TW!u").is_err());
+        assert_eq!(
+            percent_decode("my%20tex%2Fture.png"),
+            PathBuf::from("my tex/ture.png")
+        );
+    }
+
+    #[test]
+    fn binary_triangle() {
+        let _stats = stats::test_scene("gltf_binary_triangle");
+        let obj = load_temp(
+            "rusty_test_triangle.glb",
+            &glb(TRIANGLE_JSON, &triangle_buffer()),
+        )
+        .unwrap();
+        assert_eq!(
+            obj.positions,
+            vec![[0.0, 0.0, 1.0], [-2.0, 0.0, 1.0], [0.0, 1.0, 1.0]]
+        );
+        // The mirroring scale flips the winding but not the normals
+        let tri = &obj.triangles[0];
+        assert_eq!(tri.index_vertices.map(|v| v.pos_i), [0, 2, 1]);
+        assert_eq!(
+            tri.index_vertices.map(|v| v.normal_i),
+            [Some(0), Some(2), Some(1)]
+        );
+        assert_eq!(obj.normals[0], [0.0, 0.0, 1.0]);
+        assert_eq!(obj.tex_coords, vec![[0.0, 1.0], [1.0, 1.0], [0.0, 0.75]]);
+        // Group is named after the mesh since the node has no name
+        assert_eq!(obj.group_ranges.len(), 1);
+        assert_eq!(obj.group_ranges[0].name, "tri");
+        assert_eq!(obj.material_ranges[0].name, obj_load::DEFAULT_MATERIAL);
+        assert!(obj.materials.contains_key(obj_load::DEFAULT_MATERIAL));
+    }
+
+    #[test]
+    fn invalid_files() {
+        let _stats = stats::test_scene("gltf_invalid_files");
+        let buffer = triangle_buffer();
+        let cases = [
+            ("not json", TRIANGLE_JSON.replace("\"scene\"", "scene")),
+            ("version", TRIANGLE_JSON.replace("2.0", "1.0")),
+            (
+                "cycle",
+                TRIANGLE_JSON.replace("\"mesh\": 0,", "\"mesh\": 0, \"children\": [0],"),
+            ),
+            (
+                "bounds",
+                TRIANGLE_JSON.replace(
+                    "\"count\": 3, \"type\": \"VEC2\"",
+                    "\"count\": 4, \"type\": \"VEC2\"",
+                ),
+            ),
+            (
+                "extension",
+                TRIANGLE_JSON.replace(
+                    "\"scene\"",
+                    "\"extensionsRequired\": [\"KHR_draco_mesh_compression\"], \"scene\"",
+                ),
+            ),
+        ];
+        for (name, json) in &cases {
+            assert_ne!(json, TRIANGLE_JSON, "{}", name);
+            let res = load_temp(
+                &format!("rusty_test_invalid_{}.glb", name),
+                &glb(json, &buffer),
+            );
+            assert!(res.is_err(), "{}", name);
+        }
+        // Buffer without an uri needs the binary chunk
+        assert!(load_temp("rusty_test_no_bin.gltf", TRIANGLE_JSON.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn cornell_box_matches_obj() {
+        let _stats = stats::test_scene("gltf_cornell_box");
+        let obj = obj_load::load_obj(&cornell_path("obj")).unwrap();
+        let gltf = load_gltf(&cornell_path("gltf")).unwrap();
+        assert!(gltf.warnings.is_empty(), "{:?}", gltf.warnings);
+        assert_eq!(gltf.triangles.len(), obj.triangles.len());
+        for (gltf_tri, obj_tri) in gltf.triangles.iter().zip(&obj.triangles) {
+            assert_eq!(gltf_tri.material, obj_tri.material);
+            for (gltf_v, obj_v) in gltf_tri.index_vertices.iter().zip(&obj_tri.index_vertices) {
+                let gltf_p = Vector3::from(gltf.positions[gltf_v.pos_i]);
+                let obj_p = Vector3::from(obj.positions[obj_v.pos_i]);
+                assert!(
+                    (gltf_p - obj_p).magnitude() < 1e-5,
+                    "{:?} != {:?}",
+                    gltf_p,
+                    obj_p
+                );
+            }
+        }
+        for (name, obj_mat) in &obj.materials {
+            let gltf_mat = &gltf.materials[name];
+            assert_eq!(gltf_mat.diffuse_color, obj_mat.diffuse_color, "{}", name);
+            // Emission is stored normalized with a separate strength
+            let gltf_e = gltf_mat.emissive_color.unwrap_or([0.0; 3]);
+            let obj_e = obj_mat.emissive_color.unwrap_or([0.0; 3]);
+            for c in 0..3 {
+                assert!((gltf_e[c] - obj_e[c]).abs() < 1e-4, "{}", name);
+            }
+        }
+        let light: Vec<_> = gltf
+            .group_ranges
+            .iter()
+            .filter(|r| r.name == "light")
+            .collect();
+        assert_eq!(light.len(), 1);
+        assert_eq!(light[0].end_i, gltf.triangles.len());
+    }
+
+    #[test]
+    fn cornell_box_scene() {
+        let _stats = stats::test_scene("gltf_cornell_box_scene");
+        let mut config = crate::config::RenderConfig::bdpt();
+        config.bvh_cache = false;
+        let builder = crate::scene::SceneBuilder::new(&config);
+        let obj_scene = builder.build(&cornell_path("obj")).unwrap();
+        let gltf_scene = builder.build(&cornell_path("gltf")).unwrap();
+        assert_eq!(gltf_scene.n_triangles(), obj_scene.n_triangles());
+        assert_eq!(gltf_scene.n_materials(), obj_scene.n_materials());
+        assert!((gltf_scene.center() - obj_scene.center()).magnitude() < 1e-4);
+        assert!((gltf_scene.size() - obj_scene.size()).abs() < 1e-4);
+        assert_eq!(load::scene_path("cornell-gltf"), cornell_path("gltf"),);
+    }
+}
@@ -15,6 +15,11 @@ impl<T> IndexPtr<T> {
     pub fn new(vec: &Vec<T>, i: usize) -> Self {
         Self { vec, i }
     }
+
+    /// Index of the element in its vector
+    pub fn index(&self) -> usize {
+        self.i
+    }
 }
 
 impl<T> Deref for IndexPtr<T> {
@@ -0,0 +1,136 @@
+use cgmath::prelude::*;
+use cgmath::{Matrix3, Matrix4, Point3, Vector3};
+
+use crate::aabb::Aabb;
+use crate::bvh::{Bounded, Bvh, SplitMode};
+use crate::float::*;
+use crate::intersect::{Hit, Ray};
+use crate::triangle::{Triangle, TriangleArrays};
+
+/// Triangles and bvh that are shared by all the instances of a group
+pub struct Prototype {
+    /// Triangles in the order of the bvh leaves
+    triangles: Vec<Triangle>,
+    /// Intersection data of the triangles in the same order
+    triangle_arrays: TriangleArrays,
+    pub bvh: Bvh,
+    /// Bounding box in the local space
+    aabb: Aabb,
+    /// Number of triangles copied for multiple bvh leaves
+    n_duplicates: usize,
+}
+
+impl Prototype {
+    pub fn new(triangles: Vec<Triangle>, split_mode: SplitMode, max_leaf_size: usize) -> Self {
+        let (bvh, references) = Bvh::build(&triangles, split_mode, max_leaf_size);
+        let mut aabb = Aabb::empty();
+        for tri in &triangles {
+            aabb.add_aabb(&tri.aabb());
+        }
+        let n_duplicates = references.len() - triangles.len();
+        let triangles: Vec<Triangle> = references.iter().map(|&i| triangles[i].clone()).collect();
+        Self {
+            triangle_arrays: TriangleArrays::new(&triangles),
+            triangles,
+            bvh,
+            aabb,
+            n_duplicates,
+        }
+    }
+
+    /// Intersect the ith triangle with a ray in the local space
+    pub fn intersect_triangle(&self, i: usize, ray: &Ray) -> Option<Hit<'_>> {
+        let (t, u, v) = self.triangle_arrays.intersect(i, ray)?;
+        self.triangles[i].hit(ray, t, u, v)
+    }
+
+    pub fn n_triangles(&self) -> usize {
+        self.triangles.len() - self.n_duplicates
+    }
+
+    /// Memory used by the triangles and their intersection data
+    pub fn triangle_bytes(&self) -> usize {
+        self.triangles.len() * std::mem::size_of::<Triangle>() + self.triangle_arrays.size_bytes()
+    }
+}
+
+/// Transformed copy of a prototype
+#[derive(Debug)]
+pub struct Instance {
+    pub prototype_i: usize,
+    /// Transform from the local space of the prototype to the world space
+    to_world: Matrix4<Float>,
+    to_local: Matrix4<Float>,
+    normal_to_world: Matrix3<Float>,
+    /// Uniform scale that approximates the transform
+    scale: Float,
+    /// Bounding box in the world space
+    aabb: Aabb,
+}
+
+impl Instance {
+    /// Place the prototype with the transform. None if the transform is degenerate.
+    pub fn new(
+        prototype_i: usize,
+        prototype: &Prototype,
+        to_world: Matrix4<Float>,
+    ) -> Option<Self> {
+        let to_local = to_world.invert()?;
+        let linear = Matrix3::from_cols(
+            to_world.x.truncate(),
+            to_world.y.truncate(),
+            to_world.z.truncate(),
+        );
+        let normal_to_world = linear.invert()?.transpose();
+        let mut aabb = Aabb::empty();
+        let (min, max) = (prototype.aabb.min, prototype.aabb.max);
+        for i in 0..8 {
+            let corner = Point3::new(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            );
+            let p = to_world.transform_point(corner);
+            aabb.add_aabb(&Aabb { min: p, max: p });
+        }
+        Some(Self {
+            prototype_i,
+            to_world,
+            to_local,
+            normal_to_world,
+            scale: linear.determinant().abs().cbrt(),
+            aabb,
+        })
+    }
+
+    /// Ray in the local space of the prototype with the same distances as the world ray
+    pub fn local_ray(&self, ray: &Ray) -> Ray {
+        ray.transformed(&self.to_local)
+    }
+
+    pub fn point_to_world(&self, p: Point3<Float>) -> Point3<Float> {
+        self.to_world.transform_point(p)
+    }
+
+    pub fn normal_to_world(&self, n: Vector3<Float>) -> Vector3<Float> {
+        (self.normal_to_world * n).normalize()
+    }
+
+    pub fn scale(&self) -> Float {
+        self.scale
+    }
+
+    pub fn aabb(&self) -> &Aabb {
+        &self.aabb
+    }
+}
+
+impl Bounded for Instance {
+    fn aabb(&self) -> Aabb {
+        self.aabb.clone()
+    }
+
+    fn center(&self) -> Point3<Float> {
+        self.aabb.center()
+    }
+}
@@ -1,24 +1,52 @@
+use std::cell::Cell;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 use cgmath::prelude::*;
-use cgmath::{Matrix3, Point3, Vector3};
+use cgmath::{Matrix3, Matrix4, Point3, Vector3};
 
 use crate::bsdf::Bsdf;
 use crate::color::Color;
 use crate::config::RenderConfig;
 use crate::consts;
 use crate::float::*;
+use crate::instance::Instance;
 use crate::light::Light;
 use crate::pt_renderer::PathType;
 use crate::sample;
 use crate::triangle::Triangle;
 
 static RAY_COUNT: AtomicUsize = AtomicUsize::new(0);
+thread_local! {
+    /// Rays cast by the current thread. Never reset since threads
+    /// measure their rays as the difference between two counts.
+    static THREAD_RAY_COUNT: Cell<usize> = const { Cell::new(0) };
+}
+#[cfg(feature = "traversal_stats")]
+static NODE_COUNT: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "traversal_stats")]
+static TRIANGLE_TEST_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 pub trait Intersect<'a, H> {
     fn intersect(&'a self, ray: &Ray) -> Option<H>;
 }
 
+/// Distance that rays are offset from surface points near p to avoid self intersections.
+/// Scales with the magnitude of p since the spacing of floats does.
+pub fn ray_epsilon(p: Point3<Float>) -> Float {
+    consts::EPSILON * (1.0 + p.x.abs().max(p.y.abs()).max(p.z.abs()))
+}
+
+/// Point that receives light from sampled lights
+pub trait Receiver {
+    fn pos(&self) -> Point3<Float>;
+
+    /// Ray leaving the receiver in dir
+    fn ray(&self, dir: Vector3<Float>) -> Ray;
+
+    /// Shadow ray from the receiver to the point to
+    fn shadow_ray(&self, to: Point3<Float>) -> Ray;
+}
+
 #[derive(Clone, Debug)]
 pub struct Ray {
     pub orig: Point3<Float>,
@@ -27,6 +55,11 @@ pub struct Ray {
     // For more efficient ray box intersections
     pub reciprocal_dir: Vector3<Float>,
     pub neg_dir: [bool; 3],
+    /// Growth of the ray footprint width per unit distance.
+    /// Zero for rays whose footprint is not tracked.
+    pub spread: Float,
+    /// Ignore hits on the back faces of triangles
+    pub cull_backfaces: bool,
 }
 
 impl Ray {
@@ -39,9 +72,30 @@ impl Ray {
             length,
             reciprocal_dir,
             neg_dir,
+            spread: 0.0,
+            cull_backfaces: false,
         }
     }
 
+    /// Ray with its origin and direction transformed by m. The direction is
+    /// not normalized so that distances along the ray stay the same.
+    pub fn transformed(&self, m: &Matrix4<Float>) -> Ray {
+        let mut ray = Ray::new(
+            m.transform_point(self.orig),
+            m.transform_vector(self.dir),
+            self.length,
+        );
+        ray.spread = self.spread;
+        ray.cull_backfaces = self.cull_backfaces;
+        ray
+    }
+
+    /// Track the footprint of the ray with the given spread
+    pub fn with_spread(mut self, spread: Float) -> Ray {
+        self.spread = spread;
+        self
+    }
+
     /// Infinite ray with a given direction and origin
     pub fn from_dir(orig: Point3<Float>, dir: Vector3<Float>) -> Ray {
         Ray::new(orig, dir, consts::INFINITY)
@@ -56,7 +110,7 @@ impl Ray {
     /// Shadow ray between two points
     pub fn shadow(orig: Point3<Float>, to: Point3<Float>) -> Ray {
         let dp = to - orig;
-        let length = dp.magnitude() - consts::EPSILON;
+        let length = dp.magnitude() - ray_epsilon(to);
         let dir = dp.normalize();
         Ray::new(orig, dir, length)
     }
@@ -67,10 +121,45 @@ impl Ray {
 
     pub fn increment_count() {
         RAY_COUNT.fetch_add(1, Ordering::Relaxed);
+        THREAD_RAY_COUNT.with(|count| count.set(count.get() + 1));
+    }
+
+    /// Number of rays cast by the current thread
+    pub fn thread_count() -> usize {
+        THREAD_RAY_COUNT.with(Cell::get)
     }
 
     pub fn reset_count() {
         RAY_COUNT.store(0, Ordering::SeqCst);
+        #[cfg(feature = "traversal_stats")]
+        {
+            NODE_COUNT.store(0, Ordering::SeqCst);
+            TRIANGLE_TEST_COUNT.store(0, Ordering::SeqCst);
+        }
+    }
+
+    /// Record the bvh nodes visited and the triangles tested by a traversal.
+    /// Does nothing without the traversal_stats feature.
+    #[allow(unused_variables)]
+    #[inline]
+    pub fn count_traversal(nodes: usize, triangle_tests: usize) {
+        #[cfg(feature = "traversal_stats")]
+        {
+            NODE_COUNT.fetch_add(nodes, Ordering::Relaxed);
+            TRIANGLE_TEST_COUNT.fetch_add(triangle_tests, Ordering::Relaxed);
+        }
+    }
+
+    /// Total bvh nodes visited and triangles tested since the last reset.
+    /// None without the traversal_stats feature.
+    pub fn traversal_counts() -> Option<(usize, usize)> {
+        #[cfg(feature = "traversal_stats")]
+        return Some((
+            NODE_COUNT.load(Ordering::Relaxed),
+            TRIANGLE_TEST_COUNT.load(Ordering::Relaxed),
+        ));
+        #[cfg(not(feature = "traversal_stats"))]
+        None
     }
 }
 
@@ -80,11 +169,35 @@ pub struct Hit<'a> {
     pub t: Float,
     pub u: Float,
     pub v: Float,
+    /// Width of the ray footprint on the surface
+    pub footprint: Float,
+    /// Instance whose local space the triangle is in
+    pub instance: Option<&'a Instance>,
 }
 
 impl<'a> Hit<'a> {
+    /// Texture coordinate units per world space unit
+    pub fn tex_coord_density(&self) -> Float {
+        let density = self.tri.tex_coord_density();
+        match self.instance {
+            Some(instance) => density / instance.scale(),
+            None => density,
+        }
+    }
+
     pub fn interaction(self, config: &RenderConfig) -> Interaction<'a> {
-        let (p, mut ns, t) = self.tri.bary_pnt(self.u, self.v);
+        let (mut p, mut ns, t) = self.tri.bary_pnt(self.u, self.v);
+        let mut ng = self.tri.ng;
+        let tex_footprint = if config.mipmapping {
+            self.footprint * self.tex_coord_density()
+        } else {
+            0.0
+        };
+        let vertex_color = self.tri.bary_c(self.u, self.v);
+        let mut bsdf = self.tri.material.bsdf(t, tex_footprint, vertex_color);
+        if config.energy_compensation {
+            bsdf.compensate_energy();
+        }
         if config.normal_mapping {
             if let Some(ts_normal) = self.tri.material.normal(t) {
                 if let Some(to_world) = self.tri.tangent_to_world(ns) {
@@ -92,13 +205,18 @@ impl<'a> Hit<'a> {
                 }
             }
         }
+        if let Some(instance) = self.instance {
+            p = instance.point_to_world(p);
+            ns = instance.normal_to_world(ns);
+            ng = instance.normal_to_world(ng);
+        }
         Interaction {
             tri: self.tri,
             to_local: sample::local_to_world(ns).transpose(),
             p,
             ns,
-            ng: self.tri.ng,
-            bsdf: self.tri.material.bsdf(t),
+            ng,
+            bsdf,
         }
     }
 }
@@ -109,13 +227,18 @@ pub struct Interaction<'a> {
     to_local: Matrix3<Float>,
     pub p: Point3<Float>,
     pub ns: Vector3<Float>,
-    ng: Vector3<Float>,
+    pub ng: Vector3<Float>,
     bsdf: Bsdf,
 }
 
 impl Interaction<'_> {
     pub fn le(&self, wo: Vector3<Float>) -> Color {
-        self.tri.le(wo)
+        self.tri.le(self.p, wo)
+    }
+
+    /// Absorption coefficient of the medium behind the surface
+    pub fn absorption(&self) -> Option<Color> {
+        self.tri.material.absorption
     }
 
     pub fn ray(&self, dir: Vector3<Float>) -> Ray {
@@ -127,10 +250,11 @@ impl Interaction<'_> {
     }
 
     pub fn ray_origin(&self, dir: Vector3<Float>) -> Point3<Float> {
+        let offset = ray_epsilon(self.p) * self.ng;
         if dir.dot(self.ng) > 0.0 {
-            self.p + consts::EPSILON * self.ng
+            self.p + offset
         } else {
-            self.p - consts::EPSILON * self.ng
+            self.p - offset
         }
     }
 
@@ -138,6 +262,10 @@ impl Interaction<'_> {
         self.bsdf.is_specular()
     }
 
+    pub fn albedo(&self) -> Color {
+        self.bsdf.albedo()
+    }
+
     /// Evaluate geometric cosine of dir
     pub fn cos_g(&self, dir: Vector3<Float>) -> Float {
         self.ng.dot(dir)
@@ -148,6 +276,19 @@ impl Interaction<'_> {
         self.ns.dot(dir)
     }
 
+    /// Evaluate the absolute shading cosine of dir.
+    /// Zero if the shading and geometric normals disagree on the side of dir,
+    /// since such directions would otherwise leak energy through the surface.
+    /// Transmitted directions are below both normals so they are unaffected.
+    pub fn cos_t(&self, dir: Vector3<Float>) -> Float {
+        let cos_s = self.cos_s(dir);
+        if cos_s * self.cos_g(dir) > 0.0 {
+            cos_s.abs()
+        } else {
+            0.0
+        }
+    }
+
     pub fn pdf(&self, wo: Vector3<Float>, wi: Vector3<Float>) -> Float {
         let wo_local = self.to_local * wo;
         let wi_local = self.to_local * wi;
@@ -177,6 +318,20 @@ impl Interaction<'_> {
         }
     }
 
+    /// Evaluate the value divided by the pdf of a specular bsdf sample
+    /// for directions in world coordinates
+    pub fn specular_weight(
+        &self,
+        wo: Vector3<Float>,
+        wi: Vector3<Float>,
+        path_type: PathType,
+    ) -> Color {
+        let wo_local = self.to_local * wo;
+        let wi_local = self.to_local * wi;
+        self.normal_correction(wo, wi, path_type)
+            * self.bsdf.specular_weight(wo_local, wi_local, path_type)
+    }
+
     /// Sample the bsdf for outgoing world dir wo.
     /// Return the value of the bsdf, continuation ray and sampling pdf.
     pub fn sample_bsdf(
@@ -213,3 +368,17 @@ impl Interaction<'_> {
         }
     }
 }
+
+impl Receiver for Interaction<'_> {
+    fn pos(&self) -> Point3<Float> {
+        self.p
+    }
+
+    fn ray(&self, dir: Vector3<Float>) -> Ray {
+        Interaction::ray(self, dir)
+    }
+
+    fn shadow_ray(&self, to: Point3<Float>) -> Ray {
+        Interaction::shadow_ray(self, to)
+    }
+}
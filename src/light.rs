@@ -1,21 +1,26 @@
-use std::fmt::Debug;
+use std::error::Error;
+use std::fmt::{self, Debug};
+use std::path::Path;
 
 use cgmath::prelude::*;
-use cgmath::{Point3, Vector3};
+use cgmath::{Point2, Point3, Rad, Vector3};
 
 use crate::color::Color;
 use crate::consts;
 use crate::float::*;
-use crate::intersect::{Interaction, Ray};
+use crate::index_ptr::IndexPtr;
+use crate::intersect::{Ray, Receiver};
 use crate::sample;
+use crate::sampler;
+use crate::texture::{self, Texture};
 use crate::triangle::Triangle;
 
 pub trait Light: Debug {
     /// Total emissive power of the light
     fn power(&self) -> Color;
 
-    /// Emitted radiance to dir
-    fn le(&self, dir: Vector3<Float>) -> Color;
+    /// Emitted radiance from the point p of the light to dir
+    fn le(&self, p: Point3<Float>, dir: Vector3<Float>) -> Color;
 
     /// Evaluate the geometric cosine with dir
     fn cos_g(&self, dir: Vector3<Float>) -> Float;
@@ -23,40 +28,105 @@ pub trait Light: Debug {
     /// Check if light position contains a delta distribution
     fn delta_pos(&self) -> bool;
 
+    /// Check if emitted direction contains a delta distribution
+    fn delta_dir(&self) -> bool {
+        false
+    }
+
     /// Sample a position on the lights surface
     /// Return point and area pdf
     fn sample_pos(&self) -> (Point3<Float>, Float);
 
+    /// Sample a position using the given uniform random numbers.
+    /// Lights that don't support this sample the position independently.
+    fn sample_pos_with(&self, _r: Point2<Float>) -> (Point3<Float>, Float) {
+        self.sample_pos()
+    }
+
     /// Pdf of position sampling in area measure
     fn pdf_pos(&self) -> Float;
 
-    /// Sample a direction for emitted radiance
+    /// Sample a direction for radiance emitted from the point p of the light
     /// Return radiance, direction and solid angle pdf
-    fn sample_dir(&self) -> (Color, Vector3<Float>, Float);
+    fn sample_dir(&self, p: Point3<Float>) -> (Color, Vector3<Float>, Float);
 
     /// Pdf of direction sampling in solid angle measure
     fn pdf_dir(&self, dir: Vector3<Float>) -> Float;
 
-    /// Sample radiance toward receiving interaction.
+    /// Sample radiance toward a receiving point.
     /// Return radiance, shadow ray and the pdf
-    fn sample_towards(&self, recv: &Interaction) -> (Color, Ray, Float) {
-        let (p, pdf_a) = self.sample_pos();
-        let ray = recv.shadow_ray(p);
-        let pdf = sample::to_dir_pdf(pdf_a, ray.length.powi(2), self.cos_g(ray.dir).abs());
-        let le = self.le(-ray.dir);
-        (le, ray, pdf)
+    fn sample_towards(&self, recv: &dyn Receiver) -> (Color, Ray, Float) {
+        sample_area_towards(self, recv)
+    }
+
+    /// Solid angle pdf of sample_towards from p returning dir
+    /// that reaches the light after dist
+    fn pdf_towards(&self, _p: Point3<Float>, dir: Vector3<Float>, dist: Float) -> Float {
+        sample::to_dir_pdf(self.pdf_pos(), dist.powi(2), self.cos_g(dir).abs())
+    }
+}
+
+/// Sample radiance toward a receiving point by sampling a position on the light
+fn sample_area_towards<L: Light + ?Sized>(light: &L, recv: &dyn Receiver) -> (Color, Ray, Float) {
+    let (p, pdf_a) = light.sample_pos();
+    let ray = recv.shadow_ray(p);
+    let pdf = sample::to_dir_pdf(pdf_a, ray.length.powi(2), light.cos_g(ray.dir).abs());
+    // Lights seen edge-on have infinite pdfs that would turn MIS weights into NaNs
+    if !pdf.is_finite() {
+        return (Color::black(), ray, 1.0);
+    }
+    let le = light.le(p, -ray.dir);
+    (le, ray, pdf)
+}
+
+/// Number of stratified samples per side used to integrate emission textures
+const EMISSION_SAMPLES: usize = 16;
+
+/// Triangles that subtend a solid angle outside this range
+/// are sampled by area because solid angle sampling is numerically unstable
+const MIN_SOLID_ANGLE: Float = 1e-3;
+const MAX_SOLID_ANGLE: Float = 6.0;
+
+/// Vertices of the triangle projected to the unit sphere around p and the solid angle
+/// they subtend if it is suitable for solid angle sampling
+fn spherical_triangle(tri: &Triangle, p: Point3<Float>) -> Option<([Vector3<Float>; 3], Float)> {
+    let [a, b, c] = tri.positions().map(|v| (v - p).normalize());
+    let solid_angle = sample::spherical_triangle_area(a, b, c);
+    if (MIN_SOLID_ANGLE..MAX_SOLID_ANGLE).contains(&solid_angle) {
+        Some(([a, b, c], solid_angle))
+    } else {
+        None
     }
 }
 
 impl Light for Triangle {
     fn power(&self) -> Color {
-        consts::PI * self.material.emissive.unwrap() * self.area()
+        let emissive = self.material.emissive.as_ref().unwrap();
+        let mean = match emissive {
+            Texture::Solid(color) => *color,
+            Texture::Image(..) => {
+                let mut sum = Color::black();
+                for i in 0..EMISSION_SAMPLES {
+                    for j in 0..EMISSION_SAMPLES {
+                        let r1 = (i.to_float() + 0.5) / EMISSION_SAMPLES.to_float();
+                        let r2 = (j.to_float() + 0.5) / EMISSION_SAMPLES.to_float();
+                        let (u, v) = Triangle::sample_with(r1, r2);
+                        sum += emissive.color_lod(self.bary_t(u, v), 0.0);
+                    }
+                }
+                sum / (EMISSION_SAMPLES * EMISSION_SAMPLES).to_float()
+            }
+        };
+        let sides = if self.material.two_sided { 2.0 } else { 1.0 };
+        sides * consts::PI * mean * self.area()
     }
 
-    fn le(&self, dir: Vector3<Float>) -> Color {
-        if let Some(le) = self.material.emissive {
-            if self.ng.dot(dir) > 0.0 {
-                return le;
+    fn le(&self, p: Point3<Float>, dir: Vector3<Float>) -> Color {
+        if let Some(emissive) = &self.material.emissive {
+            if self.material.two_sided || self.ng.dot(dir) > 0.0 {
+                let (u, v) = self.barycentric(p);
+                // Zero footprint gives bilinear filtering of the full resolution image
+                return emissive.color_lod(self.bary_t(u, v), 0.0);
             }
         }
         Color::black()
@@ -71,7 +141,11 @@ impl Light for Triangle {
     }
 
     fn sample_pos(&self) -> (Point3<Float>, Float) {
-        let (u, v) = Triangle::sample();
+        self.sample_pos_with(Point2::new(sampler::random(), sampler::random()))
+    }
+
+    fn sample_pos_with(&self, r: Point2<Float>) -> (Point3<Float>, Float) {
+        let (u, v) = Triangle::sample_with(r.x, r.y);
         let (p, _, _) = self.bary_pnt(u, v);
         (p, self.pdf_pos())
     }
@@ -80,21 +154,107 @@ impl Light for Triangle {
         1.0 / self.area()
     }
 
-    fn sample_dir(&self) -> (Color, Vector3<Float>, Float) {
-        let local_dir = sample::cosine_sample_hemisphere(1.0);
-        let dir_pdf = sample::cosine_hemisphere_pdf(local_dir.z.abs());
+    fn sample_dir(&self, p: Point3<Float>) -> (Color, Vector3<Float>, Float) {
+        let (sign, side_pdf) = if !self.material.two_sided {
+            (1.0, 1.0)
+        } else if sampler::random() < 0.5 {
+            (1.0, 0.5)
+        } else {
+            (-1.0, 0.5)
+        };
+        let local_dir = sample::cosine_sample_hemisphere(sign);
+        let dir_pdf = side_pdf * sample::cosine_hemisphere_pdf(local_dir.z.abs());
         let dir = sample::local_to_world(self.ng) * local_dir;
-        (self.le(dir), dir, dir_pdf)
+        (self.le(p, dir), dir, dir_pdf)
     }
 
     fn pdf_dir(&self, dir: Vector3<Float>) -> Float {
         let cos_t = self.cos_g(dir);
-        if cos_t < 0.0 {
+        if self.material.two_sided {
+            0.5 * sample::cosine_hemisphere_pdf(cos_t.abs())
+        } else if cos_t < 0.0 {
             0.0
         } else {
             sample::cosine_hemisphere_pdf(cos_t)
         }
     }
+
+    /// Sample the solid angle subtended by the triangle when it is large
+    /// enough and the area of the triangle otherwise
+    fn sample_towards(&self, recv: &dyn Receiver) -> (Color, Ray, Float) {
+        let o = recv.pos();
+        if let Some(([a, b, c], solid_angle)) = spherical_triangle(self, o) {
+            let (r1, r2) = (sampler::random(), sampler::random());
+            if let Some(dir) = sample::sample_spherical_triangle(a, b, c, r1, r2) {
+                // Find the sampled point on the plane of the triangle
+                let t = (self.positions()[0] - o).dot(self.ng) / dir.dot(self.ng);
+                if t.is_finite() && t > 0.0 {
+                    let p = o + t * dir;
+                    let ray = recv.shadow_ray(p);
+                    return (self.le(p, -ray.dir), ray, 1.0 / solid_angle);
+                }
+            }
+        }
+        sample_area_towards(self, recv)
+    }
+
+    fn pdf_towards(&self, p: Point3<Float>, dir: Vector3<Float>, dist: Float) -> Float {
+        match spherical_triangle(self, p) {
+            Some((_, solid_angle)) => 1.0 / solid_angle,
+            None => sample::to_dir_pdf(self.pdf_pos(), dist.powi(2), self.cos_g(dir).abs()),
+        }
+    }
+}
+
+// Allows storing lights that are owned by a vector
+impl<T: Light> Light for IndexPtr<T> {
+    fn power(&self) -> Color {
+        (**self).power()
+    }
+
+    fn le(&self, p: Point3<Float>, dir: Vector3<Float>) -> Color {
+        (**self).le(p, dir)
+    }
+
+    fn cos_g(&self, dir: Vector3<Float>) -> Float {
+        (**self).cos_g(dir)
+    }
+
+    fn delta_pos(&self) -> bool {
+        (**self).delta_pos()
+    }
+
+    fn delta_dir(&self) -> bool {
+        (**self).delta_dir()
+    }
+
+    fn sample_pos(&self) -> (Point3<Float>, Float) {
+        (**self).sample_pos()
+    }
+
+    fn sample_pos_with(&self, r: Point2<Float>) -> (Point3<Float>, Float) {
+        (**self).sample_pos_with(r)
+    }
+
+    fn pdf_pos(&self) -> Float {
+        (**self).pdf_pos()
+    }
+
+    fn sample_dir(&self, p: Point3<Float>) -> (Color, Vector3<Float>, Float) {
+        (**self).sample_dir(p)
+    }
+
+    fn pdf_dir(&self, dir: Vector3<Float>) -> Float {
+        (**self).pdf_dir(dir)
+    }
+
+    fn sample_towards(&self, recv: &dyn Receiver) -> (Color, Ray, Float) {
+        (**self).sample_towards(recv)
+    }
+
+    fn pdf_towards(&self, p: Point3<Float>, dir: Vector3<Float>, dist: Float) -> Float {
+        (**self).pdf_towards(p, dir, dist)
+    }
 }
 
 #[derive(Debug)]
@@ -115,7 +275,7 @@ impl Light for PointLight {
         4.0 * consts::PI * self.intensity
     }
 
-    fn le(&self, _dir: Vector3<Float>) -> Color {
+    fn le(&self, _p: Point3<Float>, _dir: Vector3<Float>) -> Color {
         self.intensity
     }
 
@@ -135,7 +295,7 @@ impl Light for PointLight {
         0.0
     }
 
-    fn sample_dir(&self) -> (Color, Vector3<Float>, Float) {
+    fn sample_dir(&self, _p: Point3<Float>) -> (Color, Vector3<Float>, Float) {
         let dir = sample::uniform_sample_sphere();
         let pdf = sample::uniform_sphere_pdf();
         (self.intensity, dir, pdf)
@@ -145,3 +305,375 @@ impl Light for PointLight {
         sample::uniform_sphere_pdf()
     }
 }
+
+/// Point light that only emits light inside a cone
+#[derive(Debug)]
+pub struct SpotLight {
+    pos: Point3<Float>,
+    /// Direction of the cone axis
+    dir: Vector3<Float>,
+    /// Cosine of the angle after which no light is emitted
+    cos_total: Float,
+    /// Cosine of the angle after which the intensity starts to fall off
+    cos_falloff: Float,
+    intensity: Color,
+}
+
+impl SpotLight {
+    pub fn new(
+        pos: Point3<Float>,
+        dir: Vector3<Float>,
+        total_angle: Rad<Float>,
+        falloff_angle: Rad<Float>,
+        intensity: Color,
+    ) -> Self {
+        let cos_total = total_angle.cos();
+        // Falloff can't start outside of the cone
+        let cos_falloff = falloff_angle.cos().max(cos_total);
+        Self {
+            pos,
+            dir: dir.normalize(),
+            cos_total,
+            cos_falloff,
+            intensity,
+        }
+    }
+
+    /// Fraction of the intensity emitted to dir
+    fn falloff(&self, dir: Vector3<Float>) -> Float {
+        let cos_t = self.dir.dot(dir);
+        if cos_t < self.cos_total {
+            0.0
+        } else if cos_t >= self.cos_falloff {
+            1.0
+        } else {
+            let delta = (cos_t - self.cos_total) / (self.cos_falloff - self.cos_total);
+            delta.powi(4)
+        }
+    }
+}
+
+impl Light for SpotLight {
+    fn power(&self) -> Color {
+        // Approximate the falloff with the average of the cone angles
+        2.0 * consts::PI * (1.0 - 0.5 * (self.cos_falloff + self.cos_total)) * self.intensity
+    }
+
+    fn le(&self, _p: Point3<Float>, dir: Vector3<Float>) -> Color {
+        self.falloff(dir) * self.intensity
+    }
+
+    fn cos_g(&self, _dir: Vector3<Float>) -> Float {
+        1.0
+    }
+
+    fn delta_pos(&self) -> bool {
+        true
+    }
+
+    fn sample_pos(&self) -> (Point3<Float>, Float) {
+        (self.pos, 1.0)
+    }
+
+    fn pdf_pos(&self) -> Float {
+        0.0
+    }
+
+    fn sample_dir(&self, p: Point3<Float>) -> (Color, Vector3<Float>, Float) {
+        let local_dir = sample::uniform_sample_cone(self.cos_total);
+        let dir = sample::local_to_world(self.dir) * local_dir;
+        let pdf = sample::uniform_cone_pdf(self.cos_total);
+        (self.le(p, dir), dir, pdf)
+    }
+
+    fn pdf_dir(&self, dir: Vector3<Float>) -> Float {
+        if self.dir.dot(dir) < self.cos_total {
+            0.0
+        } else {
+            sample::uniform_cone_pdf(self.cos_total)
+        }
+    }
+}
+
+/// Infinitely distant light that illuminates the scene from a single direction
+#[derive(Debug)]
+pub struct DirectionalLight {
+    /// Direction of the emitted light
+    dir: Vector3<Float>,
+    /// Radiance arriving from the light
+    radiance: Color,
+    /// Center of the sphere that bounds the scene
+    scene_center: Point3<Float>,
+    /// Radius of the sphere that bounds the scene
+    scene_radius: Float,
+}
+
+impl DirectionalLight {
+    pub fn new(
+        dir: Vector3<Float>,
+        radiance: Color,
+        scene_center: Point3<Float>,
+        scene_radius: Float,
+    ) -> Self {
+        Self {
+            dir: dir.normalize(),
+            radiance,
+            scene_center,
+            scene_radius,
+        }
+    }
+}
+
+impl Light for DirectionalLight {
+    fn power(&self) -> Color {
+        consts::PI * self.scene_radius.powi(2) * self.radiance
+    }
+
+    fn le(&self, _p: Point3<Float>, dir: Vector3<Float>) -> Color {
+        // Only exactly matching directions receive light
+        if dir == self.dir {
+            self.radiance
+        } else {
+            Color::black()
+        }
+    }
+
+    fn cos_g(&self, dir: Vector3<Float>) -> Float {
+        self.dir.dot(dir)
+    }
+
+    fn delta_pos(&self) -> bool {
+        false
+    }
+
+    fn delta_dir(&self) -> bool {
+        true
+    }
+
+    /// Sample a position on a disk that covers the scene
+    /// and is located behind it from the direction of the light
+    fn sample_pos(&self) -> (Point3<Float>, Float) {
+        let disk_p = sample::local_to_world(self.dir) * sample::uniform_sample_disk();
+        let p = self.scene_center + self.scene_radius * (disk_p - self.dir);
+        (p, self.pdf_pos())
+    }
+
+    fn pdf_pos(&self) -> Float {
+        1.0 / (consts::PI * self.scene_radius.powi(2))
+    }
+
+    fn sample_dir(&self, _p: Point3<Float>) -> (Color, Vector3<Float>, Float) {
+        (self.radiance, self.dir, 1.0)
+    }
+
+    fn pdf_dir(&self, _dir: Vector3<Float>) -> Float {
+        0.0
+    }
+
+    fn sample_towards(&self, recv: &dyn Receiver) -> (Color, Ray, Float) {
+        (self.radiance, recv.ray(-self.dir), 1.0)
+    }
+}
+
+/// Infinitely distant light defined by an equirectangular radiance map.
+/// The top of the map corresponds to the positive y-axis.
+/// It is not a Light since it has no positions to sample for light paths,
+/// so the tracers only sample it towards receivers.
+pub struct EnvironmentLight {
+    width: usize,
+    height: usize,
+    radiance: Vec<Color>,
+    /// Cdf of sampling each row
+    marginal_cdf: Vec<Float>,
+    /// Cdfs of sampling each column of a given row
+    conditional_cdfs: Vec<Vec<Float>>,
+    /// Radius that bounds the scene
+    scene_radius: Float,
+}
+
+impl EnvironmentLight {
+    pub fn from_path(path: &Path, scene_radius: Float) -> Result<Self, Box<dyn Error>> {
+        let (width, height, radiance) = texture::load_hdr_image(path)?;
+        Ok(Self::new(
+            width as usize,
+            height as usize,
+            radiance,
+            scene_radius,
+        ))
+    }
+
+    /// Construct the light from the rows of an equirectangular map
+    fn new(width: usize, height: usize, radiance: Vec<Color>, scene_radius: Float) -> Self {
+        let mut row_weights = Vec::with_capacity(height);
+        let mut conditional_cdfs = Vec::with_capacity(height);
+        for row in 0..height {
+            // Rows near the poles cover a smaller solid angle
+            let sin_t = (consts::PI * (row.to_float() + 0.5) / height.to_float()).sin();
+            let weights: Vec<Float> = radiance[row * width..(row + 1) * width]
+                .iter()
+                .map(|c| c.luma() * sin_t)
+                .collect();
+            row_weights.push(weights.iter().sum());
+            conditional_cdfs.push(sample::cdf(&weights));
+        }
+        Self {
+            width,
+            height,
+            radiance,
+            marginal_cdf: sample::cdf(&row_weights),
+            conditional_cdfs,
+            scene_radius,
+        }
+    }
+
+    /// Map direction to (u, v) coordinates of the map
+    fn dir_to_uv(dir: Vector3<Float>) -> (Float, Float) {
+        let theta = dir.y.clamp(-1.0, 1.0).acos();
+        let phi = dir.x.atan2(-dir.z) + consts::PI;
+        (phi / (2.0 * consts::PI), theta / consts::PI)
+    }
+
+    /// Map (u, v) coordinates of the map to a direction
+    fn uv_to_dir(u: Float, v: Float) -> Vector3<Float> {
+        let theta = consts::PI * v;
+        let phi = 2.0 * consts::PI * u - consts::PI;
+        let sin_t = theta.sin();
+        Vector3::new(sin_t * phi.sin(), theta.cos(), -sin_t * phi.cos())
+    }
+
+    /// Get the pixel containing (u, v)
+    fn pixel(&self, u: Float, v: Float) -> (usize, usize) {
+        let col = ((u * self.width.to_float()) as usize).min(self.width - 1);
+        let row = ((v * self.height.to_float()) as usize).min(self.height - 1);
+        (row, col)
+    }
+
+    /// Radiance arriving from dir
+    pub fn lookup(&self, dir: Vector3<Float>) -> Color {
+        let (u, v) = Self::dir_to_uv(dir);
+        let (row, col) = self.pixel(u, v);
+        self.radiance[row * self.width + col]
+    }
+
+    /// Importance sample a direction towards the light.
+    /// Return the direction and its solid angle pdf.
+    fn sample_incoming(&self) -> (Vector3<Float>, Float) {
+        let (row, row_pdf) = sample::sample_cdf(&self.marginal_cdf);
+        let (col, col_pdf) = sample::sample_cdf(&self.conditional_cdfs[row]);
+        let u = Self::jitter(col, self.width);
+        let v = Self::jitter(row, self.height);
+        let dir = Self::uv_to_dir(u, v);
+        let pdf_uv = row_pdf * col_pdf * (self.width * self.height).to_float();
+        (dir, Self::to_dir_pdf(pdf_uv, dir))
+    }
+
+    /// Sample a coordinate within the cell i of n.
+    /// The sample keeps a margin to the edges of the cell so that the rounding
+    /// errors of the direction don't move the lookup into the neighbouring pixel.
+    fn jitter(i: usize, n: usize) -> Float {
+        let margin = (gamma(32) * n.to_float()).min(0.5);
+        (i.to_float() + sampler::random().clamp(margin, 1.0 - margin)) / n.to_float()
+    }
+
+    /// Solid angle pdf of sample_incoming returning dir
+    pub fn pdf_incoming(&self, dir: Vector3<Float>) -> Float {
+        let (u, v) = Self::dir_to_uv(dir);
+        let (row, col) = self.pixel(u, v);
+        let row_pdf = self.marginal_cdf[row + 1] - self.marginal_cdf[row];
+        let cdf = &self.conditional_cdfs[row];
+        let col_pdf = cdf[col + 1] - cdf[col];
+        let pdf_uv = row_pdf * col_pdf * (self.width * self.height).to_float();
+        Self::to_dir_pdf(pdf_uv, dir)
+    }
+
+    /// Sample radiance toward a receiving point.
+    /// Return radiance, shadow ray and the solid angle pdf.
+    pub fn sample_towards(&self, recv: &dyn Receiver) -> (Color, Ray, Float) {
+        let (dir, pdf) = self.sample_incoming();
+        // Any point outside the scene is far enough
+        let ray = recv.shadow_ray(recv.pos() + 2.0 * self.scene_radius * dir);
+        if pdf == 0.0 {
+            return (Color::black(), ray, 1.0);
+        }
+        (self.lookup(dir), ray, pdf)
+    }
+
+    /// Convert pdf in (u, v) coordinates to solid angle measure.
+    /// Sine of the polar angle is computed from dir since v loses precision near the poles.
+    fn to_dir_pdf(pdf_uv: Float, dir: Vector3<Float>) -> Float {
+        let sin_t = (dir.x.powi(2) + dir.z.powi(2)).sqrt();
+        if sin_t <= 0.0 {
+            0.0
+        } else {
+            pdf_uv / (2.0 * consts::PI.powi(2) * sin_t)
+        }
+    }
+}
+
+// Implement debug manually to avoid printing the whole map
+impl Debug for EnvironmentLight {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EnvironmentLight {}x{}", self.width, self.height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cgmath::Deg;
+
+    #[test]
+    fn environment_pdf_matches_sampling() {
+        let radiance = (0..8usize)
+            .map(|i| Color::new(i.to_float(), i.to_float(), i.to_float()))
+            .collect();
+        let env = EnvironmentLight::new(4, 2, radiance, 1.0);
+        for _ in 0..100 {
+            let (dir, pdf) = env.sample_incoming();
+            assert!(pdf > 0.0);
+            let lookup_pdf = env.pdf_incoming(dir);
+            assert!(
+                (pdf - lookup_pdf).abs() <= 1e-3 * pdf,
+                "{} {}",
+                pdf,
+                lookup_pdf
+            );
+        }
+        // Black pixels are never sampled
+        assert_eq!(
+            env.pdf_incoming(EnvironmentLight::uv_to_dir(0.125, 0.25)),
+            0.0
+        );
+    }
+
+    #[test]
+    fn spot_light_falloff() {
+        let intensity = Color::new(2.0, 3.0, 4.0);
+        let spot = SpotLight::new(
+            Point3::new(0.0, 1.0, 0.0),
+            -Vector3::unit_y(),
+            Rad::from(Deg(30.0)),
+            Rad::from(Deg(20.0)),
+            intensity,
+        );
+        let p = Point3::new(0.0, 0.0, 0.0);
+        // Luminance emitted at the angle from the cone axis
+        let le = |deg: Float| {
+            let theta = Rad::from(Deg(deg)).0;
+            let dir = Vector3::new(theta.sin(), -theta.cos(), 0.0);
+            spot.le(p, dir).luma()
+        };
+        for &deg in &[0.0, 10.0, 19.0] {
+            assert_eq!(le(deg), intensity.luma(), "{}", deg);
+        }
+        let partial = le(25.0);
+        assert!(0.0 < partial && partial < intensity.luma(), "{}", partial);
+        for &deg in &[31.0, 60.0, 90.0, 180.0] {
+            assert_eq!(le(deg), 0.0, "{}", deg);
+        }
+        // Directions outside of the cone are never sampled
+        assert_eq!(spot.pdf_dir(Vector3::unit_y()), 0.0);
+        assert!(spot.pdf_dir(-Vector3::unit_y()) > 0.0);
+    }
+}
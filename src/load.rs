@@ -3,15 +3,18 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use cgmath::prelude::*;
-use cgmath::{Point3, Quaternion, Vector3};
+use cgmath::{Deg, Point3, Quaternion, Vector3};
 
 use glium::backend::Facade;
 use glium::glutin::event::VirtualKeyCode;
 
 use crate::camera::Camera;
+use crate::color::Color;
 use crate::config::RenderConfig;
 use crate::float::*;
-use crate::scene::{GpuScene, Scene, SceneBuilder};
+use crate::gltf_load;
+use crate::obj_load::{self, Object};
+use crate::scene::{AnalyticLight, GpuScene, Scene, SceneBuilder, SceneError};
 use crate::stats;
 use crate::util;
 
@@ -22,6 +25,8 @@ lazy_static::lazy_static! {
         let mut lib = SceneLibrary::new();
         lib.add_scene("plane".to_string(), scene_dir.join("plane.obj"),
                       CameraPos::Offset, Some(VirtualKeyCode::Key1));
+        // Single plane has nothing to bounce off
+        lib.add_config_patch("plane", "max_bounces", "1");
         lib.add_scene("chesterfield".to_string(),
                       scene_dir.join("cornell").join("cornell_chesterfield.obj"),
                       CameraPos::Defined(Point3::new(-0.74, 0.4, 0.97),
@@ -36,6 +41,8 @@ lazy_static::lazy_static! {
         lib.add_scene("cornell-water".to_string(),
                       scene_dir.join("cornell-box").join("CornellBox-Water.obj"),
                       CameraPos::Offset, Some(VirtualKeyCode::Key5));
+        // Caustics through the water need long paths
+        lib.add_config_patch("cornell-water", "pre_rr_bounces", "10");
         lib.add_scene("indirect".to_string(),
                       scene_dir.join("indirect-test").join("indirect-test_tex.obj"),
                       CameraPos::Defined(Point3::new(0.43, 0.45, 0.8),
@@ -67,6 +74,34 @@ lazy_static::lazy_static! {
         lib.add_scene("cornell-original".to_string(),
                       scene_dir.join("cornell-box").join("CornellBox-Original.obj"),
                       CameraPos::Offset, Some(VirtualKeyCode::Equals));
+        lib.add_scene("sponza-sun".to_string(),
+                      scene_dir.join("crytek-sponza").join("sponza.obj"),
+                      CameraPos::Defined(Point3::new(-783.01, 184.23, 173.92),
+                                         Quaternion::new(0.89, -0.06, 0.44, 0.03)),
+                      None);
+        lib.add_light("sponza-sun", AnalyticLight::Directional {
+            dir: Vector3::new(0.3, -1.0, 0.2),
+            radiance: Color::from([3.0, 2.8, 2.5]),
+        });
+        lib.add_scene("cornell-spot".to_string(),
+                      scene_dir.join("cornell-box").join("CornellBox-Original.obj"),
+                      CameraPos::Offset, None);
+        lib.add_light("cornell-spot", AnalyticLight::Spot {
+            pos: Point3::new(-0.5, 1.5, 0.5),
+            dir: Vector3::new(0.3, -1.0, -0.2),
+            total_angle: Deg(30.0).into(),
+            falloff_angle: Deg(20.0).into(),
+            intensity: Color::from([2.0, 2.0, 2.0]),
+        });
+        lib.add_scene("cutout".to_string(),
+                      scene_dir.join("cutout").join("cutout.obj"),
+                      CameraPos::Offset, None);
+        lib.add_scene("smooth".to_string(),
+                      scene_dir.join("smooth").join("smooth.obj"),
+                      CameraPos::Offset, None);
+        lib.add_scene("cornell-gltf".to_string(),
+                      scene_dir.join("cornell-box").join("CornellBox-Original.gltf"),
+                      CameraPos::Offset, None);
         lib
     };
 }
@@ -82,6 +117,9 @@ enum CameraPos {
 struct SceneInfo {
     path: PathBuf,
     camera_pos: CameraPos,
+    lights: Vec<AnalyticLight>,
+    /// Settings applied to the config when the scene is loaded interactively
+    config_patch: Vec<(&'static str, &'static str)>,
 }
 
 struct SceneLibrary {
@@ -107,10 +145,33 @@ impl SceneLibrary {
         if let Some(code) = key {
             self.key_map.insert(code, name.clone());
         }
-        let info = SceneInfo { path, camera_pos };
+        let info = SceneInfo {
+            path,
+            camera_pos,
+            lights: Vec::new(),
+            config_patch: Vec::new(),
+        };
         self.scene_map.insert(name, info);
     }
 
+    /// Set a config value in the format of config files for the scene
+    fn add_config_patch(&mut self, name: &str, key: &'static str, value: &'static str) {
+        let info = self
+            .scene_map
+            .get_mut(name)
+            .unwrap_or_else(|| panic!("Scene {} has not been added!", name));
+        info.config_patch.push((key, value));
+    }
+
+    /// Add a light that is not part of the scene geometry
+    fn add_light(&mut self, name: &str, light: AnalyticLight) {
+        let info = self
+            .scene_map
+            .get_mut(name)
+            .unwrap_or_else(|| panic!("Scene {} has not been added!", name));
+        info.lights.push(light);
+    }
+
     pub fn get(&self, name: &str) -> Option<&SceneInfo> {
         self.scene_map.get(name)
     }
@@ -120,6 +181,9 @@ impl SceneLibrary {
     }
 }
 
+/// Scene loaded for both CPU and GPU rendering with its initial camera
+type GpuSceneResult = Result<(Arc<Scene>, GpuScene, Camera), SceneError>;
+
 fn initialize_camera(scene: &Scene, pos: CameraPos, config: &RenderConfig) -> Camera {
     let mut camera = match pos {
         CameraPos::Center => Camera::new(scene.center(), Quaternion::one()),
@@ -131,58 +195,116 @@ fn initialize_camera(scene: &Scene, pos: CameraPos, config: &RenderConfig) -> Ca
         CameraPos::Defined(pos, rot) => Camera::new(pos, rot.normalize()),
     };
     camera.set_scale(scene.size());
-    camera.update_viewport(config.dimensions());
+    camera.update_viewport(config.dimensions(), config.pixel_aspect_ratio);
     camera
 }
 
-fn cpu_scene(path: &Path, camera_pos: CameraPos, config: &RenderConfig) -> (Arc<Scene>, Camera) {
-    let scene = SceneBuilder::new(config).build(path);
-    let camera = initialize_camera(&scene, camera_pos, config);
-    (scene, camera)
+fn cpu_scene(info: &SceneInfo, config: &RenderConfig) -> Result<(Arc<Scene>, Camera), SceneError> {
+    // Invalid bvh settings would panic in the middle of the build
+    config.validate().map_err(SceneError::Config)?;
+    let mut builder = SceneBuilder::new(config);
+    for &light in &info.lights {
+        builder.add_light(light);
+    }
+    let scene = builder.build(&info.path)?;
+    let camera = initialize_camera(&scene, info.camera_pos, config);
+    Ok((scene, camera))
 }
 
-fn gpu_scene<F: Facade>(
-    facade: &F,
-    path: &Path,
-    camera_pos: CameraPos,
-    config: &RenderConfig,
-) -> (Arc<Scene>, GpuScene, Camera) {
-    let (scene, camera) = cpu_scene(path, camera_pos, config);
+fn gpu_scene<F: Facade>(facade: &F, info: &SceneInfo, config: &RenderConfig) -> GpuSceneResult {
+    let (scene, camera) = cpu_scene(info, config)?;
     let gpu_scene = scene.upload_data(facade);
-    (scene, gpu_scene, camera)
+    Ok((scene, gpu_scene, camera))
+}
+
+/// Get the file of a library scene or interpret name as a path
+pub fn scene_path(name: &str) -> PathBuf {
+    match SCENE_LIBRARY.get(name) {
+        Some(info) => info.path.clone(),
+        None => PathBuf::from(name),
+    }
+}
+
+/// Check if the file is in one of the supported scene formats
+pub fn is_scene_file(path: &Path) -> bool {
+    matches!(
+        util::lowercase_extension(path).as_deref(),
+        Some("obj") | Some("gltf") | Some("glb")
+    )
 }
 
-pub fn cpu_scene_from_name(name: &str, config: &RenderConfig) -> (Arc<Scene>, Camera) {
+/// Load the geometry and materials of a scene file.
+/// .gltf and .glb files are loaded as glTF and everything else as an object file.
+pub fn load_object(path: &Path) -> Result<Object, SceneError> {
+    let res = match util::lowercase_extension(path).as_deref() {
+        Some("gltf") | Some("glb") => gltf_load::load_gltf(path),
+        _ => obj_load::load_obj(path),
+    };
+    res.map_err(|err| SceneError::Load(path.to_path_buf(), err))
+}
+
+/// Load a library scene or interpret name as a path to a scene file
+pub fn cpu_scene_from_name(
+    name: &str,
+    config: &RenderConfig,
+) -> Result<(Arc<Scene>, Camera), SceneError> {
     let _t = stats::time("Load");
-    let info = SCENE_LIBRARY.get(name).unwrap();
-    cpu_scene(&info.path, info.camera_pos, config)
+    match SCENE_LIBRARY.get(name) {
+        Some(info) => cpu_scene(info, config),
+        None => {
+            let info = SceneInfo {
+                path: PathBuf::from(name),
+                camera_pos: CameraPos::Offset,
+                lights: Vec::new(),
+                config_patch: Vec::new(),
+            };
+            cpu_scene(&info, config)
+        }
+    }
 }
 
 pub fn gpu_scene_from_path<F: Facade>(
     facade: &F,
     path: &Path,
     config: &RenderConfig,
-) -> Option<(Arc<Scene>, GpuScene, Camera)> {
-    if let Some("obj") = util::lowercase_extension(path).as_deref() {
+) -> GpuSceneResult {
+    if is_scene_file(path) {
         stats::new_scene(path.to_str().unwrap());
-        let res = gpu_scene(facade, path, CameraPos::Offset, config);
+        let info = SceneInfo {
+            path: path.to_path_buf(),
+            camera_pos: CameraPos::Offset,
+            lights: Vec::new(),
+            config_patch: Vec::new(),
+        };
+        let res = gpu_scene(facade, &info, config)?;
         println!("Loaded scene from {:?}", path);
-        Some(res)
+        Ok(res)
     } else {
-        println!("{:?} is not object file (.obj)", path);
-        None
+        Err(SceneError::Unsupported(path.to_path_buf()))
     }
 }
 
+/// Get the config patch of the scene bound to key.
+/// Keys without a scene have an empty patch.
+pub fn config_patch_from_key(key: VirtualKeyCode) -> &'static [(&'static str, &'static str)] {
+    match SCENE_LIBRARY.key_to_name(key) {
+        Some(name) => &SCENE_LIBRARY.get(name).unwrap().config_patch,
+        None => &[],
+    }
+}
+
+/// Load the scene bound to key. None if no scene is bound to the key.
 pub fn gpu_scene_from_key<F: Facade>(
     facade: &F,
     key: VirtualKeyCode,
     config: &RenderConfig,
-) -> Option<(Arc<Scene>, GpuScene, Camera)> {
+) -> Option<GpuSceneResult> {
     let name = SCENE_LIBRARY.key_to_name(key)?;
     stats::new_scene(name);
     let info = SCENE_LIBRARY.get(name).unwrap();
-    let res = gpu_scene(facade, &info.path, info.camera_pos, config);
-    println!("Loaded scene {}", name);
+    let res = gpu_scene(facade, info, config);
+    if res.is_ok() {
+        println!("Loaded scene {}", name);
+    }
     Some(res)
 }
@@ -1,9 +1,13 @@
+use std::error::Error;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use chrono::Local;
 
 use glium::glutin::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use glium::glutin::event_loop::ControlFlow;
+use glium::glutin::platform::run_return::EventLoopExtRunReturn;
 use glium::Surface;
 
 mod aabb;
@@ -11,20 +15,25 @@ mod bsdf;
 mod bvh;
 mod camera;
 mod color;
+mod compare;
 mod config;
 mod consts;
 mod float;
 mod gl_renderer;
+mod gltf_load;
 mod index_ptr;
 mod input;
+mod instance;
 mod intersect;
 mod light;
 mod load;
 mod material;
+mod medium;
 mod mesh;
 mod obj_load;
 mod pt_renderer;
 mod sample;
+mod sampler;
 mod scattering;
 mod scene;
 mod stats;
@@ -33,24 +42,390 @@ mod triangle;
 mod util;
 mod vertex;
 
-use self::config::RenderConfig;
+use self::camera::Camera;
+use self::config::{RenderConfig, RenderMode, UpAxis};
+use self::float::*;
 use self::gl_renderer::GlRenderer;
 use self::input::InputState;
 use self::pt_renderer::PtRenderer;
+use self::scene::Scene;
+
+const USAGE: &str = "Usage: [--up-axis y|z] [--config <file>] \
+                     [hq | pt | comp | b | leaf | precision | render | diff | check | bvh]";
 
-// TODO: add comparison mode
 fn main() {
-    match std::env::args().nth(1).as_deref() {
-        Some("hq") => high_quality(),
-        Some("pt") => high_quality_pt(),
-        Some("comp") => compare(),
-        Some("b") => benchmark("bdpt", RenderConfig::bdpt_benchmark()),
-        Some(_) => benchmark("", RenderConfig::benchmark()),
-        None => online_render(),
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let up_axis_arg = parse_up_axis(&mut args).unwrap_or_else(|err| exit_with_usage(&err, USAGE));
+    let config_file =
+        parse_config_file(&mut args).unwrap_or_else(|err| exit_with_usage(&err, USAGE));
+    let up_axis = up_axis_arg.unwrap_or(UpAxis::Y);
+    match args.first().map(String::as_str) {
+        Some("hq") => high_quality(up_axis),
+        Some("pt") => high_quality_pt(up_axis),
+        Some("comp") => compare(up_axis),
+        Some("b") => benchmark("bdpt", RenderConfig::bdpt_benchmark(), up_axis),
+        Some("leaf") => leaf_size_benchmark(up_axis),
+        Some("precision") => precision_benchmark(up_axis),
+        Some("render") => match parse_render_args(&args[1..], config_file, up_axis_arg) {
+            Ok(args) => offline_render(&[&args.scene], "", &args.output_dir, args.config),
+            Err(err) => exit_with_usage(&err, RENDER_USAGE),
+        },
+        Some("diff") => match parse_diff_args(&args[1..], up_axis_arg) {
+            Ok(args) => diff(args),
+            Err(err) => exit_with_usage(&err, DIFF_USAGE),
+        },
+        Some("check") => match args.get(1) {
+            Some(scene) => check(scene, up_axis),
+            None => exit_with_usage("No scene given", CHECK_USAGE),
+        },
+        Some("bvh") => match (args.get(1), args.get(2).map(|depth| depth.parse())) {
+            (Some(scene), None) => export_bvh(scene, DEFAULT_EXPORT_DEPTH, up_axis),
+            (Some(scene), Some(Ok(depth))) => export_bvh(scene, depth, up_axis),
+            _ => exit_with_usage("Invalid arguments", BVH_USAGE),
+        },
+        Some(_) => benchmark("", RenderConfig::benchmark(), up_axis),
+        None => online_render(config_file, up_axis_arg),
+    }
+}
+
+/// Report invalid command line arguments and exit with an error
+fn exit_with_usage(err: &str, usage: &str) -> ! {
+    println!("{}\n{}", err, usage);
+    std::process::exit(1);
+}
+
+/// Parse and remove the --up-axis {y, z} option from args
+fn parse_up_axis(args: &mut Vec<String>) -> Result<Option<UpAxis>, String> {
+    let i = match args.iter().position(|arg| arg == "--up-axis") {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    args.remove(i);
+    if i >= args.len() {
+        return Err("--up-axis requires a value (y or z)".to_string());
+    }
+    match args.remove(i).to_lowercase().as_str() {
+        "y" => Ok(Some(UpAxis::Y)),
+        "z" => Ok(Some(UpAxis::Z)),
+        axis => Err(format!("Unknown up axis {}! Expected y or z.", axis)),
+    }
+}
+
+/// Parse and remove the --config <file> option from args
+fn parse_config_file(args: &mut Vec<String>) -> Result<Option<PathBuf>, String> {
+    let i = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    args.remove(i);
+    if i >= args.len() {
+        return Err("--config requires a file".to_string());
+    }
+    Ok(Some(PathBuf::from(args.remove(i))))
+}
+
+const RENDER_USAGE: &str = "Usage: render <scene name | scene file> [--config <file>] \
+                            [--mode pt|bdpt|ppm|debug] [--samples <samples per dir>] \
+                            [--resolution <width>x<height>] [--output <dir>] [--preview]";
+
+/// Offline render selected from the command line
+struct RenderArgs {
+    /// Library scene name or path to a scene file
+    scene: String,
+    config: RenderConfig,
+    output_dir: PathBuf,
+}
+
+/// Parse the arguments of the render command.
+/// The config is read from the config file if one is given and otherwise
+/// it is the benchmark config of the mode. Other flags override the config.
+fn parse_render_args(
+    args: &[String],
+    config_file: Option<PathBuf>,
+    up_axis: Option<UpAxis>,
+) -> Result<RenderArgs, String> {
+    let mut scene = None;
+    let mut mode = None;
+    let mut samples = None;
+    let mut resolution = None;
+    let mut output_dir = PathBuf::from("results");
+    let mut preview = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            if scene.replace(arg.clone()).is_some() {
+                return Err(format!("Unexpected argument {}", arg));
+            }
+            continue;
+        }
+        if arg == "--preview" {
+            preview = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} requires a value", arg))?;
+        match arg.as_str() {
+            "--mode" => mode = Some(value.as_str()),
+            "--samples" => {
+                let n = value
+                    .parse()
+                    .map_err(|_| format!("Invalid sample count {}", value))?;
+                samples = Some(n);
+            }
+            "--resolution" => {
+                let invalid = || format!("Invalid resolution {}, expected <width>x<height>", value);
+                let (width, height) = value.split_once('x').ok_or_else(invalid)?;
+                let width = width.parse().map_err(|_| invalid())?;
+                let height = height.parse().map_err(|_| invalid())?;
+                resolution = Some((width, height));
+            }
+            "--output" => output_dir = PathBuf::from(value),
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
     }
+    let scene = scene.ok_or("No scene given")?;
+    let mode_config = match mode.unwrap_or("pt") {
+        "pt" => RenderConfig::benchmark(),
+        "bdpt" => RenderConfig::bdpt_benchmark(),
+        "ppm" => RenderConfig {
+            render_mode: RenderMode::Ppm,
+            ..RenderConfig::benchmark()
+        },
+        "debug" => RenderConfig {
+            max_iterations: Some(1),
+            ..RenderConfig::debug_normals()
+        },
+        mode => {
+            return Err(format!(
+                "Unknown mode {}! Expected pt, bdpt, ppm or debug.",
+                mode
+            ))
+        }
+    };
+    let mut config = match config_file {
+        Some(path) => {
+            let mut config = RenderConfig::from_file(&path)
+                .map_err(|err| format!("Failed to load config {:?}: {}", path, err))?;
+            if mode.is_some() {
+                config.render_mode = mode_config.render_mode;
+            }
+            config
+        }
+        None => mode_config,
+    };
+    if let Some(up_axis) = up_axis {
+        config.up_axis = up_axis;
+    }
+    config.offline_preview |= preview;
+    if let Some(samples) = samples {
+        config.samples_per_dir = samples;
+    }
+    if let Some((width, height)) = resolution {
+        config.width = width;
+        config.height = height;
+    }
+    config.validate()?;
+    Ok(RenderArgs {
+        scene,
+        config,
+        output_dir,
+    })
 }
 
-fn compare() {
+const DIFF_USAGE: &str = "Usage: diff <preset | config file> <preset | config file> \
+                          <scene name | scene file> [--output <dir>]";
+
+/// Renders of a scene with two configs that are compared to each other
+struct DiffArgs {
+    /// Library scene name or path to a scene file
+    scene: String,
+    /// Labels and configs of the renders
+    configs: [(String, RenderConfig); 2],
+    output_dir: PathBuf,
+}
+
+/// Parse the arguments of the diff command
+fn parse_diff_args(args: &[String], up_axis: Option<UpAxis>) -> Result<DiffArgs, String> {
+    let mut positional = Vec::new();
+    let mut output_dir = PathBuf::from("results").join("diff");
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" => {
+                let value = args.next().ok_or("--output requires a value")?;
+                output_dir = PathBuf::from(value);
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ => positional.push(arg.as_str()),
+        }
+    }
+    let (config_a, config_b, scene) = match positional[..] {
+        [config_a, config_b, scene] => (config_a, config_b, scene),
+        _ => return Err("Expected two configs and a scene".to_string()),
+    };
+    // Configs are read from files if they exist and are presets otherwise
+    let load_config = |name: &str| -> Result<(String, RenderConfig), String> {
+        let path = Path::new(name);
+        let (label, mut config) = if path.is_file() {
+            let config = RenderConfig::from_file(path)
+                .map_err(|err| format!("Failed to load config {:?}: {}", path, err))?;
+            let label = path.file_stem().and_then(|stem| stem.to_str());
+            (label.unwrap_or(name).to_string(), config)
+        } else {
+            (name.to_string(), RenderConfig::preset(name)?)
+        };
+        if let Some(up_axis) = up_axis {
+            config.up_axis = up_axis;
+        }
+        // Differences are computed from the linear radiance
+        config.save_hdr = true;
+        config.hdr_metadata = false;
+        config.validate()?;
+        Ok((label, config))
+    };
+    let (label_a, config_a) = load_config(config_a)?;
+    let (label_b, config_b) = load_config(config_b)?;
+    if (config_a.width, config_a.height) != (config_b.width, config_b.height) {
+        return Err(format!(
+            "Configs have different resolutions {}x{} and {}x{}",
+            config_a.width, config_a.height, config_b.width, config_b.height
+        ));
+    }
+    Ok(DiffArgs {
+        scene: scene.to_string(),
+        configs: [(label_a, config_a), (label_b, config_b)],
+        output_dir,
+    })
+}
+
+/// Render a scene with two configs and save the renders side by side
+/// together with a heatmap of their differences
+fn diff(args: DiffArgs) {
+    let root_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let output_dir = root_dir.join(&args.output_dir);
+    let scene_label = Path::new(&args.scene)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(&args.scene);
+    // Tags are prefixed so that renders with the same label don't overwrite each other
+    let tags: Vec<String> = ["a", "b"]
+        .iter()
+        .zip(&args.configs)
+        .map(|(prefix, (label, _))| format!("{}_{}", prefix, label))
+        .collect();
+    for (tag, (_, config)) in tags.iter().zip(&args.configs) {
+        offline_render(&[&args.scene], tag, &output_dir, config.clone());
+    }
+    let render = |tag: &str, extension| {
+        output_dir
+            .join(format!("{}_{}", scene_label, tag))
+            .with_extension(extension)
+    };
+    let result = (|| -> Result<Float, Box<dyn Error>> {
+        let ldr_a = image::open(render(&tags[0], "png"))?.to_rgba8();
+        let ldr_b = image::open(render(&tags[1], "png"))?.to_rgba8();
+        compare::side_by_side(&ldr_a, &ldr_b)?
+            .save(output_dir.join(format!("{}_side_by_side.png", scene_label)))?;
+        let hdr_a = compare::load_hdr(&render(&tags[0], "hdr"))?;
+        let hdr_b = compare::load_hdr(&render(&tags[1], "hdr"))?;
+        compare::difference_heatmap(&hdr_a, &hdr_b)?
+            .save(output_dir.join(format!("{}_difference.png", scene_label)))?;
+        compare::relative_rmse(&hdr_b, &hdr_a)
+    })();
+    match result {
+        Ok(error) => {
+            let summary = format!(
+                "{}: relative RMSE of {} against {} is {:.4}\n",
+                scene_label, args.configs[1].0, args.configs[0].0, error
+            );
+            print!("{}", summary);
+            let stats_file = output_dir.join(format!("stats_diff_{}.txt", scene_label));
+            std::fs::write(stats_file, summary).unwrap();
+        }
+        Err(err) => println!("{}: failed to compare renders: {}", scene_label, err),
+    }
+}
+
+const BVH_USAGE: &str = "Usage: bvh <scene name | scene file> [max depth]";
+/// Deeper levels of large scenes are too dense to see anything
+const DEFAULT_EXPORT_DEPTH: usize = 8;
+
+/// Export the bvh of the scene as wireframe boxes to results/bvh
+fn export_bvh(scene_name: &str, max_depth: usize, up_axis: UpAxis) {
+    let path = load::scene_path(scene_name);
+    stats::new_scene(scene_name);
+    let mut config = RenderConfig::benchmark();
+    config.up_axis = up_axis;
+    let scene = scene::SceneBuilder::new(&config)
+        .build(&path)
+        .unwrap_or_else(|err| panic!("Failed to load {:?}: {}", path, err));
+    let bvh = match scene.bvh() {
+        Some(bvh) => bvh,
+        None => {
+            println!("{:?} only contains instanced triangles", path);
+            return;
+        }
+    };
+    let output_dir = PathBuf::from("results").join("bvh");
+    std::fs::create_dir_all(output_dir.clone()).unwrap();
+    let label = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(scene_name);
+    let obj_path = output_dir.join(format!("{}_bvh.obj", label));
+    match bvh.export_obj(&obj_path, max_depth) {
+        Ok(()) => println!("Exported the bvh of {:?} to {:?}", path, obj_path),
+        Err(err) => println!("Failed to export the bvh to {:?}: {}", obj_path, err),
+    }
+}
+
+const CHECK_USAGE: &str = "Usage: check <scene name | scene file>";
+
+/// Load a scene without rendering it and report any problems.
+/// Exits with an error code if the scene has problems.
+fn check(scene_name: &str, up_axis: UpAxis) {
+    let path = load::scene_path(scene_name);
+    stats::new_scene(scene_name);
+    let obj = match load::load_object(&path) {
+        Ok(obj) => obj,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let mut report = obj_load::check(&obj);
+    // The scene build would fail with the same errors
+    if report.errors.is_empty() {
+        let mut config = RenderConfig::benchmark();
+        config.up_axis = up_axis;
+        match scene::SceneBuilder::new(&config).build(&path) {
+            Ok(scene) => println!(
+                "{:?}: {} triangles, {} materials",
+                path,
+                scene.n_triangles(),
+                scene.n_materials()
+            ),
+            Err(err) => report.errors.push(err.to_string()),
+        }
+    }
+    for error in &report.errors {
+        println!("Error: {}", error);
+    }
+    for warning in &report.warnings {
+        println!("Warning: {}", warning);
+    }
+    println!(
+        "{} errors, {} warnings",
+        report.errors.len(),
+        report.warnings.len()
+    );
+    if !report.errors.is_empty() || !report.warnings.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+fn compare(up_axis: UpAxis) {
     let scenes = [
         "cornell-sphere",
         "cornell-glossy",
@@ -60,12 +435,14 @@ fn compare() {
         "sponza",
     ];
     let mut config = RenderConfig::benchmark();
+    config.up_axis = up_axis;
     config.samples_per_dir *= 4;
     config.width /= 2;
     config.height /= 2;
     let output_dir = PathBuf::from("results").join("compare");
     offline_render(&scenes, "pt", &output_dir, config);
     config = RenderConfig::bdpt_benchmark();
+    config.up_axis = up_axis;
     config.samples_per_dir *= 4;
     config.width /= 2;
     config.height /= 2;
@@ -74,8 +451,7 @@ fn compare() {
     offline_render(&scenes, "no_mis", &output_dir, config);
 }
 
-fn high_quality_pt() {
-    // TODO: Add command line switches to select scenes and config settings
+fn high_quality_pt(up_axis: UpAxis) {
     let scenes = [
         // "cornell-sphere",
         // "cornell-glossy",
@@ -85,13 +461,13 @@ fn high_quality_pt() {
         // "sponza",
     ];
     let tag = "pt_hq";
-    let config = RenderConfig::high_quality_pt();
+    let mut config = RenderConfig::high_quality_pt();
+    config.up_axis = up_axis;
     let output_dir = PathBuf::from("results").join("hq");
     offline_render(&scenes, tag, &output_dir, config);
 }
 
-fn high_quality() {
-    // TODO: Add command line switches to select scenes and config settings
+fn high_quality(up_axis: UpAxis) {
     let scenes = [
         // "cornell-sphere",
         // "cornell-glossy",
@@ -101,12 +477,14 @@ fn high_quality() {
         // "sponza",
     ];
     let tag = "hq";
-    let config = RenderConfig::high_quality();
+    let mut config = RenderConfig::high_quality();
+    config.up_axis = up_axis;
     let output_dir = PathBuf::from("results").join("hq");
     offline_render(&scenes, tag, &output_dir, config);
 }
 
-fn benchmark(tag: &str, config: RenderConfig) {
+fn benchmark(tag: &str, mut config: RenderConfig, up_axis: UpAxis) {
+    config.up_axis = up_axis;
     let scenes = [
         "cornell-sphere",
         "cornell-glossy",
@@ -119,7 +497,81 @@ fn benchmark(tag: &str, config: RenderConfig) {
     offline_render(&scenes, tag, &output_dir, config);
 }
 
+/// Benchmark the bvh leaf sizes.
+/// Stats of all the leaf sizes accumulate to the final stats table.
+fn leaf_size_benchmark(up_axis: UpAxis) {
+    let scenes = ["conference", "sponza"];
+    let output_dir = PathBuf::from("results").join("leaf_size");
+    for &leaf_size in &[2, 4, 8, 16] {
+        let mut config = RenderConfig::benchmark();
+        config.up_axis = up_axis;
+        config.bvh_max_leaf_size = leaf_size;
+        offline_render(&scenes, &format!("leaf{}", leaf_size), &output_dir, config);
+    }
+}
+
+/// Relative RMSE below which single and double precision renders are considered equivalent.
+/// Renders of different precisions use different random samples so this needs to
+/// allow for the noise of the benchmark config.
+const PRECISION_TOLERANCE: Float = 0.05;
+
+/// Render the benchmark scenes and compare them to the renders of the other precision.
+/// Needs to be run both with and without the single_precision feature.
+fn precision_benchmark(up_axis: UpAxis) {
+    let scenes = ["cornell-sphere", "cornell-glossy", "indirect", "conference"];
+    let (precision, other) = if cfg!(feature = "single_precision") {
+        ("f32", "f64")
+    } else {
+        ("f64", "f32")
+    };
+    let mut config = RenderConfig::benchmark();
+    config.up_axis = up_axis;
+    config.save_hdr = true;
+    config.hdr_metadata = false;
+    let output_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("results")
+        .join("precision");
+    offline_render(
+        &scenes,
+        &format!("precision_{}", precision),
+        &output_dir,
+        config,
+    );
+    for scene_name in &scenes {
+        let image =
+            |precision| output_dir.join(format!("{}_precision_{}.hdr", scene_name, precision));
+        let other_image = image(other);
+        if !other_image.exists() {
+            println!("{}: render with {} to compare", scene_name, other);
+            continue;
+        }
+        let error = compare::load_hdr(&image(precision))
+            .and_then(|image| compare::relative_rmse(&image, &compare::load_hdr(&other_image)?));
+        match error {
+            Ok(error) => println!(
+                "{}: relative RMSE between {} and {} is {:.4} ({} tolerance {})",
+                scene_name,
+                precision,
+                other,
+                error,
+                if error <= PRECISION_TOLERANCE {
+                    "within"
+                } else {
+                    "exceeds"
+                },
+                PRECISION_TOLERANCE,
+            ),
+            Err(err) => println!("{}: failed to compare renders: {}", scene_name, err),
+        }
+    }
+}
+
 fn offline_render(scenes: &[&str], tag: &str, output_dir: &Path, config: RenderConfig) {
+    // Scenes are built with the config so check it before loading any
+    if let Err(err) = config.validate() {
+        println!("Invalid render config: {}", err);
+        std::process::exit(1);
+    }
     let tag = if tag.is_empty() {
         tag.to_string()
     } else {
@@ -131,54 +583,221 @@ fn offline_render(scenes: &[&str], tag: &str, output_dir: &Path, config: RenderC
     let time_stamp = Local::now().format("%F_%H%M%S").to_string();
 
     // Initialize an OpenGL context that is needed for post-processing
-    let events_loop = glium::glutin::event_loop::EventLoop::new();
+    let mut events_loop = glium::glutin::event_loop::EventLoop::new();
     // Preferably this wouldn't need use a window at all but alas this is the closest I have gotten.
     // There exists HeadlessContext but that still pops up a window (atleast on Windows).
-    // TODO: Maybe change this such that the window displays the current render?
-    let window = glium::glutin::window::WindowBuilder::new()
-        .with_inner_size(glium::glutin::dpi::LogicalSize::new(0.0, 0.0))
-        .with_visible(false)
-        .with_decorations(false)
-        .with_title("Rusty");
+    let window = if config.offline_preview {
+        glium::glutin::window::WindowBuilder::new()
+            .with_inner_size(config.dimensions())
+            .with_resizable(false)
+    } else {
+        glium::glutin::window::WindowBuilder::new()
+            .with_inner_size(glium::glutin::dpi::LogicalSize::new(1.0, 1.0))
+            .with_visible(false)
+            .with_decorations(false)
+    }
+    .with_title("Rusty");
     let context = glium::glutin::ContextBuilder::new();
     let display = glium::Display::new(window, context, &events_loop).unwrap();
+    let encode_srgb = gl_renderer::needs_srgb_encoding(&display);
+    let mut window_closed = false;
 
     for scene_name in scenes {
         stats::new_scene(scene_name);
         let _t = stats::time("Total");
         println!("{}...", scene_name);
-        let (scene, camera) = load::cpu_scene_from_name(scene_name, &config);
-        let pt_renderer = PtRenderer::offline_render(&display, &scene, &camera, &config);
-
-        stats::time("Post-process");
-        let scene_prefix = format!("{}{}", scene_name, tag);
+        let (scene, camera) = match load::cpu_scene_from_name(scene_name, &config) {
+            Ok(res) => res,
+            Err(err) => {
+                println!("Skipping {}: {}", scene_name, err);
+                continue;
+            }
+        };
+        // Obj files are named after the file
+        let scene_label = Path::new(scene_name)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or(scene_name);
+        let scene_prefix = format!("{}{}", scene_label, tag);
         let scene_dir = output_dir.join(&scene_prefix);
         std::fs::create_dir_all(scene_dir.clone()).unwrap();
+        let frame_dir = if config.convergence_frames {
+            let frame_dir = scene_dir.join(format!("convergence_{}", time_stamp));
+            std::fs::create_dir_all(frame_dir.clone()).unwrap();
+            Some(frame_dir)
+        } else {
+            None
+        };
+        // Draw the partial render and stop if the window has been closed
+        let mut preview = |renderer: &mut PtRenderer| {
+            events_loop.run_return(|event, _, control_flow| {
+                // Only handle the pending events
+                *control_flow = ControlFlow::Exit;
+                if let Event::WindowEvent {
+                    event: WindowEvent::CloseRequested,
+                    ..
+                } = event
+                {
+                    window_closed = true;
+                }
+            });
+            let mut target = display.draw();
+            target.clear_color(0.0, 0.0, 0.0, 1.0);
+            renderer.render_image(&mut target, encode_srgb);
+            target.finish().unwrap();
+            !window_closed
+        };
+        let mut pt_renderer = match PtRenderer::offline_render(
+            &display,
+            &scene,
+            &camera,
+            &config,
+            frame_dir.as_deref().map(|dir| (dir, "conv_")),
+            if config.offline_preview {
+                Some(&mut preview)
+            } else {
+                None
+            },
+        ) {
+            Ok(renderer) => renderer,
+            Err(err) => {
+                println!("Invalid render config: {}", err);
+                std::process::exit(1);
+            }
+        };
+
+        stats::time("Post-process");
         let timestamped_image = scene_dir.join(format!("{}_{}.png", scene_prefix, time_stamp));
         pt_renderer.save_image(&display, &timestamped_image);
+        if config.adaptive_threshold.is_some() {
+            let sample_map = scene_dir.join(format!("{}_{}_samples.png", scene_prefix, time_stamp));
+            pt_renderer.save_sample_map(&sample_map);
+        }
         // Make a copy to the main output directory
-        let default_image = output_dir.join(scene_prefix).with_extension("png");
-        std::fs::copy(timestamped_image, default_image).unwrap();
+        let default_image = output_dir.join(&scene_prefix).with_extension("png");
+        std::fs::copy(&timestamped_image, default_image).unwrap();
+        if config.save_hdr {
+            let hdr_image = timestamped_image.with_extension("hdr");
+            pt_renderer.save_hdr(&hdr_image, config.hdr_metadata);
+            let default_hdr = output_dir.join(&scene_prefix).with_extension("hdr");
+            std::fs::copy(hdr_image, default_hdr).unwrap();
+        }
+        if config.aovs {
+            let albedo = scene_dir.join(format!("{}_{}_albedo.pfm", scene_prefix, time_stamp));
+            pt_renderer.save_albedo(&albedo);
+            let normal = scene_dir.join(format!("{}_{}_normal.pfm", scene_prefix, time_stamp));
+            pt_renderer.save_normal(&normal);
+        }
+        // Report the error if a reference render of the scene exists
+        let reference_dir = root_dir.join("results").join("reference");
+        let reference = ["hdr", "png"]
+            .iter()
+            .map(|extension| reference_dir.join(scene_label).with_extension(extension))
+            .find(|path| path.is_file());
+        if let Some(reference) = reference {
+            let error = compare::load_reference(&reference)
+                .and_then(|reference| pt_renderer.error(&reference));
+            match error {
+                Ok(error) => {
+                    println!(
+                        "{}: RMSE {:.4}, relMSE {:.4} against {:?}",
+                        scene_name, error.rmse, error.rel_mse, reference
+                    );
+                    stats::set_error(error);
+                }
+                Err(err) => println!(
+                    "{}: failed to compare to {:?}: {}",
+                    scene_name, reference, err
+                ),
+            }
+        }
+        // Partial render is still saved but the remaining scenes are skipped
+        if window_closed {
+            println!("Preview window closed, stopping the render.");
+            break;
+        }
     }
     let stats_dir = output_dir.join(format!("stats{}", tag));
     std::fs::create_dir_all(stats_dir.clone()).unwrap();
     let stats_file = stats_dir.join(format!("stats{}_{}.txt", tag, time_stamp));
-    // TODO: add config to stats
-    stats::print_and_save(&stats_file);
+    stats::print_and_save(&stats_file, &config);
+    // Renders can be reproduced with the render command
+    let config_file = stats_dir.join(format!("config{}_{}.cfg", tag, time_stamp));
+    std::fs::write(config_file, config.to_file_string()).unwrap();
 }
 
-fn online_render() {
-    let mut config = RenderConfig::bdpt();
+/// Start path tracing or report why it couldn't be started
+fn start_render(
+    display: &glium::Display,
+    scene: &Arc<Scene>,
+    camera: &Camera,
+    config: &RenderConfig,
+) -> Option<PtRenderer> {
+    match PtRenderer::start_render(display, scene, camera, config) {
+        Ok(mut renderer) => {
+            if config.convergence_frames {
+                let time_stamp = Local::now().format("%F_%H%M%S").to_string();
+                let frame_dir = PathBuf::from("results")
+                    .join("online")
+                    .join(format!("convergence_{}", time_stamp));
+                std::fs::create_dir_all(frame_dir.clone()).unwrap();
+                renderer.snapshot_to(&frame_dir, "conv_");
+            }
+            let display = display.clone();
+            renderer.set_progress_callback(move |progress| {
+                let title = format!("Rusty - {}", progress);
+                display.gl_window().window().set_title(&title);
+            });
+            Some(renderer)
+        }
+        Err(err) => {
+            println!("Invalid render config: {}", err);
+            None
+        }
+    }
+}
+
+fn online_render(config_file: Option<PathBuf>, up_axis: Option<UpAxis>) {
+    let mut config = match &config_file {
+        Some(path) => RenderConfig::from_file(path).unwrap_or_else(|err| {
+            println!("Failed to load config {:?}: {}", path, err);
+            std::process::exit(1);
+        }),
+        None => RenderConfig::bdpt(),
+    };
+    if let Some(up_axis) = up_axis {
+        config.up_axis = up_axis;
+    }
+    // Explicitly given config takes precedence over the scene specific settings
+    let use_scene_patches = config_file.is_none();
+    let patch_from_key = move |key| {
+        if use_scene_patches {
+            load::config_patch_from_key(key)
+        } else {
+            &[]
+        }
+    };
     let events_loop = glium::glutin::event_loop::EventLoop::new();
     let window = glium::glutin::window::WindowBuilder::new()
         .with_inner_size(config.dimensions())
-        .with_resizable(false); // TODO: enable resizing
-    let context = glium::glutin::ContextBuilder::new().with_depth_buffer(24);
+        .with_resizable(true)
+        .with_title("Rusty");
+    let context = glium::glutin::ContextBuilder::new()
+        .with_depth_buffer(24)
+        .with_srgb(config.srgb_framebuffer);
     let display =
         glium::Display::new(window, context, &events_loop).expect("Failed to create display");
+    // Make sure that the preview matches the saved images regardless of the framebuffer
+    let encode_srgb = gl_renderer::needs_srgb_encoding(&display);
+    if encode_srgb {
+        println!("Framebuffer is linear. Applying sRGB encoding in the shaders.");
+    }
 
+    let mut scene_patch = patch_from_key(VirtualKeyCode::Key1);
     let (mut scene, mut gpu_scene, mut camera) =
-        load::gpu_scene_from_key(&display, VirtualKeyCode::Key1, &config).unwrap();
+        load::gpu_scene_from_key(&display, VirtualKeyCode::Key1, &config.patched(scene_patch))
+            .expect("No scene bound to key 1")
+            .unwrap_or_else(|err| panic!("Failed to load the default scene: {}", err));
     let gl_renderer = GlRenderer::new(&display);
     let mut pt_renderer: Option<PtRenderer> = None;
 
@@ -186,13 +805,15 @@ fn online_render() {
     let mut last_frame = Instant::now();
 
     events_loop.run(move |event, _window_target, control_flow| {
+        // Set when the accumulated image no longer matches the scene
+        let mut reset_render = false;
         let mut target = display.draw();
         target.clear_color_and_depth((0.0, 0.0, 0.0, 1.0), 1.0);
         if let Some(renderer) = &mut pt_renderer {
-            renderer.update_image();
-            renderer.render_image(&display, &mut target);
+            renderer.update_image(&display);
+            renderer.render_image(&mut target, encode_srgb);
         } else {
-            gl_renderer.render(&mut target, &gpu_scene, &camera);
+            gl_renderer.render(&mut target, &gpu_scene, &camera, encode_srgb);
         }
         target.finish().unwrap();
 
@@ -209,9 +830,10 @@ fn online_render() {
                 } => {
                     if pt_renderer.is_some() {
                         pt_renderer = None;
+                        display.gl_window().window().set_title("Rusty");
                     } else {
                         pt_renderer =
-                            Some(PtRenderer::start_render(&display, &scene, &camera, &config));
+                            start_render(&display, &scene, &camera, &config.patched(scene_patch));
                     }
                 }
                 KeyboardInput {
@@ -219,22 +841,63 @@ fn online_render() {
                     virtual_keycode: Some(VirtualKeyCode::C),
                     ..
                 } => println!("camera: {:?}", camera.pos),
+                KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(VirtualKeyCode::R),
+                    ..
+                } => reset_render = true,
+                KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(VirtualKeyCode::X),
+                    ..
+                } => {
+                    // Exposure is only a post-process so the render can continue
+                    match pt_renderer.as_mut().and_then(PtRenderer::auto_exposure) {
+                        Some(exposure) => {
+                            config.exposure = exposure;
+                            println!("Exposure: {:.2} EV", exposure);
+                        }
+                        None => println!("Auto exposure needs a path traced image!"),
+                    }
+                }
                 KeyboardInput {
                     state: ElementState::Pressed,
                     virtual_keycode: Some(keycode),
                     ..
                 } => {
-                    if pt_renderer.is_none() {
-                        if let Some(res) = load::gpu_scene_from_key(&display, keycode, &config) {
+                    let patch = patch_from_key(keycode);
+                    match load::gpu_scene_from_key(&display, keycode, &config.patched(patch)) {
+                        Some(Ok(res)) => {
+                            scene_patch = patch;
                             scene = res.0;
                             gpu_scene = res.1;
                             camera = res.2;
+                            reset_render = true;
                         }
-                        config.handle_key(keycode);
+                        Some(Err(err)) => println!("Failed to load scene: {}", err),
+                        None => (),
                     }
+                    let old_config = config.clone();
+                    config.handle_key(keycode);
+                    reset_render |= config != old_config;
                 }
                 _ => (),
             },
+            Event::WindowEvent {
+                event: WindowEvent::Resized(size),
+                ..
+            } => {
+                let scale_factor = display.gl_window().window().scale_factor();
+                let size = size.to_logical::<u32>(scale_factor);
+                // Minimized windows report zero size, keep the old dimensions for those
+                if size.width > 0 && size.height > 0 {
+                    config.width = size.width;
+                    config.height = size.height;
+                    // Viewport change restarts the render with the new dimensions
+                    camera.update_viewport(config.dimensions(), config.pixel_aspect_ratio);
+                    reset_render = true;
+                }
+            }
             Event::WindowEvent {
                 event: WindowEvent::CloseRequested,
                 ..
@@ -243,22 +906,30 @@ fn online_render() {
                 event: WindowEvent::DroppedFile(path),
                 ..
             } => {
-                if pt_renderer.is_none() {
-                    // TODO: don't crash on bad scenes
-                    if let Some(res) = load::gpu_scene_from_path(&display, &path, &config) {
+                // Keep the previous scene if the new one can't be loaded
+                match load::gpu_scene_from_path(&display, &path, &config) {
+                    Ok(res) => {
+                        scene_patch = &[];
                         scene = res.0;
                         gpu_scene = res.1;
                         camera = res.2;
+                        reset_render = true;
                         // TODO: would be nice if this grabbed the focus
                     }
+                    Err(err) => println!("Failed to load scene: {}", err),
                 }
             }
             _ => (),
         }
-        if pt_renderer.is_none() {
-            camera.process_input(&input);
-        }
+        let old_camera = camera.clone();
+        camera.process_input(&input);
+        reset_render |= camera != old_camera;
         input.reset_deltas();
+        if reset_render && pt_renderer.is_some() {
+            // Stop the old render before starting a new one
+            pt_renderer = None;
+            pt_renderer = start_render(&display, &scene, &camera, &config.patched(scene_patch));
+        }
         // Limit frame rate
         let frame_time = Duration::from_millis(5);
         let elapsed = last_frame.elapsed();
@@ -269,3 +940,83 @@ fn online_render() {
         last_frame = Instant::now();
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn up_axis_is_removed_from_args() {
+        let mut args = to_args(&["render", "--up-axis", "Z", "cornell"]);
+        assert_eq!(parse_up_axis(&mut args), Ok(Some(UpAxis::Z)));
+        assert_eq!(args, to_args(&["render", "cornell"]));
+        assert_eq!(parse_up_axis(&mut args), Ok(None));
+    }
+
+    #[test]
+    fn invalid_global_options() {
+        assert!(parse_up_axis(&mut to_args(&["--up-axis"])).is_err());
+        assert!(parse_up_axis(&mut to_args(&["--up-axis", "x"])).is_err());
+        assert!(parse_config_file(&mut to_args(&["--config"])).is_err());
+        let mut args = to_args(&["--config", "test.cfg", "render"]);
+        assert_eq!(
+            parse_config_file(&mut args),
+            Ok(Some(PathBuf::from("test.cfg")))
+        );
+        assert_eq!(args, to_args(&["render"]));
+    }
+
+    #[test]
+    fn render_flags_map_to_config() {
+        let args = to_args(&[
+            "cornell",
+            "--mode",
+            "bdpt",
+            "--samples",
+            "7",
+            "--resolution",
+            "320x240",
+            "--output",
+            "out",
+            "--preview",
+        ]);
+        let args = parse_render_args(&args, None, Some(UpAxis::Z)).unwrap();
+        assert_eq!(args.scene, "cornell");
+        assert_eq!(args.output_dir, PathBuf::from("out"));
+        let expected = RenderConfig {
+            samples_per_dir: 7,
+            width: 320,
+            height: 240,
+            offline_preview: true,
+            up_axis: UpAxis::Z,
+            ..RenderConfig::bdpt_benchmark()
+        };
+        assert_eq!(args.config, expected);
+    }
+
+    #[test]
+    fn render_defaults_to_path_tracing_benchmark() {
+        let args = parse_render_args(&to_args(&["scene.obj"]), None, None).unwrap();
+        assert_eq!(args.scene, "scene.obj");
+        assert_eq!(args.output_dir, PathBuf::from("results"));
+        assert_eq!(args.config, RenderConfig::benchmark());
+    }
+
+    #[test]
+    fn invalid_render_args() {
+        let parse = |args: &[&str]| parse_render_args(&to_args(args), None, None).is_err();
+        assert!(parse(&[]));
+        assert!(parse(&["a", "b"]));
+        assert!(parse(&["cornell", "--mode", "fast"]));
+        assert!(parse(&["cornell", "--samples"]));
+        assert!(parse(&["cornell", "--samples", "many"]));
+        assert!(parse(&["cornell", "--resolution", "320"]));
+        assert!(parse(&["cornell", "--unknown", "1"]));
+        // Configs are validated after the flags are applied
+        assert!(parse(&["cornell", "--resolution", "0x240"]));
+    }
+}
@@ -7,15 +7,23 @@ use crate::bsdf::Bsdf;
 use crate::color::Color;
 use crate::float::*;
 use crate::obj_load;
+use crate::sampler;
 use crate::scattering::Scattering;
-use crate::texture::{self, NormalMap};
+use crate::texture::{self, NormalMap, OpacityMap, Texture};
 
 /// Material for CPU rendering
 #[derive(Debug)]
 pub struct Material {
     scattering: Scattering,
     normal_map: Option<NormalMap>,
-    pub emissive: Option<Color>,
+    opacity_map: Option<OpacityMap>,
+    /// Emission from map_Ke or a nonzero Ke regardless of the illumination model.
+    /// Triangles with emission become area lights of the scene.
+    pub emissive: Option<Texture>,
+    /// Emission is not limited to the side of the geometric normal
+    pub two_sided: bool,
+    /// Absorption coefficient of the medium behind the surface
+    pub absorption: Option<Color>,
 }
 
 /// Material for GPU rendering
@@ -24,27 +32,35 @@ pub struct GpuMaterial {
     pub is_emissive: bool,
 }
 
-// TODO: handle opaqueness_texture
 impl Material {
     /// Create a new material based on a material loaded from the scene file
     pub fn new(obj_mat: &obj_load::Material) -> Material {
         let scattering = Scattering::from_obj(obj_mat);
-        // TODO: handle emissive textures
-        let emissive = obj_mat.emissive_color.and_then(|e| {
-            if e == [0.0, 0.0, 0.0] {
-                None
-            } else {
-                Some(Color::from(e))
-            }
-        });
+        let emissive = match &obj_mat.emissive_texture {
+            Some(map) => Some(Texture::from_map(map)),
+            None => obj_mat.emissive_color.and_then(|e| {
+                if e == [0.0, 0.0, 0.0] {
+                    None
+                } else {
+                    Some(Texture::from_color(Color::from(e)))
+                }
+            }),
+        };
         let normal_map = obj_mat
             .bump_map
             .as_ref()
-            .map(|path| texture::load_normal_map(path));
+            .map(|map| texture::load_normal_map(&map.path));
+        let opacity_map = obj_mat
+            .opaqueness_texture
+            .as_ref()
+            .map(|map| texture::load_opacity_map(&map.path));
         Material {
             scattering,
             normal_map,
+            opacity_map,
             emissive,
+            two_sided: obj_mat.two_sided,
+            absorption: obj_mat.absorption.map(Color::from),
         }
     }
 
@@ -58,11 +74,39 @@ impl Material {
         }
     }
 
-    pub fn bsdf(&self, tex_coords: Point2<Float>) -> Bsdf {
-        self.scattering.local(tex_coords)
+    /// Get the bsdf at tex_coords. Footprint is the width of the
+    /// sampled area in texture coordinates, which is used for texture filtering.
+    /// Untextured diffuse materials are tinted by the vertex color.
+    pub fn bsdf(&self, tex_coords: Point2<Float>, footprint: Float, vertex_color: Color) -> Bsdf {
+        match &self.scattering {
+            Scattering::Dr(dr) => dr.local_tinted(tex_coords, footprint, vertex_color),
+            scattering => scattering.local(tex_coords, footprint),
+        }
+    }
+
+    pub fn has_opacity_map(&self) -> bool {
+        self.opacity_map.is_some()
+    }
+
+    /// Stochastically decide whether a hit at tex_coords passes through the surface.
+    /// The probability of passing through is 1 - opacity.
+    pub fn is_cut_out(&self, tex_coords: Point2<Float>) -> bool {
+        match &self.opacity_map {
+            Some(map) => sampler::random() >= map.opacity(tex_coords),
+            None => false,
+        }
     }
 
     pub fn normal(&self, tex_coords: Point2<Float>) -> Option<Vector3<Float>> {
         self.normal_map.as_ref().map(|map| map.normal(tex_coords))
     }
+
+    /// Approximate memory used by the material including its decoded textures
+    pub fn size_bytes(&self) -> usize {
+        std::mem::size_of::<Material>()
+            + self.scattering.texture_bytes()
+            + self.normal_map.as_ref().map_or(0, NormalMap::size_bytes)
+            + self.opacity_map.as_ref().map_or(0, OpacityMap::size_bytes)
+            + self.emissive.as_ref().map_or(0, Texture::size_bytes)
+    }
 }
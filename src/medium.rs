@@ -0,0 +1,182 @@
+use cgmath::prelude::*;
+use cgmath::{Point3, Vector3};
+
+use crate::config::RenderConfig;
+use crate::consts;
+use crate::float::*;
+use crate::intersect::{Ray, Receiver};
+use crate::sample;
+use crate::sampler;
+
+/// Homogeneous participating medium that fills the whole scene
+#[derive(Clone, Debug)]
+pub struct Medium {
+    /// Absorption coefficient
+    sigma_a: Float,
+    /// Scattering coefficient
+    sigma_s: Float,
+    /// Henyey-Greenstein asymmetry parameter
+    g: Float,
+}
+
+impl Medium {
+    pub fn new(sigma_a: Float, sigma_s: Float, g: Float) -> Self {
+        Self {
+            sigma_a,
+            sigma_s,
+            g: g.clamp(-0.99, 0.99),
+        }
+    }
+
+    /// Fog of the config or None if the config has no fog
+    pub fn from_config(config: &RenderConfig) -> Option<Self> {
+        if config.fog_density <= 0.0 {
+            return None;
+        }
+        let albedo = config.fog_albedo.clamp(0.0, 1.0);
+        let sigma_s = albedo * config.fog_density;
+        let sigma_a = config.fog_density - sigma_s;
+        Some(Self::new(sigma_a, sigma_s, config.fog_g))
+    }
+
+    /// Extinction coefficient
+    fn sigma_t(&self) -> Float {
+        self.sigma_a + self.sigma_s
+    }
+
+    /// Fraction of the extinction that is scattering
+    pub fn albedo(&self) -> Float {
+        self.sigma_s / self.sigma_t()
+    }
+
+    /// Fraction of light that passes through the medium for dist
+    pub fn transmittance(&self, dist: Float) -> Float {
+        (-self.sigma_t() * dist).exp()
+    }
+
+    /// Sample a free-flight distance proportionally to the transmittance.
+    /// Return the distance if the flight ends before max_dist or None if the
+    /// ray reaches max_dist. The transmittance cancels out with the pdf in both cases
+    /// so only the albedo needs to be applied to paths that scatter in the medium.
+    pub fn sample_distance(&self, max_dist: Float) -> Option<Float> {
+        let dist = -(1.0 - sampler::random()).ln() / self.sigma_t();
+        if dist < max_dist {
+            Some(dist)
+        } else {
+            None
+        }
+    }
+
+    /// Henyey-Greenstein phase function for light travelling in dir
+    /// that scatters to wi. Doubles as the solid angle pdf of sample_phase.
+    pub fn phase(&self, dir: Vector3<Float>, wi: Vector3<Float>) -> Float {
+        let cos_t = dir.dot(wi);
+        let denom = 1.0 + self.g.powi(2) - 2.0 * self.g * cos_t;
+        (1.0 - self.g.powi(2)) / (4.0 * consts::PI * denom * denom.sqrt())
+    }
+
+    /// Sample the scattered direction of light travelling in dir.
+    /// Return the direction and its pdf which equals the phase function.
+    pub fn sample_phase(&self, dir: Vector3<Float>) -> (Vector3<Float>, Float) {
+        let r = sampler::random();
+        let cos_t = if self.g.abs() < 1e-3 {
+            1.0 - 2.0 * r
+        } else {
+            let sq = (1.0 - self.g.powi(2)) / (1.0 + self.g - 2.0 * self.g * r);
+            (1.0 + self.g.powi(2) - sq.powi(2)) / (2.0 * self.g)
+        };
+        let cos_t = cos_t.clamp(-1.0, 1.0);
+        let sin_t = (1.0 - cos_t.powi(2)).sqrt();
+        let phi = 2.0 * consts::PI * sampler::random();
+        let local = Vector3::new(sin_t * phi.cos(), sin_t * phi.sin(), cos_t);
+        let wi = (sample::local_to_world(dir) * local).normalize();
+        (wi, self.phase(dir, wi))
+    }
+}
+
+/// Scattering event inside a medium
+pub struct MediumPoint {
+    pub p: Point3<Float>,
+}
+
+impl Receiver for MediumPoint {
+    fn pos(&self) -> Point3<Float> {
+        self.p
+    }
+
+    /// Nothing to self intersect so rays start from the point itself
+    fn ray(&self, dir: Vector3<Float>) -> Ray {
+        Ray::from_dir(self.p, dir)
+    }
+
+    fn shadow_ray(&self, to: Point3<Float>) -> Ray {
+        Ray::shadow(self.p, to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fog_from_config() {
+        let config = RenderConfig::bdpt().patched(&[("fog_density", "0.5"), ("fog_albedo", "0.8")]);
+        let medium = Medium::from_config(&config).unwrap();
+        assert!((medium.sigma_t() - 0.5).abs() < 1e-6);
+        assert!((medium.albedo() - 0.8).abs() < 1e-6);
+        assert!((medium.transmittance(2.0) - (-1.0 as Float).exp()).abs() < 1e-6);
+        assert!(Medium::from_config(&RenderConfig::bdpt()).is_none());
+    }
+
+    #[test]
+    fn phase_is_normalized() {
+        let dir = Vector3::unit_z();
+        let n = 2000usize;
+        for &g in &[0.0, 0.7, -0.3] {
+            let medium = Medium::new(0.0, 1.0, g);
+            // Midpoint rule over cos_t, the phase function is symmetric in phi
+            let integral: Float = (0..n)
+                .map(|i| {
+                    let cos_t = -1.0 + 2.0 * (i.to_float() + 0.5) / n.to_float();
+                    let wi = Vector3::new((1.0 - cos_t.powi(2)).sqrt(), 0.0, cos_t);
+                    2.0 * consts::PI * medium.phase(dir, wi) * 2.0 / n.to_float()
+                })
+                .sum();
+            assert!((integral - 1.0).abs() < 1e-3, "{} {}", g, integral);
+        }
+    }
+
+    #[test]
+    fn sampled_phase_has_mean_cosine_g() {
+        sampler::seed(5);
+        let dir = Vector3::new(1.0, 2.0, -0.5).normalize();
+        let n = 100_000usize;
+        for &g in &[0.0, 0.7, -0.3] {
+            let medium = Medium::new(0.0, 1.0, g);
+            let mut sum = 0.0;
+            for _ in 0..n {
+                let (wi, pdf) = medium.sample_phase(dir);
+                assert!((pdf - medium.phase(dir, wi)).abs() < 1e-6);
+                sum += dir.dot(wi);
+            }
+            let mean = sum / n.to_float();
+            assert!((mean - g).abs() < 0.01, "{} {}", g, mean);
+        }
+    }
+
+    #[test]
+    fn sampled_distances_follow_transmittance() {
+        sampler::seed(6);
+        let medium = Medium::new(0.5, 1.5, 0.0);
+        let n = 100_000usize;
+        let escaped = (0..n)
+            .filter(|_| medium.sample_distance(1.0).is_none())
+            .count();
+        let fraction = escaped.to_float() / n.to_float();
+        assert!(
+            (fraction - medium.transmittance(1.0)).abs() < 0.01,
+            "{}",
+            fraction
+        );
+    }
+}
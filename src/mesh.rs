@@ -6,6 +6,8 @@ use glium::IndexBuffer;
 pub struct Mesh {
     pub indices: Vec<u32>,
     pub material_i: usize,
+    /// Mesh is in the local space of an instance prototype
+    pub is_prototype: bool,
 }
 
 /// Mesh for GPU rendering
@@ -19,6 +21,7 @@ impl Mesh {
         Mesh {
             indices: Vec::new(),
             material_i,
+            is_prototype: false,
         }
     }
 
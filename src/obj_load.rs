@@ -1,5 +1,5 @@
 /// Simple module for loading wavefront object files
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs::File;
 use std::io::prelude::*;
@@ -8,8 +8,15 @@ use std::path::{Path, PathBuf};
 use std::str::SplitWhitespace;
 use std::vec::Vec;
 
+use cgmath::prelude::*;
+use cgmath::Vector3;
+
 use crate::stats;
 
+/// Name of the material of the triangles that precede all usemtl statements.
+/// Parentheses keep it from colliding with names of the material libraries.
+pub const DEFAULT_MATERIAL: &str = "(default)";
+
 /// Indices of vertex attributes in attribute vectors
 #[derive(Debug, Default, Copy, Clone, Hash, PartialEq, Eq)]
 pub struct IndexVertex {
@@ -110,7 +117,7 @@ pub struct Range {
 impl Range {
     /// Create a new named range [start, start)
     /// End should be set when whole range has been processed
-    fn new(name: &str, start: usize) -> Range {
+    pub fn new(name: &str, start: usize) -> Range {
         Range {
             name: name.to_string(),
             start_i: start,
@@ -123,45 +130,184 @@ impl Range {
     }
 }
 
+/// Texture map options of a material
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextureOptions {
+    /// Scale of the texture coordinates (-s)
+    pub scale: [f32; 3],
+    /// Offset of the texture coordinates (-o)
+    pub offset: [f32; 3],
+    /// Value added to the texture values (-mm base)
+    pub base: f32,
+    /// Multiplier of the texture values (-mm gain)
+    pub gain: f32,
+}
+
+impl Default for TextureOptions {
+    fn default() -> Self {
+        TextureOptions {
+            scale: [1.0; 3],
+            offset: [0.0; 3],
+            base: 0.0,
+            gain: 1.0,
+        }
+    }
+}
+
+/// Texture file of a material with its options
+#[derive(Debug, Clone)]
+pub struct TextureMap {
+    pub path: PathBuf,
+    pub options: TextureOptions,
+}
+
 /// Representation of a loaded material
 #[derive(Debug, Default, Clone)]
 #[allow(dead_code)]
 pub struct Material {
     pub name: String,
     pub ambient_color: Option<[f32; 3]>,
-    pub ambient_texture: Option<PathBuf>,
+    pub ambient_texture: Option<TextureMap>,
     pub diffuse_color: Option<[f32; 3]>,
-    pub diffuse_texture: Option<PathBuf>,
+    pub diffuse_texture: Option<TextureMap>,
     pub specular_color: Option<[f32; 3]>,
-    pub specular_texture: Option<PathBuf>,
+    pub specular_texture: Option<TextureMap>,
     pub transmission_filter: Option<[f32; 3]>,
-    pub transmission_texture: Option<PathBuf>,
+    pub transmission_texture: Option<TextureMap>,
     pub emissive_color: Option<[f32; 3]>,
-    pub emissive_texture: Option<PathBuf>,
+    pub emissive_texture: Option<TextureMap>,
     pub illumination_model: Option<u32>,
     /// 1.0 is fully opaque (1.0 - transparency)
     pub opaqueness: Option<f32>,
-    pub opaqueness_texture: Option<PathBuf>,
+    pub opaqueness_texture: Option<TextureMap>,
     /// 1.0 is fully transparent (1.0 - opaqueness)
     pub transparency: Option<f32>,
-    pub transparency_texture: Option<PathBuf>,
+    pub transparency_texture: Option<TextureMap>,
     pub specular_exponent: Option<f32>,
-    pub specular_exponent_texture: Option<PathBuf>,
+    pub specular_exponent_texture: Option<TextureMap>,
     /// Sharpness of reflections
     pub sharpness: Option<f32>,
     pub index_of_refraction: Option<f32>,
-    pub displacement_texture: Option<PathBuf>,
-    pub decal_texture: Option<PathBuf>,
-    pub bump_map: Option<PathBuf>,
+    /// Imaginary part of the index of refraction of a conductor (non-standard Nk)
+    pub extinction_coefficient: Option<[f32; 3]>,
+    /// Name of a conductor preset (non-standard)
+    pub metal: Option<String>,
+    /// Emit light from both sides of the surface (non-standard)
+    pub two_sided: bool,
+    /// Absorption coefficient per unit length of the medium behind the surface (non-standard)
+    pub absorption: Option<[f32; 3]>,
+    pub displacement_texture: Option<TextureMap>,
+    pub decal_texture: Option<TextureMap>,
+    pub bump_map: Option<TextureMap>,
 }
 
 impl Material {
-    fn new(name: &str) -> Material {
+    pub fn new(name: &str) -> Material {
+        Material {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Mid-gray lambertian material for geometry without a material
+    pub fn default_material() -> Material {
         Material {
+            diffuse_color: Some([0.5; 3]),
+            ..Material::new(DEFAULT_MATERIAL)
+        }
+    }
+
+    /// All the textures referenced by the material
+    pub fn textures(&self) -> impl Iterator<Item = &TextureMap> {
+        vec![
+            &self.ambient_texture,
+            &self.diffuse_texture,
+            &self.specular_texture,
+            &self.transmission_texture,
+            &self.emissive_texture,
+            &self.opaqueness_texture,
+            &self.transparency_texture,
+            &self.specular_exponent_texture,
+            &self.displacement_texture,
+            &self.decal_texture,
+            &self.bump_map,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// Replacement parameters for a named material
+#[derive(Debug, Clone, Default)]
+pub struct MaterialOverride {
+    pub name: String,
+    /// Replaces the diffuse color and texture
+    pub albedo: Option<[f32; 3]>,
+    /// GGX alpha that replaces the specular exponent
+    pub roughness: Option<f32>,
+    /// Conductor preset. Some(None) turns a metal into a dielectric.
+    pub metallic: Option<Option<String>>,
+    /// Replaces the emissive color and texture
+    pub emission: Option<[f32; 3]>,
+}
+
+impl MaterialOverride {
+    fn new(name: &str) -> MaterialOverride {
+        MaterialOverride {
             name: name.to_string(),
             ..Default::default()
         }
     }
+
+    pub fn apply(&self, material: &mut Material) {
+        if let Some(albedo) = self.albedo {
+            material.diffuse_color = Some(albedo);
+            material.diffuse_texture = None;
+        }
+        if let Some(roughness) = self.roughness {
+            material.specular_exponent = Some(alpha_to_exponent(roughness));
+            material.specular_exponent_texture = None;
+        }
+        if let Some(metallic) = &self.metallic {
+            material.metal = metallic.clone();
+        }
+        if let Some(emission) = self.emission {
+            material.emissive_color = Some(emission);
+            material.emissive_texture = None;
+        }
+    }
+}
+
+/// Convert a GGX alpha to the specular exponent that maps back to it.
+/// Inverse of the exponent to alpha conversion of the microfacet model.
+pub fn alpha_to_exponent(alpha: f32) -> f32 {
+    let alpha = alpha.max(1e-3);
+    2.0 / alpha.powi(2) - 2.0
+}
+
+/// Transform that places a copy of a named group
+#[derive(Debug, Clone)]
+pub struct Placement {
+    /// Name of the group or object. * places the whole file.
+    pub name: String,
+    pub translation: [f32; 3],
+    /// Rotations in degrees around the x, y and z axes applied in that order
+    pub rotation: [f32; 3],
+    pub scale: [f32; 3],
+    /// Share the triangles of the group instead of copying them
+    pub instanced: bool,
+}
+
+impl Placement {
+    fn new(name: &str, instanced: bool) -> Placement {
+        Placement {
+            name: name.to_string(),
+            translation: [0.0; 3],
+            rotation: [0.0; 3],
+            scale: [1.0; 3],
+            instanced,
+        }
+    }
 }
 
 /// Struct containing the loaded object file properties
@@ -170,6 +316,9 @@ pub struct Object {
     /// List of loaded vertex positions
     /// Indexed by index_vertices in triangles
     pub positions: Vec<[f32; 3]>,
+    /// List of loaded vertex colors
+    /// Empty if the file has none, otherwise indexed like positions
+    pub colors: Vec<[f32; 3]>,
     /// List of loaded vertex normals
     /// Indexed by index_vertices in triangles
     pub normals: Vec<[f32; 3]>,
@@ -182,10 +331,12 @@ pub struct Object {
     /// Ranges index the triangles list
     pub group_ranges: Vec<Range>,
     /// Ranges of loaded materials
-    /// Ranges index the triangles list
+    /// Ranges index the triangles list and cover all of it
     pub material_ranges: Vec<Range>,
     /// Map of loaded materials
     pub materials: HashMap<String, Material>,
+    /// Problems that were skipped over while parsing
+    pub warnings: Vec<String>,
 }
 
 impl Object {
@@ -194,6 +345,54 @@ impl Object {
             ..Default::default()
         }
     }
+
+    /// Recompute the group and material ranges after the triangles have changed.
+    /// Group ranges are also split at the given triangle indices.
+    pub fn update_ranges(&mut self, group_breaks: &[usize]) {
+        self.group_ranges = ranges(
+            self.triangles.iter().map(|tri| tri.group.as_deref()),
+            group_breaks,
+        );
+        self.material_ranges = ranges(
+            self.triangles
+                .iter()
+                .map(|tri| Some(tri.material.as_deref().unwrap_or(DEFAULT_MATERIAL))),
+            &[],
+        );
+    }
+
+    /// Check if the triangle has zero or non-finite area
+    pub fn is_degenerate(&self, tri: &Triangle) -> bool {
+        let [p1, p2, p3] = tri.index_vertices.map(|index_vertex| {
+            Vector3::from(self.positions[index_vertex.pos_i])
+                .cast::<f64>()
+                .unwrap()
+        });
+        let area = (p2 - p1).cross(p3 - p1).magnitude();
+        area <= 0.0 || !area.is_finite()
+    }
+}
+
+/// Ranges of consecutive equal names that don't cross the breaks.
+/// Unnamed runs are left out.
+fn ranges<'a>(names: impl Iterator<Item = Option<&'a str>>, breaks: &[usize]) -> Vec<Range> {
+    let mut ranges: Vec<Range> = Vec::new();
+    for (i, name) in names.enumerate() {
+        match (ranges.last_mut(), name) {
+            (Some(range), Some(name))
+                if range.end_i == i && range.name == name && !breaks.contains(&i) =>
+            {
+                range.end_i = i + 1
+            }
+            (_, Some(name)) => {
+                let mut range = Range::new(name, i);
+                range.end_i = i + 1;
+                ranges.push(range);
+            }
+            (_, None) => {}
+        }
+    }
+    ranges
 }
 
 /// Internal representation of the parse state
@@ -271,37 +470,68 @@ fn str_to_path(string: &str) -> PathBuf {
     path
 }
 
-/// Parse a texture ignoring the potential options
-fn parse_texture(split_line: &mut SplitWhitespace) -> Option<PathBuf> {
-    let mut next_item = split_line.next();
-    while let Some(next) = next_item {
-        // Ignore potential switches
-        // TODO: handle more switches
-        // TODO: handle switches properly
-        match next {
-            "-bm" => next_item = split_line.nth(1),
-            path_str => return Some(str_to_path(path_str)),
+/// Parse the values of a texture option that takes up to max_n floats.
+/// Values that are not given keep their defaults.
+fn parse_option_floats(items: &[&str], i: &mut usize, values: &mut [f32], max_n: usize) {
+    for value in values.iter_mut().take(max_n) {
+        match items.get(*i).and_then(|item| item.parse().ok()) {
+            Some(val) => *value = val,
+            None => return,
+        }
+        *i += 1;
+    }
+}
+
+/// Parse a texture and its options. The path is relative to dir.
+fn parse_texture(split_line: &mut SplitWhitespace, dir: &Path) -> Option<TextureMap> {
+    let items: Vec<&str> = split_line.collect();
+    let mut options = TextureOptions::default();
+    let mut i = 0;
+    while let Some(&item) = items.get(i) {
+        i += 1;
+        match item {
+            "-s" => parse_option_floats(&items, &mut i, &mut options.scale, 3),
+            "-o" => parse_option_floats(&items, &mut i, &mut options.offset, 3),
+            "-mm" => {
+                let mut base_gain = [options.base, options.gain];
+                parse_option_floats(&items, &mut i, &mut base_gain, 2);
+                options.base = base_gain[0];
+                options.gain = base_gain[1];
+            }
+            // Ignore turbulence and its values
+            "-t" => parse_option_floats(&items, &mut i, &mut [0.0; 3], 3),
+            // Ignore the rest of the options that take a single value
+            "-bm" | "-blendu" | "-blendv" | "-boost" | "-cc" | "-clamp" | "-imfchan"
+            | "-texres" | "-type" => i += 1,
+            path_str => {
+                return Some(TextureMap {
+                    path: dir.join(str_to_path(path_str)),
+                    options,
+                })
+            }
         }
     }
     None
 }
 
-/// Parse a polygon from the split input line
+/// Parse a polygon from the split input line.
+/// Return the reason if the polygon has to be skipped.
 fn parse_polygon(
     split_line: &mut SplitWhitespace,
     obj: &Object,
     state: &ParseState,
-) -> Option<Polygon> {
+) -> Result<Polygon, String> {
     let mut polygon = Polygon::new(state);
     for item in split_line {
         let mut index_vertex = IndexVertex::new();
         for (i, num) in item.split('/').enumerate() {
             if i >= 3 {
-                println!("Vertex with more than three properties");
-                break;
+                return Err(format!("Vertex {} has more than three properties", item));
             }
             if !num.is_empty() {
-                let num: isize = num.parse().ok()?;
+                let num: isize = num
+                    .parse()
+                    .map_err(|_| format!("Vertex {} has a malformed index", item))?;
                 if num < 0 {
                     match i {
                         0 => index_vertex.pos_i = (obj.positions.len() as isize + num) as usize,
@@ -328,10 +558,21 @@ fn parse_polygon(
         polygon.index_vertices.push(index_vertex);
     }
     if polygon.index_vertices.len() > 2 {
-        Some(polygon)
+        Ok(polygon)
     } else {
-        println!("Polygon with less than three vertices");
-        None
+        Err("Polygon has less than three vertices".to_string())
+    }
+}
+
+/// Check that a face refers to one of the n defined attributes
+fn check_index(i: Option<usize>, n: usize, attribute: &str) -> Result<(), String> {
+    match i {
+        // Zero and too negative indices wrap around so they are caught here as well
+        Some(i) if i >= n => Err(format!(
+            "Face refers to a {} outside of the {} defined ones",
+            attribute, n
+        )),
+        _ => Ok(()),
     }
 }
 
@@ -343,19 +584,21 @@ pub fn load_obj(obj_path: &Path) -> Result<Object, Box<dyn Error>> {
     let obj_dir = obj_path.parent().ok_or("Couldn't get object directory")?;
     let obj_file = File::open(obj_path)?;
     let obj_reader = BufReader::new(obj_file);
-    for line in obj_reader.lines() {
-        let line = line.expect("Failed to unwrap line");
+    for (line_i, line) in obj_reader.lines().enumerate() {
+        let line = line?;
+        let line_n = line_i + 1;
         let mut split_line = line.split_whitespace();
         // Find the keyword of the line
         if let Some(key) = split_line.next() {
             match key {
-                "f" => {
-                    if let Some(polygon) = parse_polygon(&mut split_line, &obj, &state) {
-                        // Auto convert to triangles
-                        // TODO: Make triangle conversion optional
-                        obj.triangles.append(&mut polygon.to_triangles());
-                    }
-                }
+                "f" => match parse_polygon(&mut split_line, &obj, &state) {
+                    // Auto convert to triangles
+                    // TODO: Make triangle conversion optional
+                    Ok(polygon) => obj.triangles.append(&mut polygon.to_triangles()),
+                    Err(err) => obj
+                        .warnings
+                        .push(format!("Line {}: Skipped face: {}", line_n, err)),
+                },
                 "g" | "o" => {
                     if let Some(mut range) = state.current_group {
                         range.end_i = obj.triangles.len();
@@ -391,6 +634,11 @@ pub fn load_obj(obj_path: &Path) -> Result<Object, Box<dyn Error>> {
                 "v" => {
                     if let Some(pos) = parse_float3(&mut split_line) {
                         obj.positions.push(pos);
+                        // Some exporters append an optional color after the position
+                        if let Some(color) = parse_float3(&mut split_line) {
+                            obj.colors.resize(obj.positions.len() - 1, [1.0; 3]);
+                            obj.colors.push(color);
+                        }
                     }
                 }
                 "vn" => {
@@ -405,7 +653,8 @@ pub fn load_obj(obj_path: &Path) -> Result<Object, Box<dyn Error>> {
                 }
                 _ => {
                     if !key.starts_with('#') {
-                        println!("Unrecognised key {}", key);
+                        obj.warnings
+                            .push(format!("Line {}: Unrecognised key {}", line_n, key));
                     }
                 }
             }
@@ -420,13 +669,145 @@ pub fn load_obj(obj_path: &Path) -> Result<Object, Box<dyn Error>> {
         range.end_i = obj.triangles.len();
         obj.material_ranges.push(range);
     };
+    // Faces may refer to vertices defined after them so the indices are checked at the end
+    for tri in &obj.triangles {
+        for index_vertex in &tri.index_vertices {
+            check_index(Some(index_vertex.pos_i), obj.positions.len(), "position")?;
+            check_index(
+                index_vertex.tex_i,
+                obj.tex_coords.len(),
+                "texture coordinate",
+            )?;
+            check_index(index_vertex.normal_i, obj.normals.len(), "normal")?;
+        }
+    }
     // Load materials
     for matlib in state.mat_libs {
-        obj.materials = load_matlib(&obj_dir.join(matlib))?;
+        obj.materials = load_matlib(&matlib)?;
+    }
+    // Triangles before the first usemtl (or in files without any) use the default material
+    let first_material_i = obj
+        .material_ranges
+        .first()
+        .map_or(obj.triangles.len(), |range| range.start_i);
+    if first_material_i > 0 {
+        let mut range = Range::new(DEFAULT_MATERIAL, 0);
+        range.end_i = first_material_i;
+        obj.material_ranges.insert(0, range);
+        obj.materials
+            .insert(DEFAULT_MATERIAL.to_string(), Material::default_material());
+    }
+    // Vertices without a color are left white
+    if !obj.colors.is_empty() {
+        obj.colors.resize(obj.positions.len(), [1.0; 3]);
     }
     Ok(obj)
 }
 
+/// Problems found in a loaded object
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// Problems that prevent building the scene
+    pub errors: Vec<String>,
+    /// Problems that degrade the rendered image
+    pub warnings: Vec<String>,
+}
+
+/// Find problems in a loaded object that would break or degrade rendering
+pub fn check(obj: &Object) -> CheckReport {
+    let mut report = CheckReport {
+        warnings: obj.warnings.clone(),
+        ..Default::default()
+    };
+    let mut checked_materials = HashSet::new();
+    for range in obj.material_ranges.iter().filter(|range| !range.is_empty()) {
+        if !checked_materials.insert(&range.name) {
+            continue;
+        }
+        match obj.materials.get(&range.name) {
+            Some(material) => {
+                for texture in material.textures() {
+                    if !texture.path.exists() {
+                        report.errors.push(format!(
+                            "Material {} references missing texture {:?}",
+                            material.name, texture.path
+                        ));
+                    }
+                }
+            }
+            None => report
+                .errors
+                .push(format!("Material {} is not defined", range.name)),
+        }
+    }
+    let n_degenerate = obj
+        .triangles
+        .iter()
+        .filter(|tri| obj.is_degenerate(tri))
+        .count();
+    if n_degenerate > 0 {
+        report
+            .warnings
+            .push(format!("{} degenerate triangles", n_degenerate));
+    }
+    let n_bad_uvs = obj
+        .tex_coords
+        .iter()
+        .filter(|uv| !uv[0].is_finite() || !uv[1].is_finite())
+        .count();
+    if n_bad_uvs > 0 {
+        report
+            .warnings
+            .push(format!("{} non-finite texture coordinates", n_bad_uvs));
+    }
+    report
+}
+
+/// Load a material override file. The syntax follows mtl files:
+/// override <material name>
+/// albedo r g b
+/// roughness alpha
+/// metallic <conductor preset | off>
+/// emission r g b
+pub fn load_material_overrides(path: &Path) -> Result<Vec<MaterialOverride>, Box<dyn Error>> {
+    let mut overrides = Vec::new();
+    let reader = BufReader::new(File::open(path)?);
+    for line in reader.lines() {
+        let line = line?;
+        let mut split_line = line.split_whitespace();
+        let key = match split_line.next().map(str::to_lowercase) {
+            Some(key) => key,
+            None => continue,
+        };
+        if key.starts_with('#') {
+            continue;
+        }
+        if key == "override" {
+            let name =
+                parse_string(&mut split_line).ok_or("Tried to override a material with no name")?;
+            overrides.push(MaterialOverride::new(&name));
+            continue;
+        }
+        let material_override = overrides
+            .last_mut()
+            .ok_or_else(|| format!("Statement '{}' found before any override", line))?;
+        match key.as_str() {
+            "albedo" => material_override.albedo = parse_float3(&mut split_line),
+            "roughness" => material_override.roughness = parse_float(&mut split_line),
+            "metallic" => {
+                material_override.metallic =
+                    parse_string(&mut split_line).map(|preset| match preset.as_str() {
+                        "off" => None,
+                        _ => Some(preset),
+                    });
+            }
+            "emission" => material_override.emission = parse_float3(&mut split_line),
+            _ => println!("Unrecognised override key: {}", key),
+        }
+    }
+    Ok(overrides)
+}
+
 /// Load materials from the material library to a map
 pub fn load_matlib(matlib_path: &Path) -> Result<HashMap<String, Material>, Box<dyn Error>> {
     let mut materials = HashMap::new();
@@ -437,7 +818,7 @@ pub fn load_matlib(matlib_path: &Path) -> Result<HashMap<String, Material>, Box<
     let matlib_file = File::open(matlib_path)?;
     let matlib_reader = BufReader::new(matlib_file);
     for line in matlib_reader.lines() {
-        let line = line.unwrap();
+        let line = line?;
         let mut split_line = line.split_whitespace();
         // Find the keyword of the line
         if let Some(key) = split_line.next().map(str::to_lowercase) {
@@ -491,45 +872,49 @@ pub fn load_matlib(matlib_path: &Path) -> Result<HashMap<String, Material>, Box<
                     "ni" => {
                         material.index_of_refraction = parse_float(&mut split_line);
                     }
+                    "nk" => {
+                        material.extinction_coefficient = parse_float3(&mut split_line);
+                    }
+                    "metal" => {
+                        material.metal = parse_string(&mut split_line);
+                    }
+                    "absorption" => {
+                        material.absorption = parse_float3(&mut split_line);
+                    }
+                    // Optional on/off like texture options
+                    "two_sided" => {
+                        material.two_sided = split_line.next() != Some("off");
+                    }
                     "map_ka" => {
-                        material.ambient_texture =
-                            parse_texture(&mut split_line).map(|path| matlib_dir.join(path));
+                        material.ambient_texture = parse_texture(&mut split_line, matlib_dir);
                     }
                     "map_kd" => {
-                        material.diffuse_texture =
-                            parse_texture(&mut split_line).map(|path| matlib_dir.join(path));
+                        material.diffuse_texture = parse_texture(&mut split_line, matlib_dir);
                     }
                     "map_ks" => {
-                        material.specular_texture =
-                            parse_texture(&mut split_line).map(|path| matlib_dir.join(path));
+                        material.specular_texture = parse_texture(&mut split_line, matlib_dir);
                     }
                     "map_ke" => {
-                        material.emissive_texture =
-                            parse_texture(&mut split_line).map(|path| matlib_dir.join(path));
+                        material.emissive_texture = parse_texture(&mut split_line, matlib_dir);
                     }
                     "map_ns" => {
                         material.specular_exponent_texture =
-                            parse_texture(&mut split_line).map(|path| matlib_dir.join(path));
+                            parse_texture(&mut split_line, matlib_dir);
                     }
                     "map_d" | "map_opacity" => {
-                        material.opaqueness_texture =
-                            parse_texture(&mut split_line).map(|path| matlib_dir.join(path));
+                        material.opaqueness_texture = parse_texture(&mut split_line, matlib_dir);
                     }
                     "map_tr" => {
-                        material.transparency_texture =
-                            parse_texture(&mut split_line).map(|path| matlib_dir.join(path));
+                        material.transparency_texture = parse_texture(&mut split_line, matlib_dir);
                     }
                     "disp" => {
-                        material.displacement_texture =
-                            parse_texture(&mut split_line).map(|path| matlib_dir.join(path));
+                        material.displacement_texture = parse_texture(&mut split_line, matlib_dir);
                     }
                     "decal" => {
-                        material.decal_texture =
-                            parse_texture(&mut split_line).map(|path| matlib_dir.join(path));
+                        material.decal_texture = parse_texture(&mut split_line, matlib_dir);
                     }
                     "bump" | "map_bump" => {
-                        material.bump_map =
-                            parse_texture(&mut split_line).map(|path| matlib_dir.join(path));
+                        material.bump_map = parse_texture(&mut split_line, matlib_dir);
                     }
                     "refl" => {} // TODO: reflection maps
                     _ => {
@@ -543,3 +928,222 @@ pub fn load_matlib(matlib_path: &Path) -> Result<HashMap<String, Material>, Box<
     materials.insert(material.name.clone(), material);
     Ok(materials)
 }
+
+/// Load a placement file. Each placement adds a transformed copy of a group
+/// and groups that are placed are left out from their original position.
+/// Instances share the triangles of the group instead of copying them.
+/// transform | instance <group name | *>
+/// translate x y z
+/// rotate x y z
+/// scale s | x y z
+pub fn load_placements(path: &Path) -> Result<Vec<Placement>, Box<dyn Error>> {
+    let mut placements = Vec::new();
+    let reader = BufReader::new(File::open(path)?);
+    for line in reader.lines() {
+        let line = line?;
+        let mut split_line = line.split_whitespace();
+        let key = match split_line.next().map(str::to_lowercase) {
+            Some(key) => key,
+            None => continue,
+        };
+        if key.starts_with('#') {
+            continue;
+        }
+        if key == "transform" || key == "instance" {
+            let name =
+                parse_string(&mut split_line).ok_or("Tried to transform a group with no name")?;
+            placements.push(Placement::new(&name, key == "instance"));
+            continue;
+        }
+        let placement = placements
+            .last_mut()
+            .ok_or_else(|| format!("Statement '{}' found before any transform", line))?;
+        let malformed = || format!("Malformed statement '{}'", line);
+        match key.as_str() {
+            "translate" => {
+                placement.translation = parse_float3(&mut split_line).ok_or_else(malformed)?
+            }
+            "rotate" => placement.rotation = parse_float3(&mut split_line).ok_or_else(malformed)?,
+            "scale" => {
+                let s = parse_float(&mut split_line).ok_or_else(malformed)?;
+                placement.scale = match parse_float2(&mut split_line) {
+                    Some([y, z]) => [s, y, z],
+                    None => [s; 3],
+                };
+            }
+            _ => println!("Unrecognised transform key: {}", key),
+        }
+    }
+    Ok(placements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texture(line: &str) -> Option<TextureMap> {
+        parse_texture(&mut line.split_whitespace(), Path::new("dir"))
+    }
+
+    /// Write contents to a temporary file and load it with load
+    fn load_temp<T>(
+        name: &str,
+        contents: &str,
+        load: impl Fn(&Path) -> Result<T, Box<dyn Error>>,
+    ) -> Result<T, Box<dyn Error>> {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, contents).unwrap();
+        let res = load(&path);
+        std::fs::remove_file(&path).unwrap();
+        res
+    }
+
+    #[test]
+    fn texture_options() {
+        let map = texture("-s 2 3 -o 0.5 0.25 0.125 -mm 0.1 0.8 -bm 2 tex.png").unwrap();
+        assert_eq!(map.path, Path::new("dir").join("tex.png"));
+        assert_eq!(
+            map.options,
+            TextureOptions {
+                scale: [2.0, 3.0, 1.0],
+                offset: [0.5, 0.25, 0.125],
+                base: 0.1,
+                gain: 0.8,
+            }
+        );
+    }
+
+    #[test]
+    fn texture_without_options() {
+        let map = texture("tex.png").unwrap();
+        assert_eq!(map.path, Path::new("dir").join("tex.png"));
+        assert_eq!(map.options, TextureOptions::default());
+        assert!(texture("-s 2 2 2").is_none());
+    }
+
+    #[test]
+    fn vertex_colors() {
+        let _stats = stats::test_scene("vertex_colors");
+        let path = std::env::temp_dir().join("rusty_test_vertex_colors.obj");
+        let obj = "v 0 0 0 1 0 0\nv 1 0 0\nv 0 1 0 0 0.5 1\nf 1 2 3\n";
+        std::fs::write(&path, obj).unwrap();
+        let res = load_obj(&path);
+        std::fs::remove_file(&path).unwrap();
+        let obj = res.unwrap();
+        assert_eq!(obj.positions.len(), 3);
+        // The vertex without a color is white
+        assert_eq!(
+            obj.colors,
+            vec![[1.0, 0.0, 0.0], [1.0, 1.0, 1.0], [0.0, 0.5, 1.0]]
+        );
+    }
+
+    #[test]
+    fn no_vertex_colors() {
+        let _stats = stats::test_scene("no_vertex_colors");
+        let path = std::env::temp_dir().join("rusty_test_no_vertex_colors.obj");
+        std::fs::write(&path, "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+        let res = load_obj(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(res.unwrap().colors.is_empty());
+    }
+
+    #[test]
+    fn placements() {
+        let file = "# Copies\ntransform table\ntranslate 1 2 3\nrotate 0 90 0\nscale 2\n\n\
+                    instance chair\nscale 1 2 3\n";
+        let placements = load_temp("rusty_test_placements.txt", file, load_placements).unwrap();
+        assert_eq!(placements.len(), 2);
+        let table = &placements[0];
+        assert_eq!(table.name, "table");
+        assert!(!table.instanced);
+        assert_eq!(table.translation, [1.0, 2.0, 3.0]);
+        assert_eq!(table.rotation, [0.0, 90.0, 0.0]);
+        assert_eq!(table.scale, [2.0; 3]);
+        let chair = &placements[1];
+        assert_eq!(chair.name, "chair");
+        assert!(chair.instanced);
+        assert_eq!(chair.translation, [0.0; 3]);
+        assert_eq!(chair.scale, [1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn malformed_placements() {
+        let before_transform = load_temp(
+            "rusty_test_orphan.txt",
+            "translate 1 2 3\n",
+            load_placements,
+        );
+        assert!(before_transform.is_err());
+        let malformed = load_temp(
+            "rusty_test_malformed.txt",
+            "transform table\ntranslate 1 x 3\n",
+            load_placements,
+        );
+        assert!(malformed.is_err());
+    }
+
+    #[test]
+    fn material_overrides() {
+        let file = "override floor\nalbedo 0.1 0.2 0.3\nroughness 0.5\nmetallic gold\n\
+                    override lamp\nemission 4 4 4\nmetallic off\n";
+        let overrides =
+            load_temp("rusty_test_overrides.txt", file, load_material_overrides).unwrap();
+        assert_eq!(overrides.len(), 2);
+        let mut floor = Material {
+            diffuse_texture: Some(TextureMap {
+                path: PathBuf::from("floor.png"),
+                options: TextureOptions::default(),
+            }),
+            specular_exponent: Some(10.0),
+            ..Material::new("floor")
+        };
+        overrides[0].apply(&mut floor);
+        assert_eq!(floor.diffuse_color, Some([0.1, 0.2, 0.3]));
+        assert!(floor.diffuse_texture.is_none());
+        // Alpha 0.5 corresponds to the exponent 2 / alpha^2 - 2
+        assert_eq!(floor.specular_exponent, Some(6.0));
+        assert_eq!(floor.metal.as_deref(), Some("gold"));
+        assert!(floor.emissive_color.is_none());
+        let mut lamp = Material {
+            metal: Some("copper".to_string()),
+            ..Material::new("lamp")
+        };
+        overrides[1].apply(&mut lamp);
+        assert_eq!(lamp.emissive_color, Some([4.0; 3]));
+        assert!(lamp.metal.is_none());
+        assert!(lamp.diffuse_color.is_none());
+    }
+
+    #[test]
+    fn parse_warnings_are_collected() {
+        let _stats = stats::test_scene("parse_warnings");
+        let file = "v 0 0 0\nv 1 0 0\nv 0 1 0\n# comment\nfoo bar\nf 1 2 3\nf 1 2/x 3\n";
+        let obj = load_temp("rusty_test_parse_warnings.obj", file, load_obj).unwrap();
+        assert_eq!(obj.triangles.len(), 1);
+        assert_eq!(
+            obj.warnings,
+            vec![
+                "Line 5: Unrecognised key foo".to_string(),
+                "Line 7: Skipped face: Vertex 2/x has a malformed index".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn faces_before_usemtl_get_default_material() {
+        let _stats = stats::test_scene("default_material");
+        let file = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\nf 1 3 2\n";
+        let obj = load_temp("rusty_test_default_material.obj", file, load_obj).unwrap();
+        assert_eq!(obj.material_ranges.len(), 1);
+        let range = &obj.material_ranges[0];
+        assert_eq!(
+            (range.name.as_str(), range.start_i, range.end_i),
+            (DEFAULT_MATERIAL, 0, 2)
+        );
+        assert_eq!(
+            obj.materials[DEFAULT_MATERIAL].diffuse_color,
+            Some([0.5; 3])
+        );
+    }
+}
@@ -1,9 +1,12 @@
-use std::path::Path;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::sync::{
-    mpsc::{self, Receiver, Sender},
+    mpsc::{self, Receiver, RecvTimeoutError, Sender},
     Arc,
 };
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use cgmath::Point2;
 
@@ -11,9 +14,11 @@ use glium::backend::Facade;
 use glium::{Rect, Surface};
 
 use crate::camera::{Camera, PtCamera};
+use crate::compare::{self, HdrImage, ImageError};
 use crate::config::RenderConfig;
+use crate::float::*;
 use crate::scene::Scene;
-use crate::stats;
+use crate::stats::{self, WorkerStats};
 
 mod coordinator;
 mod render_worker;
@@ -24,6 +29,9 @@ use self::coordinator::RenderCoordinator;
 use self::render_worker::RenderWorker;
 use self::traced_image::TracedImage;
 
+/// Minimum time between the previews of offline renders
+const PREVIEW_INTERVAL: Duration = Duration::from_millis(50);
+
 /// Distinguished the start point of the traced path where necessary
 #[derive(Clone, Copy, Debug)]
 pub enum PathType {
@@ -45,33 +53,115 @@ impl PathType {
 }
 
 enum PtResult {
-    Block(Rect, Vec<f32>),
-    Splat(Point2<u32>, [f32; 3]),
+    /// Rendered block and its estimated relative variance
+    Block(Rect, Vec<f32>, Float),
+    /// Light samples splatted to pixels. Splats to the same pixel are summed.
+    Splats(Vec<(Point2<u32>, [f32; 3])>),
+    /// Mean first hit albedos and shading normals of a block
+    Aovs(Rect, Vec<f32>, Vec<f32>),
+}
+
+/// Progress of a render
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    /// Minimum number of samples of the pixels
+    pub min_samples: u32,
+    /// Mean number of samples of the pixels
+    pub mean_samples: Float,
+    /// Index of the iteration that is being rendered
+    pub iteration: usize,
+    pub max_iterations: Option<usize>,
+    /// Fraction of the samples that have been rendered.
+    /// None if the render has no iteration limit.
+    pub fraction: Option<Float>,
+    /// Estimated time until the render finishes
+    pub eta: Option<Duration>,
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.1} spp (min {})", self.mean_samples, self.min_samples)?;
+        match self.max_iterations {
+            Some(max) => write!(f, ", iteration {}/{}", (self.iteration + 1).min(max), max)?,
+            None => write!(f, ", iteration {}", self.iteration + 1)?,
+        }
+        if let Some(fraction) = self.fraction {
+            write!(f, ", {:.0}%", 100.0 * fraction)?;
+        }
+        if let Some(eta) = self.eta {
+            let secs = eta.as_secs();
+            write!(f, ", ETA {}:{:02}", secs / 60, secs % 60)?;
+        }
+        Ok(())
+    }
+}
+
+/// Estimate the time left of a render that finished fraction of its work in elapsed
+/// assuming that the rest of the work proceeds at the same rate
+fn eta(fraction: Float, elapsed: Duration) -> Option<Duration> {
+    if fraction <= 0.0 {
+        return None;
+    }
+    // Cast is unnecessary with double precision
+    #[allow(clippy::unnecessary_cast)]
+    let fraction = fraction.min(1.0) as f64;
+    Some(elapsed.mul_f64((1.0 - fraction) / fraction))
+}
+
+/// Numbered images saved during a render
+struct Snapshots {
+    dir: PathBuf,
+    prefix: String,
+    /// Number of rendered pixels between snapshots
+    interval: usize,
+    n_pixels: usize,
+    n_saved: usize,
+    /// Set when a snapshot is due but hasn't been saved yet
+    pending: bool,
 }
 
+/// Receives the progress of a render
+type ProgressCallback = Box<dyn FnMut(&Progress)>;
+
 pub struct PtRenderer {
     image: TracedImage,
+    coordinator: Arc<RenderCoordinator>,
     result_rx: Receiver<PtResult>,
+    /// Each worker sends its stats once it stops
+    stats_rx: Receiver<WorkerStats>,
     message_txs: Vec<Sender<()>>,
     thread_handles: Vec<JoinHandle<()>>,
+    start: Instant,
+    samples_per_iteration: usize,
+    max_iterations: Option<usize>,
+    /// Called with the progress after updates of the image
+    progress_callback: Option<ProgressCallback>,
+    snapshot_interval: usize,
+    snapshots: Option<Snapshots>,
 }
 
 impl PtRenderer {
+    /// Start rendering in the background.
+    /// Return an error if the config is invalid.
     pub fn start_render<F: Facade>(
         facade: &F,
         scene: &Arc<Scene>,
         camera: &Camera,
         config: &RenderConfig,
-    ) -> Self {
+    ) -> Result<Self, String> {
+        config.validate()?;
         stats::start_render();
         let image = TracedImage::new(facade, config);
-        let coordinator = Arc::new(RenderCoordinator::new(config));
+        let n_workers = num_cpus::get().min(config.max_threads);
+        let coordinator = Arc::new(RenderCoordinator::new(config, n_workers));
         let mut message_txs = Vec::new();
         let mut thread_handles = Vec::new();
 
         let (result_tx, result_rx) = mpsc::channel();
-        for _ in 0..num_cpus::get().min(config.max_threads) {
+        let (stats_tx, stats_rx) = mpsc::channel();
+        for _ in 0..n_workers {
             let result_tx = result_tx.clone();
+            let stats_tx = stats_tx.clone();
             let (message_tx, message_rx) = mpsc::channel();
             message_txs.push(message_tx);
             let coordinator = coordinator.clone();
@@ -79,62 +169,226 @@ impl PtRenderer {
             let config = config.clone();
             let scene = scene.clone();
             let handle = thread::spawn(move || {
-                let worker =
-                    RenderWorker::new(scene, camera, config, coordinator, message_rx, result_tx);
+                let worker = RenderWorker::new(
+                    scene,
+                    camera,
+                    config,
+                    coordinator,
+                    message_rx,
+                    result_tx,
+                    stats_tx,
+                );
                 worker.run();
             });
             thread_handles.push(handle);
         }
-        Self {
+        Ok(Self {
             image,
+            coordinator,
             result_rx,
+            stats_rx,
             message_txs,
             thread_handles,
-        }
+            start: Instant::now(),
+            samples_per_iteration: config.samples_per_dir.pow(2),
+            max_iterations: config.max_iterations,
+            progress_callback: None,
+            snapshot_interval: config.convergence_interval.max(1),
+            snapshots: None,
+        })
     }
 
+    /// Render until the workers have finished.
+    /// If snapshots is given, the image is saved to its directory with its file
+    /// name prefix every convergence_interval iterations and at the end.
+    /// If preview is given, it is called periodically with the partial render
+    /// and the render stops early if it returns false.
+    /// Return an error if the config is invalid.
     pub fn offline_render<F: Facade>(
         facade: &F,
         scene: &Arc<Scene>,
         camera: &Camera,
         config: &RenderConfig,
-    ) -> Self {
-        let mut renderer = Self::start_render(facade, scene, camera, config);
-        // This loops until all senders have disconnected
-        // ie. all workers have finished
-        for res in renderer.result_rx.iter() {
-            match res {
-                PtResult::Block(rect, sample) => renderer.image.add_sample(rect, &sample),
-                PtResult::Splat(pixel, sample) => renderer.image.add_splat(pixel, sample),
+        snapshots: Option<(&Path, &str)>,
+        mut preview: Option<&mut dyn FnMut(&mut Self) -> bool>,
+    ) -> Result<Self, String> {
+        let mut renderer = Self::start_render(facade, scene, camera, config)?;
+        if let Some((dir, prefix)) = snapshots {
+            renderer.snapshot_to(dir, prefix);
+        }
+        let mut last_preview = Instant::now();
+        loop {
+            if let Some(preview) = preview.as_mut() {
+                if last_preview.elapsed() >= PREVIEW_INTERVAL {
+                    last_preview = Instant::now();
+                    if !preview(&mut renderer) {
+                        break;
+                    }
+                }
+            }
+            let res = match renderer.result_rx.recv_timeout(PREVIEW_INTERVAL) {
+                Ok(res) => res,
+                Err(RecvTimeoutError::Timeout) => continue,
+                // All senders have disconnected ie. all workers have finished
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            renderer.handle_result(res);
+            renderer.save_pending_snapshot(facade);
+        }
+        // Converged blocks are skipped so the last interval may be partial
+        if let Some(snapshots) = &mut renderer.snapshots {
+            snapshots.pending = snapshots.n_pixels % snapshots.interval != 0;
+        }
+        renderer.save_pending_snapshot(facade);
+        Ok(renderer)
+    }
+
+    /// Save the image to numbered pngs in dir every convergence_interval iterations.
+    /// The file names start with prefix.
+    pub fn snapshot_to(&mut self, dir: &Path, prefix: &str) {
+        let rect = self.coordinator.render_rect();
+        self.snapshots = Some(Snapshots {
+            dir: dir.to_path_buf(),
+            prefix: prefix.to_string(),
+            interval: self.snapshot_interval * (rect.width * rect.height) as usize,
+            n_pixels: 0,
+            n_saved: 0,
+            pending: false,
+        });
+    }
+
+    fn save_pending_snapshot<F: Facade>(&mut self, facade: &F) {
+        if let Some(snapshots) = &mut self.snapshots {
+            if snapshots.pending {
+                snapshots.pending = false;
+                snapshots.n_saved += 1;
+                let file_name = format!("{}{:04}.png", snapshots.prefix, snapshots.n_saved);
+                self.image.save(facade, &snapshots.dir.join(file_name));
             }
         }
-        renderer
     }
 
-    pub fn update_image(&mut self) {
-        let mut n = 0;
+    fn handle_result(&mut self, res: PtResult) {
+        match res {
+            PtResult::Block(rect, sample, variance) => {
+                self.coordinator.report_variance(rect, variance);
+                self.image.add_sample(rect, &sample);
+                if let Some(snapshots) = &mut self.snapshots {
+                    // Blocks may arrive out of order so this is only approximately
+                    // the end of an iteration
+                    let n_pixels = snapshots.n_pixels + (rect.width * rect.height) as usize;
+                    if n_pixels / snapshots.interval > snapshots.n_pixels / snapshots.interval {
+                        snapshots.pending = true;
+                    }
+                    snapshots.n_pixels = n_pixels;
+                }
+            }
+            PtResult::Splats(splats) => {
+                // Light paths from the cropped pixels also splat outside the crop
+                for (pixel, sample) in splats {
+                    if self.coordinator.is_rendered(pixel) {
+                        self.image.add_splat(pixel, sample);
+                    }
+                }
+            }
+            PtResult::Aovs(rect, albedo, normal) => {
+                self.image.add_aovs(rect, &albedo, &normal);
+            }
+        }
+    }
+
+    pub fn update_image<F: Facade>(&mut self, facade: &F) {
         // Limit the number of updates to avoid infinite loops
         // when samples are produced faster that they are processed.
+        // Remaining results stay in the channel until the next update.
         let n_max = 100_000;
-        for res in self.result_rx.try_iter().take(n_max) {
-            n += 1;
-            match res {
-                PtResult::Block(rect, sample) => self.image.add_sample(rect, &sample),
-                PtResult::Splat(pixel, sample) => self.image.add_splat(pixel, sample),
+        let mut updated = false;
+        for _ in 0..n_max {
+            match self.result_rx.try_recv() {
+                Ok(res) => {
+                    self.handle_result(res);
+                    self.save_pending_snapshot(facade);
+                    updated = true;
+                }
+                Err(_) => break,
+            }
+        }
+        if updated && self.progress_callback.is_some() {
+            let progress = self.progress();
+            if let Some(callback) = &mut self.progress_callback {
+                callback(&progress);
             }
         }
-        if n == n_max {
-            println!("Hit maximum iterations in update!");
+    }
+
+    /// Minimum and mean number of samples per pixel rendered so far
+    pub fn samples_completed(&self) -> (u32, Float) {
+        self.image.n_samples(self.coordinator.render_rect())
+    }
+
+    /// Progress of the render so far
+    pub fn progress(&self) -> Progress {
+        let (min_samples, mean_samples) = self.samples_completed();
+        let fraction = self.max_iterations.map(|iterations| {
+            let total = (iterations * self.samples_per_iteration).max(1).to_float();
+            (mean_samples / total).min(1.0)
+        });
+        let elapsed = self.start.elapsed();
+        Progress {
+            min_samples,
+            mean_samples,
+            iteration: self.coordinator.iteration(),
+            max_iterations: self.max_iterations,
+            fraction,
+            eta: fraction.and_then(|fraction| eta(fraction, elapsed)),
         }
     }
 
-    pub fn render_image<F: Facade, S: Surface>(&mut self, facade: &F, target: &mut S) {
-        self.image.render(facade, target);
+    /// Call callback with the progress whenever update_image receives new samples
+    pub fn set_progress_callback(&mut self, callback: impl FnMut(&Progress) + 'static) {
+        self.progress_callback = Some(Box::new(callback));
+    }
+
+    pub fn render_image<S: Surface>(&mut self, target: &mut S, encode_srgb: bool) {
+        self.image.render(target, encode_srgb);
     }
 
-    pub fn save_image<F: Facade>(&self, facade: &F, path: &Path) {
+    /// Expose the image such that the bright pixels map to white.
+    /// Return the chosen exposure or None if the image is still black.
+    pub fn auto_exposure(&mut self) -> Option<Float> {
+        let exposure = self.image.auto_exposure()?;
+        self.image.set_exposure(exposure);
+        Some(exposure)
+    }
+
+    pub fn save_image<F: Facade>(&mut self, facade: &F, path: &Path) {
         self.image.save(facade, path);
     }
+
+    /// Save the linear radiance of the image
+    pub fn save_hdr(&self, path: &Path, metadata: bool) {
+        self.image.save_hdr(path, metadata);
+    }
+
+    /// Error of the linear radiance of the image against a reference
+    pub fn error(&self, reference: &HdrImage) -> Result<ImageError, Box<dyn Error>> {
+        compare::image_error(&self.image.radiance(), reference)
+    }
+
+    /// Save the first hit albedo of the image
+    pub fn save_albedo(&self, path: &Path) {
+        self.image.save_albedo(path);
+    }
+
+    /// Save the first hit shading normals of the image
+    pub fn save_normal(&self, path: &Path) {
+        self.image.save_normal(path);
+    }
+
+    /// Save the distribution of the samples over the image
+    pub fn save_sample_map(&self, path: &Path) {
+        self.image.save_sample_map(path);
+    }
 }
 
 impl Drop for PtRenderer {
@@ -147,6 +401,45 @@ impl Drop for PtRenderer {
         for handle in self.thread_handles.drain(..) {
             handle.join().unwrap();
         }
-        stats::stop_render();
+        stats::stop_render(self.stats_rx.try_iter().collect());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eta_extrapolates_elapsed_time() {
+        let elapsed = Duration::from_secs(30);
+        assert_eq!(eta(0.0, elapsed), None);
+        assert_eq!(eta(0.25, elapsed), Some(Duration::from_secs(90)));
+        assert_eq!(eta(0.5, elapsed), Some(Duration::from_secs(30)));
+        assert_eq!(eta(1.0, elapsed), Some(Duration::from_secs(0)));
+        // Overshooting the sample count doesn't give negative estimates
+        assert_eq!(eta(1.5, elapsed), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn progress_title() {
+        let progress = Progress {
+            min_samples: 3,
+            mean_samples: 4.3,
+            iteration: 9,
+            max_iterations: Some(8),
+            fraction: Some(1.0),
+            eta: Some(Duration::from_secs(125)),
+        };
+        assert_eq!(
+            progress.to_string(),
+            "4.3 spp (min 3), iteration 8/8, 100%, ETA 2:05"
+        );
+        let unlimited = Progress {
+            max_iterations: None,
+            fraction: None,
+            eta: None,
+            ..progress
+        };
+        assert_eq!(unlimited.to_string(), "4.3 spp (min 3), iteration 10");
     }
 }
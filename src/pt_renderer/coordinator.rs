@@ -1,61 +1,509 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use cgmath::Point2;
 
 use glium::Rect;
 
+use crate::config::{RenderMode, TileOrder};
+use crate::float::*;
 use crate::pt_renderer::RenderConfig;
 
+use super::tracers::Strategies;
+
+/// Number of times each block is rendered before it can be considered converged
+const ADAPTIVE_WARMUP_ITERATIONS: usize = 4;
+/// Variance at which variance driven russian roulette starts to lower survival
+/// probabilities if adaptive sampling doesn't define a threshold
+const DEFAULT_RR_VARIANCE: Float = 1e-3;
+/// Minimum scale of the survival probabilities of variance driven russian roulette
+const MIN_RR_SCALE: Float = 0.25;
+/// Number of parts per side that the blocks at the end of the render are split into
+const TAIL_SPLIT: usize = 2;
+
 pub struct RenderCoordinator {
     pub width: u32,
     pub height: u32,
     max_blocks: Option<usize>,
+    /// Index of the first block that is split into TAIL_SPLIT^2 parts so that
+    /// the workers run out of work at roughly the same time
+    split_start: Option<usize>,
+    /// Number of blocks or parts of split blocks handed out so far
     current_block: AtomicUsize,
     block_width: u32,
     block_height: u32,
     x_blocks: usize,
-    y_blocks: usize,
+    /// Part of the image that is rendered. The whole image if the render isn't cropped.
+    render_rect: Rect,
+    /// Indices of the blocks in the order they are rendered within an iteration.
+    /// Only contains the blocks that intersect the render rect.
+    block_order: Vec<usize>,
+    adaptive_threshold: Option<Float>,
+    variance_rr: bool,
+    /// Sum of the reported variances and the number of reports for each block
+    block_variances: Mutex<Vec<(Float, usize)>>,
+    converged: Vec<AtomicBool>,
+    n_converged: AtomicUsize,
+    /// Strategy statistics of all the workers for variance aware MIS
+    strategies: Option<Mutex<Strategies>>,
 }
 
 impl RenderCoordinator {
-    pub fn new(config: &RenderConfig) -> RenderCoordinator {
+    pub fn new(config: &RenderConfig, n_workers: usize) -> RenderCoordinator {
         let width = config.width;
         let height = config.height;
         let block_height = 50;
         let block_width = 50;
         let x_blocks = (f64::from(width) / f64::from(block_width)).ceil() as usize;
         let y_blocks = (f64::from(height) / f64::from(block_height)).ceil() as usize;
-        let blocks_per_iter = x_blocks * y_blocks;
-        let max_blocks = config.max_iterations.map(|iters| iters * blocks_per_iter);
+        let n_blocks = x_blocks * y_blocks;
+        let full_rect = Rect {
+            left: 0,
+            bottom: 0,
+            width,
+            height,
+        };
+        let render_rect = match config.crop {
+            Some(crop) => intersection(crop, full_rect)
+                .unwrap_or_else(|| panic!("Crop {:?} is outside the image", crop)),
+            None => full_rect,
+        };
+        let block_order: Vec<usize> = block_order(config.tile_order, x_blocks, y_blocks)
+            .into_iter()
+            .filter(|&block_i| {
+                let rect = grid_rect(block_i, x_blocks, block_width, block_height, full_rect);
+                intersection(rect, render_rect).is_some()
+            })
+            .collect();
+        let max_blocks = config.max_iterations.map(|iters| iters * block_order.len());
+        // The last block of each worker is split. A single worker has no one to
+        // share with and each part of a ppm block would trace all the photons.
+        let split_start = match max_blocks {
+            Some(max) if n_workers > 1 && config.render_mode != RenderMode::Ppm => {
+                Some(max.saturating_sub(n_workers))
+            }
+            _ => None,
+        };
         RenderCoordinator {
             width,
             height,
             max_blocks,
+            split_start,
             current_block: AtomicUsize::new(0),
             block_width,
             block_height,
             x_blocks,
-            y_blocks,
+            render_rect,
+            block_order,
+            adaptive_threshold: config.adaptive_threshold,
+            variance_rr: config.variance_rr,
+            block_variances: Mutex::new(vec![(0.0, 0); n_blocks]),
+            converged: (0..n_blocks).map(|_| AtomicBool::new(false)).collect(),
+            n_converged: AtomicUsize::new(0),
+            strategies: if config.variance_aware_mis && config.render_mode == RenderMode::Bdpt {
+                Some(Mutex::new(Strategies::new(config.max_bounces)))
+            } else {
+                None
+            },
         }
     }
 
-    pub fn next_block(&self) -> Option<Rect> {
-        let block_i = self.current_block.fetch_add(1, Ordering::Relaxed);
-        if let Some(max) = self.max_blocks {
-            if block_i >= max {
+    /// Number of blocks rendered in each iteration
+    pub fn blocks_per_iter(&self) -> usize {
+        self.block_order.len()
+    }
+
+    /// Part of the image that is rendered
+    pub fn render_rect(&self) -> Rect {
+        self.render_rect
+    }
+
+    /// Check if the pixel is inside the rendered part of the image
+    pub fn is_rendered(&self, pixel: Point2<u32>) -> bool {
+        let rect = self.render_rect;
+        pixel.x >= rect.left
+            && pixel.x < rect.left + rect.width
+            && pixel.y >= rect.bottom
+            && pixel.y < rect.bottom + rect.height
+    }
+
+    /// Scale of the splats that compensates for the light paths that cropped
+    /// pixels would have traced. Light paths are traced per camera sample and
+    /// the splats of a full render are normalized by the number of image pixels.
+    pub fn splat_scale(&self) -> Float {
+        let full = self.width * self.height;
+        let rendered = self.render_rect.width * self.render_rect.height;
+        full.to_float() / rendered.to_float()
+    }
+
+    /// Index of the iteration whose blocks are being handed out to the workers.
+    /// Equals max_iterations once all the blocks have been handed out.
+    pub fn iteration(&self) -> usize {
+        let started = self.current_block.load(Ordering::Relaxed);
+        let (block_i, part) = self.split_index(started);
+        // Blocks whose first part has been handed out have started
+        let started = if part.unwrap_or(0) > 0 {
+            block_i + 1
+        } else {
+            block_i
+        };
+        let started = self.max_blocks.map_or(started, |max| started.min(max));
+        started / self.blocks_per_iter()
+    }
+
+    /// Get the next block to render and the index of the iteration it belongs to.
+    /// Converged blocks are skipped and None is returned once all blocks
+    /// have converged or the maximum number of iterations has been reached.
+    /// The last blocks are handed out in parts.
+    pub fn next_block(&self) -> Option<(Rect, usize)> {
+        loop {
+            if self.n_converged.load(Ordering::Relaxed) == self.blocks_per_iter() {
                 return None;
             }
+            let (block_i, part) =
+                self.split_index(self.current_block.fetch_add(1, Ordering::Relaxed));
+            if let Some(max) = self.max_blocks {
+                if block_i >= max {
+                    return None;
+                }
+            };
+            let iter_i = self.block_order[block_i % self.blocks_per_iter()];
+            if self.converged[iter_i].load(Ordering::Relaxed) {
+                continue;
+            }
+            let rect = self.block_rect(iter_i);
+            let rect = match part {
+                Some(part) => split_rect(rect, part),
+                None => rect,
+            };
+            // Parts of blocks narrower than TAIL_SPLIT pixels may be empty
+            if rect.width > 0 && rect.height > 0 {
+                let iteration = block_i / self.blocks_per_iter();
+                return Some((rect, iteration));
+            }
+        }
+    }
+
+    /// Map the number of handed out blocks and parts to the index of the block
+    /// and the index of the part if the block is split
+    fn split_index(&self, i: usize) -> (usize, Option<usize>) {
+        match self.split_start {
+            Some(start) if i >= start => {
+                let n_parts = TAIL_SPLIT * TAIL_SPLIT;
+                (start + (i - start) / n_parts, Some((i - start) % n_parts))
+            }
+            _ => (i, None),
+        }
+    }
+
+    /// Report the estimated relative variance of a single pass over the block.
+    /// Blocks are marked converged once the variance of their accumulated mean
+    /// drops below the adaptive threshold.
+    pub fn report_variance(&self, rect: Rect, variance: Float) {
+        if self.adaptive_threshold.is_none() && !self.variance_rr {
+            return;
+        }
+        let block_i = self.block_index(rect);
+        // Parts of split blocks are rendered last so their variances are never used
+        if rect != self.block_rect(block_i) {
+            return;
+        }
+        let mut block_variances = self.block_variances.lock().unwrap();
+        let (sum, n) = &mut block_variances[block_i];
+        *sum += variance;
+        *n += 1;
+        let threshold = match self.adaptive_threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        // Passes are independent so the variance of the mean is the sum over n^2
+        let mean_variance = *sum / (*n * *n).to_float();
+        if *n >= ADAPTIVE_WARMUP_ITERATIONS
+            && mean_variance < threshold
+            && !self.converged[block_i].swap(true, Ordering::Relaxed)
+        {
+            self.n_converged.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Get empty strategy statistics for a worker if variance aware MIS is used
+    pub fn new_strategies(&self) -> Option<Strategies> {
+        self.strategies.as_ref().map(|strategies| {
+            let mut local = strategies.lock().unwrap().clone();
+            local.clear_samples();
+            local
+        })
+    }
+
+    /// Merge the samples of a worker to the shared statistics
+    /// and update the worker with the new factors
+    pub fn report_strategies(&self, local: &mut Strategies) {
+        if let Some(strategies) = &self.strategies {
+            let mut strategies = strategies.lock().unwrap();
+            strategies.merge(local);
+            local.copy_factors(&strategies);
+            local.clear_samples();
+        }
+    }
+
+    /// Scale for the russian roulette survival probabilities of the paths in rect.
+    /// Blocks whose mean has a low variance use lower survival probabilities
+    /// so that the work is focused on the noisy blocks.
+    pub fn rr_scale(&self, rect: Rect) -> Float {
+        if !self.variance_rr {
+            return 1.0;
+        }
+        let block_i = self.block_index(rect);
+        let (sum, n) = self.block_variances.lock().unwrap()[block_i];
+        if n < ADAPTIVE_WARMUP_ITERATIONS {
+            return 1.0;
+        }
+        let mean_variance = sum / (n * n).to_float();
+        let reference = self.adaptive_threshold.unwrap_or(DEFAULT_RR_VARIANCE);
+        (mean_variance / reference).sqrt().clamp(MIN_RR_SCALE, 1.0)
+    }
+
+    fn block_index(&self, rect: Rect) -> usize {
+        let x_i = (rect.left / self.block_width) as usize;
+        let y_i = (rect.bottom / self.block_height) as usize;
+        y_i * self.x_blocks + x_i
+    }
+
+    /// Rect of the block clipped to the rendered part of the image
+    fn block_rect(&self, block_i: usize) -> Rect {
+        let full_rect = Rect {
+            left: 0,
+            bottom: 0,
+            width: self.width,
+            height: self.height,
         };
-        let iter_i = block_i % (self.x_blocks * self.y_blocks);
-        let x_i = (iter_i % self.x_blocks) as u32;
-        let y_i = (iter_i / self.x_blocks) as u32;
-        let start_x = self.block_width * x_i;
-        let end_x = (self.block_width * (x_i + 1)).min(self.width);
-        let start_y = self.block_height * y_i;
-        let end_y = (self.block_height * (y_i + 1)).min(self.height);
+        let rect = grid_rect(
+            block_i,
+            self.x_blocks,
+            self.block_width,
+            self.block_height,
+            full_rect,
+        );
+        intersection(rect, self.render_rect).expect("Block outside the render rect")
+    }
+}
+
+/// Rect of the block block_i of a grid that covers the image
+fn grid_rect(
+    block_i: usize,
+    x_blocks: usize,
+    block_width: u32,
+    block_height: u32,
+    image: Rect,
+) -> Rect {
+    let x_i = (block_i % x_blocks) as u32;
+    let y_i = (block_i / x_blocks) as u32;
+    let start_x = block_width * x_i;
+    let end_x = (block_width * (x_i + 1)).min(image.width);
+    let start_y = block_height * y_i;
+    let end_y = (block_height * (y_i + 1)).min(image.height);
+    Rect {
+        left: start_x,
+        bottom: start_y,
+        width: end_x - start_x,
+        height: end_y - start_y,
+    }
+}
+
+/// Part part of rect when it is split into TAIL_SPLIT parts per side.
+/// The parts may be empty if the rect is narrower than TAIL_SPLIT pixels.
+fn split_rect(rect: Rect, part: usize) -> Rect {
+    let n = TAIL_SPLIT as u32;
+    let x_i = part as u32 % n;
+    let y_i = part as u32 / n;
+    let start_x = rect.left + rect.width * x_i / n;
+    let end_x = rect.left + rect.width * (x_i + 1) / n;
+    let start_y = rect.bottom + rect.height * y_i / n;
+    let end_y = rect.bottom + rect.height * (y_i + 1) / n;
+    Rect {
+        left: start_x,
+        bottom: start_y,
+        width: end_x - start_x,
+        height: end_y - start_y,
+    }
+}
+
+/// Overlap of the rects or None if they don't overlap
+fn intersection(r1: Rect, r2: Rect) -> Option<Rect> {
+    let left = r1.left.max(r2.left);
+    let bottom = r1.bottom.max(r2.bottom);
+    let right = (r1.left + r1.width).min(r2.left + r2.width);
+    let top = (r1.bottom + r1.height).min(r2.bottom + r2.height);
+    if left < right && bottom < top {
         Some(Rect {
-            left: start_x,
-            bottom: start_y,
-            width: end_x - start_x,
-            height: end_y - start_y,
+            left,
+            bottom,
+            width: right - left,
+            height: top - bottom,
         })
+    } else {
+        None
+    }
+}
+
+/// Indices of the blocks of an x_blocks * y_blocks grid in the given order
+fn block_order(order: TileOrder, x_blocks: usize, y_blocks: usize) -> Vec<usize> {
+    // Offset of the block from the center of the grid in blocks
+    let offset = |block_i: usize| {
+        let dx = (block_i % x_blocks) as f64 - (x_blocks - 1) as f64 / 2.0;
+        let dy = (block_i / x_blocks) as f64 - (y_blocks - 1) as f64 / 2.0;
+        (dx, dy)
+    };
+    let mut blocks: Vec<usize> = (0..x_blocks * y_blocks).collect();
+    match order {
+        TileOrder::Raster => {}
+        TileOrder::Spiral => {
+            // Square rings around the center and counterclockwise within a ring
+            let key = |block_i: usize| {
+                let (dx, dy) = offset(block_i);
+                (dx.abs().max(dy.abs()), dy.atan2(dx))
+            };
+            blocks.sort_by(|&b1, &b2| key(b1).partial_cmp(&key(b2)).unwrap());
+        }
+        TileOrder::Hilbert => {
+            // Curve over the smallest power of two grid that covers all the blocks
+            let n = x_blocks.max(y_blocks).next_power_of_two();
+            blocks = (0..n * n)
+                .map(|d| hilbert_point(n, d))
+                .filter(|&(x, y)| x < x_blocks && y < y_blocks)
+                .map(|(x, y)| y * x_blocks + x)
+                .collect();
+            let distance = |block_i: usize| {
+                let (dx, dy) = offset(block_i);
+                dx * dx + dy * dy
+            };
+            // Start from the center and wrap around to the start of the curve
+            let start = (0..blocks.len()).min_by(|&i1, &i2| {
+                distance(blocks[i1])
+                    .partial_cmp(&distance(blocks[i2]))
+                    .unwrap()
+            });
+            if let Some(start) = start {
+                blocks.rotate_left(start);
+            }
+        }
+    }
+    blocks
+}
+
+/// Point at distance d along the Hilbert curve that fills an n * n grid.
+/// n has to be a power of two.
+fn hilbert_point(n: usize, d: usize) -> (usize, usize) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        // Rotate the quadrant
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_orders_cover_all_blocks() {
+        for &order in &[TileOrder::Raster, TileOrder::Spiral, TileOrder::Hilbert] {
+            for &(x_blocks, y_blocks) in &[(1, 1), (3, 2), (5, 7), (8, 8), (13, 1)] {
+                let mut blocks = block_order(order, x_blocks, y_blocks);
+                blocks.sort_unstable();
+                let expected: Vec<usize> = (0..x_blocks * y_blocks).collect();
+                assert_eq!(blocks, expected, "{:?} {}x{}", order, x_blocks, y_blocks);
+            }
+        }
+    }
+
+    #[test]
+    fn spiral_and_hilbert_start_from_center() {
+        for &order in &[TileOrder::Spiral, TileOrder::Hilbert] {
+            assert_eq!(block_order(order, 5, 3)[0], 7, "{:?}", order);
+        }
+    }
+
+    #[test]
+    fn hilbert_steps_to_neighbours() {
+        let n = 8;
+        for d in 1..n * n {
+            let (x0, y0) = hilbert_point(n, d - 1);
+            let (x1, y1) = hilbert_point(n, d);
+            let step = (x0 as isize - x1 as isize).abs() + (y0 as isize - y1 as isize).abs();
+            assert_eq!(step, 1, "{}", d);
+        }
+    }
+
+    /// Hand out all the blocks and count how many times each pixel was rendered
+    fn pixel_coverage(coordinator: &RenderCoordinator) -> Vec<usize> {
+        let (width, height) = (coordinator.width as usize, coordinator.height as usize);
+        let mut coverage = vec![0; width * height];
+        while let Some((rect, _)) = coordinator.next_block() {
+            for y in rect.bottom..rect.bottom + rect.height {
+                for x in rect.left..rect.left + rect.width {
+                    coverage[y as usize * width + x as usize] += 1;
+                }
+            }
+        }
+        coverage
+    }
+
+    #[test]
+    fn crop_renders_only_the_crop() {
+        let config = RenderConfig::bdpt().patched(&[
+            ("width", "120"),
+            ("height", "70"),
+            ("max_iterations", "2"),
+            ("crop", "\"40 30 100 20\""),
+        ]);
+        let coordinator = RenderCoordinator::new(&config, 1);
+        let crop = coordinator.render_rect();
+        // The crop is clipped to the image
+        assert_eq!(
+            (crop.left, crop.bottom, crop.width, crop.height),
+            (40, 30, 80, 20)
+        );
+        // The crop overlaps all the blocks of the first row only
+        assert_eq!(coordinator.blocks_per_iter(), 3);
+        assert_eq!(coordinator.splat_scale(), 120.0 * 70.0 / (80.0 * 20.0));
+        let coverage = pixel_coverage(&coordinator);
+        for y in 0..70 {
+            for x in 0..120 {
+                let rendered = coordinator.is_rendered(Point2::new(x, y));
+                let expected = if rendered { 2 } else { 0 };
+                assert_eq!(coverage[(y * 120 + x) as usize], expected, "{} {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn split_blocks_cover_the_image_once() {
+        for &n_workers in &[1, 2, 4, 8] {
+            let config = RenderConfig::bdpt().patched(&[
+                ("width", "133"),
+                ("height", "61"),
+                ("max_iterations", "1"),
+            ]);
+            let coordinator = RenderCoordinator::new(&config, n_workers);
+            let coverage = pixel_coverage(&coordinator);
+            assert!(coverage.iter().all(|&n| n == 1), "{} workers", n_workers);
+            assert_eq!(coordinator.iteration(), 1);
+        }
     }
 }
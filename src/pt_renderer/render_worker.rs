@@ -1,17 +1,29 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::sync::{
-    mpsc::{Receiver, Sender, TryRecvError},
+    mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError},
     Arc,
 };
+use std::time::Instant;
 
 use cgmath::prelude::*;
-use cgmath::{Point2, Point3, Vector4};
+use cgmath::{Point2, Point3, Vector3, Vector4};
+
+use glium::Rect;
+
+use rand::prelude::*;
+use rand::rngs::StdRng;
 
 use crate::camera::PtCamera;
 use crate::color::Color;
 use crate::config::*;
+use crate::consts;
 use crate::float::*;
 use crate::intersect::Ray;
+use crate::sampler;
 use crate::scene::Scene;
+use crate::stats::WorkerStats;
 
 use super::tracers;
 use super::{PtResult, RenderCoordinator};
@@ -23,6 +35,7 @@ pub struct RenderWorker {
     coordinator: Arc<RenderCoordinator>,
     message_rx: Receiver<()>,
     result_tx: Sender<PtResult>,
+    stats_tx: Sender<WorkerStats>,
 }
 
 impl RenderWorker {
@@ -33,6 +46,7 @@ impl RenderWorker {
         coordinator: Arc<RenderCoordinator>,
         message_rx: Receiver<()>,
         result_tx: Sender<PtResult>,
+        stats_tx: Sender<WorkerStats>,
     ) -> RenderWorker {
         RenderWorker {
             scene,
@@ -41,14 +55,34 @@ impl RenderWorker {
             coordinator,
             message_rx,
             result_tx,
+            stats_tx,
         }
     }
 
+    /// Render blocks until the coordinator runs out of them or the worker is
+    /// stopped and then send the stats of the worker
     pub fn run(&self) {
+        let start_rays = Ray::thread_count();
+        let mut stats = WorkerStats::default();
+        self.render(&mut stats);
+        stats.rays = Ray::thread_count() - start_rays;
+        // The renderer may not collect the stats
+        self.stats_tx.send(stats).ok();
+    }
+
+    fn render(&self, stats: &mut WorkerStats) {
         let (width, height) = (self.coordinator.width, self.coordinator.height);
         let clip_to_world = self.camera.world_to_clip().invert().unwrap();
+        let pixel_spread = self.camera.pixel_spread(height);
         let mut node_stack = Vec::new();
         let mut splats = Vec::new();
+        let mut bdpt_buffers = tracers::BdptBuffers::default();
+        let mut photon_map = tracers::PhotonMap::default();
+        // Splats of a block are summed per pixel and sent together
+        // to avoid flooding the result channel
+        let mut block_splats: HashMap<(u32, u32), [f32; 3]> = HashMap::new();
+        let mut rng = StdRng::from_entropy();
+        let mut strategies = self.coordinator.new_strategies();
         loop {
             match self.message_rx.try_recv() {
                 Err(TryRecvError::Empty) => (),
@@ -58,18 +92,84 @@ impl RenderWorker {
                     return;
                 }
             }
-            if let Some(rect) = self.coordinator.next_block() {
+            if let Some((rect, iteration)) = self.coordinator.next_block() {
+                let block_start = Instant::now();
+                if let Some(seed) = self.config.seed {
+                    rng = StdRng::seed_from_u64(block_seed(seed, rect, iteration));
+                    sampler::seed(rng.gen());
+                }
                 let mut block = vec![0.0f32; (3 * rect.width * rect.height) as usize];
-                let sample_weight = 1.0 / self.config.samples_per_dir.pow(2).to_float();
+                let (mut albedo_block, mut normal_block) = if self.config.aovs {
+                    (block.clone(), block.clone())
+                } else {
+                    (Vec::new(), Vec::new())
+                };
+                let n_samples = self.config.samples_per_dir.pow(2);
+                let n_camera_paths = (rect.width * rect.height) as usize * n_samples;
+                stats.samples += n_camera_paths;
+                stats.paths += n_camera_paths + self.n_light_paths(n_camera_paths);
+                let sample_weight = 1.0 / n_samples.to_float();
+                let splat_weight = sample_weight * self.coordinator.splat_scale();
+                let mut block_variance = 0.0;
+                let rr_scale = self.coordinator.rr_scale(rect);
+                if self.config.render_mode == RenderMode::Bdpt && self.config.light_path_reuse > 0 {
+                    // The cache is shared by the pixels of the block
+                    // so it isn't stratified with the samples of any pixel
+                    sampler::start_pixel(SamplerType::Independent, 0);
+                    tracers::trace_light_cache(
+                        n_camera_paths,
+                        &self.scene,
+                        &self.camera,
+                        &self.config,
+                        &mut node_stack,
+                        &mut splats,
+                        &mut bdpt_buffers,
+                        strategies.as_mut(),
+                    );
+                    // Cache has a light path per camera sample so its splats weigh the same
+                    add_splats(&mut splats, splat_weight, width, height, &mut block_splats);
+                }
+                if self.config.render_mode == RenderMode::Ppm {
+                    // Each iteration of a block is an independent estimate
+                    // with its own photons and a smaller radius than the last
+                    sampler::start_pixel(SamplerType::Independent, 0);
+                    tracers::trace_photons(
+                        self.config.ppm_photons,
+                        tracers::gather_radius(iteration, &self.scene, &self.config),
+                        &self.scene,
+                        self.camera.flash(),
+                        &self.config,
+                        &mut node_stack,
+                        &mut photon_map,
+                    );
+                }
                 for h in 0..rect.height {
                     for w in 0..rect.width {
                         let mut c = Color::black();
-                        for j in 0..self.config.samples_per_dir {
-                            for i in 0..self.config.samples_per_dir {
-                                let dx = (i.to_float() + rand::random::<Float>())
-                                    / self.config.samples_per_dir.to_float();
-                                let dy = (j.to_float() + rand::random::<Float>())
-                                    / self.config.samples_per_dir.to_float();
+                        let mut albedo = Color::black();
+                        let mut normal = Vector3::zero();
+                        let mut luma_sum = 0.0;
+                        let mut luma_sq_sum = 0.0;
+                        let n = self.config.samples_per_dir;
+                        sampler::start_pixel(self.config.sampler, n_samples);
+                        let camera_samples = sampler::pixel_samples(n);
+                        // Light samples are stratified over the pixel as well but
+                        // they are shuffled to decorrelate them from the camera samples
+                        let mut light_samples = sampler::pixel_samples(n);
+                        light_samples.shuffle(&mut rng);
+                        for j in 0..n {
+                            for i in 0..n {
+                                let sample_i = j * n + i;
+                                sampler::start_sample(sample_i);
+                                // Debug modes sample the center of each stratum
+                                // so that their output is deterministic
+                                let (dx, dy) = match self.config.render_mode {
+                                    RenderMode::Debug(_) => (
+                                        (i.to_float() + 0.5) / n.to_float(),
+                                        (j.to_float() + 0.5) / n.to_float(),
+                                    ),
+                                    _ => (camera_samples[sample_i].x, camera_samples[sample_i].y),
+                                };
                                 let clip_x = 2.0 * ((rect.left + w).to_float() + dx)
                                     / width.to_float()
                                     - 1.0;
@@ -78,8 +178,19 @@ impl RenderWorker {
                                     - 1.0;
                                 let clip_p = Vector4::new(clip_x, clip_y, 1.0, 1.0);
                                 let world_p = Point3::from_homogeneous(clip_to_world * clip_p);
-                                let ray = Ray::from_point(self.camera.pos, world_p);
-                                c += match &self.config.render_mode {
+                                let ray = Ray::from_point(self.camera.pos, world_p)
+                                    .with_spread(pixel_spread);
+                                if self.config.aovs {
+                                    let (a, n) = tracers::trace_aovs(
+                                        ray.clone(),
+                                        &self.scene,
+                                        &self.config,
+                                        &mut node_stack,
+                                    );
+                                    albedo += a;
+                                    normal += n;
+                                }
+                                let sample = match &self.config.render_mode {
                                     RenderMode::Debug(mode) => tracers::debug_trace(
                                         ray,
                                         mode,
@@ -94,46 +205,292 @@ impl RenderWorker {
                                         self.camera.flash(),
                                         &self.config,
                                         &mut node_stack,
+                                        rr_scale,
                                     ),
                                     RenderMode::Bdpt => {
                                         let c = tracers::bdpt(
                                             ray,
+                                            light_samples[sample_i],
                                             &self.scene,
                                             &self.camera,
                                             &self.config,
                                             &mut node_stack,
                                             &mut splats,
+                                            &mut bdpt_buffers,
+                                            strategies.as_mut(),
+                                        );
+                                        add_splats(
+                                            &mut splats,
+                                            splat_weight,
+                                            width,
+                                            height,
+                                            &mut block_splats,
                                         );
-                                        // Consume splats
-                                        for (pos, mut rad) in splats.drain(..) {
-                                            let x = (0.5 * (pos.x + 1.0) * width.to_float()).floor()
-                                                as u32;
-                                            let y = (0.5 * (pos.y + 1.0) * height.to_float())
-                                                .floor()
-                                                as u32;
-                                            rad *= sample_weight;
-                                            let arr: [f32; 3] = rad.into();
-                                            self.result_tx
-                                                .send(PtResult::Splat(Point2::new(x, y), arr))
-                                                .expect("Receiver closed!");
-                                        }
                                         c
                                     }
-                                }
+                                    RenderMode::Ppm => tracers::ppm(
+                                        ray,
+                                        &self.scene,
+                                        self.camera.flash(),
+                                        &self.config,
+                                        &mut node_stack,
+                                        &photon_map,
+                                    ),
+                                };
+                                c += sample;
+                                luma_sum += sample.luma();
+                                luma_sq_sum += sample.luma().powi(2);
                             }
                         }
+                        block_variance += relative_variance(luma_sum, luma_sq_sum, n_samples);
                         c *= sample_weight;
                         let pixel_i = 3 * (h * rect.width + w) as usize;
                         let data: [f32; 3] = c.into();
                         block[pixel_i..pixel_i + 3].copy_from_slice(&data);
+                        if self.config.aovs {
+                            let albedo: [f32; 3] = (sample_weight * albedo).into();
+                            albedo_block[pixel_i..pixel_i + 3].copy_from_slice(&albedo);
+                            let normal: [f32; 3] =
+                                (sample_weight * normal).cast::<f32>().unwrap().into();
+                            normal_block[pixel_i..pixel_i + 3].copy_from_slice(&normal);
+                        }
                     }
                 }
+                block_variance /= (rect.width * rect.height).to_float();
+                if !block_splats.is_empty() {
+                    let splats = block_splats
+                        .drain()
+                        .map(|((x, y), sample)| (Point2::new(x, y), sample))
+                        .collect();
+                    self.result_tx
+                        .send(PtResult::Splats(splats))
+                        .expect("Receiver closed!");
+                }
+                if self.config.aovs {
+                    self.result_tx
+                        .send(PtResult::Aovs(rect, albedo_block, normal_block))
+                        .expect("Receiver closed!");
+                }
+                if let Some(strategies) = strategies.as_mut() {
+                    self.coordinator.report_strategies(strategies);
+                }
                 self.result_tx
-                    .send(PtResult::Block(rect, block))
+                    .send(PtResult::Block(rect, block, block_variance))
                     .expect("Receiver closed!");
+                if self.config.cpu_budget < 1.0 {
+                    // Sleep such that work is the budgeted fraction of the total time.
+                    // Waiting on the message channel lets the render stop during the sleep.
+                    // Cast is unnecessary with double precision
+                    #[allow(clippy::unnecessary_cast)]
+                    let sleep = block_start
+                        .elapsed()
+                        .mul_f64((1.0 / self.config.cpu_budget - 1.0) as f64);
+                    match self.message_rx.recv_timeout(sleep) {
+                        Err(RecvTimeoutError::Timeout) => (),
+                        Ok(_) => return,
+                        Err(RecvTimeoutError::Disconnected) => {
+                            println!(
+                                "Threads were not properly stopped before disconnecting channel!"
+                            );
+                            return;
+                        }
+                    }
+                }
             } else {
                 return;
             }
         }
     }
+
+    /// Number of light paths or photons traced for the camera paths of a block
+    fn n_light_paths(&self, n_camera_paths: usize) -> usize {
+        match self.config.render_mode {
+            // The cache has a light path per camera path
+            RenderMode::Bdpt if self.config.light_path_reuse > 0 => n_camera_paths,
+            RenderMode::Bdpt => n_camera_paths * self.config.light_paths_per_pixel.max(1),
+            RenderMode::Ppm => self.config.ppm_photons,
+            _ => 0,
+        }
+    }
+}
+
+/// Seed of the random numbers of a block. Depends only on the block
+/// so that it renders the same regardless of the thread rendering it.
+fn block_seed(seed: u64, rect: Rect, iteration: usize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (seed, rect.left, rect.bottom, iteration).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Consume the splats and add them to the per pixel sums of the block
+fn add_splats(
+    splats: &mut Vec<(Point2<Float>, Color)>,
+    weight: Float,
+    width: u32,
+    height: u32,
+    block_splats: &mut HashMap<(u32, u32), [f32; 3]>,
+) {
+    for (pos, mut rad) in splats.drain(..) {
+        // Splats on the right or top edge belong to the last pixel
+        let x = ((0.5 * (pos.x + 1.0) * width.to_float()).floor() as u32).min(width - 1);
+        let y = ((0.5 * (pos.y + 1.0) * height.to_float()).floor() as u32).min(height - 1);
+        rad *= weight;
+        let arr: [f32; 3] = rad.into();
+        let sum = block_splats.entry((x, y)).or_insert([0.0; 3]);
+        for c in 0..3 {
+            sum[c] += arr[c];
+        }
+    }
+}
+
+/// Estimate the relative variance of the mean of n samples from their sums.
+/// Dark pixels are clamped to avoid dividing by zero which means that
+/// their noise is measured in absolute rather than relative terms.
+fn relative_variance(sum: Float, sq_sum: Float, n: usize) -> Float {
+    // Variance can't be estimated from a single sample
+    if n < 2 {
+        return consts::INFINITY;
+    }
+    let n_f = n.to_float();
+    let mean = sum / n_f;
+    let sample_variance = ((sq_sum - n_f * mean.powi(2)) / (n_f - 1.0)).max(0.0);
+    let min_mean: Float = 0.01;
+    sample_variance / n_f / mean.max(min_mean).powi(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+    use std::sync::mpsc;
+
+    use cgmath::Quaternion;
+
+    use crate::camera::Camera;
+    use crate::scene::SceneBuilder;
+    use crate::stats;
+
+    fn cornell_box(config: &RenderConfig) -> Arc<Scene> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("scenes")
+            .join("cornell-box")
+            .join("CornellBox-Original.obj");
+        SceneBuilder::new(config).build(&path).unwrap()
+    }
+
+    /// Small single iteration render
+    fn test_config(render_mode: RenderMode) -> RenderConfig {
+        RenderConfig {
+            width: 60,
+            height: 40,
+            samples_per_dir: 2,
+            max_bounces: 3,
+            render_mode,
+            ..RenderConfig::benchmark()
+        }
+    }
+
+    /// Rendered blocks keyed by the position of their bottom left pixel
+    type Blocks = Vec<((u32, u32), Vec<f32>)>;
+
+    /// Render with n_workers threads until the coordinator runs out of blocks.
+    /// Return the blocks ordered by their position and the stats of the workers.
+    fn render(
+        scene: &Arc<Scene>,
+        config: &RenderConfig,
+        n_workers: usize,
+    ) -> (Blocks, Vec<WorkerStats>) {
+        let coordinator = Arc::new(RenderCoordinator::new(config, n_workers));
+        let (result_tx, result_rx) = mpsc::channel();
+        let (stats_tx, stats_rx) = mpsc::channel();
+        // Workers stop if their message channel disconnects
+        let mut message_txs = Vec::new();
+        std::thread::scope(|s| {
+            for _ in 0..n_workers {
+                let (message_tx, message_rx) = mpsc::channel();
+                message_txs.push(message_tx);
+                let camera = Camera::new(Point3::new(0.0, 0.8, 2.5), Quaternion::one());
+                let worker = RenderWorker::new(
+                    scene.clone(),
+                    PtCamera::new(camera),
+                    config.clone(),
+                    coordinator.clone(),
+                    message_rx,
+                    result_tx.clone(),
+                    stats_tx.clone(),
+                );
+                s.spawn(move || worker.run());
+            }
+        });
+        drop((result_tx, stats_tx));
+        let mut blocks: Vec<_> = result_rx
+            .try_iter()
+            .filter_map(|result| match result {
+                PtResult::Block(rect, block, _) => Some(((rect.left, rect.bottom), block)),
+                _ => None,
+            })
+            .collect();
+        blocks.sort_by_key(|(pos, _)| *pos);
+        (blocks, stats_rx.try_iter().collect())
+    }
+
+    /// Bits of the blocks so that NaNs compare equal as well
+    fn block_bits(blocks: &Blocks) -> Vec<Vec<u32>> {
+        blocks
+            .iter()
+            .map(|(_, block)| block.iter().map(|c| c.to_bits()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn fixed_seed_is_reproducible() {
+        let config = RenderConfig {
+            seed: Some(7),
+            ..test_config(RenderMode::PathTracing)
+        };
+        let _stats = stats::test_scene("seed");
+        let scene = cornell_box(&config);
+        let (first, _) = render(&scene, &config, 1);
+        let (second, _) = render(&scene, &config, 1);
+        assert_eq!(first.len(), 2);
+        assert!(first
+            .iter()
+            .any(|(_, block)| block.iter().any(|&c| c > 0.0)));
+        assert_eq!(block_bits(&first), block_bits(&second));
+        // A different seed gives different noise
+        let other_seed = RenderConfig {
+            seed: Some(8),
+            ..config
+        };
+        let (other, _) = render(&scene, &other_seed, 1);
+        assert_ne!(block_bits(&first), block_bits(&other));
+    }
+
+    #[test]
+    fn debug_render_is_deterministic() {
+        // Without a seed and with blocks split between threads
+        let config = test_config(RenderMode::Debug(DebugMode::Depth));
+        let _stats = stats::test_scene("debug");
+        let scene = cornell_box(&config);
+        let (first, _) = render(&scene, &config, 2);
+        let (second, _) = render(&scene, &config, 2);
+        assert!(first
+            .iter()
+            .any(|(_, block)| block.iter().any(|&c| c > 0.0)));
+        assert_eq!(block_bits(&first), block_bits(&second));
+    }
+
+    #[test]
+    fn worker_ray_counts_sum_to_total() {
+        let config = test_config(RenderMode::PathTracing);
+        let _stats = stats::test_scene("ray_counts");
+        let scene = cornell_box(&config);
+        stats::start_render();
+        let (_, workers) = render(&scene, &config, 3);
+        assert_eq!(workers.len(), 3);
+        assert!(workers.iter().any(|w| w.rays > 0));
+        let total: usize = workers.iter().map(|w| w.rays).sum();
+        assert_eq!(total, Ray::count());
+    }
 }
@@ -6,18 +6,100 @@ use glium::backend::Facade;
 use glium::framebuffer::SimpleFrameBuffer;
 use glium::texture::{
     ClientFormat, MipmapsOption, RawImage2d, SrgbTexture2d, Texture2d, UncompressedFloatFormat,
-    UncompressedUintFormat, UnsignedTexture2d,
 };
 use glium::{uniform, DrawParameters, IndexBuffer, Rect, Surface, VertexBuffer};
 
+use crate::color::Color;
+use crate::compare::HdrImage;
+use crate::config::ToneMap;
+use crate::float::*;
 use crate::pt_renderer::RenderConfig;
 use crate::vertex::RawVertex;
 
+/// Running statistics of the samples of a single pixel
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PixelAccumulator {
+    sum: [f32; 3],
+    sum_sq: [f32; 3],
+    n: u32,
+}
+
+#[allow(dead_code)]
+impl PixelAccumulator {
+    #[allow(clippy::needless_range_loop)]
+    pub fn add(&mut self, sample: [f32; 3]) {
+        for c in 0..3 {
+            self.sum[c] += sample[c];
+            self.sum_sq[c] += sample[c].powi(2);
+        }
+        self.n += 1;
+    }
+
+    /// Add a splatted light sample. Splats only add to the value of
+    /// the current samples so they don't count as separate samples.
+    #[allow(clippy::needless_range_loop)]
+    pub fn add_splat(&mut self, sample: [f32; 3]) {
+        for c in 0..3 {
+            self.sum[c] += sample[c];
+        }
+    }
+
+    pub fn mean(&self) -> [f32; 3] {
+        let n = self.n.max(1) as f32;
+        [self.sum[0] / n, self.sum[1] / n, self.sum[2] / n]
+    }
+
+    /// Unbiased sample variance of each channel
+    pub fn variance(&self) -> [f32; 3] {
+        if self.n < 2 {
+            return [0.0; 3];
+        }
+        let n = self.n as f32;
+        let mut var = [0.0; 3];
+        for (c, v) in var.iter_mut().enumerate() {
+            let mean = self.sum[c] / n;
+            // Rounding errors can make this slightly negative
+            *v = ((self.sum_sq[c] - n * mean.powi(2)) / (n - 1.0)).max(0.0);
+        }
+        var
+    }
+
+    /// Standard error of the mean of each channel
+    pub fn std_error(&self) -> [f32; 3] {
+        let n = self.n.max(1) as f32;
+        let var = self.variance();
+        [
+            (var[0] / n).sqrt(),
+            (var[1] / n).sqrt(),
+            (var[2] / n).sqrt(),
+        ]
+    }
+
+    pub fn sum(&self) -> [f32; 3] {
+        self.sum
+    }
+
+    pub fn n_samples(&self) -> u32 {
+        self.n
+    }
+}
+
+/// Linear luminance that the tone mapping maps to white
+const TONE_MAP_WHITE: f32 = 10.0;
+/// Percentile of the pixel luminances that auto exposure maps to white
+const AUTO_EXPOSURE_PERCENTILE: f32 = 0.9;
+
 pub struct TracedImage {
-    pixels: Vec<f32>,
-    n_samples: Vec<u32>,
+    pixels: Vec<PixelAccumulator>,
+    /// First hit albedos and shading normals. Empty if aovs are disabled.
+    albedo: Vec<PixelAccumulator>,
+    normal: Vec<PixelAccumulator>,
     width: u32,
     height: u32,
+    /// Bounding rect of the pixels that have changed since the last upload
+    dirty: Option<Rect>,
+    upload_interval: usize,
+    frames_since_upload: usize,
     visualizer: Visualizer,
 }
 
@@ -25,55 +107,170 @@ impl TracedImage {
     pub fn new<F: Facade>(facade: &F, config: &RenderConfig) -> Self {
         let width = config.width;
         let height = config.height;
-        let pixels = vec![0.0; (3 * width * height) as usize];
-        let n_samples = vec![0; (width * height) as usize];
+        let pixels = vec![PixelAccumulator::default(); (width * height) as usize];
+        let (albedo, normal) = if config.aovs {
+            (pixels.clone(), pixels.clone())
+        } else {
+            (Vec::new(), Vec::new())
+        };
         let visualizer = Visualizer::new(facade, config);
+        // Textures are uninitialized so everything needs to be uploaded
+        let dirty = Some(Rect {
+            left: 0,
+            bottom: 0,
+            width,
+            height,
+        });
         Self {
             pixels,
-            n_samples,
+            albedo,
+            normal,
             width,
             height,
+            dirty,
+            upload_interval: config.upload_interval.max(1),
+            frames_since_upload: 0,
             visualizer,
         }
     }
 
+    /// Minimum and mean number of samples of the pixels in rect
+    pub fn n_samples(&self, rect: Rect) -> (u32, Float) {
+        let counts: Vec<u32> = (rect.bottom..rect.bottom + rect.height)
+            .flat_map(|h| {
+                (rect.left..rect.left + rect.width)
+                    .map(move |w| self.pixels[(h * self.width + w) as usize].n_samples())
+            })
+            .collect();
+        let min = counts.iter().copied().min().unwrap_or(0);
+        let sum: u64 = counts.iter().map(|&n| u64::from(n)).sum();
+        (min, sum as Float / counts.len().max(1).to_float())
+    }
+
     pub fn add_sample(&mut self, rect: Rect, sample: &[f32]) {
         for h in 0..rect.height {
             for w in 0..rect.width {
                 let i_image = ((h + rect.bottom) * self.width + w + rect.left) as usize;
                 let i_block = (h * rect.width + w) as usize;
-                self.n_samples[i_image] += 1;
-                for c in 0..3 {
-                    self.pixels[3 * i_image + c] += sample[3 * i_block + c];
-                }
+                let s = &sample[3 * i_block..3 * i_block + 3];
+                self.pixels[i_image].add([s[0], s[1], s[2]]);
+            }
+        }
+        self.mark_dirty(rect);
+    }
+
+    pub fn add_aovs(&mut self, rect: Rect, albedo: &[f32], normal: &[f32]) {
+        for h in 0..rect.height {
+            for w in 0..rect.width {
+                let i_image = ((h + rect.bottom) * self.width + w + rect.left) as usize;
+                let i_block = 3 * (h * rect.width + w) as usize;
+                let a = &albedo[i_block..i_block + 3];
+                self.albedo[i_image].add([a[0], a[1], a[2]]);
+                let n = &normal[i_block..i_block + 3];
+                self.normal[i_image].add([n[0], n[1], n[2]]);
             }
         }
     }
 
-    #[allow(clippy::needless_range_loop)]
     pub fn add_splat(&mut self, pixel: Point2<u32>, sample: [f32; 3]) {
         let i_image = (pixel.y * self.width + pixel.x) as usize;
-        for c in 0..3 {
-            self.pixels[3 * i_image + c] += sample[c];
+        self.pixels[i_image].add_splat(sample);
+        self.mark_dirty(Rect {
+            left: pixel.x,
+            bottom: pixel.y,
+            width: 1,
+            height: 1,
+        });
+    }
+
+    fn mark_dirty(&mut self, rect: Rect) {
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => {
+                let left = dirty.left.min(rect.left);
+                let bottom = dirty.bottom.min(rect.bottom);
+                let right = (dirty.left + dirty.width).max(rect.left + rect.width);
+                let top = (dirty.bottom + dirty.height).max(rect.bottom + rect.height);
+                Rect {
+                    left,
+                    bottom,
+                    width: right - left,
+                    height: top - bottom,
+                }
+            }
+            None => rect,
+        });
+    }
+
+    /// Upload the changed pixels to the GPU
+    fn upload(&mut self) {
+        if let Some(rect) = self.dirty.take() {
+            let mut data = Vec::with_capacity((3 * rect.width * rect.height) as usize);
+            let mut n_samples = Vec::with_capacity((rect.width * rect.height) as usize);
+            for y in rect.bottom..rect.bottom + rect.height {
+                let start = (y * self.width + rect.left) as usize;
+                let end = start + rect.width as usize;
+                for pixel in &self.pixels[start..end] {
+                    data.extend_from_slice(&pixel.sum());
+                    // Sample counts are uploaded as floats since unsigned textures can't be
+                    // partially updated. Floats are exact up to 2^24 samples.
+                    n_samples.push(pixel.n_samples() as f32);
+                }
+            }
+            self.visualizer.upload(rect, data, n_samples);
         }
+        self.frames_since_upload = 0;
     }
 
-    pub fn render<F: Facade, S: Surface>(&self, facade: &F, target: &mut S) {
-        self.visualizer.render(
-            facade,
-            target,
-            &self.pixels,
-            &self.n_samples,
-            self.width,
-            self.height,
-        );
+    /// Render the image to target.
+    /// Changes are uploaded to the GPU only every upload_interval frames.
+    /// encode_srgb should be set if target is a linear framebuffer.
+    pub fn render<S: Surface>(&mut self, target: &mut S, encode_srgb: bool) {
+        self.frames_since_upload += 1;
+        if self.frames_since_upload >= self.upload_interval {
+            self.upload();
+        }
+        self.visualizer.render(target, encode_srgb);
+    }
+
+    /// Set exposure compensation in stops
+    pub fn set_exposure(&mut self, exposure: Float) {
+        self.visualizer.exposure = exposure;
     }
 
-    pub fn save<F: Facade>(&self, facade: &F, path: &Path) {
+    /// Compute the exposure that maps the 90th percentile of the pixel luminances to white.
+    /// Return None if the image contains no light.
+    pub fn auto_exposure(&self) -> Option<Float> {
+        let mut lumas: Vec<Float> = self
+            .pixels
+            .iter()
+            .filter(|pixel| pixel.n_samples() > 0)
+            .map(|pixel| Color::from(pixel.mean()).luma())
+            .collect();
+        if lumas.is_empty() {
+            return None;
+        }
+        lumas.sort_unstable_by(|l1, l2| l1.partial_cmp(l2).unwrap());
+        let i = ((lumas.len() - 1) as f32 * AUTO_EXPOSURE_PERCENTILE).round() as usize;
+        let luma = lumas[i];
+        if luma <= 0.0 {
+            return None;
+        }
+        let white = if self.visualizer.tone_map {
+            TONE_MAP_WHITE
+        } else {
+            1.0
+        };
+        Some((white.to_float() / luma).log2())
+    }
+
+    pub fn save<F: Facade>(&mut self, facade: &F, path: &Path) {
         let texture = SrgbTexture2d::empty(facade, self.width, self.height).unwrap();
         let mut target = SimpleFrameBuffer::new(facade, &texture).unwrap();
         target.clear_color_and_depth((0.0, 0.0, 0.0, 1.0), 1.0);
-        self.render(facade, &mut target);
+        // Saved image should contain all the samples
+        self.upload();
+        // OpenGL handles the encoding for sRGB textures
+        self.visualizer.render(&mut target, false);
         let pb = texture.read_to_pixel_buffer();
         let raw_image: RawImage2d<u8> = pb.read_as_texture_2d().unwrap();
         let image =
@@ -81,13 +278,118 @@ impl TracedImage {
         let image = image::imageops::flip_vertical(&image);
         image.save(path).unwrap();
     }
+
+    /// Save the mean radiance of the pixels as a linear Radiance .hdr image.
+    /// If metadata is set, the display settings are written to a .json file
+    /// with the same name.
+    pub fn save_hdr(&self, path: &Path, metadata: bool) {
+        let radiance = self.radiance();
+        let pixels: Vec<_> = radiance.pixels().copied().collect();
+        let file = std::fs::File::create(path).unwrap();
+        let writer = std::io::BufWriter::new(file);
+        image::codecs::hdr::HdrEncoder::new(writer)
+            .encode(&pixels, self.width as usize, self.height as usize)
+            .unwrap();
+        if metadata {
+            std::fs::write(path.with_extension("json"), self.display_metadata()).unwrap();
+        }
+    }
+
+    /// Mean radiance of the pixels with the top row first
+    pub fn radiance(&self) -> HdrImage {
+        HdrImage::from_fn(self.width, self.height, |x, y| {
+            // Pixels are stored bottom row first
+            let i = ((self.height - 1 - y) * self.width + x) as usize;
+            image::Rgb(self.pixels[i].mean())
+        })
+    }
+
+    /// Save the mean first hit albedo of the pixels as a .pfm image
+    pub fn save_albedo(&self, path: &Path) {
+        let albedo = self.albedo.iter().map(PixelAccumulator::mean);
+        self.save_pfm(path, albedo);
+    }
+
+    /// Save the mean first hit shading normal of the pixels as a .pfm image.
+    /// Normals are renormalized after averaging and background pixels are zero.
+    pub fn save_normal(&self, path: &Path) {
+        let normal = self.normal.iter().map(|pixel| {
+            let [x, y, z] = pixel.mean();
+            let len = (x * x + y * y + z * z).sqrt();
+            if len > 0.0 {
+                [x / len, y / len, z / len]
+            } else {
+                [0.0; 3]
+            }
+        });
+        self.save_pfm(path, normal);
+    }
+
+    /// Write little endian float pixels as a Portable Float Map,
+    /// which can store the negative components of normals
+    fn save_pfm(&self, path: &Path, pixels: impl Iterator<Item = [f32; 3]>) {
+        let mut data = format!("PF\n{} {}\n-1.0\n", self.width, self.height).into_bytes();
+        // Pfm rows go from bottom to top like the pixels
+        for pixel in pixels {
+            for c in &pixel {
+                data.extend_from_slice(&c.to_le_bytes());
+            }
+        }
+        std::fs::write(path, data).unwrap();
+    }
+
+    /// Display settings of the image as json
+    fn display_metadata(&self) -> String {
+        let (tone_map, white) = if self.visualizer.tone_map {
+            let operator = match self.visualizer.tone_map_operator {
+                ToneMap::PerChannel => "\"hable\"",
+                ToneMap::Luminance => "\"hable_luminance\"",
+                ToneMap::HuePreserving => "\"hable_hue_preserving\"",
+            };
+            (operator, TONE_MAP_WHITE)
+        } else {
+            ("null", 1.0)
+        };
+        let fields = [
+            format!("\"exposure\": {}", self.visualizer.exposure),
+            format!("\"tone_map\": {}", tone_map),
+            // Linear luminance that is displayed as white after the exposure
+            format!("\"white\": {}", white),
+            "\"encoding\": \"srgb\"".to_string(),
+        ];
+        format!("{{\n  {}\n}}\n", fields.join(",\n  "))
+    }
+
+    /// Save the sample counts of the pixels as a grayscale image
+    /// where white corresponds to the most sampled pixel
+    pub fn save_sample_map(&self, path: &Path) {
+        let max_n = self
+            .pixels
+            .iter()
+            .map(PixelAccumulator::n_samples)
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        let image = image::GrayImage::from_fn(self.width, self.height, |x, y| {
+            // Pixels are stored bottom row first
+            let i = ((self.height - 1 - y) * self.width + x) as usize;
+            let n = self.pixels[i].n_samples();
+            image::Luma([(255 * u64::from(n) / u64::from(max_n)) as u8])
+        });
+        image.save(path).unwrap();
+    }
 }
 
 struct Visualizer {
     shader: glium::Program,
     vertex_buffer: VertexBuffer<RawVertex>,
     index_buffer: IndexBuffer<u32>,
+    data_texture: Texture2d,
+    n_texture: Texture2d,
     tone_map: bool,
+    tone_map_operator: ToneMap,
+    /// Exposure compensation in stops
+    exposure: Float,
 }
 
 impl Visualizer {
@@ -128,55 +430,65 @@ impl Visualizer {
             glium::Program::from_source(facade, vertex_shader_src, fragment_shader_src, None)
                 .expect("Failed to create program!");
 
+        let data_texture = Texture2d::empty_with_format(
+            facade,
+            UncompressedFloatFormat::F32F32F32,
+            MipmapsOption::NoMipmap,
+            config.width,
+            config.height,
+        )
+        .expect("Failed to create image texture!");
+        let n_texture = Texture2d::empty_with_format(
+            facade,
+            UncompressedFloatFormat::F32,
+            MipmapsOption::NoMipmap,
+            config.width,
+            config.height,
+        )
+        .expect("Failed to create sample count texture!");
+
         Self {
             shader,
             vertex_buffer,
             index_buffer,
+            data_texture,
+            n_texture,
             tone_map: config.tone_map,
+            tone_map_operator: config.tone_map_operator,
+            exposure: config.exposure,
         }
     }
 
-    fn render<F: Facade, S: Surface>(
-        &self,
-        facade: &F,
-        target: &mut S,
-        data: &[f32],
-        n_samples: &[u32],
-        width: u32,
-        height: u32,
-    ) {
+    /// Write data and n_samples of the pixels in rect to the textures
+    fn upload(&self, rect: Rect, data: Vec<f32>, n_samples: Vec<f32>) {
         let data_raw = RawImage2d {
             data: std::borrow::Cow::from(data),
-            width,
-            height,
+            width: rect.width,
+            height: rect.height,
             format: ClientFormat::F32F32F32,
         };
-        let data_texture = Texture2d::with_format(
-            facade,
-            data_raw,
-            UncompressedFloatFormat::F32F32F32,
-            MipmapsOption::NoMipmap,
-        )
-        .unwrap();
-
+        self.data_texture.write(rect, data_raw);
         let n_raw = RawImage2d {
             data: std::borrow::Cow::from(n_samples),
-            width,
-            height,
-            format: ClientFormat::U32,
+            width: rect.width,
+            height: rect.height,
+            format: ClientFormat::F32,
         };
-        let n_texture = UnsignedTexture2d::with_format(
-            facade,
-            n_raw,
-            UncompressedUintFormat::U32,
-            MipmapsOption::NoMipmap,
-        )
-        .unwrap();
+        self.n_texture.write(rect, n_raw);
+    }
 
+    // Cast is unnecessary with single precision
+    #[allow(clippy::unnecessary_cast)]
+    fn render<S: Surface>(&self, target: &mut S, encode_srgb: bool) {
         let uniforms = uniform! {
-            image: &data_texture,
-            n: &n_texture,
+            image: &self.data_texture,
+            n: &self.n_texture,
+            exposure_scale: self.exposure.exp2() as f32,
             tone_map: self.tone_map,
+            luminance_tone_map: self.tone_map_operator != ToneMap::PerChannel,
+            desaturate: self.tone_map_operator == ToneMap::HuePreserving,
+            white: TONE_MAP_WHITE,
+            encode_srgb: encode_srgb,
         };
         let draw_parameters = DrawParameters {
             ..Default::default()
@@ -192,3 +504,105 @@ impl Visualizer {
             .unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accumulator_variance() {
+        let mut acc = PixelAccumulator::default();
+        assert_eq!(acc.variance(), [0.0; 3]);
+        acc.add([1.0, 2.0, 0.0]);
+        // A single sample has no variance estimate
+        assert_eq!(acc.variance(), [0.0; 3]);
+        for sample in &[[3.0, 2.0, 0.0], [5.0, 2.0, 0.0], [7.0, 2.0, 0.0]] {
+            acc.add(*sample);
+        }
+        assert_eq!(acc.mean(), [4.0, 2.0, 0.0]);
+        // Unbiased variance of 1, 3, 5, 7
+        let var = acc.variance();
+        assert!((var[0] - 20.0 / 3.0).abs() < 1e-5, "{:?}", var);
+        assert_eq!(&var[1..], &[0.0, 0.0]);
+        let err = acc.std_error();
+        assert!((err[0] - (5.0f32 / 3.0).sqrt()).abs() < 1e-5, "{:?}", err);
+    }
+
+    #[test]
+    fn splats_are_not_samples() {
+        let mut acc = PixelAccumulator::default();
+        acc.add([1.0; 3]);
+        acc.add([3.0; 3]);
+        acc.add_splat([2.0; 3]);
+        assert_eq!(acc.n_samples(), 2);
+        assert_eq!(acc.mean(), [3.0; 3]);
+    }
+
+    fn hable(x: f32) -> f32 {
+        let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+        ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+    }
+
+    /// Luminance operators of image.frag followed by the clamp of the framebuffer
+    fn tone_map_luminance(color: [f32; 3], operator: ToneMap) -> [f32; 3] {
+        let white_scale = 1.0 / hable(TONE_MAP_WHITE);
+        let luma = 0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2];
+        let mut color = color;
+        match operator {
+            ToneMap::Luminance => {
+                let hable_scale = hable(luma) / luma;
+                color
+                    .iter_mut()
+                    .for_each(|c| *c *= hable_scale * white_scale);
+            }
+            ToneMap::HuePreserving => {
+                let mapped_luma = hable(luma) * white_scale;
+                color.iter_mut().for_each(|c| *c *= mapped_luma / luma);
+                let max_channel = color.iter().cloned().fold(0.0, f32::max);
+                if max_channel > 1.0 && max_channel > mapped_luma {
+                    let t = ((1.0 - mapped_luma) / (max_channel - mapped_luma)).max(0.0);
+                    color
+                        .iter_mut()
+                        .for_each(|c| *c = mapped_luma + t * (*c - mapped_luma));
+                }
+            }
+            ToneMap::PerChannel => unreachable!(),
+        }
+        color.map(|c| c.clamp(0.0, 1.0))
+    }
+
+    /// Hue angle in degrees
+    fn hue(color: [f32; 3]) -> f32 {
+        let [r, g, b] = color;
+        (3.0f32.sqrt() * (g - b))
+            .atan2(2.0 * r - g - b)
+            .to_degrees()
+    }
+
+    #[test]
+    fn hue_preserving_tone_map() {
+        // Bright saturated orange that falls out of gamut after tone mapping
+        let color = [20.0, 6.0, 1.0];
+        let luminance = tone_map_luminance(color, ToneMap::Luminance);
+        let hue_preserving = tone_map_luminance(color, ToneMap::HuePreserving);
+        // Clipping the red channel shifts the hue towards yellow
+        assert!(hue(luminance) - hue(color) > 5.0, "{:?}", luminance);
+        assert!(
+            (hue(hue_preserving) - hue(color)).abs() < 1e-3,
+            "{:?}",
+            hue_preserving
+        );
+        // Colors in gamut are mapped identically
+        let dim = [0.2, 0.1, 0.05];
+        let (a, b) = (
+            tone_map_luminance(dim, ToneMap::Luminance),
+            tone_map_luminance(dim, ToneMap::HuePreserving),
+        );
+        assert!(
+            (0..3).all(|i| (a[i] - b[i]).abs() < 1e-6),
+            "{:?} {:?}",
+            a,
+            b
+        );
+    }
+}
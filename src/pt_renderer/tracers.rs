@@ -1,7 +1,55 @@
+use cgmath::prelude::*;
+use cgmath::Vector3;
+
+use crate::color::Color;
+use crate::config::RenderConfig;
+use crate::float::*;
+use crate::intersect::Interaction;
+
+mod aov;
 mod bdpt;
 mod debug;
 mod path_tracer;
+mod ppm;
 
-pub use self::bdpt::bdpt;
+pub use self::aov::trace_aovs;
+pub use self::bdpt::{bdpt, trace_light_cache, BdptBuffers, Strategies};
 pub use self::debug::debug_trace;
 pub use self::path_tracer::path_trace;
+pub use self::ppm::{gather_radius, ppm, trace_photons, PhotonMap};
+
+/// Clamp indirect radiance according to the config
+fn clamp_indirect(radiance: Color, config: &RenderConfig) -> Color {
+    match config.clamp_indirect {
+        Some(max_luma) => radiance.clamp_luma(max_luma),
+        None => radiance,
+    }
+}
+
+/// Absorption coefficient of the medium the path is in after scattering from wo to wi.
+/// Refraction enters the medium behind the surface or leaves it to the outside.
+/// Nested media are not tracked.
+fn next_medium(
+    isect: &Interaction,
+    wo: Vector3<Float>,
+    wi: Vector3<Float>,
+    medium: Option<Color>,
+) -> Option<Color> {
+    let cos_o = isect.ng.dot(wo);
+    let cos_i = isect.ng.dot(wi);
+    if cos_o * cos_i >= 0.0 {
+        medium
+    } else if cos_i < 0.0 {
+        isect.absorption()
+    } else {
+        None
+    }
+}
+
+/// Beer-Lambert attenuation of a segment of length dist inside the medium
+fn transmittance(medium: Option<Color>, dist: Float) -> Color {
+    match medium {
+        Some(sigma_a) => (-dist * sigma_a).exp(),
+        None => Color::white(),
+    }
+}
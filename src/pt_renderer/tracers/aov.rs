@@ -0,0 +1,26 @@
+use cgmath::prelude::*;
+use cgmath::Vector3;
+
+use crate::bvh::BvhNode;
+use crate::color::Color;
+use crate::config::RenderConfig;
+use crate::float::*;
+use crate::intersect::Ray;
+use crate::scene::Scene;
+
+/// Albedo and shading normal of the first surface hit by the ray.
+/// Rays that miss the scene return black and a zero normal.
+pub fn trace_aovs<'a>(
+    mut ray: Ray,
+    scene: &'a Scene,
+    config: &RenderConfig,
+    node_stack: &mut Vec<(&'a BvhNode, Float)>,
+) -> (Color, Vector3<Float>) {
+    match scene.intersect(&mut ray, node_stack) {
+        Some(hit) => {
+            let isect = hit.interaction(config);
+            (isect.albedo(), isect.ns)
+        }
+        None => (Color::black(), Vector3::zero()),
+    }
+}
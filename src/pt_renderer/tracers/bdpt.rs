@@ -6,108 +6,504 @@ use crate::color::Color;
 use crate::config::*;
 use crate::float::*;
 use crate::intersect::Ray;
+use crate::light::EnvironmentLight;
 use crate::pt_renderer::PathType;
+use crate::sampler;
 use crate::scene::Scene;
 
+mod strategies;
 mod vertex;
 
+pub use self::strategies::Strategies;
 use self::vertex::*;
+use super::{clamp_indirect, next_medium, transmittance};
 
-// TODO: avoid allocations
+/// Storage for the paths that bdpt traces for each camera sample.
+/// The render worker keeps the buffers across samples so that
+/// tracing doesn't allocate once they have grown large enough.
+#[derive(Default)]
+pub struct BdptBuffers<'a> {
+    camera_path: Vec<SurfaceVertex<'a>>,
+    light_path: Vec<SurfaceVertex<'a>>,
+    pdfs: PathPdfs,
+    /// Unweighted luminance of each evaluated strategy
+    contributions: Vec<(usize, usize, Float)>,
+    light_cache: LightVertexCache<'a>,
+}
+
+/// Vertices of the light paths that are traced once per block
+/// and shared by the camera paths of the block
+#[derive(Default)]
+struct LightVertexCache<'a> {
+    light_vertices: Vec<LightVertex<'a>>,
+    /// Surface vertices of all the paths one after another
+    vertices: Vec<SurfaceVertex<'a>>,
+    /// End of the surface vertices of each path
+    ends: Vec<usize>,
+    /// Path and light side index s of each cached surface vertex
+    entries: Vec<(usize, usize)>,
+}
+
+impl<'a> LightVertexCache<'a> {
+    fn clear(&mut self) {
+        self.light_vertices.clear();
+        self.vertices.clear();
+        self.ends.clear();
+        self.entries.clear();
+    }
+
+    /// Add the light path that starts from light_vertex and move its vertices to the cache
+    fn push(&mut self, light_vertex: LightVertex<'a>, light_path: &mut Vec<SurfaceVertex<'a>>) {
+        let path_i = self.light_vertices.len();
+        self.entries
+            .extend((2..=light_path.len() + 1).map(|s| (path_i, s)));
+        self.light_vertices.push(light_vertex);
+        self.vertices.append(light_path);
+        self.ends.push(self.vertices.len());
+    }
+
+    fn path(&self, i: usize) -> (&LightVertex<'a>, &[SurfaceVertex<'a>]) {
+        let start = if i == 0 { 0 } else { self.ends[i - 1] };
+        (&self.light_vertices[i], &self.vertices[start..self.ends[i]])
+    }
+
+    /// Uniformly chosen surface vertex of the cache.
+    /// Return the light vertex and the surface vertices of its path up to the chosen
+    /// vertex and the light side index s of the chosen vertex.
+    /// Will panic if the cache has no surface vertices.
+    fn sample_vertex(&self) -> (&LightVertex<'a>, &[SurfaceVertex<'a>], usize) {
+        let n = self.entries.len();
+        let i = ((sampler::random() * n.to_float()) as usize).min(n - 1);
+        let (path_i, s) = self.entries[i];
+        let (light_vertex, light_path) = self.path(path_i);
+        (light_vertex, &light_path[..s - 1], s)
+    }
+
+    /// Sample counts of the strategies when each camera vertex is connected
+    /// to a light vertex and to n_connections cached surface vertices.
+    /// Splats land in other blocks whose caches have slightly different counts,
+    /// but the differences average out over the blocks.
+    fn counts(&self, n_connections: usize) -> SampleCounts {
+        // A chosen vertex has light side index s with the probability
+        // of the light path having vertex s divided by the vertices per path
+        let connections = if self.entries.is_empty() {
+            0.0
+        } else {
+            let vertices_per_path = self.entries.len().to_float() / self.ends.len().to_float();
+            n_connections.to_float() / vertices_per_path
+        };
+        SampleCounts {
+            nee: 1.0,
+            connections,
+            splats: 1.0,
+        }
+    }
+}
+
+/// Sample counts of the strategies when light paths are not cached
+fn fresh_counts(config: &RenderConfig) -> SampleCounts {
+    let n_light_paths = config.light_paths_per_pixel.max(1).to_float();
+    SampleCounts {
+        nee: n_light_paths,
+        connections: n_light_paths,
+        splats: n_light_paths,
+    }
+}
+
+/// Trace n light paths into the light vertex cache of the buffers,
+/// which replaces the previous cache. The cached paths are connected
+/// to the camera here so that each of them is splatted once.
+#[allow(clippy::too_many_arguments)]
+pub fn trace_light_cache<'a>(
+    n: usize,
+    scene: &'a Scene,
+    camera: &'a PtCamera,
+    config: &RenderConfig,
+    node_stack: &mut Vec<(&'a BvhNode, Float)>,
+    splats: &mut Vec<(Point2<Float>, Color)>,
+    buffers: &mut BdptBuffers<'a>,
+    mut strategies: Option<&mut Strategies>,
+) {
+    let BdptBuffers {
+        light_path,
+        pdfs,
+        contributions,
+        light_cache,
+        ..
+    } = buffers;
+    light_cache.clear();
+    for _ in 0..n {
+        let r = Point2::new(sampler::random(), sampler::random());
+        let light_vertex = trace_light_path(r, scene, camera, config, node_stack, light_path);
+        light_cache.push(light_vertex, light_path);
+    }
+    if let Some(strategies) = strategies.as_mut() {
+        strategies.add_splats(n);
+    }
+    // The weights depend on the number of vertices in the cache
+    // so the paths can only be splatted once all of them have been traced
+    let counts = light_cache.counts(config.light_path_reuse);
+    let camera_vertex = CameraVertex::new(camera);
+    for i in 0..n {
+        let (light_vertex, light_path) = light_cache.path(i);
+        let bd_path = BdPath::new(
+            light_vertex,
+            light_path,
+            &camera_vertex,
+            &[],
+            std::mem::take(pdfs),
+            config,
+            counts,
+            strategies.as_deref(),
+        );
+        for s in 2..=light_path.len() + 1 {
+            if s - 1 > config.max_bounces {
+                break;
+            }
+            if let Some((mut radiance, path, Some(clip_p))) =
+                bd_path.connect(s, 1, scene, node_stack)
+            {
+                if strategies.is_some() {
+                    contributions.push((s, 1, radiance.luma()));
+                }
+                radiance *= path.weight() / counts.splats;
+                if s - 1 > 1 {
+                    radiance = clamp_indirect(radiance, config);
+                }
+                splats.push((clip_p, radiance));
+            }
+        }
+        *pdfs = bd_path.into_pdfs();
+        if let Some(strategies) = strategies.as_mut() {
+            for (s, t, luma) in contributions.drain(..) {
+                strategies.record(s, t, luma);
+            }
+        }
+    }
+}
+
+/// Sample a light and a position on it with the uniform sample r
+fn sample_light_vertex<'a>(
+    r: Point2<Float>,
+    scene: &'a Scene,
+    camera: &'a PtCamera,
+    config: &RenderConfig,
+) -> LightVertex<'a> {
+    let (light, light_pdf, r_x) = match config.light_mode {
+        LightMode::Scene => scene
+            .sample_light_with(r.x)
+            .unwrap_or((camera.flash(), 1.0, r.x)),
+        LightMode::Camera => (camera.flash(), 1.0, r.x),
+    };
+    let (light_pos, pos_pdf) = light.sample_pos_with(Point2::new(r_x, r.y));
+    LightVertex::new(light, light_pos, light_pdf * pos_pdf)
+}
+
+/// Sample a light vertex with the uniform sample r and trace
+/// the rest of the light path into light_path
+fn trace_light_path<'a>(
+    r: Point2<Float>,
+    scene: &'a Scene,
+    camera: &'a PtCamera,
+    config: &RenderConfig,
+    node_stack: &mut Vec<(&'a BvhNode, Float)>,
+    light_path: &mut Vec<SurfaceVertex<'a>>,
+) -> LightVertex<'a> {
+    let light_vertex = sample_light_vertex(r, scene, camera, config);
+    let (beta, ray) = light_vertex.sample_next();
+    generate_path(
+        beta,
+        ray,
+        PathType::Light,
+        scene,
+        config,
+        node_stack,
+        light_path,
+    );
+    light_vertex
+}
+
+/// Radiance of a camera sample split by the number of bounces
+struct SampleRadiance {
+    direct: Color,
+    /// Radiance that has bounced more than once
+    indirect: Color,
+}
+
+impl SampleRadiance {
+    fn new() -> Self {
+        Self {
+            direct: Color::black(),
+            indirect: Color::black(),
+        }
+    }
+
+    /// Add the weighted radiance of a path with the given number of bounces.
+    /// Radiance of splatted paths is pushed to splats instead.
+    fn add(
+        &mut self,
+        mut radiance: Color,
+        bounces: usize,
+        splat: Option<Point2<Float>>,
+        splats: &mut Vec<(Point2<Float>, Color)>,
+        config: &RenderConfig,
+    ) {
+        let is_indirect = bounces > 1;
+        if let Some(clip_p) = splat {
+            if is_indirect {
+                radiance = clamp_indirect(radiance, config);
+            }
+            splats.push((clip_p, radiance));
+        } else if is_indirect {
+            self.indirect += radiance;
+        } else {
+            self.direct += radiance;
+        }
+    }
+}
+
+/// light_sample should be a stratified uniform sample which is used
+/// to select and sample the light of the first light path.
+/// If light paths are reused, the camera vertices are connected to the light
+/// sampled with light_sample and to the light vertex cache of the buffers instead.
+/// If strategies is given, the MIS weights use its factors and the
+/// contributions of the strategies are recorded to it.
+#[allow(clippy::too_many_arguments)]
 pub fn bdpt<'a>(
     camera_ray: Ray,
+    light_sample: Point2<Float>,
     scene: &'a Scene,
     camera: &'a PtCamera,
     config: &RenderConfig,
     node_stack: &mut Vec<(&'a BvhNode, Float)>,
     splats: &mut Vec<(Point2<Float>, Color)>,
+    buffers: &mut BdptBuffers<'a>,
+    mut strategies: Option<&mut Strategies>,
 ) -> Color {
-    let camera_vertex = CameraVertex::new(camera, camera_ray);
-    let (beta, ray) = camera_vertex.sample_next();
-    let camera_path = generate_path(beta, ray, PathType::Camera, scene, config, node_stack);
-    let (light, light_pdf) = match config.light_mode {
-        LightMode::Scene => scene.sample_light().unwrap_or((camera.flash(), 1.0)),
-        LightMode::Camera => (camera.flash(), 1.0),
-    };
-    let (light_pos, pos_pdf) = light.sample_pos();
-    let light_vertex = LightVertex::new(light, light_pos, light_pdf * pos_pdf);
-    let (beta, ray) = light_vertex.sample_next();
-    let light_path = generate_path(beta, ray, PathType::Light, scene, config, node_stack);
-    let bd_path = BdPath::new(
-        &light_vertex,
-        &light_path,
-        &camera_vertex,
-        &camera_path,
+    let BdptBuffers {
+        camera_path,
+        light_path,
+        pdfs,
+        contributions,
+        light_cache,
+    } = buffers;
+    let camera_vertex = CameraVertex::new(camera);
+    let (beta, ray) = camera_vertex.sample_next(camera_ray);
+    let escaped = generate_path(
+        beta,
+        ray,
+        PathType::Camera,
+        scene,
         config,
+        node_stack,
+        camera_path,
     );
-    let mut c = Color::black();
-    // Paths contain vertices after the light / camera
-    // 0 corresponds to no vertices from that subpath,
-    // 1 is the starting vertex
-    // 2+ are regular path vertices
-    for s in (0..=light_path.len() + 1).rev() {
-        // Light path can't hit camera so start t from 1
-        for t in (1..=camera_path.len() + 1).rev() {
-            let length = s + t;
-            if length < 2 || length - 2 > config.max_bounces {
+    let mut radiance = SampleRadiance::new();
+    if let Some(env) = scene.environment() {
+        let (direct, env_indirect) =
+            environment_radiance(env, camera_path, escaped, scene, config, node_stack);
+        radiance.direct += direct;
+        radiance.indirect += env_indirect;
+    }
+    if config.light_path_reuse > 0 {
+        let n_connections = config.light_path_reuse;
+        let counts = light_cache.counts(n_connections);
+        if let Some(strategies) = strategies.as_mut() {
+            strategies.add_sample(1, n_connections);
+        }
+        let light_vertex = sample_light_vertex(light_sample, scene, camera, config);
+        let mut bd_path = BdPath::new(
+            &light_vertex,
+            &[],
+            &camera_vertex,
+            camera_path,
+            std::mem::take(pdfs),
+            config,
+            counts,
+            strategies.as_deref(),
+        );
+        // Cached paths were already connected to the camera so start t from 2
+        for t in (2..=camera_path.len() + 1).rev() {
+            bd_path.set_light_path(&light_vertex, &[]);
+            // Strategies without light path vertices are sampled once per camera vertex
+            for s in 0..=1 {
+                if s + t - 2 > config.max_bounces {
+                    continue;
+                }
+                if let Some((mut c, path, _)) = bd_path.connect(s, t, scene, node_stack) {
+                    if strategies.is_some() {
+                        contributions.push((s, t, c.luma()));
+                    }
+                    c *= path.weight();
+                    radiance.add(c, s + t - 2, None, splats, config);
+                }
+            }
+            if counts.connections == 0.0 {
                 continue;
             }
-            let mut splat = None;
-            // No light vertices
-            let (mut radiance, path) = if s == 0 {
-                if let Some(vertex) = camera_path.get(t - 2) {
-                    if let Some(light_vertex) = vertex.to_light_vertex(scene) {
-                        (
-                            vertex.path_radiance(),
-                            bd_path.subpath_with_light(light_vertex, t),
-                        )
-                    } else {
-                        continue;
+            for _ in 0..n_connections {
+                let (light_vertex, light_path, s) = light_cache.sample_vertex();
+                if s + t - 2 > config.max_bounces {
+                    continue;
+                }
+                bd_path.set_light_path(light_vertex, light_path);
+                if let Some((mut c, path, _)) = bd_path.connect(s, t, scene, node_stack) {
+                    if strategies.is_some() {
+                        // Each connection is a sample of every strategy (s, t) of this t,
+                        // so the luminance is divided by the probability of choosing s
+                        let luma = c.luma() * n_connections.to_float() / counts.connections;
+                        contributions.push((s, t, luma));
                     }
-                } else {
+                    c *= path.weight() / counts.connections;
+                    radiance.add(c, s + t - 2, None, splats, config);
+                }
+            }
+        }
+        *pdfs = bd_path.into_pdfs();
+        if let Some(strategies) = strategies.as_mut() {
+            for (s, t, luma) in contributions.drain(..) {
+                strategies.record(s, t, luma);
+            }
+        }
+        return radiance.direct + clamp_indirect(radiance.indirect, config);
+    }
+    let counts = fresh_counts(config);
+    let n_light_paths = config.light_paths_per_pixel.max(1);
+    if let Some(strategies) = strategies.as_mut() {
+        strategies.add_sample(n_light_paths, n_light_paths);
+        strategies.add_splats(n_light_paths);
+    }
+    for light_path_i in 0..n_light_paths {
+        // Only one of the light paths can use the stratified sample
+        let r = if light_path_i == 0 {
+            light_sample
+        } else {
+            Point2::new(sampler::random(), sampler::random())
+        };
+        let light_vertex = trace_light_path(r, scene, camera, config, node_stack, light_path);
+        let bd_path = BdPath::new(
+            &light_vertex,
+            light_path,
+            &camera_vertex,
+            camera_path,
+            std::mem::take(pdfs),
+            config,
+            counts,
+            strategies.as_deref(),
+        );
+        // Strategies without light vertices don't depend on the light path
+        // so they are only evaluated once
+        let min_s = if light_path_i == 0 { 0 } else { 1 };
+        // Paths contain vertices after the light / camera
+        // 0 corresponds to no vertices from that subpath,
+        // 1 is the starting vertex
+        // 2+ are regular path vertices
+        for s in (min_s..=light_path.len() + 1).rev() {
+            // Light path can't hit camera so start t from 1
+            for t in (1..=camera_path.len() + 1).rev() {
+                let length = s + t;
+                if length < 2 || length - 2 > config.max_bounces {
                     continue;
                 }
-            // Connect camera and light
-            } else if s == 1 && t == 1 {
-                // This should be sampled well enough by strategy (0, 2)
-                continue;
-            // Everything else
-            } else {
-                let l_vertex: &dyn Vertex = if s == 1 {
-                    &light_vertex
-                } else {
-                    &light_path[s - 2]
-                };
-                let c_vertex: &dyn Vertex = if t == 1 {
-                    &camera_vertex
-                } else {
-                    &camera_path[t - 2]
-                };
-                // Connect camera vertex to light vertex since shadow rays
-                // from the camera are simpler than those from the light
-                let (mut connection_ray, radiance) = c_vertex.connect_to(l_vertex);
-                if !radiance.is_black() && !scene.intersect_shadow(&mut connection_ray, node_stack)
-                {
-                    if t == 1 {
-                        // Splat is always valid if radiance is not black
-                        splat = camera_vertex.camera.clip_pos(connection_ray.dir);
+                if let Some((mut c, path, splat)) = bd_path.connect(s, t, scene, node_stack) {
+                    if strategies.is_some() {
+                        contributions.push((s, t, c.luma()));
                     }
-                    (radiance, bd_path.subpath(s, t))
+                    // Average the contributions of the light paths
+                    c *= path.weight() / counts.get(s, t);
+                    radiance.add(c, length - 2, splat, splats, config);
+                }
+            }
+        }
+        *pdfs = bd_path.into_pdfs();
+        if let Some(strategies) = strategies.as_mut() {
+            for (s, t, luma) in contributions.drain(..) {
+                strategies.record(s, t, luma);
+            }
+        }
+    }
+    radiance.direct + clamp_indirect(radiance.indirect, config)
+}
+
+/// Weight of a strategy with solid angle pdf against other_pdf of the other
+/// strategy that samples the environment. Both pdfs need to be non-zero.
+fn environment_weight(pdf: Float, other_pdf: Float, config: &RenderConfig) -> Float {
+    if config.mis {
+        // Power heuristic
+        pdf.powi(2) / (pdf.powi(2) + other_pdf.powi(2))
+    } else {
+        0.5
+    }
+}
+
+/// Environment radiance along the camera path.
+/// The environment is not part of the light paths so it is sampled separately
+/// by the escaped camera path and by sampling it explicitly at the camera vertices.
+/// Return the direct and the indirect radiance.
+fn environment_radiance<'a>(
+    env: &EnvironmentLight,
+    camera_path: &[SurfaceVertex<'a>],
+    escaped: Option<(Color, Ray)>,
+    scene: &'a Scene,
+    config: &RenderConfig,
+    node_stack: &mut Vec<(&'a BvhNode, Float)>,
+) -> (Color, Color) {
+    let mut c = Color::black();
+    let mut indirect = Color::black();
+    let nee = config.environment_nee && config.light_mode == LightMode::Scene;
+    if let Some((beta, ray)) = escaped {
+        let mut radiance = beta * env.lookup(ray.dir);
+        match camera_path.last() {
+            // Directly visible environment can only be sampled by the camera
+            None => c += radiance,
+            Some(vertex) => {
+                if nee && !vertex.delta_dir() {
+                    let bsdf_pdf = vertex.isect.pdf(-vertex.ray.dir, ray.dir);
+                    let env_pdf = env.pdf_incoming(ray.dir);
+                    if env_pdf > 0.0 {
+                        radiance *= environment_weight(bsdf_pdf, env_pdf, config);
+                    }
+                }
+                if camera_path.len() > 1 {
+                    indirect += radiance;
                 } else {
-                    continue;
+                    c += radiance;
                 }
-            };
-            radiance *= path.weight();
-            if let Some(clip_p) = splat.take() {
-                splats.push((clip_p, radiance));
-            } else {
-                c += radiance;
             }
         }
     }
-    c
+    if !nee {
+        return (c, indirect);
+    }
+    // Escaped paths can't continue from the last vertex if it reached max bounces
+    for (i, vertex) in camera_path.iter().enumerate().take(config.max_bounces) {
+        if vertex.delta_dir() {
+            continue;
+        }
+        let (le, mut shadow_ray, env_pdf) = env.sample_towards(&vertex.isect);
+        let beta = vertex.path_throughput(shadow_ray.dir);
+        if le.is_black() || beta.is_black() {
+            continue;
+        }
+        if scene.intersect_shadow(&mut shadow_ray, node_stack) {
+            continue;
+        }
+        let bsdf_pdf = vertex.isect.pdf(-vertex.ray.dir, shadow_ray.dir);
+        let mut radiance = beta * le * vertex.isect.cos_t(shadow_ray.dir) / env_pdf;
+        if bsdf_pdf > 0.0 {
+            radiance *= environment_weight(env_pdf, bsdf_pdf, config);
+        }
+        if i > 0 {
+            indirect += radiance;
+        } else {
+            c += radiance;
+        }
+    }
+    (c, indirect)
 }
 
+/// Trace a path starting from ray and replace the vertices of path with it.
+/// Return the throughput and ray of the final segment if it escaped the scene.
 fn generate_path<'a>(
     mut beta: Color,
     mut ray: Ray,
@@ -115,36 +511,61 @@ fn generate_path<'a>(
     scene: &'a Scene,
     config: &RenderConfig,
     node_stack: &mut Vec<(&'a BvhNode, Float)>,
-) -> Vec<SurfaceVertex<'a>> {
+    path: &mut Vec<SurfaceVertex<'a>>,
+) -> Option<(Color, Ray)> {
     let mut bounce = 0;
-    let mut path = Vec::new();
-    while let Some(hit) = scene.intersect(&mut ray, node_stack) {
+    // Dynamic russian roulette uses the throughput relative to the start of the path
+    // so that the survival of light paths doesn't depend on the scale of the emission
+    let start_luma = beta.luma();
+    // Absorption coefficient of the medium the path is travelling in
+    let mut medium = None;
+    path.clear();
+    // Black paths carry nothing and would divide by zero in the survival probability
+    if start_luma <= 0.0 {
+        return None;
+    }
+    loop {
+        let hit = match scene.intersect(&mut ray, node_stack) {
+            Some(hit) => hit,
+            None => return Some((beta, ray)),
+        };
+        beta *= transmittance(medium, ray.length);
         path.push(SurfaceVertex::new(
             ray.clone(),
             beta,
             path_type,
             hit.interaction(config),
+            medium,
         ));
-        let isect = &path.last().unwrap().isect;
         let mut pdf = 1.0;
         let terminate = if bounce >= config.max_bounces {
             true
         } else if bounce >= config.pre_rr_bounces {
             match config.russian_roulette {
-                RussianRoulette::Dynamic => panic!("Bdpt does not support dynamic RR"),
+                RussianRoulette::Dynamic => {
+                    // Survival probability
+                    let prob = (beta.luma() / start_luma).min(0.95);
+                    pdf *= prob;
+                    sampler::random() > prob
+                }
                 RussianRoulette::Static(prob) => {
                     pdf *= prob;
-                    rand::random::<Float>() > prob
+                    sampler::random() > prob
                 }
                 RussianRoulette::Off => false,
             }
         } else {
             false
         };
+        let vertex = path.last_mut().unwrap();
+        // MIS weights need the dynamic probabilities of the sampled vertices
+        vertex.survival_prob = pdf;
+        let isect = &vertex.isect;
         if !terminate {
             if let Some((bsdf, new_ray, bsdf_pdf)) = isect.sample_bsdf(-ray.dir, path_type) {
                 pdf *= bsdf_pdf;
-                beta *= isect.cos_s(new_ray.dir).abs() * bsdf / pdf;
+                beta *= isect.cos_t(new_ray.dir) * bsdf / pdf;
+                medium = next_medium(isect, -ray.dir, new_ray.dir, medium);
                 ray = new_ray;
                 bounce += 1;
                 if !beta.is_black() {
@@ -154,5 +575,182 @@ fn generate_path<'a>(
         }
         break;
     }
-    path
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use cgmath::prelude::*;
+    use cgmath::{Point3, Quaternion, Vector4};
+
+    use rand::prelude::*;
+    use rand::rngs::StdRng;
+
+    use crate::camera::Camera;
+    use crate::scene::SceneBuilder;
+    use crate::stats;
+
+    /// Closed unit cube whose inward facing walls emit 1 and reflect 0.5
+    /// so that the radiance is 2 everywhere inside it
+    fn furnace_scene(config: &RenderConfig) -> Arc<Scene> {
+        let dir = std::env::temp_dir().join("rusty_test_furnace");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("furnace.mtl"),
+            "newmtl wall\nKd 0.5 0.5 0.5\nKe 1 1 1\nillum 1\n",
+        )
+        .unwrap();
+        let obj = "mtllib furnace.mtl\n\
+                   v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+                   v 0 0 1\nv 1 0 1\nv 1 1 1\nv 0 1 1\n\
+                   usemtl wall\n\
+                   f 1 5 6\nf 1 6 2\nf 4 7 8\nf 4 3 7\n\
+                   f 1 4 8\nf 1 8 5\nf 2 7 3\nf 2 6 7\n\
+                   f 1 2 3\nf 1 3 4\nf 5 7 6\nf 5 8 7\n";
+        let path = dir.join("furnace.obj");
+        fs::write(&path, obj).unwrap();
+        SceneBuilder::new(config).build(&path).unwrap()
+    }
+
+    /// Mean luminance of the image and its standard error estimated from
+    /// n camera samples. Splats are included since an image pixel sums them
+    /// with the same weight as the camera samples.
+    fn image_mean(
+        scene: &Scene,
+        camera: &PtCamera,
+        config: &RenderConfig,
+        n: usize,
+    ) -> (Float, Float) {
+        let mut rng = StdRng::seed_from_u64(1);
+        sampler::seed(2);
+        sampler::start_pixel(SamplerType::Independent, 0);
+        let clip_to_world = camera.world_to_clip().invert().unwrap();
+        let mut node_stack = Vec::new();
+        let mut splats = Vec::new();
+        let mut buffers = BdptBuffers::default();
+        let mut sum = 0.0;
+        let mut sq_sum = 0.0;
+        for _ in 0..n {
+            let clip_p = Vector4::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 1.0, 1.0);
+            let world_p = Point3::from_homogeneous(clip_to_world * clip_p);
+            let ray = Ray::from_point(camera.pos, world_p);
+            let light_sample = Point2::new(rng.gen(), rng.gen());
+            let mut c = bdpt(
+                ray,
+                light_sample,
+                scene,
+                camera,
+                config,
+                &mut node_stack,
+                &mut splats,
+                &mut buffers,
+                None,
+            );
+            for (_, splat) in splats.drain(..) {
+                c += splat;
+            }
+            sum += c.luma();
+            sq_sum += c.luma().powi(2);
+        }
+        let n = n.to_float();
+        let mean = sum / n;
+        let variance = (sq_sum / n - mean.powi(2)).max(0.0);
+        (mean, (variance / n).sqrt())
+    }
+
+    fn cornell_glossy(config: &RenderConfig) -> Arc<Scene> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("scenes")
+            .join("cornell-box")
+            .join("CornellBox-Glossy.obj");
+        SceneBuilder::new(config).build(&path).unwrap()
+    }
+
+    fn cornell_camera() -> PtCamera {
+        PtCamera::new(Camera::new(Point3::new(0.0, 0.8, 2.5), Quaternion::one()))
+    }
+
+    #[test]
+    fn furnace_with_dynamic_rr() {
+        let _stats = stats::test_scene("furnace");
+        let config = RenderConfig {
+            russian_roulette: RussianRoulette::Dynamic,
+            pre_rr_bounces: 0,
+            bvh_cache: false,
+            ..RenderConfig::bdpt()
+        };
+        let scene = furnace_scene(&config);
+        let camera = PtCamera::new(Camera::new(Point3::new(0.5, 0.5, 0.9), Quaternion::one()));
+        let (mean, error) = image_mean(&scene, &camera, &config, 20_000);
+        assert!(
+            (mean - 2.0).abs() < 4.0 * error,
+            "Furnace mean {} +- {} is not 2",
+            mean,
+            error
+        );
+    }
+
+    #[test]
+    fn dynamic_rr_matches_no_rr() {
+        let _stats = stats::test_scene("cornell-glossy");
+        let config = RenderConfig {
+            max_bounces: 5,
+            bvh_cache: false,
+            ..RenderConfig::bdpt()
+        };
+        let scene = cornell_glossy(&config);
+        let camera = cornell_camera();
+        let off_config = RenderConfig {
+            russian_roulette: RussianRoulette::Off,
+            ..config.clone()
+        };
+        let dynamic_config = RenderConfig {
+            russian_roulette: RussianRoulette::Dynamic,
+            pre_rr_bounces: 0,
+            ..config
+        };
+        let (off, off_error) = image_mean(&scene, &camera, &off_config, 20_000);
+        let (dynamic, dynamic_error) = image_mean(&scene, &camera, &dynamic_config, 20_000);
+        let error = (off_error.powi(2) + dynamic_error.powi(2)).sqrt();
+        assert!(
+            (off - dynamic).abs() < 4.0 * error,
+            "Mean {} +- {} with dynamic russian roulette doesn't match {} +- {} without it",
+            dynamic,
+            dynamic_error,
+            off,
+            off_error
+        );
+    }
+
+    /// Image mean of cornell-glossy and its standard error
+    /// rendered in double precision with 2 000 000 samples
+    const DOUBLE_PRECISION_MEAN: (Float, Float) = (0.13229, 0.00057);
+
+    /// Single precision is checked with cargo test --features single_precision
+    #[test]
+    fn render_matches_double_precision() {
+        let _stats = stats::test_scene("cornell-glossy");
+        let config = RenderConfig {
+            max_bounces: 5,
+            bvh_cache: false,
+            ..RenderConfig::bdpt()
+        };
+        let scene = cornell_glossy(&config);
+        let (mean, error) = image_mean(&scene, &cornell_camera(), &config, 50_000);
+        let (reference, reference_error) = DOUBLE_PRECISION_MEAN;
+        let error = (error.powi(2) + reference_error.powi(2)).sqrt();
+        assert!(
+            (mean - reference).abs() < 4.0 * error,
+            "Mean {} doesn't match {} of double precision within {}",
+            mean,
+            reference,
+            4.0 * error
+        );
+    }
 }
@@ -0,0 +1,163 @@
+use crate::float::*;
+
+/// Factors are clamped to this range so that no strategy can be switched off completely
+const MIN_FACTOR: Float = 0.05;
+const MAX_FACTOR: Float = 20.0;
+/// Longer paths are rare so their strategies are not tracked
+const MAX_TRACKED_BOUNCES: usize = 16;
+
+/// Variance estimates of the individual bdpt strategies.
+/// The MIS weights of the strategies are multiplied by factors that are
+/// inversely proportional to their variance, which favors the strategies
+/// that have worked well so far. The factors are only updated between
+/// samples so the weights of a single path always sum to one and the
+/// estimate stays unbiased.
+#[derive(Clone, Debug)]
+pub struct Strategies {
+    /// Strategies of paths with more bounces always use a factor of 1
+    max_bounces: usize,
+    /// Number of samples of strategies without light vertices
+    n_camera: usize,
+    /// Number of samples of strategies that connect the light to camera path vertices
+    n_nee: usize,
+    /// Number of samples of strategies that connect light path vertices
+    /// to camera path vertices
+    n_light: usize,
+    /// Number of samples of strategies that connect light path vertices to the camera
+    n_splat: usize,
+    /// Sum and squared sum of the unweighted luminance of each strategy
+    moments: Vec<(Float, Float)>,
+    /// Weight multiplier of each strategy
+    factors: Vec<Float>,
+}
+
+impl Strategies {
+    pub fn new(max_bounces: usize) -> Self {
+        let max_bounces = max_bounces.min(MAX_TRACKED_BOUNCES);
+        // Path with b bounces has b + 3 strategies
+        let n_strategies = (max_bounces + 1) * (max_bounces + 6) / 2;
+        Self {
+            max_bounces,
+            n_camera: 0,
+            n_nee: 0,
+            n_light: 0,
+            n_splat: 0,
+            moments: vec![(0.0, 0.0); n_strategies],
+            factors: vec![1.0; n_strategies],
+        }
+    }
+
+    /// Strategies of the same length are stored consecutively ordered by s.
+    /// Return None if the strategy is not tracked.
+    fn index(&self, s: usize, t: usize) -> Option<usize> {
+        let bounces = s + t - 2;
+        if bounces > self.max_bounces {
+            None
+        } else {
+            // Path with b bounces has b + 3 strategies
+            Some(bounces * (bounces + 5) / 2 + s)
+        }
+    }
+
+    /// Weight multiplier of strategy (s, t)
+    pub fn factor(&self, s: usize, t: usize) -> Float {
+        self.index(s, t).map_or(1.0, |i| self.factors[i])
+    }
+
+    /// Start a new camera sample whose vertices are connected n_nee times
+    /// to the light and n_light times to light path vertices
+    pub fn add_sample(&mut self, n_nee: usize, n_light: usize) {
+        self.n_camera += 1;
+        self.n_nee += n_nee;
+        self.n_light += n_light;
+    }
+
+    /// Add n light paths that were connected to the camera
+    pub fn add_splats(&mut self, n: usize) {
+        self.n_splat += n;
+    }
+
+    /// Record the unweighted luminance of a sample of strategy (s, t)
+    pub fn record(&mut self, s: usize, t: usize, luma: Float) {
+        if let Some(i) = self.index(s, t) {
+            let (sum, sum_sq) = &mut self.moments[i];
+            *sum += luma;
+            *sum_sq += luma.powi(2);
+        }
+    }
+
+    /// Add the samples of other and recompute the factors
+    pub fn merge(&mut self, other: &Strategies) {
+        self.n_camera += other.n_camera;
+        self.n_nee += other.n_nee;
+        self.n_light += other.n_light;
+        self.n_splat += other.n_splat;
+        for (moments, other) in self.moments.iter_mut().zip(&other.moments) {
+            moments.0 += other.0;
+            moments.1 += other.1;
+        }
+        self.update_factors();
+    }
+
+    /// Clear the samples but keep the factors
+    pub fn clear_samples(&mut self) {
+        self.n_camera = 0;
+        self.n_nee = 0;
+        self.n_light = 0;
+        self.n_splat = 0;
+        for moments in &mut self.moments {
+            *moments = (0.0, 0.0);
+        }
+    }
+
+    pub fn copy_factors(&mut self, other: &Strategies) {
+        self.factors.copy_from_slice(&other.factors);
+    }
+
+    fn update_factors(&mut self) {
+        for bounces in 0..=self.max_bounces {
+            let variances: Vec<Option<Float>> = (0..bounces + 3)
+                .map(|s| self.variance(s, bounces + 2 - s))
+                .collect();
+            let known: Vec<Float> = variances.iter().filter_map(|&v| v).collect();
+            if known.is_empty() {
+                continue;
+            }
+            // Only the relative factors of strategies of the same length matter
+            let mean = known.iter().sum::<Float>() / known.len().to_float();
+            for (s, variance) in variances.into_iter().enumerate() {
+                let i = bounces * (bounces + 5) / 2 + s;
+                self.factors[i] = match variance {
+                    Some(variance) => (mean / variance).clamp(MIN_FACTOR, MAX_FACTOR),
+                    None => 1.0,
+                };
+            }
+        }
+    }
+
+    /// Sample variance of strategy (s, t).
+    /// Samples where the strategy couldn't be used count as zeros.
+    fn variance(&self, s: usize, t: usize) -> Option<Float> {
+        let n = if s == 0 {
+            self.n_camera
+        } else if t == 1 {
+            self.n_splat
+        } else if s == 1 {
+            self.n_nee
+        } else {
+            self.n_light
+        };
+        if n < 2 {
+            return None;
+        }
+        let n_f = n.to_float();
+        let (sum, sum_sq) = self.moments[self.index(s, t)?];
+        let mean = sum / n_f;
+        let variance = (sum_sq - n_f * mean.powi(2)) / (n_f - 1.0);
+        if variance > 0.0 {
+            Some(variance)
+        } else {
+            None
+        }
+    }
+}
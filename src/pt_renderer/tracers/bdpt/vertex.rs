@@ -1,17 +1,20 @@
 use cgmath::prelude::*;
-use cgmath::{Point3, Vector3};
+use cgmath::{Point2, Point3, Vector3};
 
+use crate::bvh::BvhNode;
 use crate::camera::PtCamera;
 use crate::color::Color;
 use crate::config::*;
-use crate::consts;
 use crate::float::*;
-use crate::intersect::{Interaction, Ray};
+use crate::intersect::{ray_epsilon, Interaction, Ray};
 use crate::light::Light;
 use crate::pt_renderer::PathType;
 use crate::sample;
 use crate::scene::Scene;
 
+use super::super::{next_medium, transmittance};
+use super::Strategies;
+
 fn dir_and_dist(from: &dyn Vertex, to: &dyn Vertex) -> (Vector3<Float>, Float) {
     let to_next = to.pos() - from.pos();
     let dist = to_next.magnitude();
@@ -63,31 +66,101 @@ pub fn pdf_precompute(
     (Some(pdf_fwd), Some(pdf_rev))
 }
 
+/// Precomputed fwd and rev pdfs of the light and camera paths.
+/// None pdf corresponds to a delta distribution.
+#[derive(Default)]
+pub struct PathPdfs {
+    light_fwd: Vec<Option<Float>>,
+    light_rev: Vec<Option<Float>>,
+    camera_fwd: Vec<Option<Float>>,
+    camera_rev: Vec<Option<Float>>,
+}
+
+/// Number of samples of the strategies for each camera sample
+#[derive(Clone, Copy, Debug)]
+pub struct SampleCounts {
+    /// Strategies that connect the light to camera path vertices
+    pub nee: Float,
+    /// Strategies that connect light path vertices to camera path vertices
+    pub connections: Float,
+    /// Strategies that connect light path vertices to the camera
+    pub splats: Float,
+}
+
+impl SampleCounts {
+    /// Number of samples of strategy (s, t)
+    pub fn get(&self, s: usize, t: usize) -> Float {
+        if s == 0 {
+            1.0
+        } else if t == 1 {
+            self.splats
+        } else if s == 1 {
+            self.nee
+        } else {
+            self.connections
+        }
+    }
+}
+
 pub struct BdPath<'a> {
     light_vertex: &'a LightVertex<'a>,
     light_path: &'a [SurfaceVertex<'a>],
-    light_pdf_fwd: Vec<Option<Float>>,
-    light_pdf_rev: Vec<Option<Float>>,
     camera_vertex: &'a CameraVertex<'a>,
     camera_path: &'a [SurfaceVertex<'a>],
-    camera_pdf_fwd: Vec<Option<Float>>,
-    camera_pdf_rev: Vec<Option<Float>>,
+    pdfs: PathPdfs,
     config: &'a RenderConfig,
+    counts: SampleCounts,
+    /// Learned MIS factors of the strategies if variance aware MIS is used
+    strategies: Option<&'a Strategies>,
 }
 
 impl<'a> BdPath<'a> {
+    /// The pdfs of the path are computed into the given buffers
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         light_vertex: &'a LightVertex<'a>,
         light_path: &'a [SurfaceVertex<'a>],
         camera_vertex: &'a CameraVertex,
         camera_path: &'a [SurfaceVertex<'a>],
+        pdfs: PathPdfs,
         config: &'a RenderConfig,
+        counts: SampleCounts,
+        strategies: Option<&'a Strategies>,
     ) -> Self {
-        // Precompute fwd and rev pdfs
-        // None pdf corresponds to a delta distribution
+        let mut path = Self {
+            light_vertex,
+            light_path,
+            camera_vertex,
+            camera_path,
+            pdfs,
+            config,
+            counts,
+            strategies,
+        };
+        path.precompute_light_pdfs();
+        path.precompute_camera_pdfs();
+        path
+    }
+
+    /// Replace the light path but keep the camera path
+    pub fn set_light_path(
+        &mut self,
+        light_vertex: &'a LightVertex<'a>,
+        light_path: &'a [SurfaceVertex<'a>],
+    ) {
+        self.light_vertex = light_vertex;
+        self.light_path = light_path;
+        self.precompute_light_pdfs();
+    }
+
+    fn precompute_light_pdfs(&mut self) {
+        let light_vertex = self.light_vertex;
+        let light_path = self.light_path;
         // TODO: handle delta distributions already in primitives and not just here
-        let mut light_pdf_fwd = Vec::new();
-        let mut light_pdf_rev = Vec::new();
+        let light_pdf_fwd = &mut self.pdfs.light_fwd;
+        let light_pdf_rev = &mut self.pdfs.light_rev;
+        light_pdf_fwd.clear();
+        light_pdf_rev.clear();
         for i in 0..=light_path.len() {
             if i == 0 {
                 if light_vertex.light.delta_pos() {
@@ -114,9 +187,15 @@ impl<'a> BdPath<'a> {
                 light_pdf_rev.push(pdf_rev);
             }
         }
+    }
 
-        let mut camera_pdf_fwd = Vec::new();
-        let mut camera_pdf_rev = Vec::new();
+    fn precompute_camera_pdfs(&mut self) {
+        let camera_vertex = self.camera_vertex;
+        let camera_path = self.camera_path;
+        let camera_pdf_fwd = &mut self.pdfs.camera_fwd;
+        let camera_pdf_rev = &mut self.pdfs.camera_rev;
+        camera_pdf_fwd.clear();
+        camera_pdf_rev.clear();
         for i in 0..=camera_path.len() {
             if i == 0 {
                 // Pinhole camera
@@ -140,23 +219,71 @@ impl<'a> BdPath<'a> {
                 camera_pdf_rev.push(pdf_rev);
             }
         }
+    }
 
-        Self {
-            light_vertex,
-            light_path,
-            light_pdf_fwd,
-            light_pdf_rev,
-            camera_vertex,
-            camera_path,
-            camera_pdf_fwd,
-            camera_pdf_rev,
-            config,
+    /// Release the pdf buffers for reuse
+    pub fn into_pdfs(self) -> PathPdfs {
+        self.pdfs
+    }
+
+    /// Number of samples of strategy (s, t) for each camera sample
+    fn n_samples(&self, s: usize, t: usize) -> Float {
+        self.counts.get(s, t)
+    }
+
+    /// Evaluate strategy (s, t) of the path.
+    /// Return the unweighted radiance, the sub path for weighting it and
+    /// the position on the image plane if the radiance is splatted.
+    /// None is returned if the strategy doesn't carry any radiance.
+    pub fn connect<'s>(
+        &self,
+        s: usize,
+        t: usize,
+        scene: &'s Scene,
+        node_stack: &mut Vec<(&'s BvhNode, Float)>,
+    ) -> Option<(Color, SubPath<'_>, Option<Point2<Float>>)> {
+        // No light vertices
+        if s == 0 {
+            let vertex = self.camera_path.get(t - 2)?;
+            let light_vertex = vertex.to_light_vertex(scene)?;
+            return Some((
+                vertex.path_radiance(),
+                self.subpath_with_light(light_vertex, t),
+                None,
+            ));
         }
+        // This should be sampled well enough by strategy (0, 2)
+        if s == 1 && t == 1 {
+            return None;
+        }
+        let l_vertex: &dyn Vertex = if s == 1 {
+            self.light_vertex
+        } else {
+            &self.light_path[s - 2]
+        };
+        let c_vertex: &dyn Vertex = if t == 1 {
+            self.camera_vertex
+        } else {
+            &self.camera_path[t - 2]
+        };
+        // Connect camera vertex to light vertex since shadow rays
+        // from the camera are simpler than those from the light
+        let (mut connection_ray, radiance) = c_vertex.connect_to(l_vertex);
+        if radiance.is_black() || scene.intersect_shadow(&mut connection_ray, node_stack) {
+            return None;
+        }
+        let splat = if t == 1 {
+            // Splat is always valid if radiance is not black
+            Some(self.camera_vertex.camera.clip_pos(connection_ray.dir)?)
+        } else {
+            None
+        };
+        Some((radiance, self.subpath(s, t), splat))
     }
 
     /// Get a subpath with s light vertices and t camera vertices
     /// Will panic if (s, t) is not a valid subpath
-    pub fn subpath(&self, s: usize, t: usize) -> SubPath {
+    pub fn subpath(&self, s: usize, t: usize) -> SubPath<'_> {
         let bounces = s + t - 2;
         assert!(
             bounces <= self.config.max_bounces,
@@ -191,7 +318,7 @@ impl<'a> BdPath<'a> {
     }
 
     /// Get a sub path with only camera vertices which ends at light_vertex
-    pub fn subpath_with_light(&self, light_vertex: LightVertex<'a>, t: usize) -> SubPath {
+    pub fn subpath_with_light(&self, light_vertex: LightVertex<'a>, t: usize) -> SubPath<'_> {
         let mut subpath = self.subpath(0, t);
         subpath.tmp_light_vertex = Some(light_vertex);
         subpath
@@ -205,6 +332,32 @@ pub struct SubPath<'a> {
     tmp_light_vertex: Option<LightVertex<'a>>,
 }
 
+/// Dynamic russian roulette probabilities of continuing the path from each vertex
+/// when the path is sampled from the light and from the camera. Indexed by s.
+struct Survival {
+    light: Vec<Float>,
+    camera: Vec<Float>,
+}
+
+/// Throughput weight of scattering wo -> v -> wi that sampling the bsdf would give
+fn scatter_weight(
+    v: &SurfaceVertex,
+    wo: Vector3<Float>,
+    wi: Vector3<Float>,
+    path_type: PathType,
+) -> Color {
+    let weight = if v.delta_dir() {
+        v.isect.specular_weight(wo, wi, path_type)
+    } else {
+        let pdf = v.isect.pdf(wo, wi);
+        if pdf <= 0.0 {
+            return Color::black();
+        }
+        v.isect.bsdf(wo, wi, path_type) / pdf
+    };
+    v.isect.cos_t(wi) * weight
+}
+
 impl SubPath<'_> {
     /// Compute the weight for the radiance that is transported along this path
     pub fn weight(&self) -> Float {
@@ -212,34 +365,58 @@ impl SubPath<'_> {
         if bounces == 0 {
             1.0
         } else if !self.path.config.mis {
-            1.0 / (bounces + 2).to_float()
+            let n_total: Float = (0..=bounces + 1)
+                .map(|s| self.path.n_samples(s, bounces + 2 - s))
+                .sum();
+            self.path.n_samples(self.s, self.t) / n_total
         } else {
             let power = 2; // for power heuristic
+
+            // Strategies with light vertices are sampled once per light path
+            // so their relative weight is scaled by their number of samples
+            let n_ratio = |s: usize| {
+                (self.path.n_samples(s, self.s + self.t - s) / self.path.n_samples(self.s, self.t))
+                    .powi(power)
+            };
+            // Variance aware MIS scales the pdf of each strategy by its factor
+            let factor = |s: usize| match self.path.strategies {
+                Some(strategies) => (strategies.factor(s, self.s + self.t - s)
+                    / strategies.factor(self.s, self.t))
+                .powi(power),
+                None => 1.0,
+            };
+            let survival = match self.path.config.russian_roulette {
+                RussianRoulette::Dynamic => Some(self.survival()),
+                _ => None,
+            };
+            let survival = survival.as_ref();
             let mut sum = 1.0;
             let mut light_ratio = 1.0;
             for si in (0..self.s).rev() {
-                light_ratio *= (self.camera_pdf(si + 1).unwrap_or(1.0)
-                    / self.light_pdf(si + 1).unwrap_or(1.0))
+                light_ratio *= (self.camera_pdf(si + 1, survival).unwrap_or(1.0)
+                    / self.light_pdf(si + 1, survival).unwrap_or(1.0))
                 .powi(power);
                 let delta_light = if si == 0 {
                     // No need to care about the tmp_light_vertex, since if it exists
                     // then self.s is always 0, and this branch is not evaluated.
-                    self.path.light_vertex.light.delta_pos()
+                    // Camera paths can't hit lights with any delta distribution.
+                    let light = self.path.light_vertex.light;
+                    light.delta_pos() || light.delta_dir()
                 } else {
                     self.get_vertex(si).delta_dir()
                 };
                 if !delta_light && !self.get_vertex(si + 1).delta_dir() {
-                    sum += light_ratio;
+                    sum += factor(si) * n_ratio(si) * light_ratio;
                 }
             }
             let mut camera_ratio = 1.0;
             for ti in (2..=self.t).rev() {
                 let si = self.t_to_s(ti);
-                camera_ratio *= (self.light_pdf(si).unwrap_or(1.0)
-                    / self.camera_pdf(si).unwrap_or(1.0))
+                camera_ratio *= (self.light_pdf(si, survival).unwrap_or(1.0)
+                    / self.camera_pdf(si, survival).unwrap_or(1.0))
                 .powi(power);
                 if !self.get_vertex(si).delta_dir() && !self.get_vertex(si + 1).delta_dir() {
-                    sum += camera_ratio;
+                    sum += factor(si) * n_ratio(si) * camera_ratio;
                 }
             }
             1.0 / sum
@@ -273,7 +450,7 @@ impl SubPath<'_> {
 
     /// Get the s:th surface vertex on the path
     /// Will panic if the vertex does not exist
-    fn get_surface(&self, s: usize) -> &SurfaceVertex {
+    fn get_surface(&self, s: usize) -> &SurfaceVertex<'_> {
         if s <= self.s {
             &self.path.light_path[s - 2]
         } else {
@@ -281,10 +458,111 @@ impl SubPath<'_> {
         }
     }
 
+    /// Get the light vertex of the path
+    fn light_vertex(&self) -> &LightVertex<'_> {
+        match &self.tmp_light_vertex {
+            Some(light) => light,
+            None => self.path.light_vertex,
+        }
+    }
+
+    /// Transmittance of the segment between the vertices s and s + 1
+    /// for a path sampled in the direction of path_type
+    fn segment_transmittance(&self, s: usize, path_type: PathType) -> Color {
+        if s == 1 || self.s_to_t(s + 1) == 1 {
+            // Paths leave the light and the camera outside of media
+            Color::white()
+        } else if s < self.s {
+            let v = self.get_surface(s + 1);
+            transmittance(v.medium, v.ray.length)
+        } else if s > self.s {
+            let v = self.get_surface(s);
+            transmittance(v.medium, v.ray.length)
+        } else {
+            // Connection continues from the medium of the vertex it is sampled from
+            let (from, to) = if path_type.is_light() {
+                (self.get_surface(s), self.get_surface(s + 1))
+            } else {
+                (self.get_surface(s + 1), self.get_surface(s))
+            };
+            let (dir, dist) = dir_and_dist(from, to);
+            let medium = next_medium(&from.isect, -from.ray.dir, dir, from.medium);
+            transmittance(medium, dist)
+        }
+    }
+
+    /// Compute the dynamic survival probabilities of the vertices for sampling the
+    /// path from either end. Probabilities of the sampled vertices are stored in them
+    /// and the rest are found by following the throughput from the other end.
+    fn survival(&self) -> Survival {
+        let config = self.path.config;
+        let n = self.s + self.t;
+        let prob = |beta: Color, start: Float, bounce: usize| {
+            if bounce < config.pre_rr_bounces {
+                1.0
+            } else if start > 0.0 {
+                (beta.luma() / start).min(0.95)
+            } else {
+                0.0
+            }
+        };
+        let mut light = vec![1.0; n + 1];
+        let mut beta = Color::black();
+        let mut start = 0.0;
+        for s in 2..n {
+            let v = self.get_surface(s);
+            if s <= self.s {
+                beta = v.beta;
+            } else if s == 2 {
+                beta = self.light_vertex().beta_next(v);
+            } else {
+                let v_prev = self.get_surface(s - 1);
+                let (wo, _) = dir_and_dist(v_prev, self.get_vertex(s - 2));
+                let (wi, _) = dir_and_dist(v_prev, v);
+                beta *= scatter_weight(v_prev, wo, wi, PathType::Light) / light[s - 1];
+                beta *= self.segment_transmittance(s - 1, PathType::Light);
+            }
+            if s == 2 {
+                start = beta.luma();
+            }
+            light[s] = if s <= self.s {
+                v.survival_prob
+            } else {
+                prob(beta, start, s - 2)
+            };
+        }
+        let mut camera = vec![1.0; n + 1];
+        for s in (2..n).rev() {
+            let v = self.get_surface(s);
+            let t = self.s_to_t(s);
+            if s > self.s {
+                beta = v.beta;
+            } else if t == 2 {
+                // Camera paths start with white throughput
+                beta = Color::white();
+            } else {
+                let v_prev = self.get_surface(s + 1);
+                let (wo, _) = dir_and_dist(v_prev, self.get_vertex(s + 2));
+                let (wi, _) = dir_and_dist(v_prev, v);
+                beta *= scatter_weight(v_prev, wo, wi, PathType::Camera) / camera[s + 1];
+                beta *= self.segment_transmittance(s, PathType::Camera);
+            }
+            if t == 2 {
+                start = beta.luma();
+            }
+            camera[s] = if s > self.s {
+                v.survival_prob
+            } else {
+                prob(beta, start, t - 2)
+            };
+        }
+        Survival { light, camera }
+    }
+
     /// Get the pdf of sampling vertex s from direction of the light
-    fn light_pdf(&self, s: usize) -> Option<Float> {
+    fn light_pdf(&self, s: usize, survival: Option<&Survival>) -> Option<Float> {
         let mut pdf = if s <= self.s {
-            self.path.light_pdf_fwd[s - 1]?
+            self.path.pdfs.light_fwd[s - 1]?
         } else {
             let t = self.s_to_t(s);
             // Connection vertex interpreted as light
@@ -310,24 +588,26 @@ impl SubPath<'_> {
                 }
             // Backwards scattering along the light path
             } else {
-                self.path.camera_pdf_rev[t - 1]?
+                self.path.pdfs.camera_rev[t - 1]?
             }
         };
         // Check if russian roulette bounce was needed to sample the vertex
-        if let RussianRoulette::Static(rr_prob) = self.path.config.russian_roulette {
-            if s > 2 && s - 2 > self.path.config.pre_rr_bounces {
-                pdf *= rr_prob;
+        if s > 2 && s - 2 > self.path.config.pre_rr_bounces {
+            match (&self.path.config.russian_roulette, survival) {
+                (RussianRoulette::Static(rr_prob), _) => pdf *= rr_prob,
+                (RussianRoulette::Dynamic, Some(survival)) => pdf *= survival.light[s - 1],
+                _ => (),
             }
         }
         Some(pdf)
     }
 
     /// Get the pdf of sampling vertex s from direction of the camera
-    fn camera_pdf(&self, s: usize) -> Option<Float> {
+    fn camera_pdf(&self, s: usize, survival: Option<&Survival>) -> Option<Float> {
         let t = self.s_to_t(s);
         let mut pdf = if s > self.s {
             // Regular sampling of the camera path
-            self.path.camera_pdf_fwd[t - 1]?
+            self.path.pdfs.camera_fwd[t - 1]?
         } else {
             // Connection vertex sampled from the camera
             if self.t == 1 && s == self.s {
@@ -344,13 +624,15 @@ impl SubPath<'_> {
                 }
             // Backwards scattering along the light path
             } else {
-                self.path.light_pdf_rev[s - 1]?
+                self.path.pdfs.light_rev[s - 1]?
             }
         };
         // Check if russian roulette bounce was needed to sample the vertex
-        if let RussianRoulette::Static(rr_prob) = self.path.config.russian_roulette {
-            if t > 2 && t - 2 > self.path.config.pre_rr_bounces {
-                pdf *= rr_prob;
+        if t > 2 && t - 2 > self.path.config.pre_rr_bounces {
+            match (&self.path.config.russian_roulette, survival) {
+                (RussianRoulette::Static(rr_prob), _) => pdf *= rr_prob,
+                (RussianRoulette::Dynamic, Some(survival)) => pdf *= survival.camera[s + 1],
+                _ => (),
             }
         }
         Some(pdf)
@@ -376,12 +658,22 @@ pub trait Vertex: std::fmt::Debug {
     /// Evaluate the throughput for a path continuing in dir
     fn path_throughput(&self, dir: Vector3<Float>) -> Color;
 
+    /// Absorption coefficient of the medium that the vertex is in
+    fn medium(&self) -> Option<Color> {
+        None
+    }
+
     /// Connect vertex to a surface vertex.
     /// Return the shadow ray and total path throughput.
     /// Will panic if other is not a surface vertex.
     fn connect_to(&self, other: &dyn Vertex) -> (Ray, Color) {
         let ray = Ray::shadow(self.shadow_origin(other.pos() - self.pos()), other.pos());
-        let beta = self.path_throughput(ray.dir) * other.path_throughput(-ray.dir);
+        let mut beta = self.path_throughput(ray.dir) * other.path_throughput(-ray.dir);
+        // Connections that leave a medium are blocked by its boundary
+        // so only connections between vertices inside a medium are attenuated
+        if self.medium().is_some() && other.medium().is_some() {
+            beta *= transmittance(self.medium(), ray.length);
+        }
         let g = (self.cos_s(ray.dir) * other.cos_s(ray.dir) / ray.length.powi(2)).abs();
         (ray, g * beta)
     }
@@ -390,20 +682,20 @@ pub trait Vertex: std::fmt::Debug {
 #[derive(Debug)]
 pub struct CameraVertex<'a> {
     pub camera: &'a PtCamera,
-    ray: Ray,
 }
 
 impl<'a> CameraVertex<'a> {
-    pub fn new(camera: &'a PtCamera, ray: Ray) -> Self {
-        Self { camera, ray }
+    pub fn new(camera: &'a PtCamera) -> Self {
+        Self { camera }
     }
 
-    pub fn sample_next(&self) -> (Color, Ray) {
+    /// Start the camera path along the camera ray
+    pub fn sample_next(&self, ray: Ray) -> (Color, Ray) {
         // This is the real value but it always equals to 1.0
-        // let dir = self.ray.dir;
+        // let dir = ray.dir;
         // let beta = self.camera.we(dir) * self.camera.cos_s(dir).abs() / self.camera.pdf(dir);
         let beta = Color::white();
-        (beta, self.ray.clone())
+        (beta, ray)
     }
 
     pub fn pdf_next(&self, next: &SurfaceVertex) -> Float {
@@ -457,8 +749,8 @@ impl<'a> LightVertex<'a> {
     }
 
     pub fn sample_next(&self) -> (Color, Ray) {
-        let (le, dir, dir_pdf) = self.light.sample_dir();
-        let ray = Ray::from_dir(self.pos + consts::EPSILON * dir, dir);
+        let (le, dir, dir_pdf) = self.light.sample_dir(self.pos);
+        let ray = Ray::from_dir(self.pos + ray_epsilon(self.pos) * dir, dir);
         let beta = le * self.cos_s(ray.dir).abs() / (self.pdf_pos * dir_pdf);
         (beta, ray)
     }
@@ -468,6 +760,21 @@ impl<'a> LightVertex<'a> {
         let pdf_dir = self.light.pdf_dir(dir);
         sample::to_area_pdf(pdf_dir, dist.powi(2), next.cos_g(dir).abs())
     }
+
+    /// Throughput that sample_next would give for the path continuing to next
+    pub fn beta_next(&self, next: &SurfaceVertex) -> Color {
+        let (le, dir, dir_pdf) = if self.delta_dir() {
+            // Delta directions are sampled deterministically
+            self.light.sample_dir(self.pos)
+        } else {
+            let (dir, _) = dir_and_dist(self, next);
+            (self.light.le(self.pos, dir), dir, self.light.pdf_dir(dir))
+        };
+        if dir_pdf <= 0.0 {
+            return Color::black();
+        }
+        le * self.cos_s(dir).abs() / (self.pdf_pos * dir_pdf)
+    }
 }
 
 impl Vertex for LightVertex<'_> {
@@ -488,11 +795,11 @@ impl Vertex for LightVertex<'_> {
     }
 
     fn delta_dir(&self) -> bool {
-        false
+        self.light.delta_dir()
     }
 
     fn path_throughput(&self, dir: Vector3<Float>) -> Color {
-        self.light.le(dir) / self.pdf_pos
+        self.light.le(self.pos, dir) / self.pdf_pos
     }
 }
 
@@ -504,15 +811,27 @@ pub struct SurfaceVertex<'a> {
     beta: Color,
     path_type: PathType,
     pub isect: Interaction<'a>,
+    /// Probability of russian roulette continuing the path from this vertex
+    pub survival_prob: Float,
+    /// Absorption coefficient of the medium that the vertex was reached through
+    medium: Option<Color>,
 }
 
 impl<'a> SurfaceVertex<'a> {
-    pub fn new(ray: Ray, beta: Color, path_type: PathType, isect: Interaction<'a>) -> Self {
+    pub fn new(
+        ray: Ray,
+        beta: Color,
+        path_type: PathType,
+        isect: Interaction<'a>,
+        medium: Option<Color>,
+    ) -> Self {
         Self {
             ray,
             beta,
             isect,
             path_type,
+            survival_prob: 1.0,
+            medium,
         }
     }
 
@@ -522,7 +841,7 @@ impl<'a> SurfaceVertex<'a> {
     }
 
     /// Attempt to convert the vertex to a light vertex
-    pub fn to_light_vertex(&self, scene: &Scene) -> Option<LightVertex> {
+    pub fn to_light_vertex(&self, scene: &Scene) -> Option<LightVertex<'_>> {
         let tri = self.isect.tri;
         if tri.is_emissive() {
             let pdf_light = scene.pdf_light(tri);
@@ -558,4 +877,8 @@ impl Vertex for SurfaceVertex<'_> {
     fn path_throughput(&self, dir: Vector3<Float>) -> Color {
         self.beta * self.isect.bsdf(-self.ray.dir, dir, self.path_type)
     }
+
+    fn medium(&self) -> Option<Color> {
+        self.medium
+    }
 }
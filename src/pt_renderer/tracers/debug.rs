@@ -4,6 +4,7 @@ use crate::bvh::BvhNode;
 use crate::color::Color;
 use crate::config::*;
 use crate::float::*;
+use crate::intersect::Hit;
 use crate::intersect::Ray;
 use crate::scene::Scene;
 
@@ -17,6 +18,11 @@ pub fn debug_trace<'a>(
     match mode {
         DebugMode::Normals => trace_normals(ray, scene, config, node_stack, false),
         DebugMode::ForwardNormals => trace_normals(ray, scene, config, node_stack, true),
+        DebugMode::Depth => trace_depth(ray, scene, node_stack),
+        DebugMode::TexCoords => trace_tex_coords(ray, scene, node_stack, false),
+        DebugMode::TexelDensity => trace_tex_coords(ray, scene, node_stack, true),
+        DebugMode::MaterialId => trace_ids(ray, scene, node_stack, false),
+        DebugMode::MeshId => trace_ids(ray, scene, node_stack, true),
     }
 }
 
@@ -36,3 +42,79 @@ fn trace_normals<'a>(
     }
     c
 }
+
+fn trace_depth<'a>(
+    mut ray: Ray,
+    scene: &'a Scene,
+    node_stack: &mut Vec<(&'a BvhNode, Float)>,
+) -> Color {
+    match scene.intersect(&mut ray, node_stack) {
+        Some(hit) => {
+            let depth = (hit.t / scene.diagonal()).min(1.0);
+            Color::new(depth, depth, depth)
+        }
+        None => Color::white(),
+    }
+}
+
+/// Texture width assumed by the texel density visualization
+const DEBUG_TEXTURE_SIZE: Float = 1024.0;
+/// Octaves of texel density from one texel per pixel that saturate the colors
+const DEBUG_DENSITY_OCTAVES: Float = 4.0;
+
+fn trace_tex_coords<'a>(
+    mut ray: Ray,
+    scene: &'a Scene,
+    node_stack: &mut Vec<(&'a BvhNode, Float)>,
+    density: bool,
+) -> Color {
+    let hit = match scene.intersect(&mut ray, node_stack) {
+        Some(hit) => hit,
+        None => return Color::black(),
+    };
+    let tex_density = hit.tex_coord_density();
+    if tex_density == 0.0 {
+        return Color::new(1.0, 0.0, 1.0);
+    }
+    if density {
+        texel_density_color(&hit, tex_density)
+    } else {
+        let t = hit.tri.bary_t(hit.u, hit.v);
+        Color::new(t.x.rem_euclid(1.0), t.y.rem_euclid(1.0), 0.0)
+    }
+}
+
+/// Blend from green towards red for minified and towards blue for magnified textures
+fn texel_density_color(hit: &Hit, tex_density: Float) -> Color {
+    let texels = DEBUG_TEXTURE_SIZE * tex_density * hit.footprint;
+    let octaves = (texels.log2() / DEBUG_DENSITY_OCTAVES).clamp(-1.0, 1.0);
+    if octaves > 0.0 {
+        Color::new(octaves, 1.0 - octaves, 0.0)
+    } else {
+        Color::new(0.0, 1.0 + octaves, -octaves)
+    }
+}
+
+fn trace_ids<'a>(
+    mut ray: Ray,
+    scene: &'a Scene,
+    node_stack: &mut Vec<(&'a BvhNode, Float)>,
+    mesh: bool,
+) -> Color {
+    match scene.intersect(&mut ray, node_stack) {
+        Some(hit) if mesh => id_color(hit.tri.mesh_i),
+        Some(hit) => id_color(hit.tri.material.index()),
+        None => Color::black(),
+    }
+}
+
+/// Deterministic pseudo random color for id that is never too dark to see
+fn id_color(id: usize) -> Color {
+    // Integer hash from splitmix64
+    let mut x = (id as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    let channel = |shift: u64| 0.2 + 0.8 * (((x >> shift) & 0xff) as usize).to_float() / 255.0;
+    Color::new(channel(0), channel(8), channel(16))
+}
@@ -2,71 +2,222 @@ use crate::bvh::BvhNode;
 use crate::color::Color;
 use crate::config::*;
 use crate::float::*;
-use crate::intersect::{Interaction, Ray};
+use crate::intersect::{Interaction, Ray, Receiver};
 use crate::light::Light;
+use crate::medium::{Medium, MediumPoint};
 use crate::pt_renderer::PathType;
+use crate::sampler;
 use crate::scene::Scene;
 
-fn sample_light(
-    isect: &Interaction,
+use super::{clamp_indirect, next_medium, transmittance};
+
+/// Sample a light towards recv.
+/// Return the radiance, the shadow ray, the solid angle pdf and
+/// whether the light can only be reached by sampling it explicitly.
+pub(super) fn sample_light(
+    recv: &dyn Receiver,
     scene: &Scene,
     flash: &dyn Light,
     config: &RenderConfig,
-) -> (Color, Ray, Float) {
+) -> Option<(Color, Ray, Float, bool)> {
     let (light, pdf) = match config.light_mode {
-        LightMode::Scene => scene.sample_light().unwrap_or((flash, 1.0)),
+        LightMode::Scene => match scene.sample_light() {
+            Some(res) => res,
+            // Environment is sampled separately
+            None if scene.environment().is_some() => return None,
+            None => (flash, 1.0),
+        },
         LightMode::Camera => (flash, 1.0),
     };
-    let (li, ray, lpdf) = light.sample_towards(isect);
-    (li, ray, pdf * lpdf)
+    let (li, ray, lpdf) = light.sample_towards(recv);
+    let delta = light.delta_pos() || light.delta_dir();
+    Some((li, ray, pdf * lpdf, delta))
 }
 
+pub(super) fn sample_environment(
+    isect: &Interaction,
+    scene: &Scene,
+    config: &RenderConfig,
+) -> Option<(Color, Ray, Float, bool)> {
+    match config.light_mode {
+        LightMode::Scene if config.environment_nee => scene.environment().map(|env| {
+            let (le, ray, pdf) = env.sample_towards(isect);
+            (le, ray, pdf, false)
+        }),
+        _ => None,
+    }
+}
+
+/// Power heuristic weight of a strategy with pdf against a strategy with other_pdf
+fn mis_weight(pdf: Float, other_pdf: Float) -> Float {
+    pdf.powi(2) / (pdf.powi(2) + other_pdf.powi(2))
+}
+
+/// Solid angle pdf of sampling the emissive triangle of isect
+/// as a light from the origin of ray
+fn pdf_light_dir(isect: &Interaction, ray: &Ray, scene: &Scene) -> Float {
+    scene.pdf_light(isect.tri) * isect.tri.pdf_towards(ray.orig, ray.dir, ray.length)
+}
+
+/// Check if the shadow ray is blocked before reaching its end
+fn occluded<'a>(
+    shadow_ray: &mut Ray,
+    scene: &'a Scene,
+    config: &RenderConfig,
+    node_stack: &mut Vec<(&'a BvhNode, Float)>,
+) -> bool {
+    match config.approx_shadows {
+        Some(depth) => scene.intersect_shadow_approx(shadow_ray, depth),
+        None => scene.intersect_shadow(shadow_ray, node_stack),
+    }
+}
+
+/// Decide with russian roulette and the bounce limit whether the path continues.
+/// Return the survival probability or None if the path terminates.
+fn survival_prob(
+    bounce: usize,
+    beta: Color,
+    rr_scale: Float,
+    config: &RenderConfig,
+) -> Option<Float> {
+    if bounce >= config.max_bounces {
+        return None;
+    }
+    if bounce < config.pre_rr_bounces {
+        return Some(1.0);
+    }
+    let prob = match config.russian_roulette {
+        RussianRoulette::Dynamic => (rr_scale * beta.luma()).min(0.95),
+        RussianRoulette::Static(prob) => rr_scale * prob,
+        RussianRoulette::Off => return Some(1.0),
+    };
+    if sampler::random() > prob {
+        None
+    } else {
+        Some(prob)
+    }
+}
+
+/// Russian roulette survival probabilities are multiplied by rr_scale
 pub fn path_trace<'a>(
     mut ray: Ray,
     scene: &'a Scene,
     flash: &dyn Light,
     config: &RenderConfig,
     node_stack: &mut Vec<(&'a BvhNode, Float)>,
+    rr_scale: Float,
 ) -> Color {
     let mut c = Color::black();
+    // Radiance that has bounced more than once
+    let mut indirect = Color::black();
     let mut beta = Color::white();
     let mut bounce = 0;
     let mut specular_bounce = false;
-    while let Some(hit) = scene.intersect(&mut ray, node_stack) {
+    // Solid angle pdf of sampling the last bounce from the bsdf or the phase function
+    let mut bsdf_pdf = 1.0;
+    // Absorption coefficient of the medium the path is travelling in
+    let mut medium = None;
+    let fog = Medium::from_config(config);
+    loop {
+        let radiance = if bounce == 0 { &mut c } else { &mut indirect };
+        let hit = scene.intersect(&mut ray, node_stack);
+        // Scatter in the fog if the sampled flight ends before the surface
+        let fog_dist = fog.as_ref().and_then(|fog| fog.sample_distance(ray.length));
+        if let (Some(fog), Some(dist)) = (&fog, fog_dist) {
+            beta *= fog.albedo() * transmittance(medium, dist);
+            let point = MediumPoint {
+                p: ray.orig + dist * ray.dir,
+            };
+            if let Some((le, mut shadow_ray, light_pdf, delta)) =
+                sample_light(&point, scene, flash, config)
+            {
+                let phase = fog.phase(ray.dir, shadow_ray.dir);
+                if !occluded(&mut shadow_ray, scene, config, node_stack) {
+                    let mut weight = 1.0;
+                    if config.mis && !delta {
+                        weight = mis_weight(light_pdf, phase);
+                    }
+                    let tr = fog.transmittance(shadow_ray.length);
+                    *radiance += weight * tr * phase * beta * le / light_pdf;
+                }
+            }
+            if let Some(prob) = survival_prob(bounce, beta, rr_scale, config) {
+                // Phase function and its pdf cancel out
+                let (dir, phase_pdf) = fog.sample_phase(ray.dir);
+                bsdf_pdf = phase_pdf;
+                beta /= prob;
+                ray = point.ray(dir);
+                bounce += 1;
+                specular_bounce = false;
+                if !beta.is_black() {
+                    continue;
+                }
+            }
+            break;
+        }
+        let hit = match hit {
+            Some(hit) => hit,
+            None => {
+                if let Some(env) = scene.environment() {
+                    // Environment is only hit by chance if it isn't sampled explicitly
+                    if bounce == 0 || specular_bounce || !config.environment_nee {
+                        *radiance += beta * env.lookup(ray.dir);
+                    } else if config.mis {
+                        let weight = mis_weight(bsdf_pdf, env.pdf_incoming(ray.dir));
+                        *radiance += weight * beta * env.lookup(ray.dir);
+                    }
+                }
+                break;
+            }
+        };
+        beta *= transmittance(medium, ray.length);
         let isect = hit.interaction(config);
         if bounce == 0 || specular_bounce {
-            c += beta * isect.le(-ray.dir);
-        }
-        let (le, mut shadow_ray, light_pdf) = sample_light(&isect, scene, flash, config);
-        let bsdf = isect.bsdf(-ray.dir, shadow_ray.dir, PathType::Camera);
-        if !bsdf.is_black() && !scene.intersect_shadow(&mut shadow_ray, node_stack) {
-            let cos_t = isect.cos_s(shadow_ray.dir).abs();
-            c += beta * le * bsdf * cos_t / light_pdf;
+            *radiance += beta * isect.le(-ray.dir);
+        } else if config.mis && config.light_mode == LightMode::Scene {
+            // Emission found by sampling the bsdf is weighted against sampling the light
+            let le = isect.le(-ray.dir);
+            if !le.is_black() {
+                let weight = mis_weight(bsdf_pdf, pdf_light_dir(&isect, &ray, scene));
+                *radiance += weight * beta * le;
+            }
         }
-        let mut pdf = 1.0;
-        let terminate = if bounce >= config.max_bounces {
-            true
-        } else if bounce >= config.pre_rr_bounces {
-            match config.russian_roulette {
-                RussianRoulette::Dynamic => {
-                    // Survival probability
-                    let prob = beta.luma().min(0.95);
-                    pdf *= prob;
-                    rand::random::<Float>() > prob
+        // Specular bsdfs are zero for every light sample so skip light sampling entirely.
+        // Lights seen through specular bounces are accounted for by the emission above.
+        // Other lights are found by both strategies which are combined with MIS.
+        if !isect.is_specular() {
+            // The fog fills all of space so the environment never reaches the scene
+            let env_sample = match fog {
+                Some(_) => None,
+                None => sample_environment(&isect, scene, config),
+            };
+            let light_samples = sample_light(&isect, scene, flash, config)
+                .into_iter()
+                .chain(env_sample);
+            for (le, mut shadow_ray, light_pdf, delta) in light_samples {
+                let bsdf = isect.bsdf(-ray.dir, shadow_ray.dir, PathType::Camera);
+                if bsdf.is_black() {
+                    continue;
                 }
-                RussianRoulette::Static(prob) => {
-                    pdf *= prob;
-                    rand::random::<Float>() > prob
+                if !occluded(&mut shadow_ray, scene, config, node_stack) {
+                    let cos_t = isect.cos_t(shadow_ray.dir);
+                    let mut weight = 1.0;
+                    // Delta lights can't be hit by sampling the bsdf
+                    if config.mis && !delta {
+                        weight = mis_weight(light_pdf, isect.pdf(-ray.dir, shadow_ray.dir));
+                    }
+                    let tr = fog
+                        .as_ref()
+                        .map_or(1.0, |fog| fog.transmittance(shadow_ray.length));
+                    *radiance += weight * tr * beta * le * bsdf * cos_t / light_pdf;
                 }
-                RussianRoulette::Off => false,
             }
-        } else {
-            false
-        };
-        if !terminate {
-            if let Some((bsdf, new_ray, bsdf_pdf)) = isect.sample_bsdf(-ray.dir, PathType::Camera) {
-                pdf *= bsdf_pdf;
-                beta *= isect.cos_s(new_ray.dir).abs() * bsdf / pdf;
+        }
+        if let Some(prob) = survival_prob(bounce, beta, rr_scale, config) {
+            if let Some((bsdf, new_ray, new_pdf)) = isect.sample_bsdf(-ray.dir, PathType::Camera) {
+                bsdf_pdf = new_pdf;
+                beta *= isect.cos_t(new_ray.dir) * bsdf / (prob * bsdf_pdf);
+                medium = next_medium(&isect, -ray.dir, new_ray.dir, medium);
                 ray = new_ray;
                 bounce += 1;
                 specular_bounce = isect.is_specular();
@@ -77,5 +228,5 @@ pub fn path_trace<'a>(
         }
         break;
     }
-    c
+    c + clamp_indirect(indirect, config)
 }
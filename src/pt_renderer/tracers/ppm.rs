@@ -0,0 +1,178 @@
+use crate::bvh::BvhNode;
+use crate::color::Color;
+use crate::config::*;
+use crate::float::*;
+use crate::intersect::{ray_epsilon, Ray};
+use crate::light::Light;
+use crate::pt_renderer::PathType;
+use crate::sampler;
+use crate::scene::Scene;
+
+mod photon_map;
+
+use self::photon_map::Photon;
+pub use self::photon_map::PhotonMap;
+use super::clamp_indirect;
+use super::path_tracer::{sample_environment, sample_light};
+
+/// Radius within which photons are gathered during the given iteration.
+/// The radius shrinks such that the bias and the variance both vanish
+/// as the iterations are averaged.
+pub fn gather_radius(iteration: usize, scene: &Scene, config: &RenderConfig) -> Float {
+    let mut radius_sq = (config.ppm_radius * scene.size()).powi(2);
+    for i in 1..=iteration {
+        radius_sq *= (i.to_float() + config.ppm_alpha) / (i.to_float() + 1.0);
+    }
+    radius_sq.sqrt()
+}
+
+/// Trace n photon paths from the lights into photon_map, which replaces its
+/// previous photons. Photons are only stored after the first bounce
+/// since direct lighting is sampled explicitly at the gathering points.
+pub fn trace_photons<'a>(
+    n: usize,
+    radius: Float,
+    scene: &'a Scene,
+    flash: &dyn Light,
+    config: &RenderConfig,
+    node_stack: &mut Vec<(&'a BvhNode, Float)>,
+    photon_map: &mut PhotonMap,
+) {
+    photon_map.clear(radius);
+    for _ in 0..n {
+        photon_map.add_path();
+        let (light, light_pdf) = match config.light_mode {
+            LightMode::Scene => match scene.sample_light() {
+                Some(res) => res,
+                // Environment only lights the gathering points directly
+                None if scene.environment().is_some() => continue,
+                None => (flash, 1.0),
+            },
+            LightMode::Camera => (flash, 1.0),
+        };
+        let (pos, pos_pdf) = light.sample_pos();
+        let (le, dir, dir_pdf) = light.sample_dir(pos);
+        let mut ray = Ray::from_dir(pos + ray_epsilon(pos) * dir, dir);
+        let mut beta = le * light.cos_g(dir).abs() / (light_pdf * pos_pdf * dir_pdf);
+        // Dynamic russian roulette uses the throughput relative to the start of the path
+        // so that the survival of photons doesn't depend on the scale of the emission
+        let start_luma = beta.luma();
+        // Black photons carry nothing and would divide by zero in the survival probability
+        if start_luma <= 0.0 {
+            continue;
+        }
+        let mut bounce = 0;
+        while !beta.is_black() {
+            let hit = match scene.intersect(&mut ray, node_stack) {
+                Some(hit) => hit,
+                None => break,
+            };
+            let isect = hit.interaction(config);
+            if bounce > 0 && !isect.is_specular() {
+                photon_map.push(Photon::new(&isect, -ray.dir, beta));
+            }
+            let mut pdf = 1.0;
+            let terminate = if bounce >= config.max_bounces {
+                true
+            } else if bounce >= config.pre_rr_bounces {
+                match config.russian_roulette {
+                    RussianRoulette::Dynamic => {
+                        // Survival probability
+                        let prob = (beta.luma() / start_luma).min(0.95);
+                        pdf *= prob;
+                        sampler::random() > prob
+                    }
+                    RussianRoulette::Static(prob) => {
+                        pdf *= prob;
+                        sampler::random() > prob
+                    }
+                    RussianRoulette::Off => false,
+                }
+            } else {
+                false
+            };
+            if terminate {
+                break;
+            }
+            match isect.sample_bsdf(-ray.dir, PathType::Light) {
+                Some((bsdf, new_ray, bsdf_pdf)) => {
+                    pdf *= bsdf_pdf;
+                    beta *= isect.cos_t(new_ray.dir) * bsdf / pdf;
+                    ray = new_ray;
+                    bounce += 1;
+                }
+                None => break,
+            }
+        }
+    }
+    photon_map.build();
+}
+
+/// Follow the camera ray through specular bounces and estimate the radiance
+/// of the first non-specular surface from the photons around it.
+/// Direct lighting of the surface is sampled explicitly.
+pub fn ppm<'a>(
+    mut ray: Ray,
+    scene: &'a Scene,
+    flash: &dyn Light,
+    config: &RenderConfig,
+    node_stack: &mut Vec<(&'a BvhNode, Float)>,
+    photon_map: &PhotonMap,
+) -> Color {
+    let mut c = Color::black();
+    // Radiance that has bounced more than once
+    let mut indirect = Color::black();
+    let mut beta = Color::white();
+    let mut bounce = 0;
+    loop {
+        let radiance = if bounce == 0 { &mut c } else { &mut indirect };
+        let hit = match scene.intersect(&mut ray, node_stack) {
+            Some(hit) => hit,
+            None => {
+                if let Some(env) = scene.environment() {
+                    *radiance += beta * env.lookup(ray.dir);
+                }
+                break;
+            }
+        };
+        let isect = hit.interaction(config);
+        // Only specular bounces are followed so emission can't be sampled explicitly
+        *radiance += beta * isect.le(-ray.dir);
+        if !isect.is_specular() {
+            let light_samples = sample_light(&isect, scene, flash, config)
+                .into_iter()
+                .chain(sample_environment(&isect, scene, config));
+            for (le, mut shadow_ray, light_pdf, _) in light_samples {
+                let bsdf = isect.bsdf(-ray.dir, shadow_ray.dir, PathType::Camera);
+                if bsdf.is_black() {
+                    continue;
+                }
+                let occluded = match config.approx_shadows {
+                    Some(depth) => scene.intersect_shadow_approx(&mut shadow_ray, depth),
+                    None => scene.intersect_shadow(&mut shadow_ray, node_stack),
+                };
+                if !occluded {
+                    let cos_t = isect.cos_t(shadow_ray.dir);
+                    *radiance += beta * le * bsdf * cos_t / light_pdf;
+                }
+            }
+            indirect += beta * photon_map.gather(&isect, -ray.dir);
+            break;
+        }
+        if bounce >= config.max_bounces {
+            break;
+        }
+        match isect.sample_bsdf(-ray.dir, PathType::Camera) {
+            Some((bsdf, new_ray, pdf)) => {
+                beta *= isect.cos_t(new_ray.dir) * bsdf / pdf;
+                ray = new_ray;
+                bounce += 1;
+                if beta.is_black() {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    c + clamp_indirect(indirect, config)
+}
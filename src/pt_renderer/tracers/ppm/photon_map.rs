@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use cgmath::prelude::*;
+use cgmath::{Point3, Vector3};
+
+use crate::color::Color;
+use crate::consts;
+use crate::float::*;
+use crate::intersect::Interaction;
+use crate::pt_renderer::PathType;
+
+/// Light path vertex at a non-specular surface
+#[derive(Clone, Debug)]
+pub struct Photon {
+    pos: Point3<Float>,
+    /// Direction towards the previous vertex of the light path
+    wi: Vector3<Float>,
+    /// Geometric normal of the surface
+    ng: Vector3<Float>,
+    /// Flux carried by the photon
+    beta: Color,
+}
+
+impl Photon {
+    pub fn new(isect: &Interaction, wi: Vector3<Float>, beta: Color) -> Self {
+        Self {
+            pos: isect.p,
+            wi,
+            ng: isect.ng,
+            beta,
+        }
+    }
+}
+
+/// Photons stored in a uniform hash grid whose cells are
+/// as wide as the diameter of the gathering radius
+#[derive(Default)]
+pub struct PhotonMap {
+    /// Photons sorted by their cell
+    photons: Vec<Photon>,
+    cells: HashMap<(i64, i64, i64), Range<usize>>,
+    radius: Float,
+    /// Number of light paths that the photons were traced from
+    n_paths: usize,
+}
+
+impl PhotonMap {
+    /// Remove the photons and set the gathering radius for the next ones
+    pub fn clear(&mut self, radius: Float) {
+        self.photons.clear();
+        self.cells.clear();
+        self.radius = radius;
+        self.n_paths = 0;
+    }
+
+    pub fn push(&mut self, photon: Photon) {
+        self.photons.push(photon);
+    }
+
+    /// Count a traced light path regardless of how many photons it stored
+    pub fn add_path(&mut self) {
+        self.n_paths += 1;
+    }
+
+    /// Sort the pushed photons into the grid so that they can be gathered
+    pub fn build(&mut self) {
+        let cell_size = 2.0 * self.radius;
+        let key = |pos: Point3<Float>| {
+            (
+                (pos.x / cell_size).floor() as i64,
+                (pos.y / cell_size).floor() as i64,
+                (pos.z / cell_size).floor() as i64,
+            )
+        };
+        self.photons.sort_unstable_by_key(|photon| key(photon.pos));
+        let mut start = 0;
+        for end in 1..=self.photons.len() {
+            let cell = key(self.photons[start].pos);
+            if end == self.photons.len() || key(self.photons[end].pos) != cell {
+                self.cells.insert(cell, start..end);
+                start = end;
+            }
+        }
+    }
+
+    /// Estimate the radiance scattered from isect to wo by the photons within
+    /// the gathering radius. Photons on differently oriented surfaces are skipped
+    /// since they would leak light around corners.
+    pub fn gather(&self, isect: &Interaction, wo: Vector3<Float>) -> Color {
+        if self.n_paths == 0 {
+            return Color::black();
+        }
+        let cell_size = 2.0 * self.radius;
+        // The sphere around the point overlaps at most two cells along each axis
+        let min = (isect.p - Vector3::from_value(self.radius)) / cell_size;
+        let (x0, y0, z0) = (
+            min.x.floor() as i64,
+            min.y.floor() as i64,
+            min.z.floor() as i64,
+        );
+        let mut sum = Color::black();
+        for x in x0..=x0 + 1 {
+            for y in y0..=y0 + 1 {
+                for z in z0..=z0 + 1 {
+                    let range = match self.cells.get(&(x, y, z)) {
+                        Some(range) => range.clone(),
+                        None => continue,
+                    };
+                    for photon in &self.photons[range] {
+                        if photon.pos.distance2(isect.p) > self.radius.powi(2)
+                            || photon.ng.dot(isect.ng) <= 0.0
+                        {
+                            continue;
+                        }
+                        // The flux density is measured on the geometric surface
+                        // while the bsdf scatters with the shading normal
+                        let cos_g = isect.cos_g(photon.wi).abs();
+                        if cos_g == 0.0 {
+                            continue;
+                        }
+                        let bsdf = isect.bsdf(wo, photon.wi, PathType::Camera);
+                        sum += isect.cos_t(photon.wi) / cos_g * bsdf * photon.beta;
+                    }
+                }
+            }
+        }
+        sum / (consts::PI * self.radius.powi(2) * self.n_paths.to_float())
+    }
+}
@@ -3,6 +3,7 @@ use cgmath::{Matrix3, Vector3};
 
 use crate::consts;
 use crate::float::*;
+use crate::sampler;
 
 /// Compute an orthonormal coordinate frame where n defines is the z-axis
 pub fn local_to_world(n: Vector3<Float>) -> Matrix3<Float> {
@@ -28,8 +29,8 @@ pub fn to_area_pdf(pdf_dir: Float, dist2: Float, abs_cos_t: Float) -> Float {
 #[allow(clippy::many_single_char_names)]
 /// Cosine sample either (0, 0, 1) or (0, 0, -1) hemisphere decided by sign
 pub fn cosine_sample_hemisphere(sign: Float) -> Vector3<Float> {
-    let phi = 2.0 * consts::PI * rand::random::<Float>();
-    let r = rand::random::<Float>().sqrt();
+    let phi = 2.0 * consts::PI * sampler::random();
+    let r = sampler::random().sqrt();
     let x = r * phi.cos();
     let y = r * phi.sin();
     // Make sure sampled vector is in the correct hemisphere
@@ -42,9 +43,28 @@ pub fn cosine_hemisphere_pdf(abs_cos_t: Float) -> Float {
     abs_cos_t / consts::PI
 }
 
+/// Uniformly sample a point on the unit disk in the xy-plane
+pub fn uniform_sample_disk() -> Vector3<Float> {
+    let phi = 2.0 * consts::PI * sampler::random();
+    let r = sampler::random().sqrt();
+    Vector3::new(r * phi.cos(), r * phi.sin(), 0.0)
+}
+
+/// Uniformly sample a direction inside a cone around (0, 0, 1)
+pub fn uniform_sample_cone(cos_max: Float) -> Vector3<Float> {
+    let phi = 2.0 * consts::PI * sampler::random();
+    let z = 1.0 - sampler::random() * (1.0 - cos_max);
+    let r = (1.0 - z.powi(2)).sqrt();
+    Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn uniform_cone_pdf(cos_max: Float) -> Float {
+    1.0 / (2.0 * consts::PI * (1.0 - cos_max))
+}
+
 pub fn uniform_sample_sphere() -> Vector3<Float> {
-    let phi = 2.0 * consts::PI * rand::random::<Float>();
-    let z = 1.0 - 2.0 * rand::random::<Float>();
+    let phi = 2.0 * consts::PI * sampler::random();
+    let z = 1.0 - 2.0 * sampler::random();
     let r = (1.0 - z.powi(2)).sqrt();
     Vector3::new(r * phi.cos(), r * phi.sin(), z)
 }
@@ -52,3 +72,125 @@ pub fn uniform_sample_sphere() -> Vector3<Float> {
 pub fn uniform_sphere_pdf() -> Float {
     1.0 / (4.0 * consts::PI)
 }
+
+/// Solid angle of the spherical triangle with the unit vector vertices a, b and c
+pub fn spherical_triangle_area(a: Vector3<Float>, b: Vector3<Float>, c: Vector3<Float>) -> Float {
+    // Van Oosterom and Strackee
+    let triple = a.dot(b.cross(c)).abs();
+    2.0 * triple.atan2(1.0 + a.dot(b) + b.dot(c) + c.dot(a))
+}
+
+/// Component of v that is orthogonal to the unit vector n normalized
+fn orthonormal(v: Vector3<Float>, n: Vector3<Float>) -> Vector3<Float> {
+    (v - v.dot(n) * n).normalize()
+}
+
+/// Uniformly sample a direction inside the spherical triangle with the unit vector
+/// vertices a, b and c using the uniform random numbers r1 and r2 (Arvo 1995).
+/// None if the triangle is degenerate.
+pub fn sample_spherical_triangle(
+    a: Vector3<Float>,
+    b: Vector3<Float>,
+    c: Vector3<Float>,
+    r1: Float,
+    r2: Float,
+) -> Option<Vector3<Float>> {
+    let n_ab = a.cross(b);
+    let n_bc = b.cross(c);
+    let n_ca = c.cross(a);
+    if n_ab.magnitude2() == 0.0 || n_bc.magnitude2() == 0.0 || n_ca.magnitude2() == 0.0 {
+        return None;
+    }
+    let (n_ab, n_bc, n_ca) = (n_ab.normalize(), n_bc.normalize(), n_ca.normalize());
+    // Interior angles of the spherical triangle
+    let alpha = n_ab.angle(-n_ca).0;
+    let beta = n_bc.angle(-n_ab).0;
+    let gamma = n_ca.angle(-n_bc).0;
+    let area = alpha + beta + gamma - consts::PI;
+    if area <= 0.0 || !area.is_finite() {
+        return None;
+    }
+    // Find the vertex c_s on the arc between a and c
+    // so that the sub triangle (a, b, c_s) has the sampled area
+    let (sin_s, cos_s) = (r1 * area - alpha).sin_cos();
+    let (sin_a, cos_a) = alpha.sin_cos();
+    let u = cos_s - cos_a;
+    let v = sin_s + sin_a * a.dot(b);
+    let q = ((v * cos_s - u * sin_s) * cos_a - v) / ((v * sin_s + u * cos_s) * sin_a);
+    let q = q.clamp(-1.0, 1.0);
+    let c_s = q * a + (1.0 - q.powi(2)).sqrt() * orthonormal(c, a);
+    // Sample the arc between b and c_s
+    let z = 1.0 - r2 * (1.0 - c_s.dot(b));
+    Some(z * b + (1.0 - z.powi(2)).max(0.0).sqrt() * orthonormal(c_s, b))
+}
+
+/// Compute the normalized cumulative distribution of weights.
+/// The result starts from 0 and has one more element than weights.
+/// Falls back to a uniform distribution if all weights are zero.
+pub fn cdf(weights: &[Float]) -> Vec<Float> {
+    let total: Float = weights.iter().sum();
+    let mut cdf = Vec::with_capacity(weights.len() + 1);
+    let mut sum = 0.0;
+    cdf.push(sum);
+    for (i, &weight) in weights.iter().enumerate() {
+        sum += if total > 0.0 {
+            weight / total
+        } else {
+            1.0 / weights.len().to_float()
+        };
+        // Make sure that the last value is exactly 1
+        if i == weights.len() - 1 {
+            sum = 1.0;
+        }
+        cdf.push(sum);
+    }
+    cdf
+}
+
+/// Sample an index from a cdf computed with cdf().
+/// Return the index and its probability.
+pub fn sample_cdf(cdf: &[Float]) -> (usize, Float) {
+    let r = sampler::random();
+    let i = (cdf.partition_point(|&c| c <= r) - 1).min(cdf.len() - 2);
+    (i, cdf[i + 1] - cdf[i])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn octant_solid_angle() {
+        let (x, y, z) = (Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z());
+        let area = spherical_triangle_area(x, y, z);
+        assert!((area - consts::PI / 2.0).abs() < 1e-6, "{}", area);
+        assert_eq!(spherical_triangle_area(x, y, -y), 0.0);
+    }
+
+    #[test]
+    fn spherical_triangle_samples_are_uniform() {
+        let (x, y, z) = (Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z());
+        let n = 100usize;
+        let mut above_half = [0usize; 3];
+        for i in 0..n {
+            for j in 0..n {
+                let r1 = (i.to_float() + 0.5) / n.to_float();
+                let r2 = (j.to_float() + 0.5) / n.to_float();
+                let dir = sample_spherical_triangle(x, y, z, r1, r2).unwrap();
+                assert!((dir.magnitude() - 1.0).abs() < 1e-6);
+                assert!(dir.x >= -1e-6 && dir.y >= -1e-6 && dir.z >= -1e-6);
+                for c in 0..3 {
+                    if dir[c] > 0.5 {
+                        above_half[c] += 1;
+                    }
+                }
+            }
+        }
+        // The cap above 0.5 covers half of the octant along each axis
+        for &count in &above_half {
+            let fraction = count.to_float() / (n * n).to_float();
+            assert!((fraction - 0.5).abs() < 0.01, "{:?}", above_half);
+        }
+        assert!(sample_spherical_triangle(x, y, y, 0.5, 0.5).is_none());
+    }
+}
@@ -0,0 +1,325 @@
+use std::cell::RefCell;
+
+use cgmath::Point2;
+
+use rand::prelude::*;
+use rand::rngs::StdRng;
+
+use crate::config::SamplerType;
+use crate::consts;
+use crate::float::*;
+
+/// Number of dimensions of a pixel that are stratified over its samples.
+/// Deeper dimensions are rarely reached by all the samples so they are uniform.
+const MAX_STRATIFIED_DIMENSIONS: usize = 64;
+
+thread_local! {
+    /// Each render thread owns its sampler through thread local storage
+    /// so that the bsdfs and lights can draw from it without passing it around
+    static SAMPLER: RefCell<Sampler> = RefCell::new(Sampler::new());
+}
+
+/// Uniform random number in [0, 1) from the sampler of the current thread.
+/// Each call draws from the next dimension of the current sample so that
+/// every decision along a path gets a dimension of its own.
+pub fn random() -> Float {
+    SAMPLER.with(|sampler| sampler.borrow_mut().next())
+}
+
+/// Restart the random numbers of the current thread from seed
+pub fn seed(seed: u64) {
+    SAMPLER.with(|sampler| sampler.borrow_mut().rng = StdRng::seed_from_u64(seed));
+}
+
+/// Start sampling a pixel with n_samples samples.
+/// Threads that never start a pixel draw independent uniform numbers.
+pub fn start_pixel(sampler_type: SamplerType, n_samples: usize) {
+    SAMPLER.with(|sampler| sampler.borrow_mut().start_pixel(sampler_type, n_samples));
+}
+
+/// Start drawing the numbers of the sample with index i of the current pixel
+pub fn start_sample(i: usize) {
+    SAMPLER.with(|sampler| sampler.borrow_mut().start_sample(i));
+}
+
+/// Positions of samples_per_dir^2 samples within the unit square.
+/// Sample j * samples_per_dir + i is inside the stratum (i, j).
+pub fn pixel_samples(samples_per_dir: usize) -> Vec<Point2<Float>> {
+    SAMPLER.with(|sampler| sampler.borrow_mut().pixel_samples(samples_per_dir))
+}
+
+struct Sampler {
+    rng: StdRng,
+    sampler_type: SamplerType,
+    n_samples: usize,
+    sample_i: usize,
+    dimension: usize,
+    /// Random orders of the strata of each dimension of the current pixel
+    permutations: Vec<Vec<usize>>,
+    /// Scrambling seeds of each dimension of the current pixel
+    seeds: Vec<u32>,
+    /// Number of dimensions that have been randomized for the current pixel
+    n_randomized: usize,
+}
+
+impl Sampler {
+    fn new() -> Self {
+        Self {
+            rng: StdRng::from_entropy(),
+            sampler_type: SamplerType::Independent,
+            n_samples: 0,
+            sample_i: 0,
+            dimension: 0,
+            permutations: Vec::new(),
+            seeds: Vec::new(),
+            n_randomized: 0,
+        }
+    }
+
+    fn start_pixel(&mut self, sampler_type: SamplerType, n_samples: usize) {
+        self.sampler_type = sampler_type;
+        self.n_samples = n_samples;
+        self.sample_i = 0;
+        self.dimension = 0;
+        self.n_randomized = 0;
+    }
+
+    fn start_sample(&mut self, i: usize) {
+        self.sample_i = i;
+        self.dimension = 0;
+    }
+
+    fn uniform(&mut self) -> Float {
+        self.rng.gen()
+    }
+
+    fn next(&mut self) -> Float {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        match self.sampler_type {
+            SamplerType::Stratified
+                if self.n_samples >= 2 && dimension < MAX_STRATIFIED_DIMENSIONS =>
+            {
+                self.latin_hypercube(dimension)
+            }
+            SamplerType::Sobol if dimension < MAX_STRATIFIED_DIMENSIONS => {
+                self.padded_sobol(dimension)
+            }
+            _ => self.uniform(),
+        }
+    }
+
+    /// Shuffle the samples and draw the scrambling seeds of the dimensions up to dimension
+    fn randomize(&mut self, dimension: usize) {
+        while self.n_randomized <= dimension {
+            if self.permutations.len() <= self.n_randomized {
+                self.permutations.push(Vec::new());
+                self.seeds.push(0);
+            }
+            let permutation = &mut self.permutations[self.n_randomized];
+            permutation.clear();
+            permutation.extend(0..self.n_samples);
+            permutation.shuffle(&mut self.rng);
+            self.seeds[self.n_randomized] = self.rng.gen();
+            self.n_randomized += 1;
+        }
+    }
+
+    /// Latin hypercube samples: each dimension places the samples of the pixel
+    /// into separate strata whose order is shuffled independently per dimension
+    fn latin_hypercube(&mut self, dimension: usize) -> Float {
+        self.randomize(dimension);
+        let stratum = self.permutations[dimension][self.sample_i % self.n_samples];
+        let r = (stratum.to_float() + self.uniform()) / self.n_samples.to_float();
+        // Rounding can push the value to 1
+        r.min(consts::ONE_MINUS_EPSILON)
+    }
+
+    /// Owen scrambled first Sobol dimension (van der Corput sequence).
+    /// The samples are shuffled per dimension to decorrelate the dimensions.
+    fn padded_sobol(&mut self, dimension: usize) -> Float {
+        self.randomize(dimension);
+        let i = self.permutations[dimension][self.sample_i % self.n_samples.max(1)];
+        let bits = owen_scramble(sobol(0, i as u32), self.seeds[dimension]);
+        bits_to_float(bits)
+    }
+
+    fn pixel_samples(&mut self, n: usize) -> Vec<Point2<Float>> {
+        let n_f = n.to_float();
+        let mut samples = Vec::with_capacity(n * n);
+        match self.sampler_type {
+            // Jittered strata
+            SamplerType::Independent => {
+                for j in 0..n {
+                    for i in 0..n {
+                        samples.push(Point2::new(
+                            (i.to_float() + self.uniform()) / n_f,
+                            (j.to_float() + self.uniform()) / n_f,
+                        ));
+                    }
+                }
+            }
+            // Multi-jittered samples are stratified in 2D and also in each 1D projection
+            SamplerType::Stratified => {
+                for j in 0..n {
+                    for i in 0..n {
+                        samples.push(Point2::new(
+                            (i.to_float() + (j.to_float() + self.uniform()) / n_f) / n_f,
+                            (j.to_float() + (i.to_float() + self.uniform()) / n_f) / n_f,
+                        ));
+                    }
+                }
+                // Shuffling the substrata within columns and rows keeps the stratification
+                for j in 0..n {
+                    for i in 0..n {
+                        let k = self.rng.gen_range(j..n);
+                        let x = samples[j * n + i].x;
+                        samples[j * n + i].x = samples[k * n + i].x;
+                        samples[k * n + i].x = x;
+                    }
+                }
+                for i in 0..n {
+                    for j in 0..n {
+                        let k = self.rng.gen_range(i..n);
+                        let y = samples[j * n + i].y;
+                        samples[j * n + i].y = samples[j * n + k].y;
+                        samples[j * n + k].y = y;
+                    }
+                }
+            }
+            // Owen scrambled 2D Sobol points are stratified over all the elementary
+            // intervals of the pixel so the sample order doesn't need to match the strata
+            SamplerType::Sobol => {
+                let (seed_x, seed_y) = (self.rng.gen(), self.rng.gen());
+                for i in 0..(n * n) as u32 {
+                    samples.push(Point2::new(
+                        bits_to_float(owen_scramble(sobol(0, i), seed_x)),
+                        bits_to_float(owen_scramble(sobol(1, i), seed_y)),
+                    ));
+                }
+            }
+        }
+        samples
+    }
+}
+
+/// Bits of the Sobol point i in the first or second dimension.
+/// The most significant bit is the first binary digit after the point.
+fn sobol(dimension: usize, mut i: u32) -> u32 {
+    let mut bits = 0;
+    // Generator matrices are the identity and the Pascal matrix
+    let mut v: u32 = 1 << 31;
+    while i != 0 {
+        if i & 1 != 0 {
+            bits ^= v;
+        }
+        i >>= 1;
+        v = if dimension == 0 { v >> 1 } else { v ^ (v >> 1) };
+    }
+    bits
+}
+
+/// Nested uniform (Owen) scrambling with the hash of Burley,
+/// "Practical Hash-based Owen Scrambling", 2020
+fn owen_scramble(bits: u32, seed: u32) -> u32 {
+    let mut x = bits.reverse_bits();
+    x ^= x.wrapping_mul(0x3d20_adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x0552_6c56);
+    x ^= x.wrapping_mul(0x53a2_2864);
+    x.reverse_bits()
+}
+
+fn bits_to_float(bits: u32) -> Float {
+    let r = f64::from(bits) / 2f64.powi(32);
+    // Cast is unnecessary with double precision
+    #[allow(clippy::unnecessary_cast)]
+    (r as Float).min(consts::ONE_MINUS_EPSILON)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check that the values fall into separate strata of [0, 1)
+    fn assert_stratified(values: &[Float]) {
+        let n = values.len();
+        let mut strata: Vec<usize> = values
+            .iter()
+            .map(|&v| (v * n.to_float()) as usize)
+            .collect();
+        strata.sort_unstable();
+        assert_eq!(strata, (0..n).collect::<Vec<_>>(), "{:?}", values);
+    }
+
+    #[test]
+    fn multi_jittered_pixel_samples() {
+        seed(1);
+        let n = 4;
+        start_pixel(SamplerType::Stratified, n * n);
+        let samples = pixel_samples(n);
+        for (k, p) in samples.iter().enumerate() {
+            let stratum = ((p.x * n.to_float()) as usize, (p.y * n.to_float()) as usize);
+            assert_eq!(stratum, (k % n, k / n));
+        }
+        assert_stratified(&samples.iter().map(|p| p.x).collect::<Vec<_>>());
+        assert_stratified(&samples.iter().map(|p| p.y).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn latin_hypercube_dimensions() {
+        seed(2);
+        let n = 8;
+        start_pixel(SamplerType::Stratified, n);
+        let mut dimensions = vec![Vec::new(); 3];
+        for i in 0..n {
+            start_sample(i);
+            for values in &mut dimensions {
+                values.push(random());
+            }
+        }
+        for values in &dimensions {
+            assert_stratified(values);
+        }
+    }
+
+    #[test]
+    fn sobol_pixel_samples_are_a_net() {
+        seed(3);
+        let n = 4;
+        start_pixel(SamplerType::Sobol, n * n);
+        let samples = pixel_samples(n);
+        // Every elementary interval of area 1 / 16 contains one sample
+        for log_x in 0..=4 {
+            let (nx, ny): (usize, usize) = (1 << log_x, 1 << (4 - log_x));
+            let mut cells: Vec<usize> = samples
+                .iter()
+                .map(|p| {
+                    let x = (p.x * nx.to_float()) as usize;
+                    let y = (p.y * ny.to_float()) as usize;
+                    y * nx + x
+                })
+                .collect();
+            cells.sort_unstable();
+            assert_eq!(cells, (0..n * n).collect::<Vec<_>>(), "{}x{}", nx, ny);
+        }
+    }
+
+    #[test]
+    fn sobol_dimensions() {
+        seed(4);
+        let n = 16;
+        start_pixel(SamplerType::Sobol, n);
+        let mut dimensions = vec![Vec::new(); 3];
+        for i in 0..n {
+            start_sample(i);
+            for values in &mut dimensions {
+                values.push(random());
+            }
+        }
+        for values in &dimensions {
+            assert_stratified(values);
+        }
+    }
+}
@@ -2,31 +2,37 @@ use std::ops::Deref;
 
 use cgmath::Point2;
 
-use crate::bsdf::Bsdf;
+use crate::bsdf::{Bsdf, Conductor};
 use crate::color::Color;
 use crate::float::*;
 use crate::obj_load;
-use crate::texture::Texture;
+use crate::texture::{self, Texture};
 
+mod conductor;
 mod diffuse;
 mod glossy;
 mod specular;
 
+use self::conductor::*;
 use self::diffuse::*;
 use self::glossy::*;
 use self::specular::*;
 
 /// Scattering model over the whole surface
 pub trait ScatteringT {
-    /// Get the local scattering functions
-    fn local(&self, tex_coords: Point2<Float>) -> Bsdf;
+    /// Get the local scattering functions.
+    /// Footprint is the width of the sampled area in texture coordinates.
+    fn local(&self, tex_coords: Point2<Float>, footprint: Float) -> Bsdf;
     /// The texture to use for preview rendering
     fn preview_texture(&self) -> &Texture;
+    /// Approximate memory used by the decoded textures
+    fn texture_bytes(&self) -> usize;
 }
 
 #[derive(Debug)]
 #[allow(dead_code)]
 pub enum Scattering {
+    Cr(ConductorReflection),
     Dr(DiffuseReflection),
     Gb(GlossyBlend),
     Gr(GlossyReflection),
@@ -35,9 +41,33 @@ pub enum Scattering {
     St(SpecularTransmission),
 }
 
+/// Nearest implemented illumination model for the unimplemented models of the mtl spec
+const ILLUMINATION_FALLBACKS: [(u32, u32); 4] = [
+    // Color on and ambient off
+    (0, 1),
+    // Reflection on and ray trace on
+    (3, 5),
+    // Reflection on and ray trace off
+    (8, 5),
+    // Casts shadows onto invisible surfaces
+    (10, 1),
+];
+
+/// Map unimplemented illumination models to their nearest implemented model
+fn nearest_illumination_model(i: u32) -> u32 {
+    match ILLUMINATION_FALLBACKS.iter().find(|(model, _)| *model == i) {
+        Some(&(_, fallback)) => {
+            println!("Unimplemented illumination model {}!", i);
+            println!("Using illumination model {} instead.", fallback);
+            fallback
+        }
+        None => i,
+    }
+}
+
 fn diffuse_texture(obj_mat: &obj_load::Material) -> Texture {
     match &obj_mat.diffuse_texture {
-        Some(path) => Texture::from_image_path(path),
+        Some(map) => Texture::from_map(map),
         None => {
             let color = Color::from(obj_mat.diffuse_color.unwrap_or([0.0, 0.0, 0.0]));
             Texture::from_color(color)
@@ -45,9 +75,22 @@ fn diffuse_texture(obj_mat: &obj_load::Material) -> Texture {
     }
 }
 
+/// Specular exponent from Ns that is scaled by map_Ns if it is given.
+/// Ns defaults to one with a map so that the -mm option can set the range.
+fn shininess(obj_mat: &obj_load::Material) -> Option<Shininess> {
+    let exponent = obj_mat.specular_exponent.map(ToFloat::to_float);
+    match &obj_mat.specular_exponent_texture {
+        Some(map) => Some(Shininess::Map(
+            exponent.unwrap_or(1.0),
+            texture::load_scalar_map(map),
+        )),
+        None => exponent.map(Shininess::Constant),
+    }
+}
+
 fn specular_texture(obj_mat: &obj_load::Material) -> Texture {
     match &obj_mat.specular_texture {
-        Some(path) => Texture::from_image_path(path),
+        Some(map) => Texture::from_map(map),
         None => {
             let color = Color::from(obj_mat.specular_color.unwrap_or([0.0, 0.0, 0.0]));
             Texture::from_color(color)
@@ -55,6 +98,20 @@ fn specular_texture(obj_mat: &obj_load::Material) -> Texture {
     }
 }
 
+/// Get the conductor of a metallic material.
+/// Presets take precedence over the Ni and Nk values.
+fn conductor(obj_mat: &obj_load::Material) -> Option<Conductor> {
+    if let Some(name) = &obj_mat.metal {
+        match Conductor::from_preset(name) {
+            Some(conductor) => return Some(conductor),
+            None => println!("Unknown metal preset {}!", name),
+        }
+    }
+    let k = Color::from(obj_mat.extinction_coefficient?);
+    let eta = obj_mat.index_of_refraction.unwrap_or(1.0).to_float();
+    Some(Conductor::new(Color::new(eta, eta, eta), k))
+}
+
 fn transmission_filter(obj_mat: &obj_load::Material) -> Texture {
     let mut color = Color::from(
         obj_mat
@@ -75,22 +132,36 @@ impl Scattering {
     pub fn from_obj(obj_mat: &obj_load::Material) -> Self {
         use self::Scattering::*;
 
+        if let Some(conductor) = conductor(obj_mat) {
+            let exponent = obj_mat.specular_exponent.map(ToFloat::to_float);
+            return Cr(ConductorReflection::new(conductor, exponent));
+        }
         let diffuse = diffuse_texture(obj_mat);
         let specular = specular_texture(obj_mat);
-        match obj_mat.illumination_model {
+        // Reflective materials use the index of refraction for fresnel if it is given
+        let eta = obj_mat.index_of_refraction.map(ToFloat::to_float);
+        let illumination_model = obj_mat.illumination_model.map(nearest_illumination_model);
+        match illumination_model {
             Some(2) => {
-                let exponent = obj_mat.specular_exponent.map(ToFloat::to_float);
                 if diffuse.is_black() {
-                    Gr(GlossyReflection::new(specular, exponent.unwrap()))
+                    Gr(GlossyReflection::new(
+                        specular,
+                        shininess(obj_mat).unwrap(),
+                        eta,
+                    ))
                 } else if specular.is_black() {
                     Dr(DiffuseReflection::new(diffuse))
                 } else {
-                    Gb(GlossyBlend::new(diffuse, specular, exponent.unwrap()))
+                    Gb(GlossyBlend::new(
+                        diffuse,
+                        specular,
+                        shininess(obj_mat).unwrap(),
+                    ))
                 }
             }
             Some(5) => {
                 let texture = specular_texture(obj_mat);
-                Sr(SpecularReflection::new(texture))
+                Sr(SpecularReflection::new(texture, eta))
             }
             Some(4) | Some(9) => {
                 let filter = transmission_filter(obj_mat);
@@ -111,17 +182,14 @@ impl Scattering {
                     // and the distribution would be the same anyways
                     St(SpecularTransmission::new(specular, filter, eta))
                 } else {
-                    let exponent = obj_mat.specular_exponent.unwrap().to_float();
-                    Gt(GlossyTransmission::new(specular, filter, exponent, eta))
+                    let shininess = shininess(obj_mat).unwrap();
+                    Gt(GlossyTransmission::new(specular, filter, shininess, eta))
                 }
             }
             Some(i) => {
                 if i > 10 {
                     println!("Illumination model {} is not defined in the mtl spec!", i);
                     println!("Defaulting to diffuse reflection.");
-                } else if i != 1 {
-                    println!("Unimplemented illumination model {}!", i);
-                    println!("Defaulting to diffuse reflection.");
                 }
                 Dr(DiffuseReflection::new(diffuse))
             }
@@ -136,6 +204,7 @@ impl Deref for Scattering {
     fn deref(&self) -> &Self::Target {
         use self::Scattering::*;
         match self {
+            Cr(inner) => inner,
             Dr(inner) => inner,
             Gb(inner) => inner,
             Gr(inner) => inner,
@@ -145,3 +214,40 @@ impl Deref for Scattering {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_model(illumination_model: u32) -> Scattering {
+        Scattering::from_obj(&obj_load::Material {
+            diffuse_color: Some([0.5; 3]),
+            specular_color: Some([0.8; 3]),
+            illumination_model: Some(illumination_model),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn unimplemented_illumination_models() {
+        assert_eq!(nearest_illumination_model(0), 1);
+        assert_eq!(nearest_illumination_model(3), 5);
+        assert_eq!(nearest_illumination_model(8), 5);
+        assert_eq!(nearest_illumination_model(10), 1);
+        for &model in &[1, 2, 4, 5, 6, 7, 9] {
+            assert_eq!(nearest_illumination_model(model), model);
+        }
+        // Fallbacks are implemented themselves
+        for &(_, fallback) in &ILLUMINATION_FALLBACKS {
+            assert_eq!(nearest_illumination_model(fallback), fallback);
+        }
+    }
+
+    #[test]
+    fn fallback_scattering() {
+        assert!(matches!(with_model(0), Scattering::Dr(_)));
+        assert!(matches!(with_model(3), Scattering::Sr(_)));
+        assert!(matches!(with_model(8), Scattering::Sr(_)));
+        assert!(matches!(with_model(10), Scattering::Dr(_)));
+    }
+}
@@ -0,0 +1,41 @@
+use cgmath::{Point2, Vector3};
+
+use crate::bsdf::{Bsdf, Conductor};
+use crate::float::*;
+use crate::texture::Texture;
+
+use super::ScatteringT;
+
+#[derive(Debug)]
+pub struct ConductorReflection {
+    conductor: Conductor,
+    /// Glossy if given and specular otherwise
+    shininess: Option<Float>,
+    /// Normal incidence reflectance
+    preview: Texture,
+}
+
+impl ConductorReflection {
+    pub fn new(conductor: Conductor, shininess: Option<Float>) -> Self {
+        let preview = Texture::from_color(conductor.reflectance(Vector3::unit_z()));
+        Self {
+            conductor,
+            shininess,
+            preview,
+        }
+    }
+}
+
+impl ScatteringT for ConductorReflection {
+    fn local(&self, _tex_coords: Point2<Float>, _footprint: Float) -> Bsdf {
+        Bsdf::conductor_brdf(self.conductor, self.shininess)
+    }
+
+    fn preview_texture(&self) -> &Texture {
+        &self.preview
+    }
+
+    fn texture_bytes(&self) -> usize {
+        self.preview.size_bytes()
+    }
+}
@@ -1,6 +1,7 @@
 use cgmath::Point2;
 
 use crate::bsdf::Bsdf;
+use crate::color::Color;
 use crate::float::*;
 use crate::texture::Texture;
 
@@ -15,14 +16,27 @@ impl DiffuseReflection {
     pub fn new(texture: Texture) -> Self {
         Self { texture }
     }
+
+    /// Get the local scattering with the albedo modulated by color
+    /// if the texture is solid. Textured albedos are left as is.
+    pub fn local_tinted(&self, tex_coords: Point2<Float>, footprint: Float, color: Color) -> Bsdf {
+        match &self.texture {
+            Texture::Solid(albedo) => Bsdf::lambertian_brdf(color * *albedo),
+            Texture::Image(..) => self.local(tex_coords, footprint),
+        }
+    }
 }
 
 impl ScatteringT for DiffuseReflection {
-    fn local(&self, tex_coords: Point2<Float>) -> Bsdf {
-        Bsdf::lambertian_brdf(self.texture.color(tex_coords))
+    fn local(&self, tex_coords: Point2<Float>, footprint: Float) -> Bsdf {
+        Bsdf::lambertian_brdf(self.texture.color_lod(tex_coords, footprint))
     }
 
     fn preview_texture(&self) -> &Texture {
         &self.texture
     }
+
+    fn texture_bytes(&self) -> usize {
+        self.texture.size_bytes()
+    }
 }
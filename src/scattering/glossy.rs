@@ -2,41 +2,81 @@ use cgmath::Point2;
 
 use crate::bsdf::Bsdf;
 use crate::float::*;
-use crate::texture::Texture;
+use crate::texture::{ScalarMap, Texture};
 
 use super::ScatteringT;
 
+/// Specular exponent of a glossy surface
+#[derive(Debug)]
+pub enum Shininess {
+    Constant(Float),
+    /// Exponent multiplied by the value of the map (map_Ns)
+    Map(Float, ScalarMap),
+}
+
+impl Shininess {
+    /// Get the specular exponent at tex_coords
+    pub fn at(&self, tex_coords: Point2<Float>) -> Float {
+        match self {
+            Shininess::Constant(exponent) => *exponent,
+            Shininess::Map(exponent, map) => (exponent * map.value(tex_coords)).max(0.0),
+        }
+    }
+
+    /// Approximate memory used by the map
+    pub fn size_bytes(&self) -> usize {
+        match self {
+            Shininess::Constant(_) => 0,
+            Shininess::Map(_, map) => map.size_bytes(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct GlossyReflection {
     texture: Texture,
-    shininess: Float,
+    shininess: Shininess,
+    /// Index of refraction for dielectric fresnel
+    eta: Option<Float>,
 }
 
 impl GlossyReflection {
-    pub fn new(texture: Texture, shininess: Float) -> Self {
-        Self { texture, shininess }
+    pub fn new(texture: Texture, shininess: Shininess, eta: Option<Float>) -> Self {
+        Self {
+            texture,
+            shininess,
+            eta,
+        }
     }
 }
 
 impl ScatteringT for GlossyReflection {
-    fn local(&self, tex_coords: Point2<Float>) -> Bsdf {
-        Bsdf::microfacet_brdf(self.texture.color(tex_coords), self.shininess)
+    fn local(&self, tex_coords: Point2<Float>, footprint: Float) -> Bsdf {
+        Bsdf::microfacet_brdf(
+            self.texture.color_lod(tex_coords, footprint),
+            self.shininess.at(tex_coords),
+            self.eta,
+        )
     }
 
     fn preview_texture(&self) -> &Texture {
         &self.texture
     }
+
+    fn texture_bytes(&self) -> usize {
+        self.texture.size_bytes() + self.shininess.size_bytes()
+    }
 }
 
 #[derive(Debug)]
 pub struct GlossyBlend {
     diffuse: Texture,
     specular: Texture,
-    shininess: Float,
+    shininess: Shininess,
 }
 
 impl GlossyBlend {
-    pub fn new(diffuse: Texture, specular: Texture, shininess: Float) -> Self {
+    pub fn new(diffuse: Texture, specular: Texture, shininess: Shininess) -> Self {
         Self {
             diffuse,
             specular,
@@ -46,27 +86,36 @@ impl GlossyBlend {
 }
 
 impl ScatteringT for GlossyBlend {
-    fn local(&self, tex_coords: Point2<Float>) -> Bsdf {
-        let diffuse = self.diffuse.color(tex_coords);
-        let specular = self.specular.color(tex_coords);
-        Bsdf::fresnel_blend_brdf(diffuse, specular, self.shininess)
+    fn local(&self, tex_coords: Point2<Float>, footprint: Float) -> Bsdf {
+        let diffuse = self.diffuse.color_lod(tex_coords, footprint);
+        let specular = self.specular.color_lod(tex_coords, footprint);
+        Bsdf::fresnel_blend_brdf(diffuse, specular, self.shininess.at(tex_coords))
     }
 
     fn preview_texture(&self) -> &Texture {
         &self.diffuse
     }
+
+    fn texture_bytes(&self) -> usize {
+        self.diffuse.size_bytes() + self.specular.size_bytes() + self.shininess.size_bytes()
+    }
 }
 
 #[derive(Debug)]
 pub struct GlossyTransmission {
     reflective: Texture,
     transmissive: Texture,
-    shininess: Float,
+    shininess: Shininess,
     eta: Float,
 }
 
 impl GlossyTransmission {
-    pub fn new(reflective: Texture, transmissive: Texture, shininess: Float, eta: Float) -> Self {
+    pub fn new(
+        reflective: Texture,
+        transmissive: Texture,
+        shininess: Shininess,
+        eta: Float,
+    ) -> Self {
         if (eta - 1.0).abs() < crate::consts::EPSILON {
             println!(
                 "IOR is almost one ({:?}). Specular bsdf should be used instead of glossy.",
@@ -83,13 +132,18 @@ impl GlossyTransmission {
 }
 
 impl ScatteringT for GlossyTransmission {
-    fn local(&self, tex_coords: Point2<Float>) -> Bsdf {
-        let reflect = self.reflective.color(tex_coords);
-        let transmit = self.transmissive.color(tex_coords);
-        Bsdf::microfacet_bsdf(reflect, transmit, self.shininess, self.eta)
+    fn local(&self, tex_coords: Point2<Float>, footprint: Float) -> Bsdf {
+        let reflect = self.reflective.color_lod(tex_coords, footprint);
+        let transmit = self.transmissive.color_lod(tex_coords, footprint);
+        let shininess = self.shininess.at(tex_coords);
+        Bsdf::microfacet_bsdf(reflect, transmit, shininess, self.eta)
     }
 
     fn preview_texture(&self) -> &Texture {
         &self.transmissive
     }
+
+    fn texture_bytes(&self) -> usize {
+        self.reflective.size_bytes() + self.transmissive.size_bytes() + self.shininess.size_bytes()
+    }
 }
@@ -9,22 +9,28 @@ use super::ScatteringT;
 #[derive(Debug)]
 pub struct SpecularReflection {
     texture: Texture,
+    /// Index of refraction for dielectric fresnel
+    eta: Option<Float>,
 }
 
 impl SpecularReflection {
-    pub fn new(texture: Texture) -> Self {
-        Self { texture }
+    pub fn new(texture: Texture, eta: Option<Float>) -> Self {
+        Self { texture, eta }
     }
 }
 
 impl ScatteringT for SpecularReflection {
-    fn local(&self, tex_coords: Point2<Float>) -> Bsdf {
-        Bsdf::specular_brdf(self.texture.color(tex_coords))
+    fn local(&self, tex_coords: Point2<Float>, footprint: Float) -> Bsdf {
+        Bsdf::specular_brdf(self.texture.color_lod(tex_coords, footprint), self.eta)
     }
 
     fn preview_texture(&self) -> &Texture {
         &self.texture
     }
+
+    fn texture_bytes(&self) -> usize {
+        self.texture.size_bytes()
+    }
 }
 
 /// Fresnel modulated reflection and transmission
@@ -46,9 +52,9 @@ impl SpecularTransmission {
 }
 
 impl ScatteringT for SpecularTransmission {
-    fn local(&self, tex_coords: Point2<Float>) -> Bsdf {
-        let reflect = self.reflective.color(tex_coords);
-        let transmit = self.transmissive.color(tex_coords);
+    fn local(&self, tex_coords: Point2<Float>, footprint: Float) -> Bsdf {
+        let reflect = self.reflective.color_lod(tex_coords, footprint);
+        let transmit = self.transmissive.color_lod(tex_coords, footprint);
         let eta = self.eta;
         Bsdf::specular_bsdf(reflect, transmit, eta)
     }
@@ -56,4 +62,8 @@ impl ScatteringT for SpecularTransmission {
     fn preview_texture(&self) -> &Texture {
         &self.transmissive
     }
+
+    fn texture_bytes(&self) -> usize {
+        self.reflective.size_bytes() + self.transmissive.size_bytes()
+    }
 }
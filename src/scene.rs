@@ -1,47 +1,249 @@
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use cgmath::prelude::*;
-use cgmath::{Point3, Vector3};
+use cgmath::{Deg, Matrix3, Matrix4, Point3, Rad, Vector3};
 
 use glium::backend::Facade;
 use glium::VertexBuffer;
 
 use crate::aabb::Aabb;
 use crate::bvh::{Bvh, BvhNode, SplitMode};
-use crate::config::RenderConfig;
+use crate::color::Color;
+use crate::config::{LightSampling, RenderConfig, UpAxis};
+use crate::consts;
 use crate::float::*;
 use crate::index_ptr::IndexPtr;
+use crate::instance::{Instance, Prototype};
 use crate::intersect::{Hit, Intersect, Ray};
-use crate::light::Light;
+use crate::light::{DirectionalLight, EnvironmentLight, Light, SpotLight};
+use crate::load;
 use crate::material::{GpuMaterial, Material};
 use crate::mesh::{GpuMesh, Mesh};
 use crate::obj_load;
-use crate::stats;
-use crate::triangle::{Triangle, TriangleBuilder};
+use crate::sampler;
+use crate::stats::{self, MemoryUsage};
+use crate::triangle::{self, Triangle, TriangleArrays, TriangleBuilder};
 use crate::vertex::{RawVertex, Vertex};
 
+/// Directory next to the scene files that contains their cached bvhs
+const BVH_CACHE_DIR: &str = "bvh_cache";
+
+/// Description of a light that is not part of the scene geometry
+#[derive(Clone, Copy, Debug)]
+pub enum AnalyticLight {
+    /// Light that shines to dir from infinitely far away
+    Directional {
+        dir: Vector3<Float>,
+        radiance: Color,
+    },
+    /// Light that shines to a cone around dir
+    Spot {
+        pos: Point3<Float>,
+        dir: Vector3<Float>,
+        total_angle: Rad<Float>,
+        falloff_angle: Rad<Float>,
+        intensity: Color,
+    },
+}
+
+/// Reasons why a scene couldn't be built
+#[derive(Debug)]
+pub enum SceneError {
+    /// File is not in a supported scene format
+    Unsupported(PathBuf),
+    /// File couldn't be read or parsed
+    Load(PathBuf, Box<dyn Error>),
+    /// Scene refers to resources that don't exist
+    Invalid(Vec<String>),
+    /// Scene has no triangles to render
+    Empty,
+    /// Render config can't be used to build the scene
+    Config(String),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Unsupported(path) => {
+                write!(f, "{:?} is not a scene file (.obj, .gltf or .glb)", path)
+            }
+            SceneError::Load(path, err) => write!(f, "Failed to load {:?}: {}", path, err),
+            SceneError::Invalid(errors) => write!(f, "{}", errors.join(", ")),
+            SceneError::Empty => write!(f, "Scene doesn't contain any triangles"),
+            SceneError::Config(err) => write!(f, "Invalid render config: {}", err),
+        }
+    }
+}
+
+impl Error for SceneError {}
+
 pub struct SceneBuilder {
     split_mode: SplitMode,
+    max_leaf_size: usize,
+    bvh_cache: bool,
+    orient_normals: bool,
+    ng_from_vertex_normals: bool,
+    up_axis: UpAxis,
+    backface_cull: bool,
+    backface_cull_shadows: bool,
+    light_sampling: LightSampling,
+    environment_map: Option<PathBuf>,
+    material_overrides: Option<PathBuf>,
+    placements: Option<PathBuf>,
+    analytic_lights: Vec<AnalyticLight>,
 }
 
 impl SceneBuilder {
     pub fn new(config: &RenderConfig) -> Self {
         Self {
             split_mode: config.bvh_split,
+            max_leaf_size: config.bvh_max_leaf_size,
+            bvh_cache: config.bvh_cache,
+            orient_normals: config.orient_normals,
+            ng_from_vertex_normals: config.ng_from_vertex_normals,
+            up_axis: config.up_axis,
+            backface_cull: config.backface_cull,
+            backface_cull_shadows: config.backface_cull_shadows,
+            light_sampling: config.light_sampling,
+            environment_map: config.environment_map.clone(),
+            material_overrides: config.material_overrides.clone(),
+            placements: config.placements.clone(),
+            analytic_lights: Vec::new(),
         }
     }
 
-    pub fn build(&self, scene_file: &Path) -> Arc<Scene> {
-        let obj = obj_load::load_obj(scene_file)
-            .unwrap_or_else(|err| panic!("Failed to load scene {:?}: {}", scene_file, err));
-        let mut arc_scene = Scene::from_obj(&obj);
+    /// Add a light that is not part of the scene geometry
+    pub fn add_light(&mut self, light: AnalyticLight) -> &mut Self {
+        self.analytic_lights.push(light);
+        self
+    }
+
+    pub fn build(&self, scene_file: &Path) -> Result<Arc<Scene>, SceneError> {
+        let mut obj = load::load_object(scene_file)?;
+        if let Some(path) = &self.material_overrides {
+            let overrides = obj_load::load_material_overrides(path)
+                .map_err(|err| SceneError::Load(path.clone(), err))?;
+            for material_override in overrides {
+                match obj.materials.get_mut(&material_override.name) {
+                    Some(material) => material_override.apply(material),
+                    None => println!(
+                        "Tried to override unknown material {}",
+                        material_override.name
+                    ),
+                }
+            }
+        }
+        if !obj.warnings.is_empty() {
+            println!(
+                "{} warnings while loading {:?}. Run check on the scene for details.",
+                obj.warnings.len(),
+                scene_file
+            );
+        }
+        // Missing resources would panic while the materials are built
+        let report = obj_load::check(&obj);
+        if !report.errors.is_empty() {
+            return Err(SceneError::Invalid(report.errors));
+        }
+        if obj.triangles.is_empty() {
+            return Err(SceneError::Empty);
+        }
+        let mut instances = Vec::new();
+        if let Some(path) = &self.placements {
+            let placements = obj_load::load_placements(path)
+                .map_err(|err| SceneError::Load(path.clone(), err))?;
+            let (instanced, mut copies): (Vec<_>, Vec<_>) = placements
+                .into_iter()
+                .partition(|placement| placement.instanced);
+            for placement in instanced {
+                // Lights need to be in the world space so emissive groups are copied
+                if is_emissive_group(&obj, &placement.name) {
+                    println!("Copied instance of emissive group {}", placement.name);
+                    copies.push(placement);
+                } else {
+                    instances.push(placement);
+                }
+            }
+            place_groups(&mut obj, &copies);
+        }
+        let mut prototype_groups: Vec<String> = Vec::new();
+        for placement in &instances {
+            if !prototype_groups.contains(&placement.name) {
+                prototype_groups.push(placement.name.clone());
+            }
+        }
+        if self.up_axis == UpAxis::Z {
+            z_up_to_y_up(&mut obj);
+        }
+        if self.orient_normals {
+            let n_flipped = orient_normals(&mut obj);
+            println!("Flipped {} inconsistently wound triangles", n_flipped);
+        }
+        smooth_normals(&mut obj);
+        let (mut arc_scene, prototype_triangles) =
+            Scene::from_obj(&obj, self.ng_from_vertex_normals, &prototype_groups);
         let scene = Arc::get_mut(&mut arc_scene).unwrap();
-        scene.build_bvh(self.split_mode);
+        if scene.triangles.is_empty() {
+            return Err(SceneError::Invalid(vec![
+                "Scene has no triangles outside of instances".to_string(),
+            ]));
+        }
+        let bvh_cache = if self.bvh_cache {
+            bvh_cache_path(scene_file, self.split_mode)
+        } else {
+            None
+        };
+        scene.build_bvh(self.split_mode, self.max_leaf_size, bvh_cache.as_deref());
+        if !instances.is_empty() {
+            let z_up = self.up_axis == UpAxis::Z;
+            scene.build_instances(
+                prototype_triangles,
+                &prototype_groups,
+                &instances,
+                z_up,
+                self.split_mode,
+                self.max_leaf_size,
+            );
+        }
+        let mut analytic_lights = Vec::new();
+        for light in &self.analytic_lights {
+            let light: Box<dyn Light + Send + Sync> =
+                match *light {
+                    AnalyticLight::Directional { dir, radiance } => Box::new(
+                        DirectionalLight::new(dir, radiance, scene.center(), scene.size()),
+                    ),
+                    AnalyticLight::Spot {
+                        pos,
+                        dir,
+                        total_angle,
+                        falloff_angle,
+                        intensity,
+                    } => Box::new(SpotLight::new(
+                        pos,
+                        dir,
+                        total_angle,
+                        falloff_angle,
+                        intensity,
+                    )),
+                };
+            analytic_lights.push(light);
+        }
         // Lights need to be constructed after bvh build
-        scene.construct_lights();
-        arc_scene
+        scene.construct_lights(analytic_lights, self.light_sampling);
+        if let Some(path) = &self.environment_map {
+            let env = EnvironmentLight::from_path(path, scene.size())
+                .map_err(|err| SceneError::Load(path.clone(), err))?;
+            scene.environment = Some(env);
+        }
+        scene.backface_cull = self.backface_cull;
+        scene.backface_cull_shadows = self.backface_cull_shadows;
+        stats::set_memory(scene.memory_usage());
+        Ok(arc_scene)
     }
 }
 
@@ -51,11 +253,31 @@ pub struct Scene {
     meshes: Vec<Mesh>,
     materials: Vec<Material>,
     triangles: Vec<Triangle>,
-    /// Indices of emissive triangles
-    lights: Vec<usize>,
+    /// Intersection data of the triangles in the same order
+    triangle_arrays: TriangleArrays,
+    /// Emissive triangles and analytic lights sorted by decreasing power
+    lights: Vec<Box<dyn Light + Send + Sync>>,
+    /// Map from the index of an emissive triangle to its index in lights
+    triangle_lights: HashMap<usize, usize>,
+    /// Map from the index of a triangle copied for multiple bvh leaves
+    /// to the index of its first copy
+    duplicates: HashMap<usize, usize>,
+    /// Sampling probabilities of the lights
     light_distribution: Vec<Float>,
+    /// Light for rays that escape the scene
+    environment: Option<EnvironmentLight>,
     aabb: Aabb,
     bvh: Option<Bvh>,
+    /// Shared triangles of the instances
+    prototypes: Vec<Prototype>,
+    /// Instances in the order of the instance bvh leaves
+    instances: Vec<Instance>,
+    /// Bvh over the world space bounding boxes of the instances
+    instance_bvh: Option<Bvh>,
+    /// Ignore hits on the back faces of triangles
+    backface_cull: bool,
+    /// Ignore back face hits of shadow rays
+    backface_cull_shadows: bool,
 }
 
 /// Scene containing resources for GPU rendering
@@ -80,6 +302,242 @@ fn calculate_normal(triangle: &obj_load::Triangle, obj: &obj_load::Object) -> [f
     normal.into_array()
 }
 
+/// Path of the cached bvh of the scene in scene_file
+fn bvh_cache_path(scene_file: &Path, split_mode: SplitMode) -> Option<PathBuf> {
+    let stem = scene_file.file_stem()?.to_str()?;
+    let file_name = format!("{}_{:?}.bvh", stem, split_mode);
+    Some(scene_file.parent()?.join(BVH_CACHE_DIR).join(file_name))
+}
+
+/// Rotate the positions and normals of a z up scene such that y points up
+fn z_up_to_y_up(obj: &mut obj_load::Object) {
+    // Rotation of -90 degrees around the x axis
+    let rotate = |v: &mut [f32; 3]| *v = [v[0], v[2], -v[1]];
+    obj.positions.iter_mut().for_each(rotate);
+    obj.normals.iter_mut().for_each(rotate);
+}
+
+/// Local to world transform of a placement
+fn placement_transform(placement: &obj_load::Placement) -> Matrix4<Float> {
+    let [x, y, z] = placement.rotation;
+    let [sx, sy, sz] = placement.scale;
+    Matrix4::from_translation(Vector3::from_array(placement.translation))
+        * Matrix4::from_angle_z(Deg(z.to_float()))
+        * Matrix4::from_angle_y(Deg(y.to_float()))
+        * Matrix4::from_angle_x(Deg(x.to_float()))
+        * Matrix4::from_nonuniform_scale(sx.to_float(), sy.to_float(), sz.to_float())
+}
+
+/// Check if some triangle of the group or the whole file with * is emissive
+fn is_emissive_group(obj: &obj_load::Object, name: &str) -> bool {
+    obj.triangles
+        .iter()
+        .filter(|tri| name == "*" || tri.group.as_deref() == Some(name))
+        .filter_map(|tri| obj.materials.get(tri.material.as_deref()?))
+        .any(|material| {
+            material.emissive_texture.is_some()
+                || matches!(material.emissive_color, Some(e) if e != [0.0; 3])
+        })
+}
+
+/// Replace the placed groups with transformed copies of them.
+/// Copies get their own vertices so that the triangles are built in world space.
+fn place_groups(obj: &mut obj_load::Object, placements: &[obj_load::Placement]) {
+    let placed: HashSet<&str> = placements.iter().map(|p| p.name.as_str()).collect();
+    let mut triangles: Vec<obj_load::Triangle> = if placed.contains("*") {
+        Vec::new()
+    } else {
+        obj.triangles
+            .iter()
+            .filter(|tri| !matches!(tri.group.as_deref(), Some(name) if placed.contains(name)))
+            .cloned()
+            .collect()
+    };
+    // Copies are separate meshes even if they are next to each other
+    let mut copy_starts = Vec::new();
+    for placement in placements {
+        copy_starts.push(triangles.len());
+        let to_world = placement_transform(placement);
+        let linear = Matrix3::from_cols(
+            to_world.x.truncate(),
+            to_world.y.truncate(),
+            to_world.z.truncate(),
+        );
+        let normal_to_world = match linear.invert() {
+            Some(inverse) => inverse.transpose(),
+            None => {
+                println!("Skipped degenerate transform of {}", placement.name);
+                continue;
+            }
+        };
+        // Mirroring transforms would flip the winding
+        let mirrored = linear.determinant() < 0.0;
+        let mut pos_map = HashMap::new();
+        let mut normal_map = HashMap::new();
+        let n_before = triangles.len();
+        for tri in &obj.triangles {
+            if placement.name != "*" && tri.group.as_deref() != Some(placement.name.as_str()) {
+                continue;
+            }
+            let mut tri = tri.clone();
+            for index_vertex in tri.index_vertices.iter_mut() {
+                let pos_i = index_vertex.pos_i;
+                index_vertex.pos_i = match pos_map.get(&pos_i) {
+                    Some(&i) => i,
+                    None => {
+                        let pos =
+                            to_world.transform_point(Point3::from_array(obj.positions[pos_i]));
+                        obj.positions.push(pos.into_array());
+                        if !obj.colors.is_empty() {
+                            obj.colors.push(obj.colors[pos_i]);
+                        }
+                        pos_map.insert(pos_i, obj.positions.len() - 1);
+                        obj.positions.len() - 1
+                    }
+                };
+                if let Some(normal_i) = index_vertex.normal_i {
+                    index_vertex.normal_i = match normal_map.get(&normal_i) {
+                        Some(&i) => Some(i),
+                        None => {
+                            let normal =
+                                normal_to_world * Vector3::from_array(obj.normals[normal_i]);
+                            obj.normals.push(normal.normalize().into_array());
+                            normal_map.insert(normal_i, obj.normals.len() - 1);
+                            Some(obj.normals.len() - 1)
+                        }
+                    };
+                }
+            }
+            if mirrored {
+                tri.index_vertices.swap(1, 2);
+            }
+            triangles.push(tri);
+        }
+        if triangles.len() == n_before {
+            println!("Tried to place unknown group {}", placement.name);
+        }
+    }
+    obj.triangles = triangles;
+    obj.update_ranges(&copy_starts);
+}
+
+/// Sum of the vertex normals of a triangle or None if some vertex has no normal
+fn vertex_normal_sum(
+    triangle: &obj_load::Triangle,
+    obj: &obj_load::Object,
+) -> Option<Vector3<Float>> {
+    let mut sum = Vector3::zero();
+    for index_vertex in &triangle.index_vertices {
+        sum += Vector3::from_array(obj.normals[index_vertex.normal_i?]);
+    }
+    Some(sum)
+}
+
+/// Flip the winding of triangles whose planar normal points inwards.
+/// Triangles with vertex normals are compared against the mean vertex normal
+/// and others against the direction from the centroid of their group,
+/// if the group is a closed mesh. Return the number of flipped triangles.
+fn orient_normals(obj: &mut obj_load::Object) -> usize {
+    let mut groups = obj.group_ranges.clone();
+    if groups.is_empty() {
+        let mut range = obj_load::Range::new("default", 0);
+        range.end_i = obj.triangles.len();
+        groups.push(range);
+    }
+    let mut n_flipped = 0;
+    for group in &groups {
+        let tris = &obj.triangles[group.start_i..group.end_i];
+        // A mesh is closed if every edge is shared by exactly two triangles
+        let mut edge_counts = HashMap::new();
+        let mut centroid = Vector3::zero();
+        for tri in tris {
+            for i in 0..3 {
+                let p1 = tri.index_vertices[i].pos_i;
+                let p2 = tri.index_vertices[(i + 1) % 3].pos_i;
+                *edge_counts.entry((p1.min(p2), p1.max(p2))).or_insert(0) += 1;
+                centroid += Vector3::from_array(obj.positions[p1]);
+            }
+        }
+        let is_closed = !tris.is_empty() && edge_counts.values().all(|&count| count == 2);
+        centroid /= (3 * tris.len()).max(1).to_float();
+
+        for tri_i in group.start_i..group.end_i {
+            let tri = &obj.triangles[tri_i];
+            let normal = Vector3::from_array(calculate_normal(tri, obj));
+            let reference = match vertex_normal_sum(tri, obj) {
+                Some(reference) => reference,
+                None if is_closed => {
+                    let mut tri_center = Vector3::zero();
+                    for index_vertex in &tri.index_vertices {
+                        tri_center += Vector3::from_array(obj.positions[index_vertex.pos_i]) / 3.0;
+                    }
+                    tri_center - centroid
+                }
+                None => continue,
+            };
+            if normal.dot(reference) < 0.0 {
+                obj.triangles[tri_i].index_vertices.swap(1, 2);
+                n_flipped += 1;
+            }
+        }
+    }
+    n_flipped
+}
+
+/// Generate vertex normals for the smoothed triangles that don't define them.
+/// Normals are area weighted averages of the planar normals of the adjacent
+/// triangles within the same smoothing group.
+fn smooth_normals(obj: &mut obj_load::Object) {
+    let needs_normal = |tri: &obj_load::Triangle| {
+        tri.smoothing_group.is_some()
+            && tri
+                .index_vertices
+                .iter()
+                .any(|index_vertex| index_vertex.normal_i.is_none())
+    };
+    // Sum of the area weighted normals for each position in each smoothing group
+    let mut normal_sums: HashMap<(usize, u32), Vector3<Float>> = HashMap::new();
+    for tri in obj.triangles.iter().filter(|tri| needs_normal(tri)) {
+        let group = tri.smoothing_group.unwrap();
+        let p1 = Vector3::from_array(obj.positions[tri.index_vertices[0].pos_i]);
+        let p2 = Vector3::from_array(obj.positions[tri.index_vertices[1].pos_i]);
+        let p3 = Vector3::from_array(obj.positions[tri.index_vertices[2].pos_i]);
+        // Length of the cross product is twice the area
+        let weighted_normal = (p2 - p1).cross(p3 - p1);
+        for index_vertex in &tri.index_vertices {
+            *normal_sums
+                .entry((index_vertex.pos_i, group))
+                .or_insert_with(Vector3::zero) += weighted_normal;
+        }
+    }
+    // Vertices in different smoothing groups get different normals,
+    // which also makes them separate vertices
+    let mut normal_indices = HashMap::new();
+    let normals = &mut obj.normals;
+    for tri in obj.triangles.iter_mut() {
+        let group = match tri.smoothing_group {
+            Some(group) => group,
+            None => continue,
+        };
+        for index_vertex in tri.index_vertices.iter_mut() {
+            if index_vertex.normal_i.is_some() {
+                continue;
+            }
+            let key = (index_vertex.pos_i, group);
+            let sum = match normal_sums.get(&key) {
+                Some(sum) if !sum.is_zero() => *sum,
+                // Degenerate triangles have no normal
+                _ => continue,
+            };
+            let normal_i = *normal_indices.entry(key).or_insert_with(|| {
+                normals.push(sum.normalize().into_array());
+                normals.len() - 1
+            });
+            index_vertex.normal_i = Some(normal_i);
+        }
+    }
+}
+
 impl Scene {
     fn empty() -> Arc<Self> {
         Arc::new(Self {
@@ -87,163 +545,350 @@ impl Scene {
             meshes: Vec::new(),
             materials: Vec::new(),
             triangles: Vec::new(),
+            triangle_arrays: TriangleArrays::default(),
             lights: Vec::new(),
+            triangle_lights: HashMap::new(),
+            duplicates: HashMap::new(),
             light_distribution: Vec::new(),
+            environment: None,
             aabb: Aabb::empty(),
             bvh: None,
+            prototypes: Vec::new(),
+            instances: Vec::new(),
+            instance_bvh: None,
+            backface_cull: false,
+            backface_cull_shadows: false,
         })
     }
 
-    pub fn from_obj(obj: &obj_load::Object) -> Arc<Self> {
+    /// Convert the obj to a scene. If ng_from_vertex_normals is set, geometric normals
+    /// point to the side of the vertex normals instead of following the winding.
+    /// Triangles of the prototype groups are returned separately for instancing.
+    pub fn from_obj(
+        obj: &obj_load::Object,
+        ng_from_vertex_normals: bool,
+        prototype_groups: &[String],
+    ) -> (Arc<Self>, Vec<Vec<Triangle>>) {
         let _t = stats::time("Convert");
 
         let mut arc_scene = Self::empty();
         let scene = Arc::get_mut(&mut arc_scene).unwrap();
         let mut vertex_map = HashMap::new();
         let mut material_map = HashMap::new();
-        // TODO: handle scenes with no materials
-        for range in &obj.material_ranges {
-            // No need to load unused materials
-            if range.is_empty() {
-                continue;
-            }
-            let material_i = match material_map.get(&range.name) {
-                Some(&i) => i,
-                None => {
-                    let obj_mat = obj
-                        .materials
-                        .get(&range.name)
-                        .unwrap_or_else(|| panic!("Couldn't find material {}!", range.name));
-                    let material = Material::new(obj_mat);
-                    let i = scene.materials.len();
-                    scene.materials.push(material);
-                    material_map.insert(&range.name, i);
-                    i
+        let mut n_degenerate = 0;
+        // Triangles of the prototype groups are converted after the rest of the scene
+        let tri_targets: Vec<Option<usize>> = obj
+            .triangles
+            .iter()
+            .map(|tri| {
+                prototype_groups
+                    .iter()
+                    .position(|name| name == "*" || tri.group.as_deref() == Some(name))
+            })
+            .collect();
+        let mut prototype_triangles = vec![Vec::new(); prototype_groups.len()];
+        let targets = std::iter::once(None).chain((0..prototype_groups.len()).map(Some));
+        for target in targets {
+            for range in &obj.material_ranges {
+                let in_target = |i: &usize| tri_targets[*i] == target;
+                // No need to load unused materials
+                if !(range.start_i..range.end_i).any(|i| in_target(&i)) {
+                    continue;
                 }
-            };
-            let mut mesh = Mesh::new(material_i);
-            for tri in &obj.triangles[range.start_i..range.end_i] {
-                let mut tri_builder = TriangleBuilder::new();
-                let planar_normal = calculate_normal(tri, obj);
-                for index_vertex in &tri.index_vertices {
-                    let vertex_i = match vertex_map.get(index_vertex) {
-                        // Vertex has already been added
-                        Some(&i) => {
-                            mesh.indices.push(i as u32);
-                            i
+                let material_i = match material_map.get(&range.name) {
+                    Some(&i) => i,
+                    None => {
+                        let obj_mat = obj
+                            .materials
+                            .get(&range.name)
+                            .unwrap_or_else(|| panic!("Couldn't find material {}!", range.name));
+                        let material = Material::new(obj_mat);
+                        let i = scene.materials.len();
+                        scene.materials.push(material);
+                        material_map.insert(&range.name, i);
+                        i
+                    }
+                };
+                let mut mesh = Mesh::new(material_i);
+                mesh.is_prototype = target.is_some();
+                for tri_i in (range.start_i..range.end_i).filter(in_target) {
+                    let tri = &obj.triangles[tri_i];
+                    // Degenerate triangles can't be hit and have no barycentric transform
+                    if obj.is_degenerate(tri) {
+                        n_degenerate += 1;
+                        continue;
+                    }
+                    let mut tri_builder = TriangleBuilder::new();
+                    let mut planar_normal = calculate_normal(tri, obj);
+                    if ng_from_vertex_normals {
+                        if let Some(reference) = vertex_normal_sum(tri, obj) {
+                            if Vector3::from_array(planar_normal).dot(reference) < 0.0 {
+                                planar_normal = planar_normal.map(|c| -c);
+                            }
                         }
-                        None => {
-                            let mut save = true;
-                            let pos = obj.positions[index_vertex.pos_i];
-
-                            let tex_coords = match index_vertex.tex_i {
-                                Some(tex_i) => obj.tex_coords[tex_i],
-                                None => [0.0; 2],
-                            };
-                            let normal = match index_vertex.normal_i {
-                                Some(normal_i) => obj.normals[normal_i],
-                                None => {
-                                    // Don't save vertices without normals.
-                                    // Otherwise the first tri defines the normal
-                                    // for all remaining uses of the vertex.
-                                    save = false;
-                                    planar_normal
-                                }
-                            };
+                    }
+                    for index_vertex in &tri.index_vertices {
+                        let vertex_i = match vertex_map.get(index_vertex) {
+                            // Vertex has already been added
+                            Some(&i) => {
+                                mesh.indices.push(i as u32);
+                                i
+                            }
+                            None => {
+                                let mut save = true;
+                                let pos = obj.positions[index_vertex.pos_i];
+                                let color = match obj.colors.get(index_vertex.pos_i) {
+                                    Some(&color) => color,
+                                    None => [1.0; 3],
+                                };
 
-                            mesh.indices.push(scene.vertices.len() as u32);
-                            if save {
-                                vertex_map.insert(index_vertex, scene.vertices.len());
+                                let tex_coords = match index_vertex.tex_i {
+                                    Some(tex_i) => obj.tex_coords[tex_i],
+                                    None => [0.0; 2],
+                                };
+                                let normal = match index_vertex.normal_i {
+                                    Some(normal_i) => obj.normals[normal_i],
+                                    None => {
+                                        // Don't save vertices without normals.
+                                        // Otherwise the first tri defines the normal
+                                        // for all remaining uses of the vertex.
+                                        save = false;
+                                        planar_normal
+                                    }
+                                };
+
+                                mesh.indices.push(scene.vertices.len() as u32);
+                                if save {
+                                    vertex_map.insert(index_vertex, scene.vertices.len());
+                                }
+                                scene
+                                    .vertices
+                                    .push(Vertex::new(pos, normal, tex_coords, color));
+                                scene.vertices.len() - 1
                             }
-                            scene.vertices.push(Vertex::new(pos, normal, tex_coords));
-                            scene.vertices.len() - 1
+                        };
+                        tri_builder.add_vertex(scene.vertex_ptr(vertex_i));
+                    }
+                    let triangle = tri_builder
+                        .build(
+                            planar_normal,
+                            scene.material_ptr(material_i),
+                            scene.meshes.len(),
+                        )
+                        .expect("Failed to build tri!");
+                    match target {
+                        Some(prototype_i) => prototype_triangles[prototype_i].push(triangle),
+                        None => {
+                            scene.aabb.add_aabb(&triangle.aabb());
+                            scene.triangles.push(triangle);
                         }
-                    };
-                    tri_builder.add_vertex(scene.vertex_ptr(vertex_i));
+                    }
+                }
+                if !mesh.indices.is_empty() {
+                    scene.meshes.push(mesh);
                 }
-                let triangle = tri_builder
-                    .build(planar_normal, scene.material_ptr(material_i))
-                    .expect("Failed to build tri!");
-                scene.aabb.add_aabb(&triangle.aabb());
-                scene.triangles.push(triangle);
-            }
-            if !mesh.indices.is_empty() {
-                scene.meshes.push(mesh);
             }
         }
-        arc_scene
+        if n_degenerate > 0 {
+            println!("Skipped {} degenerate triangles", n_degenerate);
+        }
+        (arc_scene, prototype_triangles)
     }
 
     // Warning: this will reorder triangles!
-    fn build_bvh(&mut self, split_mode: SplitMode) {
-        let (bvh, permutation) = Bvh::build(&self.triangles, split_mode);
+    /// Build the bvh or load it from cache_path if the cached bvh is up to date.
+    /// Built bvhs are saved to cache_path.
+    fn build_bvh(
+        &mut self,
+        split_mode: SplitMode,
+        max_leaf_size: usize,
+        cache_path: Option<&Path>,
+    ) {
+        stats::start_bvh();
+        let key = Bvh::cache_key(&self.triangles, split_mode, max_leaf_size);
+        let cached = cache_path.filter(|path| path.exists()).and_then(|path| {
+            match Bvh::load(path, self.triangles.len(), key) {
+                Ok(cached) => Some(cached),
+                Err(err) => {
+                    println!("Rebuilding cached bvh {:?}: {}", path, err);
+                    None
+                }
+            }
+        });
+        let (bvh, references) = cached.unwrap_or_else(|| {
+            let (bvh, references) = Bvh::build(&self.triangles, split_mode, max_leaf_size);
+            if let Some(path) = cache_path {
+                let saved = std::fs::create_dir_all(path.parent().unwrap())
+                    .map_err(|err| err.into())
+                    .and_then(|_| bvh.save(path, &references, key));
+                if let Err(err) = saved {
+                    println!("Failed to cache bvh {:?}: {}", path, err);
+                }
+            }
+            (bvh, references)
+        });
+        stats::stop_bvh(&bvh, self.triangles.len());
         self.bvh = Some(bvh);
+        // Triangles referenced by multiple leaves are copied
+        let mut first_copies = vec![None; self.triangles.len()];
+        for (i, &tri_i) in references.iter().enumerate() {
+            match first_copies[tri_i] {
+                Some(first_i) => {
+                    self.duplicates.insert(i, first_i);
+                }
+                None => first_copies[tri_i] = Some(i),
+            }
+        }
         // TODO: this could be done better
-        self.triangles = permutation
+        self.triangles = references
             .iter()
             .map(|i| self.triangles[*i].clone())
             .collect();
+        self.triangle_arrays = TriangleArrays::new(&self.triangles);
+    }
+
+    /// Build the prototypes from their triangles and place their instances.
+    /// Instances of z up scenes are placed before the scene is rotated to y up.
+    fn build_instances(
+        &mut self,
+        prototype_triangles: Vec<Vec<Triangle>>,
+        prototype_groups: &[String],
+        placements: &[obj_load::Placement],
+        z_up: bool,
+        split_mode: SplitMode,
+        max_leaf_size: usize,
+    ) {
+        let _t = stats::time("Instances");
+        let mut prototype_map = HashMap::new();
+        for (triangles, name) in prototype_triangles.into_iter().zip(prototype_groups) {
+            if triangles.is_empty() {
+                println!("Tried to instance unknown group {}", name);
+                continue;
+            }
+            prototype_map.insert(name, self.prototypes.len());
+            self.prototypes
+                .push(Prototype::new(triangles, split_mode, max_leaf_size));
+        }
+        let z_up_to_y_up = Matrix4::from_angle_x(Deg(-90.0));
+        let mut instances = Vec::new();
+        for placement in placements {
+            let prototype_i = match prototype_map.get(&placement.name) {
+                Some(&i) => i,
+                None => continue,
+            };
+            let mut to_world = placement_transform(placement);
+            if z_up {
+                to_world = z_up_to_y_up * to_world * z_up_to_y_up.transpose();
+            }
+            match Instance::new(prototype_i, &self.prototypes[prototype_i], to_world) {
+                Some(instance) => {
+                    self.aabb.add_aabb(instance.aabb());
+                    instances.push(instance);
+                }
+                None => println!("Skipped degenerate transform of {}", placement.name),
+            }
+        }
+        if instances.is_empty() {
+            return;
+        }
+        // Each instance is a full traversal so they get leaves of their own
+        let (bvh, references) = Bvh::build_bounded(&instances, split_mode, 1);
+        let mut instances: Vec<Option<Instance>> = instances.into_iter().map(Some).collect();
+        self.instances = references
+            .iter()
+            .map(|&i| instances[i].take().unwrap())
+            .collect();
+        self.instance_bvh = Some(bvh);
     }
 
     // Should be called after Bvh build
-    fn construct_lights(&mut self) {
+    fn construct_lights(
+        &mut self,
+        analytic_lights: Vec<Box<dyn Light + Send + Sync>>,
+        sampling: LightSampling,
+    ) {
         let _t = stats::time("Lights");
         if self.bvh.is_none() {
             panic!("Constructing lights when there is no bvh!");
         }
+        // Emissive triangles are tagged with their index
+        let mut lights: Vec<(Option<usize>, Box<dyn Light + Send + Sync>)> = Vec::new();
         for (i, tri) in self.triangles.iter().enumerate() {
-            if tri.material.emissive.is_some() {
-                self.lights.push(i);
+            if tri.material.emissive.is_some() && !self.duplicates.contains_key(&i) {
+                lights.push((Some(i), Box::new(self.triangle_ptr(i))));
             }
         }
-        // Sort light by decreasing power
-        let tris = &self.triangles;
-        self.lights.sort_unstable_by(|&i1, &i2| {
-            let l1 = &tris[i1];
-            let l2 = &tris[i2];
-            let b1 = l1.power().luma();
-            let b2 = l2.power().luma();
-            b2.partial_cmp(&b1).unwrap()
-        });
-        let mut power_distr: Vec<Float> = self
-            .lights
-            .iter()
-            .map(|&i| self.triangles[i].power().luma())
+        lights.extend(analytic_lights.into_iter().map(|light| (None, light)));
+        // Power of textured lights is integrated so compute it only once
+        let mut lights: Vec<_> = lights
+            .into_iter()
+            .map(|(tri_i, light)| (light.power().luma(), tri_i, light))
             .collect();
-        let total_power: Float = power_distr.iter().sum();
-        for power in &mut power_distr {
-            *power /= total_power;
+        // Sort light by decreasing power
+        lights.sort_unstable_by(|(b1, _, _), (b2, _, _)| b2.partial_cmp(b1).unwrap());
+        let mut distr = Vec::with_capacity(lights.len());
+        for (light_i, (power, tri_i, light)) in lights.into_iter().enumerate() {
+            if let Some(tri_i) = tri_i {
+                self.triangle_lights.insert(tri_i, light_i);
+            }
+            self.lights.push(light);
+            distr.push(match sampling {
+                LightSampling::Power => power,
+                LightSampling::Uniform => 1.0,
+            });
+        }
+        let total: Float = distr.iter().sum();
+        for weight in &mut distr {
+            *weight /= total;
         }
-        self.light_distribution = power_distr;
+        self.light_distribution = distr;
     }
 
     pub fn sample_light(&self) -> Option<(&dyn Light, Float)> {
-        let r = rand::random::<Float>();
+        self.sample_light_with(sampler::random())
+            .map(|(light, pdf, _)| (light, pdf))
+    }
+
+    /// Sample a light with the uniform random number r.
+    /// Also return r remapped to [0, 1) within the selected light
+    /// so that it can be reused to sample the light.
+    pub fn sample_light_with(&self, r: Float) -> Option<(&dyn Light, Float, Float)> {
         let mut sum = 0.0;
         for (i, &val) in self.light_distribution.iter().enumerate() {
-            sum += val;
-            if r < sum {
-                let i_tri = self.lights[i];
-                return Some((&self.triangles[i_tri], val));
+            if r < sum + val {
+                let remapped = ((r - sum) / val).min(1.0 - consts::MACHINE_EPSILON);
+                return Some((self.lights[i].as_ref(), val, remapped));
             }
+            sum += val;
         }
         None
     }
 
+    /// Get the light for rays that escape the scene
+    pub fn environment(&self) -> Option<&EnvironmentLight> {
+        self.environment.as_ref()
+    }
+
     /// Pdf of sampling light tri
     pub fn pdf_light(&self, tri: &Triangle) -> Float {
         if tri.material.emissive.is_none() {
             0.0
         } else {
-            for (i, &i_tri) in self.lights.iter().enumerate() {
-                if &self.triangles[i_tri] == tri {
-                    return self.light_distribution[i];
-                }
+            let i = self.triangle_index(tri);
+            let i = self.duplicates.get(&i).copied().unwrap_or(i);
+            match self.triangle_lights.get(&i) {
+                Some(&i) => self.light_distribution[i],
+                None => panic!("Could not find tri {:?} in lights", tri),
             }
-            panic!("Could not find tri {:?} in lights", tri);
         }
     }
 
+    /// Get the index of a triangle that belongs to the scene
+    fn triangle_index(&self, tri: &Triangle) -> usize {
+        let offset = tri as *const Triangle as usize - self.triangles.as_ptr() as usize;
+        offset / std::mem::size_of::<Triangle>()
+    }
+
     /// Load the textures + vertex and index buffers to the GPU
     pub fn upload_data<F: Facade>(&self, facade: &F) -> GpuScene {
         let _t = stats::time("Upload data");
@@ -252,7 +897,8 @@ impl Scene {
             VertexBuffer::new(facade, &raw_vertices).expect("Failed to create vertex buffer!");
         let mut meshes = Vec::new();
         let mut materials = Vec::new();
-        for mesh in &self.meshes {
+        // TODO: draw the instances in the preview
+        for mesh in self.meshes.iter().filter(|mesh| !mesh.is_prototype) {
             meshes.push(mesh.upload_data(facade));
         }
         for material in &self.materials {
@@ -270,11 +916,50 @@ impl Scene {
         IndexPtr::new(&self.materials, i)
     }
 
+    /// Get an IndexPtr to ith triangle
+    fn triangle_ptr(&self, i: usize) -> IndexPtr<Triangle> {
+        IndexPtr::new(&self.triangles, i)
+    }
+
     /// Get an IndexPtr to ith vertex
     fn vertex_ptr(&self, i: usize) -> IndexPtr<Vertex> {
         IndexPtr::new(&self.vertices, i)
     }
 
+    /// Approximate memory used by the geometry, materials and bvhs.
+    /// Instanced triangles are counted once per prototype.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let prototype_tris: usize = self.prototypes.iter().map(Prototype::triangle_bytes).sum();
+        let bvhs = self
+            .bvh
+            .iter()
+            .chain(&self.instance_bvh)
+            .chain(self.prototypes.iter().map(|prototype| &prototype.bvh));
+        MemoryUsage {
+            triangles: self.triangles.len() * size_of::<Triangle>()
+                + self.triangle_arrays.size_bytes()
+                + prototype_tris,
+            vertices: self.vertices.len() * size_of::<Vertex>(),
+            materials: self.materials.iter().map(Material::size_bytes).sum(),
+            bvh: bvhs.map(Bvh::size_bytes).sum(),
+        }
+    }
+
+    /// Bvh over the triangles that aren't instanced
+    pub fn bvh(&self) -> Option<&Bvh> {
+        self.bvh.as_ref()
+    }
+
+    /// Number of unique triangles including the shared triangles of the instances
+    pub fn n_triangles(&self) -> usize {
+        let n_prototype_tris: usize = self.prototypes.iter().map(Prototype::n_triangles).sum();
+        self.triangles.len() - self.duplicates.len() + n_prototype_tris
+    }
+
+    pub fn n_materials(&self) -> usize {
+        self.materials.len()
+    }
+
     /// Get the center of the scene as defined by the bounding box
     pub fn center(&self) -> Point3<Float> {
         self.aabb.center()
@@ -285,6 +970,11 @@ impl Scene {
         self.aabb.longest_edge()
     }
 
+    /// Get the length of the diagonal of the bounding box
+    pub fn diagonal(&self) -> Float {
+        self.aabb.diagonal()
+    }
+
     /// Determine if ray intersects with the scene.
     /// Return true if intersection is found, false otherwise.
     pub fn intersect_shadow<'a>(
@@ -292,18 +982,72 @@ impl Scene {
         ray: &mut Ray,
         node_stack: &mut Vec<(&'a BvhNode, Float)>,
     ) -> bool {
+        ray.cull_backfaces = self.backface_cull_shadows;
         self.intersect_impl(ray, node_stack, true).is_some()
     }
 
+    /// Cheap approximation of intersect_shadow for previews.
+    /// Traversal stops descending at the given bvh depth and treats
+    /// any hit node at that depth as an occluder, which gives blobby shadows.
+    /// Shallower leaves are intersected exactly.
+    pub fn intersect_shadow_approx(&self, ray: &mut Ray, depth: usize) -> bool {
+        Ray::increment_count();
+        ray.cull_backfaces = self.backface_cull_shadows;
+        let bvh = self.bvh.as_ref().unwrap();
+        let root = bvh.root();
+        if root.intersect(ray).is_none() {
+            return false;
+        }
+        let mut node_stack = Vec::with_capacity(bvh.max_stack_size());
+        node_stack.push((root, 0));
+        let mut n_nodes = 0;
+        let mut n_tris = 0;
+        while let Some((node, node_depth)) = node_stack.pop() {
+            n_nodes += 1;
+            if let Some(range) = node.range() {
+                for i in range {
+                    n_tris += 1;
+                    if self.intersect_triangle(i, ray).is_some() {
+                        Ray::count_traversal(n_nodes, n_tris);
+                        return true;
+                    }
+                }
+            } else if node_depth >= depth {
+                Ray::count_traversal(n_nodes, n_tris);
+                return true;
+            } else {
+                let (left, right) = bvh.get_children(node).unwrap();
+                for child in &[left, right] {
+                    if child.intersect(ray).is_some() {
+                        node_stack.push((child, node_depth + 1));
+                    }
+                }
+            }
+        }
+        Ray::count_traversal(n_nodes, n_tris);
+        // Instances are intersected exactly
+        let mut node_stack = Vec::new();
+        self.intersect_instances(ray, &mut node_stack, true)
+            .is_some()
+    }
+
     /// Find the closest hit of the ray
     pub fn intersect<'a>(
         &'a self,
         ray: &mut Ray,
         node_stack: &mut Vec<(&'a BvhNode, Float)>,
     ) -> Option<Hit<'a>> {
+        ray.cull_backfaces = self.backface_cull;
         self.intersect_impl(ray, node_stack, false)
     }
 
+    /// Intersect the ith triangle using the intersection data
+    /// and only load the triangle for candidate hits
+    fn intersect_triangle(&self, i: usize, ray: &Ray) -> Option<Hit<'_>> {
+        let (t, u, v) = self.triangle_arrays.intersect(i, ray)?;
+        self.triangles[i].hit(ray, t, u, v)
+    }
+
     /// Private intersect implementation.
     /// early_exit determines if the first found hit
     /// or the closest hit is returned.
@@ -315,46 +1059,354 @@ impl Scene {
     ) -> Option<Hit<'a>> {
         Ray::increment_count();
         let bvh = self.bvh.as_ref().unwrap();
-        node_stack.push((bvh.root(), 0.0));
+        node_stack.clear();
         let mut closest_hit = None;
-        while let Some((node, t)) = node_stack.pop() {
-            // We've already found a closer hit
-            if ray.length <= t {
-                continue;
-            }
-            if let Some(range) = node.range() {
-                for tri in &self.triangles[range] {
-                    if let Some(hit) = tri.intersect(ray) {
-                        ray.length = hit.t;
-                        closest_hit = Some(hit);
-                        if early_exit {
-                            return closest_hit;
-                        }
-                    }
-                }
-            } else {
-                let (left, right) = bvh.get_children(node).unwrap();
-                // TODO: Could this work without pushing the next node to the stack
-                let left_intersect = left.intersect(ray);
-                let right_intersect = right.intersect(ray);
-                if let Some(t_left) = left_intersect {
-                    if let Some(t_right) = right_intersect {
-                        // Put the closer hit on top
-                        if t_left >= t_right {
-                            node_stack.push((left, t_left));
-                            node_stack.push((right, t_right));
-                        } else {
-                            node_stack.push((right, t_right));
-                            node_stack.push((left, t_left));
-                        }
-                    } else {
-                        node_stack.push((left, t_left));
-                    }
-                } else if let Some(t_right) = right_intersect {
-                    node_stack.push((right, t_right));
+        let stopped = bvh.traverse(ray, node_stack, |i, ray, _| {
+            match self.intersect_triangle(i, ray) {
+                Some(hit) => {
+                    ray.length = hit.t;
+                    closest_hit = Some(hit);
+                    early_exit
                 }
+                None => false,
             }
+        });
+        if stopped {
+            return closest_hit;
+        }
+        self.intersect_instances(ray, node_stack, early_exit)
+            .or(closest_hit)
+    }
+
+    /// Find the closest hit of the ray with the instances that is closer than ray.length.
+    /// early_exit returns the first found hit instead.
+    fn intersect_instances<'a>(
+        &'a self,
+        ray: &mut Ray,
+        node_stack: &mut Vec<(&'a BvhNode, Float)>,
+        early_exit: bool,
+    ) -> Option<Hit<'a>> {
+        let instance_bvh = self.instance_bvh.as_ref()?;
+        let mut closest_hit: Option<Hit> = None;
+        instance_bvh.traverse(ray, node_stack, |i, ray, node_stack| {
+            let instance = &self.instances[i];
+            let prototype = &self.prototypes[instance.prototype_i];
+            // Distances are the same in the local space so the hits can be compared directly
+            let mut local_ray = instance.local_ray(ray);
+            let stopped =
+                prototype.bvh.traverse(
+                    &mut local_ray,
+                    node_stack,
+                    |j, local_ray, _| match prototype.intersect_triangle(j, local_ray) {
+                        Some(mut hit) => {
+                            local_ray.length = hit.t;
+                            hit.instance = Some(instance);
+                            closest_hit = Some(hit);
+                            early_exit
+                        }
+                        None => false,
+                    },
+                );
+            ray.length = local_ray.length;
+            stopped
+        });
+        // Footprints of the local hits are measured with the scaled ray
+        if let Some(hit) = &mut closest_hit {
+            let ng = hit.instance.unwrap().normal_to_world(hit.tri.ng);
+            hit.footprint = triangle::footprint(ray, hit.t, ng);
         }
         closest_hit
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use cgmath::Vector4;
+
+    /// Write the obj and its material library into a temporary directory
+    fn write_scene(name: &str, obj: &str, mtl: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rusty_test_{}", name));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("scene.mtl"), mtl).unwrap();
+        let path = dir.join("scene.obj");
+        fs::write(&path, format!("mtllib scene.mtl\n{}", obj)).unwrap();
+        path
+    }
+
+    fn build(name: &str, path: &Path) -> Result<Arc<Scene>, SceneError> {
+        let config = RenderConfig {
+            bvh_cache: false,
+            ..RenderConfig::bdpt()
+        };
+        build_with(name, path, &config)
+    }
+
+    fn build_with(
+        name: &str,
+        path: &Path,
+        config: &RenderConfig,
+    ) -> Result<Arc<Scene>, SceneError> {
+        let _stats = stats::test_scene(name);
+        SceneBuilder::new(config).build(path)
+    }
+
+    const MTL: &str = "newmtl white\nKd 0.8 0.8 0.8\n";
+
+    #[test]
+    fn degenerate_triangles_are_skipped() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 2 0 0\n\
+                   usemtl white\nf 1 2 3\nf 1 2 4\nf 1 1 3\n";
+        let path = write_scene("degenerate", obj, MTL);
+        let scene = build("degenerate", &path).unwrap();
+        assert_eq!(scene.n_triangles(), 1);
+    }
+
+    #[test]
+    fn truncated_obj() {
+        // Last face refers to a vertex that was cut off
+        let obj = "v 0 0 0\nv 1 0 0\nv 0 1 0\n\
+                   usemtl white\nf 1 2 3\nf 1 3 4\n";
+        let path = write_scene("truncated", obj, MTL);
+        assert!(matches!(
+            build("truncated", &path),
+            Err(SceneError::Load(..))
+        ));
+        // Face that was cut in the middle of the line is skipped
+        let obj = "v 0 0 0\nv 1 0 0\nv 0 1 0\n\
+                   usemtl white\nf 1 2 3\nf 1 2";
+        let path = write_scene("truncated_face", obj, MTL);
+        let scene = build("truncated_face", &path).unwrap();
+        assert_eq!(scene.n_triangles(), 1);
+    }
+
+    #[test]
+    fn missing_texture() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl textured\nf 1 2 3\n";
+        let mtl = "newmtl textured\nKd 1 1 1\nmap_Kd missing.png\n";
+        let path = write_scene("missing_texture", obj, mtl);
+        match build("missing_texture", &path) {
+            Err(SceneError::Invalid(errors)) => {
+                assert_eq!(errors.len(), 1);
+                assert!(errors[0].contains("missing.png"));
+            }
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Scene with a missing texture was built"),
+        }
+    }
+
+    #[test]
+    fn missing_scene_file() {
+        let path = std::env::temp_dir()
+            .join("rusty_test_missing")
+            .join("scene.obj");
+        assert!(matches!(build("missing", &path), Err(SceneError::Load(..))));
+    }
+
+    #[test]
+    fn smoothing_groups() {
+        // Two faces folded along the edge 1-2 that share a smoothing group,
+        // a copy of the second face in another group and an unsmoothed face
+        let obj = "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 1\n\
+                   s 1\nf 1 2 3\nf 2 1 4\ns 2\nf 2 1 4\ns off\nf 1 3 4\n";
+        let path = write_scene("smoothing", obj, MTL);
+        let _stats = stats::test_scene("smoothing");
+        let mut obj = obj_load::load_obj(&path).unwrap();
+        smooth_normals(&mut obj);
+        let normal = |tri_i: usize, vertex_i: usize| {
+            obj.triangles[tri_i].index_vertices[vertex_i]
+                .normal_i
+                .map(|i| obj.normals[i])
+        };
+        let diagonal = Vector3::new(0.0, 1.0, 1.0).normalize().into_array();
+        // Shared edge averages the face normals
+        assert_eq!(normal(0, 0), Some(diagonal));
+        assert_eq!(normal(1, 0), Some(diagonal));
+        // Vertices of a single face keep the face normal
+        assert_eq!(normal(0, 2), Some([0.0, 0.0, 1.0]));
+        assert_eq!(normal(1, 2), Some([0.0, 1.0, 0.0]));
+        // The other group isn't smoothed with the first
+        assert_eq!(normal(2, 0), Some([0.0, 1.0, 0.0]));
+        assert_eq!(normal(3, 0), None);
+        // Face vertex normals are shared within a group: 3 + 1 + 3
+        assert_eq!(obj.normals.len(), 7);
+    }
+
+    #[test]
+    fn ke_makes_an_area_light() {
+        // Glossy illumination model doesn't otherwise emit
+        let mtl = "newmtl lamp\nKd 0.8 0.8 0.8\nKs 0.2 0.2 0.2\nNs 10\nKe 5 5 5\nillum 2\n\
+                   newmtl white\nKd 0.8 0.8 0.8\n";
+        let obj = "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 1\n\
+                   usemtl lamp\nf 1 2 3\nusemtl white\nf 1 2 4\n";
+        let path = write_scene("ke", obj, mtl);
+        let scene = build("ke", &path).unwrap();
+        let (lamps, others): (Vec<_>, Vec<_>) = scene
+            .triangles
+            .iter()
+            .partition(|tri| tri.material.emissive.is_some());
+        assert_eq!((lamps.len(), others.len()), (1, 1));
+        assert_eq!(scene.lights.len(), 1);
+        assert_eq!(scene.pdf_light(lamps[0]), 1.0);
+        // One-sided emission of 5 from a triangle of area 0.5
+        let power = scene.lights[0].power().luma();
+        assert!((power - consts::PI * 5.0 * 0.5).abs() < 1e-4, "{}", power);
+    }
+
+    #[test]
+    fn memory_usage_of_quad() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nusemtl white\nf 1 2 3\nf 1 3 4\n";
+        let path = write_scene("memory", obj, MTL);
+        let scene = build("memory", &path).unwrap();
+        let memory = scene.memory_usage();
+        // Each triangle has three barycentric rows and a geometric normal
+        let tri_bytes =
+            size_of::<Triangle>() + 3 * size_of::<Vector4<Float>>() + size_of::<Vector3<Float>>();
+        assert_eq!(memory.triangles, 2 * tri_bytes);
+        // Vertices are stored per triangle
+        assert_eq!(memory.vertices, 6 * size_of::<Vertex>());
+        // A solid color has no texture data
+        assert_eq!(memory.materials, size_of::<Material>());
+        // Both triangles fit into the root leaf, which is a single cache line
+        assert_eq!(memory.bvh, 64);
+        assert_eq!(
+            memory.total(),
+            2 * tri_bytes + 6 * size_of::<Vertex>() + size_of::<Material>() + 64
+        );
+    }
+
+    #[test]
+    fn backface_culling() {
+        // Front face points towards +z
+        let obj = "v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl white\nf 1 2 3\n";
+        let path = write_scene("backface", obj, MTL);
+        let front = Point3::new(0.2, 0.2, 1.0);
+        let back = Point3::new(0.2, 0.2, -1.0);
+        // Hits of a camera and a shadow ray from behind the triangle
+        let back_hits = |backface_cull, backface_cull_shadows| {
+            let config = RenderConfig {
+                backface_cull,
+                backface_cull_shadows,
+                ..RenderConfig::bdpt()
+            };
+            let scene = build_with("backface", &path, &config).unwrap();
+            let mut node_stack = Vec::new();
+            // Front faces are never culled
+            let mut ray = Ray::from_point(front, back);
+            assert!(scene.intersect(&mut ray, &mut node_stack).is_some());
+            let mut ray = Ray::shadow(front, back);
+            assert!(scene.intersect_shadow(&mut ray, &mut node_stack));
+            let mut ray = Ray::from_point(back, front);
+            let hit = scene.intersect(&mut ray, &mut node_stack).is_some();
+            let mut ray = Ray::shadow(back, front);
+            let shadow_hit = scene.intersect_shadow(&mut ray, &mut node_stack);
+            (hit, shadow_hit)
+        };
+        assert_eq!(back_hits(false, false), (true, true));
+        assert_eq!(back_hits(true, false), (false, true));
+        assert_eq!(back_hits(false, true), (true, false));
+        assert_eq!(back_hits(true, true), (false, false));
+    }
+
+    #[test]
+    fn z_up_scene_is_rotated_to_y_up() {
+        // Triangle facing up at the height of 2 in z up coordinates
+        let obj = "v 0 0 2\nv 1 0 2\nv 0 1 2\nusemtl white\nf 1 2 3\n";
+        let path = write_scene("z_up", obj, MTL);
+        let config = RenderConfig {
+            up_axis: UpAxis::Z,
+            ..RenderConfig::bdpt()
+        };
+        let scene = build_with("z_up", &path, &config).unwrap();
+        let tri = &scene.triangles[0];
+        assert_eq!(
+            tri.positions(),
+            [
+                Point3::new(0.0, 2.0, 0.0),
+                Point3::new(1.0, 2.0, 0.0),
+                Point3::new(0.0, 2.0, -1.0),
+            ]
+        );
+        assert!(
+            (tri.ng - Vector3::unit_y()).magnitude() < 1e-6,
+            "{:?}",
+            tri.ng
+        );
+    }
+
+    #[test]
+    fn pdf_light_matches_selection_frequency() {
+        let mtl = "newmtl dim\nKd 0 0 0\nKe 1 1 1\nnewmtl bright\nKd 0 0 0\nKe 4 4 4\n";
+        let obj = "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 1\n\
+                   usemtl dim\nf 1 2 3\nusemtl bright\nf 1 2 4\n";
+        let path = write_scene("light_sampling", obj, mtl);
+        for &(light_sampling, expected) in &[
+            // Lights are ordered by decreasing power
+            (LightSampling::Power, [0.8, 0.2]),
+            (LightSampling::Uniform, [0.5, 0.5]),
+        ] {
+            let config = RenderConfig {
+                light_sampling,
+                ..RenderConfig::bdpt()
+            };
+            let scene = build_with("light_sampling", &path, &config).unwrap();
+            let light_address = |light: &dyn Light| light as *const dyn Light as *const u8;
+            let mut counts = vec![0usize; scene.lights.len()];
+            // Stratified selection numbers so that the frequencies converge quickly
+            let n = 10_000usize;
+            for i in 0..n {
+                let r = (i.to_float() + 0.5) / n.to_float();
+                let (light, pdf, _) = scene.sample_light_with(r).unwrap();
+                let light_i = scene
+                    .lights
+                    .iter()
+                    .position(|l| light_address(l.as_ref()) == light_address(light))
+                    .unwrap();
+                assert_eq!(pdf, scene.light_distribution[light_i]);
+                counts[light_i] += 1;
+            }
+            for (tri_i, tri) in scene.triangles.iter().enumerate() {
+                let pdf = scene.pdf_light(tri);
+                let light_i = scene.triangle_lights[&tri_i];
+                let frequency = counts[light_i].to_float() / n.to_float();
+                assert!(
+                    (pdf - frequency).abs() < 1e-3,
+                    "{:?}: pdf {} doesn't match frequency {}",
+                    light_sampling,
+                    pdf,
+                    frequency
+                );
+                assert!((pdf - expected[light_i]).abs() < 1e-6, "{}", pdf);
+            }
+        }
+    }
+
+    #[test]
+    fn flipped_winding_follows_vertex_normals() {
+        // Clockwise winding seen from the side of the vertex normals
+        let obj = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\n\
+                   usemtl white\nf 1//1 3//1 2//1\n";
+        let path = write_scene("flipped_winding", obj, MTL);
+        for &(orient_normals, ng_from_vertex_normals, ng_z) in
+            &[(false, false, -1.0), (true, false, 1.0), (false, true, 1.0)]
+        {
+            let config = RenderConfig {
+                orient_normals,
+                ng_from_vertex_normals,
+                ..RenderConfig::bdpt()
+            };
+            let scene = build_with("flipped_winding", &path, &config).unwrap();
+            let ng = scene.triangles[0].ng;
+            assert!(
+                (ng - Vector3::new(0.0, 0.0, ng_z)).magnitude() < 1e-6,
+                "orient_normals: {}, ng_from_vertex_normals: {}, ng: {:?}",
+                orient_normals,
+                ng_from_vertex_normals,
+                ng
+            );
+        }
+    }
+}
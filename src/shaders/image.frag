@@ -5,8 +5,15 @@ in vec2 v_tex_coords;
 out vec4 color;
 
 uniform sampler2D image;
-uniform usampler2D n;
+uniform sampler2D n;
+uniform float exposure_scale;
 uniform bool tone_map;
+uniform bool luminance_tone_map;
+uniform bool desaturate;
+uniform float white;
+uniform bool encode_srgb;
+
+const vec3 luma_weights = vec3(0.2126, 0.7152, 0.0722);
 
 float hable(float x) {
     float A = 0.15;
@@ -19,12 +26,41 @@ float hable(float x) {
     return ((x*(A*x+C*B)+D*E)/(x*(A*x+B)+D*F))-E/F;
 }
 
+vec3 linear_to_srgb(vec3 c) {
+    c = clamp(c, 0.0, 1.0);
+    vec3 low = 12.92 * c;
+    vec3 high = 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055;
+    return mix(high, low, vec3(lessThanEqual(c, vec3(0.0031308))));
+}
+
 void main() {
-    color = texture(image, v_tex_coords) / max(texture(n, v_tex_coords).r, 1u);
-    float luma = dot(color.rgb, vec3(0.2126, 0.7152, 0.0722));
+    color = texture(image, v_tex_coords) / max(texture(n, v_tex_coords).r, 1.0);
+    color.rgb *= exposure_scale;
     if (tone_map) {
-        float hable_scale = hable(luma) / luma;
-        float white_scale = 1.0 / hable(10.0);
-        color.rgb *= hable_scale * white_scale;
+        float white_scale = 1.0 / hable(white);
+        if (luminance_tone_map) {
+            float luma = dot(color.rgb, luma_weights);
+            if (desaturate) {
+                float mapped_luma = hable(luma) * white_scale;
+                if (luma > 0.0) {
+                    color.rgb *= mapped_luma / luma;
+                }
+                // Desaturate out of gamut colors towards their luminance
+                // instead of letting the clamp shift their hue
+                float max_channel = max(color.r, max(color.g, color.b));
+                if (max_channel > 1.0 && max_channel > mapped_luma) {
+                    float t = max((1.0 - mapped_luma) / (max_channel - mapped_luma), 0.0);
+                    color.rgb = mix(vec3(mapped_luma), color.rgb, t);
+                }
+            } else {
+                float hable_scale = hable(luma) / luma;
+                color.rgb *= hable_scale * white_scale;
+            }
+        } else {
+            color.rgb = vec3(hable(color.r), hable(color.g), hable(color.b)) * white_scale;
+        }
+    }
+    if (encode_srgb) {
+        color.rgb = linear_to_srgb(color.rgb);
     }
 }
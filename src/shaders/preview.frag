@@ -7,8 +7,16 @@ out vec4 color;
 
 uniform vec3 u_light;
 uniform bool u_is_emissive;
+uniform bool u_encode_srgb;
 uniform sampler2D tex;
 
+vec3 linear_to_srgb(vec3 c) {
+    c = clamp(c, 0.0, 1.0);
+    vec3 low = 12.92 * c;
+    vec3 high = 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055;
+    return mix(high, low, vec3(lessThanEqual(c, vec3(0.0031308))));
+}
+
 void main() {
     float brightness = dot(normalize(v_normal), normalize(u_light));
     vec3 d_color;
@@ -20,4 +28,7 @@ void main() {
     vec3 dark_color = 0.5 * d_color;
     vec3 regular_color = d_color;
     color = vec4(mix(dark_color, regular_color, brightness), 1.0);
+    if (u_encode_srgb) {
+        color.rgb = linear_to_srgb(color.rgb);
+    }
 }
@@ -1,4 +1,5 @@
 use std::fs::File;
+use std::io::Write;
 use std::path::Path;
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
@@ -6,6 +7,8 @@ use std::time::{Duration, Instant};
 use prettytable::{cell, Row, Table};
 
 use crate::bvh::Bvh;
+use crate::compare::ImageError;
+use crate::config::RenderConfig;
 use crate::float::*;
 use crate::intersect::Ray;
 
@@ -42,17 +45,36 @@ macro_rules! current_scene {
     };
 }
 
-pub fn print_and_save(path: &Path) {
+/// Print and save the stats table followed by the config that was used
+pub fn print_and_save(path: &Path, config: &RenderConfig) {
     let table = stats!().table();
+    let config_section = format!("\nConfig\n{}", config);
     table.printstd();
+    println!("{}", config_section);
     let mut stats_file = File::create(path).unwrap();
     table.print(&mut stats_file).unwrap();
+    writeln!(stats_file, "{}", config_section).unwrap();
 }
 
 pub fn new_scene(name: &str) {
     stats!().new_scene(name);
 }
 
+/// Start a new scene for a test that loads scene files. The timers of
+/// concurrent loads would interleave on the shared stack, so the tests
+/// hold the returned guard for as long as they record statistics.
+/// Traced rays are counted as well, so this includes rendering.
+#[cfg(test)]
+pub fn test_scene(name: &str) -> std::sync::MutexGuard<'static, ()> {
+    lazy_static::lazy_static! {
+        static ref TEST_LOCK: Mutex<()> = Mutex::new(());
+    }
+    // A failed test doesn't invalidate the lock for the others
+    let guard = TEST_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    new_scene(name);
+    guard
+}
+
 pub fn time(name: &str) -> TimerHandle {
     current_scene!().start_timer(name)
 }
@@ -71,15 +93,57 @@ pub fn stop_bvh(bvh: &Bvh, n_tris: usize) {
     current_scene!().analyze_bvh(bvh, n_tris);
 }
 
+/// Record the error of the render against a reference image
+pub fn set_error(error: ImageError) {
+    current_scene!().error = Some(error);
+}
+
 pub fn start_render() {
     let mut handle = time("Render");
     Ray::reset_count();
     handle.deactivate();
 }
 
-pub fn stop_render() {
+/// Stop the render timer and record the stats of the render workers
+pub fn stop_render(workers: Vec<WorkerStats>) {
     stop_timer("Render");
-    current_scene!().ray_count = Ray::count();
+    let mut stats = stats!();
+    let scene_stats = stats.current().unwrap();
+    scene_stats.ray_count = Ray::count();
+    scene_stats.traversal_counts = Ray::traversal_counts();
+    scene_stats.workers = workers;
+}
+
+/// Record the approximate memory used by the scene
+pub fn set_memory(memory: MemoryUsage) {
+    current_scene!().memory = Some(memory);
+}
+
+/// Approximate memory used by the main structures of a scene in bytes
+#[derive(Clone, Debug, Default)]
+pub struct MemoryUsage {
+    /// Triangles and their intersection data
+    pub triangles: usize,
+    pub vertices: usize,
+    /// Materials and their decoded textures
+    pub materials: usize,
+    pub bvh: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.triangles + self.vertices + self.materials + self.bvh
+    }
+}
+
+/// Work done by a single render thread
+#[derive(Clone, Debug, Default)]
+pub struct WorkerStats {
+    pub rays: usize,
+    /// Camera and light paths traced
+    pub paths: usize,
+    /// Samples added to the image
+    pub samples: usize,
 }
 
 struct Statistics {
@@ -107,7 +171,27 @@ impl Statistics {
         let mut mrps = vec![cell!("Mrays/s")];
         let mut n_tris = vec![cell!("Triangles")];
         let mut bvh_size = vec![cell!("Bvh Nodes")];
+        let mut bvh_refs = vec![cell!("Bvh References")];
+        let mut bvh_cost = vec![cell!("Bvh SAH Cost")];
         let mut n_rays = vec![cell!("Rays")];
+        let mut n_paths = vec![cell!("Paths")];
+        let mut n_samples = vec![cell!("Samples")];
+        let mut n_threads = vec![cell!("Threads")];
+        let mut balance = vec![cell!("Ray Balance")];
+        let mut nodes_per_ray = vec![cell!("Nodes/Ray")];
+        let mut tris_per_ray = vec![cell!("Tris/Ray")];
+        let mut memory_rows: Vec<Row> = [
+            "Triangle Memory",
+            "Vertex Memory",
+            "Material Memory",
+            "Bvh Memory",
+            "Total Memory",
+        ]
+        .iter()
+        .map(|label| Row::new(vec![cell!(label)]))
+        .collect();
+        let mut rmse = vec![cell!("RMSE")];
+        let mut rel_mse = vec![cell!("relMSE")];
         for (timer, l) in &self.scene_stats[0].timers {
             let mut row = Row::empty();
             row.add_cell(cell!(format!("{}{}", "| ".repeat(*l), timer.name)));
@@ -118,7 +202,43 @@ impl Statistics {
             mrps.push(cell!(stats.mrps()));
             n_tris.push(cell!(stats.n_tris));
             bvh_size.push(cell!(stats.bvh_size));
+            bvh_refs.push(cell!(stats.bvh_refs));
+            bvh_cost.push(cell!(format!("{:.2}", stats.bvh_cost)));
+            let memory = stats.memory.as_ref().map(|memory| {
+                [
+                    memory.triangles,
+                    memory.vertices,
+                    memory.materials,
+                    memory.bvh,
+                    memory.total(),
+                ]
+            });
+            for (i, row) in memory_rows.iter_mut().enumerate() {
+                match memory {
+                    Some(bytes) => row.add_cell(cell!(format_bytes(bytes[i]))),
+                    None => row.add_cell(cell!("-")),
+                }
+            }
             n_rays.push(cell!(stats.ray_count));
+            let paths: usize = stats.workers.iter().map(|worker| worker.paths).sum();
+            n_paths.push(cell!(paths));
+            let samples: usize = stats.workers.iter().map(|worker| worker.samples).sum();
+            n_samples.push(cell!(samples));
+            n_threads.push(cell!(stats.workers.len()));
+            balance.push(cell!(stats.ray_balance()));
+            let (nodes, tris) = stats.per_ray_counts();
+            nodes_per_ray.push(cell!(nodes));
+            tris_per_ray.push(cell!(tris));
+            match stats.error {
+                Some(error) => {
+                    rmse.push(cell!(format!("{:.4}", error.rmse)));
+                    rel_mse.push(cell!(format!("{:.4}", error.rel_mse)));
+                }
+                None => {
+                    rmse.push(cell!("-"));
+                    rel_mse.push(cell!("-"));
+                }
+            }
             for (name, row) in &mut timer_rows {
                 let timer = stats.get_timer(name).unwrap();
                 row.add_cell(cell!(timer.pretty_duration()));
@@ -131,8 +251,24 @@ impl Statistics {
             table.add_row(row);
         }
         table.add_row(Row::new(n_rays));
+        table.add_row(Row::new(n_paths));
+        table.add_row(Row::new(n_samples));
+        table.add_row(Row::new(n_threads));
+        table.add_row(Row::new(balance));
+        table.add_row(Row::new(nodes_per_ray));
+        table.add_row(Row::new(tris_per_ray));
         table.add_row(Row::new(n_tris));
         table.add_row(Row::new(bvh_size));
+        table.add_row(Row::new(bvh_refs));
+        table.add_row(Row::new(bvh_cost));
+        for row in memory_rows {
+            table.add_row(row);
+        }
+        // Errors are only shown if some scene had a reference
+        if self.scene_stats.iter().any(|stats| stats.error.is_some()) {
+            table.add_row(Row::new(rmse));
+            table.add_row(Row::new(rel_mse));
+        }
         table
     }
 }
@@ -142,8 +278,18 @@ struct SceneStatistics {
     timers: Vec<(Timer, usize)>,
     active_timers: Vec<usize>,
     ray_count: usize,
+    /// Bvh nodes visited and triangles tested if traversal stats are enabled
+    traversal_counts: Option<(usize, usize)>,
+    /// Stats of each render thread
+    workers: Vec<WorkerStats>,
     n_tris: usize,
     bvh_size: usize,
+    /// Triangle references of the bvh leaves
+    bvh_refs: usize,
+    bvh_cost: Float,
+    memory: Option<MemoryUsage>,
+    /// Error against the reference image if there was one
+    error: Option<ImageError>,
 }
 
 impl SceneStatistics {
@@ -153,8 +299,14 @@ impl SceneStatistics {
             timers: Vec::new(),
             active_timers: Vec::new(),
             ray_count: 0,
+            traversal_counts: None,
+            workers: Vec::new(),
             n_tris: 0,
             bvh_size: 0,
+            bvh_refs: 0,
+            bvh_cost: 0.0,
+            memory: None,
+            error: None,
         }
     }
 
@@ -185,6 +337,8 @@ impl SceneStatistics {
     fn analyze_bvh(&mut self, bvh: &Bvh, n_tris: usize) {
         self.n_tris = n_tris;
         self.bvh_size = bvh.size();
+        self.bvh_refs = bvh.n_references();
+        self.bvh_cost = bvh.sah_cost();
     }
 
     fn get_timer(&self, name: &str) -> Option<&Timer> {
@@ -197,6 +351,30 @@ impl SceneStatistics {
         None
     }
 
+    /// Average bvh nodes visited and triangles tested per ray
+    fn per_ray_counts(&self) -> (String, String) {
+        match self.traversal_counts {
+            Some((nodes, tris)) => {
+                let n_rays = self.ray_count.max(1) as f64;
+                (
+                    format!("{:.2}", nodes as f64 / n_rays),
+                    format!("{:.2}", tris as f64 / n_rays),
+                )
+            }
+            None => ("-".to_string(), "-".to_string()),
+        }
+    }
+
+    /// Ratio of the most and the fewest rays cast by a render thread.
+    /// Values well above 1 mean that some threads were idle.
+    fn ray_balance(&self) -> String {
+        let rays = self.workers.iter().map(|worker| worker.rays);
+        match (rays.clone().max(), rays.min()) {
+            (Some(max), Some(min)) if min > 0 => format!("{:.2}", max as f64 / min as f64),
+            _ => "-".to_string(),
+        }
+    }
+
     fn mrps(&self) -> String {
         let render_timer = self.get_timer("Render").unwrap();
         let render_duration = render_timer.duration.unwrap();
@@ -206,6 +384,18 @@ impl SceneStatistics {
     }
 }
 
+/// Bytes in a human readable unit
+fn format_bytes(bytes: usize) -> String {
+    let units = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
+
 #[derive(Clone, Debug)]
 pub struct Timer {
     name: String,
@@ -1,18 +1,23 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
 use std::fs::File;
 use std::io::BufReader;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
 
 use cgmath::Point2;
 
 use glium::backend::Facade;
 use glium::texture::{RawImage2d, SrgbTexture2d};
 
+use image::codecs::hdr::HdrDecoder;
+use image::imageops::FilterType;
 use image::{DynamicImage, GenericImage, GrayImage, ImageFormat, RgbImage};
 
 use crate::color::{self, Color, SrgbColor};
 use crate::float::*;
+use crate::obj_load::{TextureMap, TextureOptions};
 use crate::util;
 
 mod normal_map;
@@ -22,7 +27,89 @@ pub use self::normal_map::{load_normal_map, NormalMap};
 #[derive(Clone)]
 pub enum Texture {
     Solid(Color),
-    Image(RgbImage),
+    Image(Arc<MipMap>, TextureOptions),
+}
+
+lazy_static::lazy_static! {
+    static ref MIP_MAP_CACHE: ImageCache<MipMap> = Mutex::new(HashMap::new());
+}
+
+/// Decoded images that are still in use, keyed by their canonical path
+type ImageCache<T> = Mutex<HashMap<PathBuf, Weak<T>>>;
+
+/// Get the image of path from the cache or load it with load if
+/// it isn't in use anymore. This way materials that share an image
+/// also share the decoded buffer.
+fn cached_image<T>(cache: &ImageCache<T>, path: &Path, load: impl FnOnce(&Path) -> T) -> Arc<T> {
+    let key = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let mut cache = cache.lock().unwrap();
+    if let Some(image) = cache.get(&key).and_then(Weak::upgrade) {
+        return image;
+    }
+    let image = Arc::new(load(path));
+    cache.insert(key, Arc::downgrade(&image));
+    image
+}
+
+/// Share of size_bytes of the shared image that belongs to a single user
+fn shared_size<T>(image: &Arc<T>, size_bytes: usize) -> usize {
+    size_bytes / Arc::strong_count(image)
+}
+
+/// Image pyramid where each level halves the resolution of the previous one
+#[derive(Clone)]
+pub struct MipMap {
+    levels: Vec<RgbImage>,
+}
+
+impl MipMap {
+    fn new(image: RgbImage) -> Self {
+        let mut levels = vec![image];
+        loop {
+            let (width, height) = levels.last().unwrap().dimensions();
+            if width == 1 && height == 1 {
+                break;
+            }
+            let level = image::imageops::resize(
+                levels.last().unwrap(),
+                (width / 2).max(1),
+                (height / 2).max(1),
+                FilterType::Triangle,
+            );
+            levels.push(level);
+        }
+        MipMap { levels }
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.levels.iter().map(|level| level.as_raw().len()).sum()
+    }
+
+    /// Full resolution image
+    fn base(&self) -> &RgbImage {
+        &self.levels[0]
+    }
+
+    /// Sample the pyramid by blending the two levels closest to the footprint width
+    fn color(&self, tex_coords: Point2<Float>, footprint: Float) -> Color {
+        let (width, height) = self.base().dimensions();
+        let texels = footprint * width.max(height).to_float();
+        let max_level = (self.levels.len() - 1).to_float();
+        let lod = if texels > 1.0 {
+            texels.log2().min(max_level)
+        } else {
+            0.0
+        };
+        let level = lod.floor() as usize;
+        let t = lod - lod.floor();
+        let c = bilinear_interp(&self.levels[level], tex_coords).to_linear();
+        if t > 0.0 {
+            let c_next = bilinear_interp(&self.levels[level + 1], tex_coords).to_linear();
+            (1.0 - t) * c + t * c_next
+        } else {
+            c
+        }
+    }
 }
 
 // Bring enum variants to scope
@@ -33,28 +120,52 @@ impl Texture {
         Solid(color)
     }
 
-    pub fn from_image_path(path: &Path) -> Self {
-        Image(load_image(path).unwrap().to_rgb8())
+    pub fn from_map(map: &TextureMap) -> Self {
+        let mip_map = cached_image(&MIP_MAP_CACHE, &map.path, |path| {
+            MipMap::new(load_image(path).unwrap().to_rgb8())
+        });
+        Image(mip_map, map.options)
+    }
+
+    /// Approximate memory used by the decoded image. Images shared through the
+    /// cache are split between their users so that a sum counts them once.
+    pub fn size_bytes(&self) -> usize {
+        match self {
+            Solid(_) => 0,
+            Image(mip_map, _) => shared_size(mip_map, mip_map.size_bytes()),
+        }
     }
 
     pub fn is_black(&self) -> bool {
         match self {
             Solid(color) => color.is_black(),
             // Just assume that a texture is not completely black
-            Image(_) => false,
+            Image(..) => false,
         }
     }
 
-    pub fn color(&self, tex_coords: Point2<Float>) -> Color {
+    /// Get the color at tex_coords filtered over a footprint
+    /// of the given width in texture coordinates
+    pub fn color_lod(&self, tex_coords: Point2<Float>, footprint: Float) -> Color {
         match self {
             Solid(color) => *color,
-            Image(image) => bilinear_interp(image, tex_coords).to_linear(),
+            Image(mip_map, options) => {
+                let tex_coords = transform_tex_coords(options, tex_coords);
+                let footprint = footprint
+                    * options.scale[0]
+                        .abs()
+                        .max(options.scale[1].abs())
+                        .to_float();
+                let color = mip_map.color(tex_coords, footprint);
+                options.gain.to_float() * color + options.base.to_float() * Color::white()
+            }
         }
     }
 
     pub fn upload<F: Facade>(&self, facade: &F) -> SrgbTexture2d {
         match self {
-            Image(image) => {
+            Image(mip_map, _) => {
+                let image = mip_map.base();
                 let image_dim = image.dimensions();
                 let tex_image =
                     RawImage2d::from_raw_rgb_reversed(&image.clone().into_raw(), image_dim);
@@ -71,16 +182,71 @@ impl Texture {
     }
 }
 
+/// Grayscale map of opacities, where 1.0 is fully opaque
+#[derive(Clone)]
+pub struct OpacityMap {
+    map: GrayImage,
+}
+
+impl OpacityMap {
+    pub fn size_bytes(&self) -> usize {
+        self.map.as_raw().len()
+    }
+
+    pub fn opacity(&self, tex_coords: Point2<Float>) -> Float {
+        bilinear_interp(&self.map, tex_coords)
+    }
+}
+
+/// Grayscale map of linear scalar values such as specular exponents
+#[derive(Clone)]
+pub struct ScalarMap {
+    map: GrayImage,
+    options: TextureOptions,
+}
+
+impl ScalarMap {
+    pub fn size_bytes(&self) -> usize {
+        self.map.as_raw().len()
+    }
+
+    pub fn value(&self, tex_coords: Point2<Float>) -> Float {
+        let value: Float =
+            bilinear_interp(&self.map, transform_tex_coords(&self.options, tex_coords));
+        self.options.gain.to_float() * value + self.options.base.to_float()
+    }
+}
+
 // Implement debug manually because images default implementation just prints the whole image
 impl fmt::Debug for Texture {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Image(_) => write!(f, "Image"),
+            Image(..) => write!(f, "Image"),
             Solid(color) => color.fmt(f),
         }
     }
 }
 
+impl fmt::Debug for OpacityMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OpacityMap")
+    }
+}
+
+impl fmt::Debug for ScalarMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ScalarMap")
+    }
+}
+
+/// Apply the scale and offset options to the texture coordinates
+fn transform_tex_coords(options: &TextureOptions, tex_coords: Point2<Float>) -> Point2<Float> {
+    Point2::new(
+        options.scale[0].to_float() * tex_coords.x + options.offset[0].to_float(),
+        options.scale[1].to_float() * tex_coords.y + options.offset[1].to_float(),
+    )
+}
+
 trait GetColor<T> {
     fn get_color(&self, x: u32, y: u32) -> T;
 }
@@ -129,6 +295,47 @@ where
     bottom_c * y_fract + top_c * (1.0 - y_fract)
 }
 
+/// Load an opacity map from path.
+/// Images with an alpha channel use the alpha and others use the luminance as the opacity.
+pub fn load_opacity_map(path: &Path) -> OpacityMap {
+    use image::DynamicImage::*;
+
+    let image = load_image(path).unwrap();
+    let map = match image {
+        ImageLumaA8(_) | ImageRgba8(_) | ImageBgra8(_) | ImageLumaA16(_) | ImageRgba16(_) => {
+            let alpha = image.to_rgba8();
+            GrayImage::from_fn(alpha.width(), alpha.height(), |x, y| {
+                image::Luma([alpha.get_pixel(x, y)[3]])
+            })
+        }
+        _ => image.to_luma8(),
+    };
+    OpacityMap { map }
+}
+
+/// Load a scalar map from the luminance of the image without the sRGB decoding of colors
+pub fn load_scalar_map(map: &TextureMap) -> ScalarMap {
+    let image = load_image(&map.path).unwrap();
+    ScalarMap {
+        map: image.to_luma8(),
+        options: map.options,
+    }
+}
+
+/// Load a high dynamic range (.hdr) image from path.
+/// Return the dimensions and the linear pixels in row major order.
+pub fn load_hdr_image(path: &Path) -> Result<(u32, u32, Vec<Color>), Box<dyn Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let decoder = HdrDecoder::new(reader)?;
+    let metadata = decoder.metadata();
+    let pixels = decoder
+        .read_image_hdr()?
+        .into_iter()
+        .map(|pixel| Color::from(pixel.0))
+        .collect();
+    Ok((metadata.width, metadata.height, pixels))
+}
+
 /// Load an image from path
 fn load_image(path: &Path) -> Result<DynamicImage, Box<dyn Error>> {
     if let Some(ext) = util::lowercase_extension(path) {
@@ -153,3 +360,65 @@ fn load_image(path: &Path) -> Result<DynamicImage, Box<dyn Error>> {
         Err(format!("Image does not have an extension: {:?}", path).into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn larger_footprint_selects_coarser_level() {
+        // Checkerboard of single texels averages to gray on the coarser levels
+        let image = RgbImage::from_fn(8, 8, |x, y| {
+            if (x + y) % 2 == 0 {
+                image::Rgb([0; 3])
+            } else {
+                image::Rgb([255; 3])
+            }
+        });
+        let mip_map = MipMap::new(image);
+        assert_eq!(mip_map.levels.len(), 4);
+        // The corner texel is white on the full resolution level
+        let luma = |footprint: Float| mip_map.color(Point2::new(0.0, 0.0), footprint).luma();
+        let texel = 1.0 / 8.0;
+        assert!((luma(0.5 * texel) - 1.0).abs() < 1e-6);
+        assert!((luma(texel) - 1.0).abs() < 1e-6);
+        // Footprints between the levels blend them
+        let blended = luma(1.5 * texel);
+        let coarse = luma(2.0 * texel);
+        assert!(blended < 1.0 && blended > coarse, "{} {}", blended, coarse);
+        assert!(coarse < 0.5, "{}", coarse);
+        // The whole image is the 1x1 level
+        assert!((luma(1.0) - luma(16.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn materials_share_decoded_images() {
+        let dir = std::env::temp_dir().join("rusty_test_shared_textures");
+        std::fs::create_dir_all(&dir).unwrap();
+        let write_image = |name: &str| {
+            let path = dir.join(name);
+            RgbImage::from_pixel(2, 2, image::Rgb([10, 20, 30]))
+                .save(&path)
+                .unwrap();
+            path
+        };
+        let path = write_image("shared.png");
+        let other_path = write_image("other.png");
+        let mip_map = |path: PathBuf| {
+            let map = TextureMap {
+                path,
+                options: TextureOptions::default(),
+            };
+            match Texture::from_map(&map) {
+                Image(mip_map, _) => mip_map,
+                Solid(_) => panic!("Image map loaded as a solid color"),
+            }
+        };
+        let first = mip_map(path);
+        // Different spellings of the same file share the image
+        let second = mip_map(dir.join(".").join("shared.png"));
+        assert!(Arc::ptr_eq(&first, &second));
+        let other = mip_map(other_path);
+        assert!(!Arc::ptr_eq(&first, &other));
+    }
+}
@@ -1,4 +1,9 @@
-use std::path::Path;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
 
 use cgmath::prelude::*;
 use cgmath::{Point2, Vector3};
@@ -8,16 +13,30 @@ use image::{GrayImage, Rgb, RgbImage};
 use crate::color;
 use crate::float::*;
 
-use super::GetColor;
+use super::{cached_image, shared_size, GetColor, ImageCache};
+
+/// Directory next to the bump maps that contains their converted normal maps
+const CACHE_DIR: &str = "normal_map_cache";
+/// Increment to invalidate the cached conversions when bump_to_normal changes
+const CONVERSION_VERSION: u32 = 1;
 
 #[derive(Clone, Debug)]
 pub struct NormalMap {
-    map: RgbImage,
+    map: Arc<RgbImage>,
+}
+
+lazy_static::lazy_static! {
+    static ref NORMAL_MAP_CACHE: ImageCache<RgbImage> = Mutex::new(HashMap::new());
 }
 
 impl NormalMap {
+    /// Approximate memory used by the decoded map. See Texture::size_bytes.
+    pub fn size_bytes(&self) -> usize {
+        shared_size(&self.map, self.map.as_raw().len())
+    }
+
     pub fn normal(&self, tex_coords: Point2<Float>) -> Vector3<Float> {
-        let n = super::bilinear_interp(&self.map, tex_coords).to_vec();
+        let n = super::bilinear_interp(&*self.map, tex_coords).to_vec();
         (2.0 * n).sub_element_wise(1.0).normalize()
     }
 }
@@ -25,31 +44,81 @@ impl NormalMap {
 /// MTL bump map might refer to bump map or normal map.
 /// Normal maps are returned as is and bump maps are converted to normal maps.
 pub fn load_normal_map(path: &Path) -> NormalMap {
+    let map = cached_image(&NORMAL_MAP_CACHE, path, convert_normal_map);
+    NormalMap { map }
+}
+
+fn convert_normal_map(path: &Path) -> RgbImage {
     use image::DynamicImage::*;
 
+    let cache_path = converted_path(path);
+    if let Some(cache_path) = &cache_path {
+        if let Ok(map) = image::open(cache_path) {
+            return map.to_rgb8();
+        }
+    }
     let image = super::load_image(path).unwrap();
-    let map = match image {
-        ImageLuma8(map) => bump_to_normal(&map),
-        ImageLumaA8(_) => bump_to_normal(&image.to_luma8()),
+    let bump = match image {
+        ImageLuma8(map) => map,
+        ImageLumaA8(_) => image.to_luma8(),
         _ => {
             let rgb_image = image.to_rgb8();
             if is_grayscale(&rgb_image) {
                 println!("Found non-grayscale bump map {:?}", path);
-                bump_to_normal(&image.to_luma8())
+                image.to_luma8()
             } else {
-                rgb_image
+                return rgb_image;
             }
         }
     };
-    // TODO: implement proper caching for converted maps
-    // if let Some(name) = path.file_name() {
-    //     let mut s = name.to_str().unwrap().to_string();
-    //     s.insert_str(0, "to_normal_");
-    //     let save_path = path.with_file_name(s).with_extension("png");
-    //     map.save(&save_path).unwrap();
-    //     println!("saved {:?}", save_path);
-    // }
-    NormalMap { map }
+    let map = bump_to_normal(&bump);
+    if let Some(cache_path) = &cache_path {
+        save_converted(&map, cache_path);
+    }
+    map
+}
+
+/// Path of the cached normal map converted from the bump map in path.
+/// The file name contains a hash of the size and modification time of the bump map
+/// so that modified bump maps are converted again.
+fn converted_path(path: &Path) -> Option<PathBuf> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    let mut hasher = DefaultHasher::new();
+    CONVERSION_VERSION.hash(&mut hasher);
+    metadata.len().hash(&mut hasher);
+    modified.hash(&mut hasher);
+    let stem = path.file_stem()?.to_str()?;
+    let file_name = format!("{}_{:016x}.png", stem, hasher.finish());
+    Some(path.parent()?.join(CACHE_DIR).join(file_name))
+}
+
+/// Save the converted map to the cache and remove stale conversions of the same bump map
+fn save_converted(map: &RgbImage, cache_path: &Path) {
+    let cache_dir = cache_path.parent().unwrap();
+    if let Err(err) = std::fs::create_dir_all(cache_dir) {
+        println!("Failed to create normal map cache {:?}: {}", cache_dir, err);
+        return;
+    }
+    let file_name = cache_path.file_name().unwrap().to_str().unwrap();
+    // Strip the hash to get the prefix shared by all conversions of the bump map
+    let prefix = &file_name[..file_name.rfind('_').unwrap() + 1];
+    if let Ok(entries) = std::fs::read_dir(cache_dir) {
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            // Hashes are always 16 hex digits
+            let stale = name.starts_with(prefix)
+                && name.len() == file_name.len()
+                && name.as_ref() != file_name;
+            if stale {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+    if let Err(err) = map.save(cache_path) {
+        println!("Failed to cache normal map {:?}: {}", cache_path, err);
+    }
 }
 
 /// Detect if an RgbImage is infact a grayscale image
@@ -101,3 +170,62 @@ pub fn bump_to_normal(bump: &GrayImage) -> RgbImage {
     }
     nm
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use image::Luma;
+
+    /// Write a horizontal ramp bump map into dir
+    fn write_bump_map(dir: &Path, name: &str) -> PathBuf {
+        let bump = GrayImage::from_fn(4, 4, |x, _| Luma([60 * x as u8]));
+        let path = dir.join(name);
+        bump.save(&path).unwrap();
+        path
+    }
+
+    fn cached_files(dir: &Path) -> usize {
+        fs::read_dir(dir.join(CACHE_DIR)).unwrap().count()
+    }
+
+    #[test]
+    fn converted_maps_are_cached_on_disk() {
+        let dir = std::env::temp_dir().join("rusty_test_normal_map_cache");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let bump = write_bump_map(&dir, "bump.png");
+        let converted = convert_normal_map(&bump);
+        let cache_path = converted_path(&bump).unwrap();
+        assert_eq!(image::open(&cache_path).unwrap().to_rgb8(), converted);
+        // Replace the cached map to see that it's loaded instead of converted again
+        let marker = RgbImage::from_pixel(2, 2, Rgb([1, 2, 3]));
+        marker.save(&cache_path).unwrap();
+        assert_eq!(convert_normal_map(&bump), marker);
+        assert_eq!(cached_files(&dir), 1);
+        // Other bump maps are converted separately
+        let other = write_bump_map(&dir, "other.png");
+        assert_eq!(convert_normal_map(&other), converted);
+        assert_eq!(cached_files(&dir), 2);
+    }
+
+    #[test]
+    fn materials_share_normal_maps() {
+        let dir = std::env::temp_dir().join("rusty_test_shared_normal_maps");
+        fs::create_dir_all(&dir).unwrap();
+        let write_map = |name: &str| {
+            let path = dir.join(name);
+            RgbImage::from_pixel(2, 2, Rgb([128, 128, 255]))
+                .save(&path)
+                .unwrap();
+            path
+        };
+        let path = write_map("shared.png");
+        let other_path = write_map("other.png");
+        let map = load_normal_map(&path);
+        assert!(Arc::ptr_eq(&map.map, &load_normal_map(&path).map));
+        assert!(!Arc::ptr_eq(&map.map, &load_normal_map(&other_path).map));
+    }
+}
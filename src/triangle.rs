@@ -1,15 +1,19 @@
 use std::cmp::PartialEq;
 
 use cgmath::prelude::*;
-use cgmath::{Matrix3, Matrix4, Point2, Point3, Vector3};
+use cgmath::{Matrix3, Matrix4, Point2, Point3, Vector3, Vector4};
 
 use crate::aabb::{self, Aabb};
+use crate::color::Color;
 use crate::float::*;
 use crate::index_ptr::IndexPtr;
 use crate::intersect::{Hit, Intersect, Ray};
 use crate::material::Material;
 use crate::vertex::Vertex;
 
+/// Limits the footprint stretch at grazing angles
+const MIN_FOOTPRINT_COS: Float = 0.05;
+
 #[derive(Default)]
 pub struct TriangleBuilder {
     vertices: Vec<IndexPtr<Vertex>>,
@@ -26,7 +30,12 @@ impl TriangleBuilder {
         self.vertices.push(vertex);
     }
 
-    pub fn build(self, ng: [f32; 3], material: IndexPtr<Material>) -> Result<Triangle, String> {
+    pub fn build(
+        self,
+        ng: [f32; 3],
+        material: IndexPtr<Material>,
+        mesh_i: usize,
+    ) -> Result<Triangle, String> {
         if self.vertices.len() != 3 {
             Err("Triangle doesn't have 3 vertices!".to_string())
         } else {
@@ -36,6 +45,7 @@ impl TriangleBuilder {
                 self.vertices[2].clone(),
                 Vector3::from_array(ng),
                 material,
+                mesh_i,
             ))
         }
     }
@@ -51,6 +61,8 @@ pub struct Triangle {
     pub ng: Vector3<Float>, // TODO: check if this is worth saving
     to_barycentric: Matrix4<Float>,
     pub material: IndexPtr<Material>,
+    /// Index of the mesh that the triangle belongs to
+    pub mesh_i: usize,
 }
 
 impl Triangle {
@@ -60,6 +72,7 @@ impl Triangle {
         v3: IndexPtr<Vertex>,
         ng: Vector3<Float>,
         material: IndexPtr<Material>,
+        mesh_i: usize,
     ) -> Self {
         let to_barycentric = Self::world_to_barycentric(v1.p, v2.p, v3.p);
         Self {
@@ -69,26 +82,37 @@ impl Triangle {
             ng,
             to_barycentric,
             material,
+            mesh_i,
         }
     }
 
-    /// Compute the conversion from world space to barycentric space
+    /// Compute the conversion from world space to barycentric space.
+    /// The inverse is solved in double precision since thin triangles lose most
+    /// of their precision to rounding in single precision.
     fn world_to_barycentric(
         p1: Point3<Float>,
         p2: Point3<Float>,
         p3: Point3<Float>,
     ) -> Matrix4<Float> {
-        // TODO: there should be a way to do this without matrix inversion
-        let z = (p2 - p1).cross(p3 - p1).normalize();
-        let from_barycentric = Matrix4::from_cols(
-            (p2 - p1).extend(0.0),
-            (p3 - p1).extend(0.0),
-            z.extend(0.0),
-            p1.to_homogeneous(),
-        );
-        from_barycentric
-            .invert()
-            .expect("Non invertible barycentric tranform")
+        let p1 = p1.cast::<f64>().unwrap().to_vec();
+        let e1 = p2.cast::<f64>().unwrap().to_vec() - p1;
+        let e2 = p3.cast::<f64>().unwrap().to_vec() - p1;
+        let cross = e1.cross(e2);
+        let area = cross.magnitude();
+        if area <= 0.0 || !area.is_finite() {
+            panic!("Non invertible barycentric tranform");
+        }
+        let z = cross / area;
+        // Rows of the inverse of the matrix with e1, e2, z and p1 as its columns
+        let row = |r: Vector3<f64>| r.extend(-r.dot(p1));
+        let to_barycentric = Matrix4::from_cols(
+            row(e2.cross(z) / area),
+            row(z.cross(e1) / area),
+            row(z),
+            Vector4::unit_w(),
+        )
+        .transpose();
+        to_barycentric.cast().unwrap()
     }
 
     /// Compute the conversion from tangent space to world space given a normal
@@ -142,6 +166,24 @@ impl Triangle {
         (p, n, t)
     }
 
+    /// Get the barycentric texture coordinates
+    pub fn bary_t(&self, u: Float, v: Float) -> Point2<Float> {
+        let b1 = 1.0 - u - v;
+        b1 * self.v1.t + u * self.v2.t.to_vec() + v * self.v3.t.to_vec()
+    }
+
+    /// Get the barycentric vertex color
+    pub fn bary_c(&self, u: Float, v: Float) -> Color {
+        let b1 = 1.0 - u - v;
+        b1 * self.v1.c + u * self.v2.c + v * self.v3.c
+    }
+
+    /// Get the barycentric coordinates (u, v) of a point on the triangle
+    pub fn barycentric(&self, p: Point3<Float>) -> (Float, Float) {
+        let bary = self.to_barycentric * p.to_homogeneous();
+        (bary.x, bary.y)
+    }
+
     pub fn aabb(&self) -> Aabb {
         let mut min = self.v1.p;
         min = aabb::min_point(&min, &self.v2.p);
@@ -152,10 +194,22 @@ impl Triangle {
         Aabb { min, max }
     }
 
+    pub fn positions(&self) -> [Point3<Float>; 3] {
+        [self.v1.p, self.v2.p, self.v3.p]
+    }
+
     pub fn center(&self) -> Point3<Float> {
         Point3::centroid(&[self.v1.p, self.v2.p, self.v3.p])
     }
 
+    /// Texture coordinate units per world space unit
+    pub fn tex_coord_density(&self) -> Float {
+        let e1 = self.v2.t - self.v1.t;
+        let e2 = self.v3.t - self.v1.t;
+        let tex_area = 0.5 * (e1.x * e2.y - e1.y * e2.x).abs();
+        (tex_area / self.area()).sqrt()
+    }
+
     pub fn area(&self) -> Float {
         0.5 / self.to_barycentric.determinant().abs()
     }
@@ -164,29 +218,128 @@ impl Triangle {
         self.material.emissive.is_some()
     }
 
-    pub fn sample() -> (Float, Float) {
-        let r1: Float = rand::random();
-        let r2: Float = rand::random();
+    /// Map uniform random numbers r1 and r2 to uniformly distributed barycentrics
+    pub fn sample_with(r1: Float, r2: Float) -> (Float, Float) {
         let sr1 = r1.sqrt();
         let u = 1.0 - sr1;
         let v = r2 * sr1;
         (u, v)
     }
+
+    /// Complete a hit at distance t and barycentrics (u, v) found from the intersection data
+    pub fn hit(&self, ray: &Ray, t: Float, u: Float, v: Float) -> Option<Hit<'_>> {
+        // Ignore hits to the cut out parts of the material
+        if self.material.has_opacity_map() && self.material.is_cut_out(self.bary_t(u, v)) {
+            return None;
+        }
+        Some(Hit {
+            tri: self,
+            t,
+            u,
+            v,
+            footprint: footprint(ray, t, self.ng),
+            instance: None,
+        })
+    }
+}
+
+/// Width of the footprint of the ray at distance t on a surface with normal ng
+pub fn footprint(ray: &Ray, t: Float, ng: Vector3<Float>) -> Float {
+    if ray.spread > 0.0 {
+        // Grazing angles stretch the footprint along the surface
+        let cos_t = ray.dir.dot(ng).abs().max(MIN_FOOTPRINT_COS);
+        ray.spread * t / cos_t
+    } else {
+        0.0
+    }
 }
 
 impl<'a> Intersect<'a, Hit<'a>> for Triangle {
-    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+    fn intersect(&self, ray: &Ray) -> Option<Hit<'_>> {
+        if ray.cull_backfaces && ray.dir.dot(self.ng) > 0.0 {
+            return None;
+        }
         let bary_o = self.to_barycentric * ray.orig.to_homogeneous();
         let bary_d = self.to_barycentric * ray.dir.extend(0.0);
-        let t = -bary_o.z / bary_d.z;
-        let u = bary_o.x + t * bary_d.x;
-        let v = bary_o.y + t * bary_d.y;
-        if u >= 0.0 && v >= 0.0 && u + v <= 1.0 && t > 0.0 && t < ray.length {
-            Some(Hit { tri: self, t, u, v })
-        } else {
-            None
+        let (t, u, v) = barycentric_hit(bary_o.truncate(), bary_d.truncate(), ray)?;
+        self.hit(ray, t, u, v)
+    }
+}
+
+/// Distance and barycentrics of the hit given the ray origin and direction in barycentric space
+fn barycentric_hit(
+    bary_o: Vector3<Float>,
+    bary_d: Vector3<Float>,
+    ray: &Ray,
+) -> Option<(Float, Float, Float)> {
+    let t = -bary_o.z / bary_d.z;
+    let u = bary_o.x + t * bary_d.x;
+    let v = bary_o.y + t * bary_d.y;
+    if u >= 0.0 && v >= 0.0 && u + v <= 1.0 && t > 0.0 && t < ray.length {
+        Some((t, u, v))
+    } else {
+        None
+    }
+}
+
+/// Intersection data of triangles stored as separate arrays so that
+/// bvh leaves can be tested without loading the triangles themselves
+#[derive(Default)]
+pub struct TriangleArrays {
+    /// Rows of the world to barycentric transforms.
+    /// The last row is not needed for intersections.
+    bary_x: Vec<Vector4<Float>>,
+    bary_y: Vec<Vector4<Float>>,
+    bary_z: Vec<Vector4<Float>>,
+    /// Geometric normals
+    ng: Vec<Vector3<Float>>,
+}
+
+impl TriangleArrays {
+    pub fn new(triangles: &[Triangle]) -> Self {
+        Self {
+            bary_x: triangles
+                .iter()
+                .map(|tri| tri.to_barycentric.row(0))
+                .collect(),
+            bary_y: triangles
+                .iter()
+                .map(|tri| tri.to_barycentric.row(1))
+                .collect(),
+            bary_z: triangles
+                .iter()
+                .map(|tri| tri.to_barycentric.row(2))
+                .collect(),
+            ng: triangles.iter().map(|tri| tri.ng).collect(),
         }
     }
+
+    /// Intersect the ith triangle without checking its material.
+    /// Return the distance and barycentrics of the hit.
+    pub fn intersect(&self, i: usize, ray: &Ray) -> Option<(Float, Float, Float)> {
+        if ray.cull_backfaces && ray.dir.dot(self.ng[i]) > 0.0 {
+            return None;
+        }
+        // Same operations as the matrix product so that the hits match Triangle::intersect
+        let orig = ray.orig.to_homogeneous();
+        let dir = ray.dir.extend(0.0);
+        let bary_o = Vector3::new(
+            self.bary_x[i].dot(orig),
+            self.bary_y[i].dot(orig),
+            self.bary_z[i].dot(orig),
+        );
+        let bary_d = Vector3::new(
+            self.bary_x[i].dot(dir),
+            self.bary_y[i].dot(dir),
+            self.bary_z[i].dot(dir),
+        );
+        barycentric_hit(bary_o, bary_d, ray)
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        let row_size = std::mem::size_of::<Vector4<Float>>();
+        3 * self.bary_x.len() * row_size + self.ng.len() * std::mem::size_of::<Vector3<Float>>()
+    }
 }
 
 impl PartialEq for Triangle {
@@ -1,6 +1,7 @@
 use cgmath::{Point2, Point3, Vector3};
 use glium::implement_vertex;
 
+use crate::color::Color;
 use crate::float::*;
 
 /// Vertex using raw arrays that can be inserted in vertex buffers
@@ -19,14 +20,17 @@ pub struct Vertex {
     pub p: Point3<Float>,
     pub n: Vector3<Float>,
     pub t: Point2<Float>,
+    /// Vertex color, white if the mesh has none
+    pub c: Color,
 }
 
 impl Vertex {
-    pub fn new(pos: [f32; 3], normal: [f32; 3], tex_coords: [f32; 2]) -> Self {
+    pub fn new(pos: [f32; 3], normal: [f32; 3], tex_coords: [f32; 2], color: [f32; 3]) -> Self {
         Self {
             p: Point3::from_array(pos),
             n: Vector3::from_array(normal),
             t: Point2::from_array(tex_coords),
+            c: Color::from(color),
         }
     }
 }